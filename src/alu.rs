@@ -14,22 +14,22 @@ pub fn alu(insn: &Instruction, src1: i32, src2: i32, _clk: u64) -> i32 {
         And => (src1 & src2, false),
         Or => (src1 | src2, false),
         Xor => (src1 ^ src2, false),
-        BranchOnEqual => (!(src1 == src2) as i32, false),
-        BranchOnNotEqual => (!(src1 != src2) as i32, false),
-        BranchOnLessThan => (!(src1 < src2) as i32, false),
+        BranchOnEqual => ((src1 != src2) as i32, false),
+        BranchOnNotEqual => ((src1 == src2) as i32, false),
+        BranchOnLessThan => ((src1 >= src2) as i32, false),
         BranchOnLessThanUnsigned => (
-            !((src1 as u32) < (src2 as u32)) as i32,
+            ((src1 as u32) >= (src2 as u32)) as i32,
             false,
         ),
-        BranchOnGreaterOrEqual => (!(src1 >= src2) as i32, false),
+        BranchOnGreaterOrEqual => ((src1 < src2) as i32, false),
         BranchOnGreaterOrEqualUnsigned => (
-            !((src1 as u32) >= (src2 as u32)) as
-                i32,
+            ((src1 as u32) < (src2 as u32)) as i32,
             false,
         ),
-        ShiftLeft => (src1 << src2, false),
-        ShiftRightLogical => (((src1 as u32) >> src2) as i32, false),
-        ShiftRightArithmetic => src1.overflowing_shr(src2 as u32),
+        // Shifts use the low 5 bits of src2 as the shift amount
+        ShiftLeft => (src1 << (src2 & 0x1f), false),
+        ShiftRightLogical => (((src1 as u32) >> (src2 & 0x1f)) as i32, false),
+        ShiftRightArithmetic => (src1 >> (src2 & 0x1f), false),
         SetOnLessThan => ((src1 < src2) as i32, false),
         SetOnLessThanUnsigned => (
             ((src1 as u32) < (src2 as u32)) as i32,
@@ -52,9 +52,10 @@ pub fn alu(insn: &Instruction, src1: i32, src2: i32, _clk: u64) -> i32 {
 
 
 /// Available ALU operations.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum AluOp {
     // Arithmetic ops
+    #[default]
     Add,
     Sub,
     // Logical ops
@@ -78,23 +79,10 @@ pub enum AluOp {
 }
 
 
-impl Default for AluOp {
-    fn default() -> AluOp {
-        AluOp::Add
-    }
-}
-
-
 /// Selector for ALU `src2` source.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum AluSrc {
+    #[default]
     Reg,
     Imm,
 }
-
-
-impl Default for AluSrc {
-    fn default() -> AluSrc {
-        AluSrc::Reg
-    }
-}
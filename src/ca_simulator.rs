@@ -1,62 +1,574 @@
 //! Cycle accurate 5-stage pipelining RISC-V 32I simulator.
 
 
-use hazards;
+use checkpoint::{Checkpoint, Reader, Writer};
+use consts;
+use hazards::{self, HazardPolicy, HazardUnit};
 use instruction::Instruction;
 use memory::data::DataMemory;
 use memory::instruction::InstructionMemory;
 use pipeline::Pipeline;
+use pipeline::events::{Event, Observer, Occupancy, Slot, Stage,
+                       StallReason};
 use pipeline::stages::{insn_fetch, insn_decode, execute, access_memory,
                        reg_writeback};
+use predictor::{BranchResolution, BranchUnit};
+use predictor::direction::NotTaken;
 use register::RegisterFile;
+use simulator::Simulator;
+use stats::SimStats;
+
+use std::io;
+use std::mem::{replace, swap};
 
 
 /// Runs a cycle accurate RISC-V 32I simulator.
 ///
-/// Returns the address of the HALT instruction.
+/// Conditional branches are predicted not taken.
 ///
+/// Returns the run statistics, including the address of the HALT
+/// instruction.
 pub fn run(
-    insns: &InstructionMemory,
-    mut mem: &mut DataMemory,
-    mut reg: &mut RegisterFile,
-) -> usize {
-    // Clock is used to aid debugging only
-    let mut clock: u64 = 0;
+    insns: &dyn InstructionMemory,
+    mem: &mut DataMemory,
+    reg: &mut RegisterFile,
+) -> SimStats {
+    let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+    run_with_branch_unit(insns, mem, reg, &mut branch_unit)
+}
+
+
+/// Runs a cycle accurate RISC-V 32I simulator with a branch unit.
+///
+/// Branch statistics are accumulated in `branch_unit.stats`.
+///
+/// Returns the run statistics, including the address of the HALT
+/// instruction.
+pub fn run_with_branch_unit(
+    insns: &dyn InstructionMemory,
+    mem: &mut DataMemory,
+    reg: &mut RegisterFile,
+    branch_unit: &mut BranchUnit,
+) -> SimStats {
+    let mut hazard_unit = HazardUnit::new(HazardPolicy::Forwarding);
+    run_with_units(insns, mem, reg, branch_unit, &mut hazard_unit)
+}
+
+
+/// Runs a cycle accurate RISC-V 32I simulator with a branch unit and a
+/// hazard unit.
+///
+/// Branch statistics are accumulated in `branch_unit.stats`, and data
+/// hazard statistics in `hazard_unit.stats`.
+///
+/// Returns the run statistics, including the address of the HALT
+/// instruction.
+pub fn run_with_units(
+    insns: &dyn InstructionMemory,
+    mem: &mut DataMemory,
+    reg: &mut RegisterFile,
+    branch_unit: &mut BranchUnit,
+    hazard_unit: &mut HazardUnit,
+) -> SimStats {
+    run_with_observer(insns, mem, reg, branch_unit, hazard_unit, &mut Ignore)
+}
+
+
+/// Discards pipeline events.
+struct Ignore;
+
+
+impl Observer for Ignore {
+    fn event(&mut self, _cycle: u64, _event: &Event) {}
+}
+
+
+/// Runs a cycle accurate RISC-V 32I simulator, reporting pipeline events.
+///
+/// Branch statistics are accumulated in `branch_unit.stats`, and data
+/// hazard statistics in `hazard_unit.stats`. The events of each cycle are
+/// passed to `observer` at the end of the cycle.
+///
+/// Returns the run statistics, including the address of the HALT
+/// instruction.
+pub fn run_with_observer(
+    insns: &dyn InstructionMemory,
+    mem: &mut DataMemory,
+    reg: &mut RegisterFile,
+    branch_unit: &mut BranchUnit,
+    hazard_unit: &mut HazardUnit,
+    observer: &mut dyn Observer,
+) -> SimStats {
+    let mut sim = CaSimulator::new(
+        insns,
+        replace(mem, DataMemory::new(0)),
+        replace(reg, RegisterFile::new(0)),
+    );
+    sim.set_branch_unit(replace(
+        branch_unit,
+        BranchUnit::new(Box::new(NotTaken)),
+    ));
+    sim.set_hazard_unit(replace(
+        hazard_unit,
+        HazardUnit::new(HazardPolicy::Forwarding),
+    ));
+    sim.set_observer(observer);
+
+    sim.run();
+
+    swap(mem, sim.memory_mut());
+    swap(reg, sim.registers_mut());
+    swap(branch_unit, sim.branch_unit_mut());
+    swap(hazard_unit, sim.hazard_unit_mut());
+
+    sim.stats().clone()
+}
+
+
+/// A cycle accurate 5-stage pipelined RISC-V 32I simulator.
+///
+/// Conditional branches are predicted not taken and data hazards are
+/// handled by forwarding unless other units are set.
+pub struct CaSimulator<'a> {
+    insns: &'a dyn InstructionMemory,
+    mem: DataMemory,
+    reg: RegisterFile,
+    branch_unit: BranchUnit,
+    hazard_unit: HazardUnit,
+    observer: Option<&'a mut dyn Observer>,
+
+    /// Program counter after a reset
+    reset_pc: u32,
+    clock: u64,
+    halt_addr: Option<usize>,
+    stats: SimStats,
+    /// Branch penalty cycles before the run
+    penalty_cycles: u64,
 
     // Pipline registers
-    let mut write_pipeline = Pipeline::new();
-    let mut read_pipeline = Pipeline::new();
+    write_pipeline: Pipeline,
+    read_pipeline: Pipeline,
+
+    /// Events of the current or last cycle
+    events: Vec<Event>,
+
+    /// Fetch order of the next instruction or stall bubble
+    next_id: u64,
 
-    loop {
-        if hazards::load_hazard(&read_pipeline) {
-            write_pipeline.id_ex.insn = Instruction::default(); // NOP
+    /// Cycles left of a multi-cycle memory access
+    mem_stall: u32,
+
+    /// What each stage held in the last cycle that wasn't a memory stall
+    stages: Occupancy,
+}
+
+
+impl<'a> CaSimulator<'a> {
+    /// Constructs a new `CaSimulator` executing `insns`.
+    ///
+    /// The program counter of `reg` is also the reset vector.
+    pub fn new(
+        insns: &'a dyn InstructionMemory,
+        mem: DataMemory,
+        reg: RegisterFile,
+    ) -> CaSimulator<'a> {
+        CaSimulator {
+            insns,
+            mem,
+            reset_pc: reg.pc.read(),
+            reg,
+            branch_unit: BranchUnit::new(Box::new(NotTaken)),
+            hazard_unit: HazardUnit::new(HazardPolicy::Forwarding),
+            observer: None,
+            clock: 0,
+            halt_addr: None,
+            stats: SimStats::default(),
+            penalty_cycles: 0,
+            write_pipeline: Pipeline::new(),
+            read_pipeline: Pipeline::new(),
+            events: Vec::new(),
+            next_id: 1,
+            mem_stall: 0,
+            stages: [None; 5],
+        }
+    }
+
+    /// Replaces the branch unit.
+    ///
+    /// Branch statistics are accumulated in `branch_unit.stats`.
+    pub fn set_branch_unit(&mut self, branch_unit: BranchUnit) {
+        self.penalty_cycles = branch_unit.stats.penalty_cycles;
+        self.branch_unit = branch_unit;
+    }
+
+    pub fn branch_unit(&self) -> &BranchUnit {
+        &self.branch_unit
+    }
+
+    pub fn branch_unit_mut(&mut self) -> &mut BranchUnit {
+        &mut self.branch_unit
+    }
+
+    /// Replaces the hazard unit.
+    ///
+    /// Data hazard statistics are accumulated in `hazard_unit.stats`.
+    pub fn set_hazard_unit(&mut self, hazard_unit: HazardUnit) {
+        self.hazard_unit = hazard_unit;
+    }
+
+    pub fn hazard_unit(&self) -> &HazardUnit {
+        &self.hazard_unit
+    }
+
+    pub fn hazard_unit_mut(&mut self) -> &mut HazardUnit {
+        &mut self.hazard_unit
+    }
+
+    /// Passes the events of each cycle to `observer` at the end of the
+    /// cycle.
+    pub fn set_observer(&mut self, observer: &'a mut dyn Observer) {
+        self.observer = Some(observer);
+    }
+
+    /// Returns the statistics of the run so far.
+    ///
+    /// The cycle count, HALT address and branch flush cycles are set at
+    /// HALT.
+    pub fn stats(&self) -> &SimStats {
+        &self.stats
+    }
+
+    /// Reports the events of the cycle, what each stage held in it and the
+    /// state at its end.
+    fn end_cycle(&mut self, stages: &Occupancy) {
+        let clock = self.clock;
+        for event in self.events.iter() {
+            self.stats.event(clock, event);
+            if let Some(ref mut observer) = self.observer {
+                observer.event(clock, event);
+            }
+        }
+        if let Some(ref mut observer) = self.observer {
+            observer.cycle(clock, stages);
+            observer.state(clock, &self.write_pipeline, &self.reg, &self.mem);
+        }
+    }
+
+    /// Simulates a cycle of a multi-cycle memory access, which holds the
+    /// instructions in IF through MEM and sends a bubble to WB.
+    fn stall_memory(&mut self) {
+        self.mem.tick();
+        self.events.push(Event::Stall {
+            stage: Stage::Mem,
+            reason: StallReason::Memory,
+        });
+        let mut stages = self.stages;
+        stages[4] = stages[3].map(|slot| Slot {
+            id: self.next_id,
+            raw_insn: consts::NOP,
+            bubble: true,
+            ..slot
+        });
+        self.next_id += 1;
+        self.end_cycle(&stages);
+
+        self.mem_stall -= 1;
+        self.clock += 1;
+    }
+}
+
+
+impl<'a> Simulator for CaSimulator<'a> {
+    fn step(&mut self) -> Option<usize> {
+        if self.halt_addr.is_some() {
+            return self.halt_addr;
+        }
+        self.events.clear();
+        if self.mem_stall > 0 {
+            self.stall_memory();
+            return None;
+        }
+
+        let clock = self.clock;
+        self.mem.tick();
+
+        let resolution = self.branch_unit.resolution();
+        let policy = self.hazard_unit.policy();
+        let data_hazard = match policy {
+            HazardPolicy::Forwarding => {
+                hazards::load_hazard(&self.read_pipeline)
+            }
+            HazardPolicy::StallOnly => {
+                let raw_insn = self.read_pipeline.if_id.raw_insn;
+                let insn = Instruction::new(raw_insn);
+                hazards::raw_hazard(&insn, &self.read_pipeline).is_some()
+            }
+            HazardPolicy::NoInterlock => false,
+        };
+        let branch_hazard = !data_hazard &&
+            policy == HazardPolicy::Forwarding &&
+            resolution == BranchResolution::Decode &&
+            hazards::branch_hazard(&self.read_pipeline);
+
+        if data_hazard {
+            self.hazard_unit.stats.stall_cycles += 1;
+            let reason = match policy {
+                HazardPolicy::Forwarding => StallReason::LoadUse,
+                _ => StallReason::DataHazard,
+            };
+            self.events.push(Event::Stall {
+                stage: Stage::Id,
+                reason,
+            });
+        }
+        if branch_hazard {
+            self.branch_unit.stats.stall_cycles += 1;
+            self.events.push(Event::Stall {
+                stage: Stage::Id,
+                reason: StallReason::BranchOperands,
+            });
+        }
+
+        let mut fetched = None;
+        if data_hazard || branch_hazard {
+            let id_ex = &mut self.write_pipeline.id_ex;
+            id_ex.insn = Instruction::default(); // NOP
+            id_ex.trap = None;
+            id_ex.bubble = true;
+            id_ex.id = self.next_id;
+            self.next_id += 1;
         } else {
-            insn_fetch(&mut write_pipeline, insns, &mut reg, clock);
-            insn_decode(&read_pipeline, &mut write_pipeline, &mut reg, clock);
+            insn_fetch(
+                &mut self.write_pipeline,
+                self.insns,
+                &mut self.reg,
+                &mut self.branch_unit,
+                &mut self.events,
+                clock,
+            );
+            let if_id = &mut self.write_pipeline.if_id;
+            if_id.id = self.next_id;
+            self.next_id += 1;
+            fetched = Some(Slot {
+                id: if_id.id,
+                pc: if_id.pc,
+                raw_insn: if_id.raw_insn,
+                bubble: false,
+            });
+            insn_decode(
+                &self.read_pipeline,
+                &mut self.write_pipeline,
+                &mut self.reg,
+                &mut self.branch_unit,
+                &mut self.hazard_unit,
+                &mut self.events,
+                clock,
+            );
         }
 
-        execute(&read_pipeline, &mut write_pipeline, clock);
+        let stages = occupancy(&self.read_pipeline, fetched);
+
+        execute(
+            &self.read_pipeline,
+            &mut self.write_pipeline,
+            &mut self.hazard_unit,
+            &mut self.events,
+            clock,
+        );
 
-        access_memory(
-            &read_pipeline,
-            &mut write_pipeline,
-            &mut mem,
-            &mut reg,
+        let mem_stall = access_memory(
+            &self.read_pipeline,
+            &mut self.write_pipeline,
+            &mut self.mem,
+            &mut self.reg,
+            &mut self.branch_unit,
+            &mut self.events,
             clock,
         );
 
-        if let Some(addr) = write_pipeline.ex_mem.halt_addr {
+        if let Some(addr) = self.write_pipeline.ex_mem.halt_addr {
+            self.mem.drain_stores();
+            self.end_cycle(&stages);
             info!("Halt: {:#0x} (clock {}), exiting...", addr, clock);
-            return addr;
+
+            self.clock += 1;
+            self.halt_addr = Some(addr);
+            self.stats.halt_addr = addr;
+            self.stats.cycles = self.clock;
+            self.stats.branch_flush_cycles =
+                self.branch_unit.stats.penalty_cycles - self.penalty_cycles;
+            return self.halt_addr;
+        }
+
+        reg_writeback(
+            &self.read_pipeline,
+            &mut self.reg,
+            &mut self.events,
+            clock,
+        );
+
+        self.end_cycle(&stages);
+
+        // Hold the pipeline while a multi-cycle memory access completes
+        if mem_stall > 0 {
+            trace!(
+                "Stall: MEM busy for {} extra cycles (clock {})",
+                mem_stall,
+                clock
+            );
         }
+        self.mem_stall = mem_stall;
+        self.stages = stages;
+
+        self.read_pipeline = self.write_pipeline;
+        self.clock += 1;
 
-        reg_writeback(&read_pipeline, &mut reg, clock);
+        None
+    }
 
-        read_pipeline = write_pipeline;
+    fn reset(&mut self) {
+        self.reg = RegisterFile::new(self.reset_pc);
+        self.clock = 0;
+        self.halt_addr = None;
+        self.stats = SimStats::default();
+        self.penalty_cycles = self.branch_unit.stats.penalty_cycles;
+        self.write_pipeline = Pipeline::new();
+        self.read_pipeline = Pipeline::new();
+        self.events.clear();
+        self.next_id = 1;
+        self.mem_stall = 0;
+        self.stages = [None; 5];
+    }
+
+    fn set_pc(&mut self, pc: u32) {
+        self.reg.pc.write(pc);
+        self.write_pipeline = Pipeline::new();
+        self.read_pipeline = Pipeline::new();
+        self.mem_stall = 0;
+    }
+
+    fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    fn halt_addr(&self) -> Option<usize> {
+        self.halt_addr
+    }
+
+    fn registers(&self) -> &RegisterFile {
+        &self.reg
+    }
+
+    fn registers_mut(&mut self) -> &mut RegisterFile {
+        &mut self.reg
+    }
+
+    fn memory(&self) -> &DataMemory {
+        &self.mem
+    }
+
+    fn memory_mut(&mut self) -> &mut DataMemory {
+        &mut self.mem
+    }
 
-        clock += 1;
+    fn pipeline(&self) -> Option<&Pipeline> {
+        Some(&self.read_pipeline)
     }
 
+    fn events(&self) -> &[Event] {
+        &self.events
+    }
+}
+
+
+/// Saves the complete machine state, including both pipeline copies and
+/// the branch and hazard units. The events of the last cycle are not saved.
+impl<'a> Checkpoint for CaSimulator<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.str("ca");
+        w.usize(self.insns.size());
+        w.u32(self.reset_pc);
+        w.u64(self.clock);
+        w.option(self.halt_addr, |w, addr| w.usize(addr));
+        w.u64(self.penalty_cycles);
+        w.u64(self.next_id);
+        w.u32(self.mem_stall);
+        for &slot in self.stages.iter() {
+            w.option(slot, |w, slot| {
+                w.u64(slot.id);
+                w.u32(slot.pc);
+                w.u32(slot.raw_insn);
+                w.bool(slot.bubble);
+            });
+        }
+        self.stats.save(w);
+
+        self.reg.save(w);
+        self.mem.save(w);
+        self.branch_unit.save(w);
+        self.hazard_unit.save(w);
+        self.read_pipeline.save(w);
+        self.write_pipeline.save(w);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_str("model", "ca")?;
+        r.expect_usize("program size", self.insns.size())?;
+        self.reset_pc = r.u32()?;
+        self.clock = r.u64()?;
+        self.halt_addr = r.option(|r| r.usize())?;
+        self.penalty_cycles = r.u64()?;
+        self.next_id = r.u64()?;
+        self.mem_stall = r.u32()?;
+        for slot in self.stages.iter_mut() {
+            *slot = r.option(|r| {
+                Ok(Slot {
+                    id: r.u64()?,
+                    pc: r.u32()?,
+                    raw_insn: r.u32()?,
+                    bubble: r.bool()?,
+                })
+            })?;
+        }
+        self.stats.restore(r)?;
+
+        self.reg.restore(r)?;
+        self.mem.restore(r)?;
+        self.branch_unit.restore(r)?;
+        self.hazard_unit.restore(r)?;
+        self.read_pipeline.restore(r)?;
+        self.write_pipeline.restore(r)?;
+        self.events.clear();
+
+        Ok(())
+    }
+}
+
+
+/// Returns what each stage holds in a cycle, given the pipeline registers
+/// read in the cycle and the instruction fetched in it, if any.
+fn occupancy(read_pipeline: &Pipeline, fetched: Option<Slot>) -> Occupancy {
+    let slot = |id: u64, pc: u32, raw_insn: u32, bubble: bool| match id {
+        0 => None,
+        _ => Some(Slot {
+            id,
+            pc,
+            raw_insn,
+            bubble,
+        }),
+    };
+    let if_id = &read_pipeline.if_id;
+    let id_ex = &read_pipeline.id_ex;
+    let ex_mem = &read_pipeline.ex_mem;
+    let mem_wb = &read_pipeline.mem_wb;
+
+    [
+        fetched,
+        slot(if_id.id, if_id.pc, if_id.raw_insn, if_id.bubble),
+        slot(id_ex.id, id_ex.pc, id_ex.insn.as_u32(), id_ex.bubble),
+        slot(ex_mem.id, ex_mem.pc, ex_mem.insn.as_u32(), ex_mem.bubble),
+        slot(mem_wb.id, mem_wb.pc, mem_wb.insn.as_u32(), mem_wb.bubble),
+    ]
 }
 
 
@@ -64,9 +576,15 @@ pub fn run(
 mod tests {
     use super::*;
 
-    use consts;
+    use hazards::HazardViolation;
+    use ia_simulator;
+    use trap::Exception;
     use instruction::Instruction;
+    use memory::data::MisalignedPolicy;
     use memory::instruction::TestInstructionMemory;
+    use pipeline::chart::PipelineChart;
+    use predictor::direction::Taken;
+    use predictor::ras::ReturnAddressStack;
 
 
     /// Tests forwarding to ALU from EX/MEM and MEM/WB pipeline registers.
@@ -108,7 +626,8 @@ mod tests {
         registers.gpr[3].write(1);
         registers.gpr[15].write(0xffff);
 
-        let halt_addr = run(&insn_memory, &mut data_memory, &mut registers);
+        let halt_addr =
+            run(&insn_memory, &mut data_memory, &mut registers).halt_addr;
 
         assert_eq!(halt_addr, 0x20);
         assert_eq!(registers.gpr[2].read(), 1); // x2 == 1
@@ -117,7 +636,7 @@ mod tests {
         assert_eq!(registers.gpr[13].read(), 1); // x13 == 1
         assert_eq!(registers.gpr[14].read(), 2); // x14 == 2
 
-        assert_eq!(data_memory.read(100, consts::WORD_SIZE), 0x00ffff00);
+        assert_eq!(data_memory.read(100, consts::WORD_SIZE), Ok(0x00ffff00));
     }
 
     /// Tests load-use hazard detection and bubble insertion.
@@ -151,14 +670,15 @@ mod tests {
         let mut data_memory = DataMemory::new(1024);
         let mut registers = RegisterFile::new(0x0);
 
-        data_memory.write(20, consts::WORD_SIZE, 5);
+        data_memory.write(20, consts::WORD_SIZE, 5).unwrap();
 
         registers.gpr[4].write(1);
         registers.gpr[5].write(3);
         registers.gpr[6].write(2);
         registers.gpr[7].write(1);
 
-        let halt_addr = run(&insn_memory, &mut data_memory, &mut registers);
+        let halt_addr =
+            run(&insn_memory, &mut data_memory, &mut registers).halt_addr;
 
         assert_eq!(halt_addr, 0x20);
         assert_eq!(registers.gpr[4].read(), 1);
@@ -167,4 +687,630 @@ mod tests {
         assert_eq!(registers.gpr[1].read(), 1);
     }
 
+    /// Tests forwarding a jump's link address rather than its ALU result.
+    ///
+    /// The jump targets the next instruction, so it doesn't flush and its
+    /// link register is forwarded from EX/MEM and then MEM/WB.
+    #[test]
+    fn link_forwarding() {
+        let insn1 = Instruction::new(0x00_40_00_ef); // jal x1, 4
+        let insn2 = Instruction::new(0x00_00_81_13); // addi x2, x1, 0
+        let insn3 = Instruction::new(0x00_00_81_93); // addi x3, x1, 0
+
+        let insns = vec![
+            consts::NOP,
+            insn1.as_u32(),
+            insn2.as_u32(),
+            insn3.as_u32(),
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ];
+
+        let insn_memory = TestInstructionMemory::new(insns);
+        let mut data_memory = DataMemory::new(1024);
+        let mut registers = RegisterFile::new(0x0);
+
+        let halt_addr =
+            run(&insn_memory, &mut data_memory, &mut registers).halt_addr;
+
+        assert_eq!(halt_addr, 0x1c);
+        assert_eq!(registers.gpr[1].read(), 8);
+        assert_eq!(registers.gpr[2].read(), 8);
+        assert_eq!(registers.gpr[3].read(), 8);
+    }
+
+    /// Tests that fetching a mispredicted target that's past the end of the
+    /// program or misaligned doesn't trap, and that jumping there does.
+    #[test]
+    fn wrong_path_fetch() {
+        let insns = TestInstructionMemory::new(vec![
+            0x00_10_00_93, // addi x1, x0, 1
+            0x10_00_80_63, // beq x1, x0, 256: past the end
+            0x00_00_83_63, // beq x1, x0, 6: misaligned
+            0x00_20_01_13, // addi x2, x0, 2
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ]);
+        let mut registers = RegisterFile::new(0x0);
+        let mut branch_unit = BranchUnit::new(Box::new(Taken));
+
+        let stats = run_with_branch_unit(
+            &insns,
+            &mut DataMemory::new(64),
+            &mut registers,
+            &mut branch_unit,
+        );
+
+        assert_eq!(stats.halt_addr, 0x1c);
+        assert_eq!(branch_unit.stats.mispredicted, 2);
+        assert_eq!(registers.gpr[2].read(), 2);
+        assert_eq!(registers.csr.mcause, 0);
+
+        let insns = [
+            0x01_00_00_93, // addi x1, x0, 16
+            0x30_50_90_73, // csrrw x0, mtvec, x1
+            0x10_00_00_6f, // jal x0, 256: past the end
+            consts::HALT,
+            0x34_20_21_73, // csrrs x2, mcause, x0
+            0x34_30_21_f3, // csrrs x3, mtval, x0
+        ];
+        for (halt_addr, reg) in run_models(&insns, &[]) {
+            assert_eq!(halt_addr, 0x24);
+            assert_eq!(reg.gpr[2].read(), 1); // instruction access fault
+            assert_eq!(reg.gpr[3].read(), 0x108);
+            assert_eq!(reg.csr.mepc, 0x108);
+        }
+    }
+
+    /// Tests that a call fetched on the wrong path after a branch resolved
+    /// in ID doesn't stay on the return address stack.
+    #[test]
+    fn decode_flush_repairs_ras() {
+        let insns = TestInstructionMemory::new(vec![
+            0x02_00_00_ef, // jal x1, 32: call f
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            0x00_10_03_13, // f: addi x6, x0, 1
+            0x00_03_14_63, // bne x6, x0, 8
+            0xfd_9f_f0_ef, // jal x1, -40: on the wrong path
+            0x00_00_80_67, // jalr x0, 0(x1): return
+        ]);
+
+        for &resolution in [BranchResolution::Memory, BranchResolution::Decode]
+            .iter()
+        {
+            let mut registers = RegisterFile::new(0x0);
+            let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+            branch_unit.set_resolution(resolution);
+            branch_unit.set_ras(Some(ReturnAddressStack::new(4)));
+
+            let stats = run_with_branch_unit(
+                &insns,
+                &mut DataMemory::new(64),
+                &mut registers,
+                &mut branch_unit,
+            );
+
+            assert_eq!(stats.halt_addr, 0x10, "{:?}", resolution);
+            assert_eq!(branch_unit.stats.jumps, 2, "{:?}", resolution);
+            // Only the call, as jumps aren't predicted without a BTB
+            assert_eq!(
+                branch_unit.stats.jumps_mispredicted,
+                1,
+                "{:?}",
+                resolution
+            );
+        }
+    }
+
+    /// Runs `insns` followed by HALT with `policy` and x1 = 2, x3 = 1.
+    fn run_policy(
+        insns: &[Instruction],
+        policy: HazardPolicy,
+    ) -> (RegisterFile, HazardUnit) {
+        let mut raw: Vec<u32> = insns.iter().map(|i| i.as_u32()).collect();
+        raw.extend_from_slice(&[consts::NOP; 3]);
+        raw.push(consts::HALT);
+        raw.extend_from_slice(&[consts::NOP; 3]);
+
+        let insn_memory = TestInstructionMemory::new(raw);
+        let mut data_memory = DataMemory::new(1024);
+        let mut registers = RegisterFile::new(0x0);
+        let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+        let mut hazard_unit = HazardUnit::new(policy);
+
+        registers.gpr[1].write(2);
+        registers.gpr[3].write(1);
+        registers.gpr[6].write(4);
+
+        run_with_units(
+            &insn_memory,
+            &mut data_memory,
+            &mut registers,
+            &mut branch_unit,
+            &mut hazard_unit,
+        );
+
+        (registers, hazard_unit)
+    }
+
+    /// Tests stalling in ID until the source register is written back.
+    #[test]
+    fn stall_only() {
+        let insns = [
+            Instruction::new(0x40_30_81_33), // sub x2, x1, x3
+            Instruction::new(0x00_23_66_b3), // or x13, x6, x2
+            Instruction::new(0x00_21_07_33), // add x14, x2, x2
+        ];
+
+        let (registers, hazard_unit) =
+            run_policy(&insns, HazardPolicy::StallOnly);
+        assert_eq!(registers.gpr[13].read(), 5);
+        assert_eq!(registers.gpr[14].read(), 2);
+        assert_eq!(hazard_unit.stats.stall_cycles, 2);
+        assert_eq!(hazard_unit.stats.violations, 0);
+
+        let (_, hazard_unit) = run_policy(&insns, HazardPolicy::Forwarding);
+        assert_eq!(hazard_unit.stats.stall_cycles, 0);
+    }
+
+    /// Tests reporting stale reads without interlocks, and NOP scheduling.
+    #[test]
+    fn no_interlock() {
+        let sub = Instruction::new(0x40_30_81_33); // sub x2, x1, x3
+        let or = Instruction::new(0x00_23_66_b3); // or x13, x6, x2
+        let add = Instruction::new(0x00_21_07_33); // add x14, x2, x2
+        let nop = Instruction::new(consts::NOP);
+
+        let (registers, hazard_unit) =
+            run_policy(&[sub, or, add], HazardPolicy::NoInterlock);
+        assert_eq!(registers.gpr[13].read(), 4); // stale x2 == 0
+        assert_eq!(registers.gpr[14].read(), 0);
+        assert_eq!(hazard_unit.stats.stall_cycles, 0);
+        assert_eq!(
+            hazard_unit.violations(),
+            &[
+                HazardViolation {
+                    pc: 0x4,
+                    reg: 2,
+                    producer_pc: 0x0,
+                    clock: 3,
+                },
+                HazardViolation {
+                    pc: 0x8,
+                    reg: 2,
+                    producer_pc: 0x0,
+                    clock: 4,
+                },
+            ]
+        );
+
+        let (registers, hazard_unit) =
+            run_policy(&[sub, nop, nop, or, add], HazardPolicy::NoInterlock);
+        assert_eq!(registers.gpr[13].read(), 5);
+        assert_eq!(registers.gpr[14].read(), 2);
+        assert_eq!(hazard_unit.stats.violations, 0);
+    }
+
+    /// Tests the events reported for forwarding and a load-use stall.
+    ///
+    /// Within a cycle, events are in the order the stages run: IF, ID, EX,
+    /// MEM then WB.
+    #[test]
+    fn events() {
+        let insns = vec![
+            0x40_30_81_33, // sub x2, x1, x3
+            0x00_51_76_33, // and x12, x2, x5
+            0x00_23_66_b3, // or x13, x6, x2
+            0x00_21_07_33, // add x14, x2, x2
+            0x00_01_22_03, // lw x4, 0(x2)
+            0x00_42_07_b3, // add x15, x4, x4
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ];
+
+        let insn_memory = TestInstructionMemory::new(insns);
+        let mut data_memory = DataMemory::new(1024);
+        let mut registers = RegisterFile::new(0x0);
+        let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+        let mut hazard_unit = HazardUnit::new(HazardPolicy::Forwarding);
+        let mut events: Vec<(u64, Event)> = Vec::new();
+
+        registers.gpr[1].write(6);
+        registers.gpr[3].write(2);
+        data_memory.write(4, consts::WORD_SIZE, 9).unwrap();
+
+        run_with_observer(
+            &insn_memory,
+            &mut data_memory,
+            &mut registers,
+            &mut branch_unit,
+            &mut hazard_unit,
+            &mut events,
+        );
+
+        let forwards: Vec<(u64, Event)> = events
+            .iter()
+            .cloned()
+            .filter(|&(_, e)| e.name() == "Forward")
+            .collect();
+        let forward = |cycle, pc, src, dst, from_stage, value| {
+            (
+                cycle,
+                Event::Forward {
+                    pc,
+                    src,
+                    dst,
+                    from_stage,
+                    value,
+                },
+            )
+        };
+        assert_eq!(
+            forwards,
+            vec![
+                forward(3, 0x4, 2, Stage::Ex, Stage::Mem, 4),
+                forward(4, 0xc, 2, Stage::Id, Stage::Wb, 4),
+                forward(4, 0xc, 2, Stage::Id, Stage::Wb, 4),
+                forward(4, 0x8, 2, Stage::Ex, Stage::Wb, 4),
+                forward(8, 0x14, 4, Stage::Ex, Stage::Wb, 9),
+                forward(8, 0x14, 4, Stage::Ex, Stage::Wb, 9),
+            ]
+        );
+
+        assert!(events.contains(&(
+            6,
+            Event::Stall {
+                stage: Stage::Id,
+                reason: StallReason::LoadUse,
+            },
+        )));
+        assert!(events.contains(&(
+            7,
+            Event::MemAccess {
+                pc: 0x10,
+                addr: 4,
+                size: 4,
+                write: false,
+                value: 9,
+            },
+        )));
+        assert!(events.contains(&(
+            10,
+            Event::RegWrite {
+                pc: 0x14,
+                rd: 15,
+                value: 18,
+            },
+        )));
+
+        // The stall's bubble doesn't retire
+        let retired: Vec<u32> = events
+            .iter()
+            .filter_map(|&(_, e)| match e {
+                Event::Retire { pc, .. } => Some(pc),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(retired, vec![0x0, 0x4, 0x8, 0xc, 0x10, 0x14, 0x18]);
+    }
+
+
+    /// Tests the stage occupancy reported around a load-use stall.
+    #[test]
+    fn stage_occupancy() {
+        let insns = vec![
+            0x00_01_22_03, // lw x4, 0(x2)
+            0x00_42_07_b3, // add x15, x4, x4
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ];
+
+        let insn_memory = TestInstructionMemory::new(insns);
+        let mut data_memory = DataMemory::new(1024);
+        let mut registers = RegisterFile::new(0x0);
+        let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+        let mut hazard_unit = HazardUnit::new(HazardPolicy::Forwarding);
+        let mut chart = PipelineChart::new(0, 6);
+
+        run_with_observer(
+            &insn_memory,
+            &mut data_memory,
+            &mut registers,
+            &mut branch_unit,
+            &mut hazard_unit,
+            &mut chart,
+        );
+
+        assert_eq!(
+            chart.to_string(),
+            "cycle                 0   1   2   3   4   5   6\n\
+             0x0: lw x4, 0(x2)     IF  ID  EX  MEM WB\n\
+             0x4: add x15, x4, x4      IF  ID  ID  EX  MEM WB\n\
+             bubble                            EX  MEM WB\n\
+             0x8: addi x0, x0, 0               IF  ID  EX  MEM\n\
+             0xc: addi x0, x0, 0                   IF  ID  EX\n\
+             0x10: addi x0, x0, 0                      IF  ID\n\
+             0x14: halt                                    IF"
+        );
+    }
+
+
+    /// Tests unknown encodings trap as illegal instructions on both models.
+    /// Runs `insns` followed by HALT on both models, with `registers` set
+    /// first.
+    ///
+    /// Returns the HALT address and registers of the CA and the IA model.
+    fn run_models(
+        insns: &[u32],
+        registers: &[(usize, u32)],
+    ) -> Vec<(usize, RegisterFile)> {
+        let mut words = insns.to_vec();
+        words.extend_from_slice(&[consts::NOP; 3]);
+        words.push(consts::HALT);
+        words.extend_from_slice(&[consts::NOP; 3]);
+        let insns = TestInstructionMemory::new(words);
+        let new_registers = || {
+            let mut reg = RegisterFile::new(0x0);
+            for &(i, value) in registers.iter() {
+                reg.gpr[i].write(value);
+            }
+            reg
+        };
+
+        let mut ca_registers = new_registers();
+        let stats = run(&insns, &mut DataMemory::new(64), &mut ca_registers);
+        let mut ia_registers = new_registers();
+        let halt_addr = ia_simulator::run(
+            &insns,
+            &mut DataMemory::new(64),
+            &mut ia_registers,
+        );
+
+        vec![(stats.halt_addr, ca_registers), (halt_addr, ia_registers)]
+    }
+
+    /// Tests that shifts by a register use its low 5 bits.
+    #[test]
+    fn shift_amount() {
+        let insns = [
+            0x00_20_95_33, // sll x10, x1, x2
+            0x00_20_d5_b3, // srl x11, x1, x2
+            0x40_20_d6_33, // sra x12, x1, x2
+        ];
+
+        let registers = [(1, 0xffff_fff0), (2, 33)];
+        for (halt_addr, reg) in run_models(&insns, &registers) {
+            assert_eq!(halt_addr, 0x18);
+            assert_eq!(reg.gpr[10].read(), 0xffff_ffe0);
+            assert_eq!(reg.gpr[11].read(), 0x7fff_fff8);
+            assert_eq!(reg.gpr[12].read(), 0xffff_fff8);
+        }
+    }
+
+    /// Tests that AUIPC adds to its own address.
+    #[test]
+    fn auipc() {
+        let insns = [
+            consts::NOP,
+            0x00_00_12_97, // auipc x5, 0x1
+            0xff_ff_f3_17, // auipc x6, 0xfffff
+        ];
+
+        for (halt_addr, reg) in run_models(&insns, &[]) {
+            assert_eq!(halt_addr, 0x18);
+            assert_eq!(reg.gpr[5].read(), 0x1004);
+            assert_eq!(reg.gpr[6].read(), 0xffff_f008);
+        }
+    }
+
+    /// Tests that LB and LH sign-extend, and LBU and LHU don't.
+    #[test]
+    fn load_extension() {
+        let insns = [
+            0xff_f0_00_93, // addi x1, x0, -1
+            0x04_10_20_23, // sw x1, 64(x0)
+            0x04_00_01_03, // lb x2, 64(x0)
+            0x04_00_11_83, // lh x3, 64(x0)
+            0x04_00_42_03, // lbu x4, 64(x0)
+            0x04_00_52_83, // lhu x5, 64(x0)
+        ];
+
+        for (halt_addr, reg) in run_models(&insns, &[]) {
+            assert_eq!(halt_addr, 0x24);
+            assert_eq!(reg.gpr[2].read(), 0xffff_ffff);
+            assert_eq!(reg.gpr[3].read(), 0xffff_ffff);
+            assert_eq!(reg.gpr[4].read(), 0xff);
+            assert_eq!(reg.gpr[5].read(), 0xffff);
+        }
+    }
+
+    /// Tests that JALR only clears bit 0 of targets above 64 KiB.
+    #[test]
+    fn jalr_target() {
+        let mut insns = vec![
+            0x00_12_80_e7, // jalr x1, 1(x5)
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+        ];
+        insns.resize(0x10004 / 4, consts::NOP);
+        insns.push(0x00_10_03_13); // addi x6, x0, 1
+
+        for (halt_addr, reg) in run_models(&insns, &[(5, 0x10004)]) {
+            assert_eq!(halt_addr, 0x10014);
+            assert_eq!(reg.gpr[1].read(), 0x4);
+            assert_eq!(reg.gpr[6].read(), 1);
+        }
+    }
+
+    #[test]
+    fn illegal_instruction() {
+        // All zeros, all ones, WFI and LD
+        for &word in [0x0, 0xffff_ffff, 0x10_50_00_73, 0x00_05_35_03].iter() {
+            let insns = TestInstructionMemory::new(vec![
+                0x01_00_00_93, // addi x1, x0, 16
+                0x30_50_90_73, // csrrw x0, mtvec, x1
+                word,
+                consts::HALT,
+                0x34_20_21_73, // csrrs x2, mcause, x0
+                0x34_30_21_f3, // csrrs x3, mtval, x0
+                consts::NOP,
+                consts::NOP,
+                consts::NOP,
+                consts::HALT,
+                consts::NOP,
+                consts::NOP,
+                consts::NOP,
+            ]);
+
+            let mut ca_registers = RegisterFile::new(0x0);
+            let stats =
+                run(&insns, &mut DataMemory::new(64), &mut ca_registers);
+            let mut ia_registers = RegisterFile::new(0x0);
+            let halt_addr = ia_simulator::run(
+                &insns,
+                &mut DataMemory::new(64),
+                &mut ia_registers,
+            );
+
+            assert_eq!(stats.halt_addr, 0x24);
+            assert_eq!(halt_addr, 0x24);
+            for reg in [ca_registers, ia_registers].iter() {
+                assert_eq!(reg.gpr[2].read(), 2); // illegal instruction
+                assert_eq!(reg.gpr[3].read(), word);
+                assert_eq!(reg.csr.mepc, 0x8);
+            }
+        }
+    }
+
+
+    /// Tests an address misaligned exception takes priority over an access
+    /// fault on both models.
+    #[test]
+    fn misaligned_before_pmp() {
+        let insns = TestInstructionMemory::new(vec![
+            0x01_00_00_93, // addi x1, x0, 16
+            0x30_50_90_73, // csrrw x0, mtvec, x1
+            0x20_10_22_03, // lw x4, 0x201(x0)
+            consts::HALT,
+            0x34_20_21_73, // csrrs x2, mcause, x0
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ]);
+
+        // The word at 0x200 is locked against every access
+        let registers = || {
+            let mut registers = RegisterFile::new(0x0);
+            registers.csr.pmp.write_addr(0, 0x200 >> 2);
+            registers.csr.pmp.write_cfg(0, 0x90); // L, NA4
+            registers
+        };
+
+        let policies = [
+            (MisalignedPolicy::Trap, Exception::LoadAddressMisaligned),
+            (MisalignedPolicy::Split, Exception::LoadAccessFault),
+        ];
+        for &(policy, cause) in policies.iter() {
+            let mut ca_mem = DataMemory::new(256);
+            ca_mem.set_misaligned_policy(policy);
+            let mut ca_registers = registers();
+            run(&insns, &mut ca_mem, &mut ca_registers);
+
+            let mut ia_mem = DataMemory::new(256);
+            ia_mem.set_misaligned_policy(policy);
+            let mut ia_registers = registers();
+            ia_simulator::run(&insns, &mut ia_mem, &mut ia_registers);
+
+            for reg in [ca_registers, ia_registers].iter() {
+                assert_eq!(reg.gpr[2].read(), cause.code());
+                assert_eq!(reg.gpr[4].read(), 0);
+                assert_eq!(reg.csr.mtval, 0x201);
+            }
+        }
+    }
+
+
+    /// Tests a misaligned load split across words holds MEM for a cycle,
+    /// sending a bubble to WB.
+    #[test]
+    fn misaligned_stall() {
+        let insns = vec![
+            0x00_10_02_93, // addi x5, x0, 1
+            0x00_20_22_03, // lw x4, 2(x0)
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ];
+
+        let insn_memory = TestInstructionMemory::new(insns);
+        let mut data_memory = DataMemory::new(1024);
+        let mut registers = RegisterFile::new(0x0);
+        let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+        let mut hazard_unit = HazardUnit::new(HazardPolicy::Forwarding);
+        let mut chart = PipelineChart::new(0, 6);
+
+        data_memory.set_misaligned_policy(MisalignedPolicy::Split);
+        data_memory.write(0, consts::WORD_SIZE, 0x4433_0000).unwrap();
+        data_memory.write(4, consts::WORD_SIZE, 0x0000_2211).unwrap();
+
+        let stats = run_with_observer(
+            &insn_memory,
+            &mut data_memory,
+            &mut registers,
+            &mut branch_unit,
+            &mut hazard_unit,
+            &mut chart,
+        );
+
+        assert_eq!(registers.gpr[4].read(), 0x2211_4433);
+        assert_eq!(stats.memory_stall_cycles, 1);
+        assert_eq!(
+            chart.to_string(),
+            "cycle                 0   1   2   3   4   5   6\n\
+             0x0: addi x5, x0, 1   IF  ID  EX  MEM WB\n\
+             0x4: lw x4, 2(x0)         IF  ID  EX  MEM MEM WB\n\
+             0x8: addi x0, x0, 0           IF  ID  EX  EX  MEM\n\
+             0xc: addi x0, x0, 0               IF  ID  ID  EX\n\
+             0x10: addi x0, x0, 0                  IF  IF  ID\n\
+             bubble                                    WB\n\
+             0x14: halt                                    IF"
+        );
+    }
+
 }
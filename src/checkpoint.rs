@@ -0,0 +1,349 @@
+//! Machine checkpoints.
+//!
+//! A checkpoint holds the complete state of a simulator: registers, CSRs,
+//! memory, device and store buffer state and, for the cycle accurate model,
+//! both copies of the pipeline and the branch predictor. Restoring it into
+//! a simulator resumes the run exactly where it was saved.
+//!
+//! Only state that changes while running is saved. A checkpoint must be
+//! restored into a simulator configured the same way, i.e. executing the
+//! same program with the same memory size, devices, store buffer, branch
+//! unit and hazard policy; differences that can be detected are rejected.
+//!
+//! The file format is a magic string and a version number, followed by the
+//! state as little endian integers in a fixed order.
+
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+
+/// Identifies a checkpoint file.
+pub const MAGIC: &[u8; 8] = b"CASIMCKP";
+
+/// Format version written to checkpoint files.
+pub const VERSION: u32 = 2;
+
+
+/// State that can be saved to and restored from a checkpoint.
+pub trait Checkpoint {
+    /// Appends the state to `w`.
+    fn save(&self, w: &mut Writer);
+
+    /// Replaces the state with the state saved by `save`.
+    ///
+    /// Fails if the checkpoint is truncated or was saved from a differently
+    /// configured machine, in which case the state may be partially
+    /// restored.
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()>;
+}
+
+
+/// Serializes state into a checkpoint.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+
+impl Writer {
+    /// Constructs a new `Writer` holding just the header.
+    pub fn new() -> Writer {
+        let mut w = Writer { buf: MAGIC.to_vec() };
+        w.u32(VERSION);
+
+        w
+    }
+
+    /// Returns the checkpoint.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length or address as 64 bits.
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    /// Writes whether `value` is present, followed by the value if it is.
+    pub fn option<T, F>(&mut self, value: Option<T>, f: F)
+    where
+        F: FnOnce(&mut Writer, T),
+    {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            f(self, value);
+        }
+    }
+
+    /// Writes a length prefixed byte string.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.usize(value.len());
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+}
+
+
+impl Default for Writer {
+    fn default() -> Writer {
+        Writer::new()
+    }
+}
+
+
+/// Deserializes state from a checkpoint.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+
+impl<'a> Reader<'a> {
+    /// Constructs a new `Reader` after checking the header of `buf`.
+    pub fn new(buf: &'a [u8]) -> io::Result<Reader<'a>> {
+        if !buf.starts_with(MAGIC) {
+            return Err(invalid("not a checkpoint"));
+        }
+        let mut r = Reader {
+            buf: &buf[MAGIC.len()..],
+        };
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "unsupported checkpoint version {}",
+                version
+            )));
+        }
+
+        Ok(r)
+    }
+
+    /// Fails unless the whole checkpoint has been read.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.buf.len() {
+            0 => Ok(()),
+            n => Err(invalid(&format!("{} bytes left over", n))),
+        }
+    }
+
+    /// Takes the next `n` bytes.
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated checkpoint",
+            ));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(invalid(&format!("invalid flag {}", b))),
+        }
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn usize(&mut self) -> io::Result<usize> {
+        let value = self.u64()?;
+        if value > usize::MAX as u64 {
+            return Err(invalid(&format!("{} out of range", value)));
+        }
+
+        Ok(value as usize)
+    }
+
+    /// Reads a value written by `Writer::option`.
+    pub fn option<T, F>(&mut self, f: F) -> io::Result<Option<T>>
+    where
+        F: FnOnce(&mut Reader<'a>) -> io::Result<T>,
+    {
+        match self.bool()? {
+            true => f(self).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    /// Reads a length prefixed byte string.
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let n = self.usize()?;
+        self.take(n)
+    }
+
+    pub fn str(&mut self) -> io::Result<&'a str> {
+        let bytes = self.bytes()?;
+        ::std::str::from_utf8(bytes).map_err(|_| invalid("invalid string"))
+    }
+
+    /// Reads a value saved as `expected` and fails if it differs.
+    ///
+    /// `what` names the setting in the error.
+    pub fn expect_usize(&mut self, what: &str, expected: usize)
+        -> io::Result<()>
+    {
+        let value = self.usize()?;
+        if value != expected {
+            return Err(mismatch(what, &value, &expected));
+        }
+
+        Ok(())
+    }
+
+    /// Reads a string saved as `expected` and fails if it differs.
+    pub fn expect_str(&mut self, what: &str, expected: &str)
+        -> io::Result<()>
+    {
+        let value = self.str()?;
+        if value != expected {
+            return Err(mismatch(what, &value, &expected));
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Returns an error for a malformed checkpoint.
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+
+/// Returns an error for a checkpoint of a differently configured machine.
+fn mismatch<T: ::std::fmt::Display>(
+    what: &str,
+    value: &T,
+    expected: &T,
+) -> io::Error {
+    invalid(&format!(
+        "checkpoint {} is {}, not {}",
+        what,
+        value,
+        expected
+    ))
+}
+
+
+/// Saves `state` as a checkpoint.
+pub fn save<C: Checkpoint + ?Sized>(state: &C) -> Vec<u8> {
+    let mut w = Writer::new();
+    state.save(&mut w);
+
+    w.into_bytes()
+}
+
+
+/// Restores `state` from the checkpoint `buf`.
+pub fn restore<C: Checkpoint + ?Sized>(
+    state: &mut C,
+    buf: &[u8],
+) -> io::Result<()> {
+    let mut r = Reader::new(buf)?;
+    state.restore(&mut r)?;
+
+    r.finish()
+}
+
+
+/// Saves `state` as a checkpoint file at `path`.
+pub fn save_file<C, P>(state: &C, path: P) -> io::Result<()>
+where
+    C: Checkpoint + ?Sized,
+    P: AsRef<Path>,
+{
+    fs::write(path, save(state))
+}
+
+
+/// Restores `state` from the checkpoint file at `path`.
+pub fn restore_file<C, P>(state: &mut C, path: P) -> io::Result<()>
+where
+    C: Checkpoint + ?Sized,
+    P: AsRef<Path>,
+{
+    let buf = fs::read(path)?;
+    restore(state, &buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut w = Writer::new();
+        w.u8(0xab);
+        w.bool(true);
+        w.u32(0xdead_beef);
+        w.u64(1 << 40);
+        w.option(Some(7u32), |w, v| w.u32(v));
+        w.option(None::<u32>, |w, v| w.u32(v));
+        w.str("gshare");
+        let buf = w.into_bytes();
+
+        let mut r = Reader::new(&buf).unwrap();
+        assert_eq!(r.u8().unwrap(), 0xab);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u32().unwrap(), 0xdead_beef);
+        assert_eq!(r.u64().unwrap(), 1 << 40);
+        assert_eq!(r.option(|r| r.u32()).unwrap(), Some(7));
+        assert_eq!(r.option(|r| r.u32()).unwrap(), None);
+        assert!(r.expect_str("predictor", "2-bit").is_err());
+        r.finish().unwrap();
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(Reader::new(b"CASIMCKQ\x02\0\0\0").is_err());
+        assert!(Reader::new(b"CASIMCKP\x01\0\0\0").is_err());
+        assert!(Reader::new(b"CASIMCKP\x03\0\0\0").is_err());
+        assert!(Reader::new(b"CASIMCKP\x02\0").is_err());
+
+        let buf = Writer::new().into_bytes();
+        let mut r = Reader::new(&buf).unwrap();
+        assert_eq!(
+            r.u32().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
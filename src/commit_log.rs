@@ -0,0 +1,498 @@
+//! Commit logs in the format of Spike's `--log-commits`.
+//!
+//! Each retired instruction is logged on one line with its address and
+//! encoding, followed by the register it wrote and the memory it accessed:
+//!
+//! ```text
+//! core   0: 3 0x00000008 (0x00b50633) x12 0x00000007
+//! core   0: 3 0x0000000c (0x00c02023) mem 0x00000000 0x00000007
+//! core   0: 3 0x00000010 (0x00002683) x13 0x00000007 mem 0x00000000
+//! ```
+//!
+//! `CommitLog` writes the log of a run, and `diff` compares a run against a
+//! reference log, such as one from Spike or an RTL simulation, stopping at
+//! the first mismatch. Only machine mode is implemented, so every entry is
+//! at privilege level 3. Writes to `x0` and to registers other than `x1` to
+//! `x31`, such as CSRs, are ignored when reading a reference log.
+
+
+use memory::instruction::InstructionMemory;
+use pipeline::events::{Event, Observer};
+use simulator::Simulator;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+
+/// Matching commits shown before a mismatch by default.
+pub const DEFAULT_CONTEXT: usize = 5;
+
+
+/// A retired instruction and its effects.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Commit {
+    pub pc: u32,
+    pub raw_insn: u32,
+    /// Register written and its new value
+    pub reg_write: Option<(u32, u32)>,
+    /// Address of a load
+    pub mem_read: Option<u32>,
+    /// Address, size and value of a store
+    pub mem_write: Option<(u32, usize, u32)>,
+}
+
+
+impl Commit {
+    /// Parses a commit log line, returning `None` if it isn't a commit.
+    ///
+    /// Lines such as Spike's disassembly and exception messages are not
+    /// commits.
+    pub fn parse(line: &str) -> Option<Commit> {
+        let mut words = line.split_whitespace().peekable();
+        if words.next()? != "core" || !words.next()?.ends_with(':') {
+            return None;
+        }
+        words.next()?.parse::<u8>().ok()?; // privilege level
+        let pc = parse_hex(words.next()?)?;
+        let raw_insn = words.next()?;
+        if !raw_insn.starts_with('(') || !raw_insn.ends_with(')') {
+            return None;
+        }
+        let raw_insn = parse_hex(&raw_insn[1..raw_insn.len() - 1])?;
+
+        let mut commit = Commit {
+            pc,
+            raw_insn,
+            ..Commit::default()
+        };
+        while let Some(word) = words.next() {
+            if word == "mem" {
+                let addr = parse_hex(words.next()?)?;
+                // A store is followed by its value
+                if !words.peek().is_some_and(|w| w.starts_with("0x")) {
+                    commit.mem_read = Some(addr);
+                    continue;
+                }
+                let value = words.next().unwrap();
+                let size = (value.len() - 2) / 2;
+                commit.mem_write = Some((addr, size, parse_hex(value)?));
+                continue;
+            }
+
+            let value = parse_hex(words.next()?)?;
+            let rd = match word.strip_prefix('x') {
+                Some(rd) => rd.parse::<u32>().ok()?,
+                None => continue,
+            };
+            if rd != 0 {
+                commit.reg_write = Some((rd, value));
+            }
+        }
+
+        Some(commit)
+    }
+}
+
+
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "core   0: 3 {:#010x} ({:#010x})", self.pc, self.raw_insn)?;
+        if let Some((rd, value)) = self.reg_write {
+            write!(f, " x{:<2} {:#010x}", rd, value)?;
+        }
+        if let Some(addr) = self.mem_read {
+            write!(f, " mem {:#010x}", addr)?;
+        }
+        if let Some((addr, size, value)) = self.mem_write {
+            let width = 2 * size + 2;
+            write!(f, " mem {:#010x} {:#0w$x}", addr, value, w = width)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Parses a hexadecimal number with a `0x` prefix.
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text.strip_prefix("0x")?, 16).ok()
+}
+
+
+/// Assembles commits from a simulator's events.
+///
+/// In the cycle accurate model, an instruction accesses memory cycles
+/// before it retires, so accesses are held until their instruction does.
+pub struct CommitTracker<'a> {
+    insns: &'a dyn InstructionMemory,
+    /// Instruction address, then the address, size, direction and value
+    /// of each memory access, oldest first
+    accesses: VecDeque<(u32, u32, usize, bool, u32)>,
+    reg_write: Option<(u32, u32)>,
+}
+
+
+impl<'a> CommitTracker<'a> {
+    /// Constructs a new `CommitTracker` of a simulator executing `insns`.
+    pub fn new(insns: &'a dyn InstructionMemory) -> CommitTracker<'a> {
+        CommitTracker {
+            insns,
+            accesses: VecDeque::new(),
+            reg_write: None,
+        }
+    }
+
+    /// Takes the next event, returning a commit if an instruction retired.
+    pub fn event(&mut self, event: &Event) -> Option<Commit> {
+        match *event {
+            Event::MemAccess {
+                pc,
+                addr,
+                size,
+                write,
+                value,
+            } => {
+                self.accesses.push_back((pc, addr, size, write, value));
+                None
+            }
+            Event::RegWrite { rd, value, .. } => {
+                self.reg_write = Some((rd, value));
+                None
+            }
+            Event::Retire { pc, .. } => {
+                let mut commit = Commit {
+                    pc,
+                    raw_insn: self.insns.read(pc as usize),
+                    reg_write: self.reg_write.take(),
+                    ..Commit::default()
+                };
+                match self.accesses.front() {
+                    Some(&(access_pc, addr, size, write, value))
+                        if access_pc == pc =>
+                    {
+                        self.accesses.pop_front();
+                        match write {
+                            true => {
+                                let mask = match size {
+                                    4 => !0,
+                                    _ => (1 << (8 * size)) - 1,
+                                };
+                                commit.mem_write =
+                                    Some((addr, size, value & mask));
+                            }
+                            false => commit.mem_read = Some(addr),
+                        }
+                    }
+                    _ => (),
+                }
+
+                Some(commit)
+            }
+            _ => None,
+        }
+    }
+}
+
+
+/// Writes the commit log of a run.
+pub struct CommitLog<'a, W: Write> {
+    tracker: CommitTracker<'a>,
+    out: W,
+    error: Option<io::Error>,
+}
+
+
+impl<'a, W: Write> CommitLog<'a, W> {
+    /// Constructs a new `CommitLog` of a simulator executing `insns`,
+    /// writing to `out`.
+    pub fn new(insns: &'a dyn InstructionMemory, out: W) -> CommitLog<'a, W> {
+        CommitLog {
+            tracker: CommitTracker::new(insns),
+            out,
+            error: None,
+        }
+    }
+
+    /// Flushes and returns the writer, or the first error writing to it.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+
+impl<'a, W: Write> Observer for CommitLog<'a, W> {
+    fn event(&mut self, _cycle: u64, event: &Event) {
+        if let Some(commit) = self.tracker.event(event) {
+            if self.error.is_none() {
+                if let Err(e) = writeln!(self.out, "{}", commit) {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+}
+
+
+/// Reads the commits of a log, with their line numbers.
+pub fn read_log<R: BufRead>(log: R) -> io::Result<Vec<(usize, Commit)>> {
+    let mut commits = Vec::new();
+    for (i, line) in log.lines().enumerate() {
+        if let Some(commit) = Commit::parse(&line?) {
+            commits.push((i + 1, commit));
+        }
+    }
+
+    Ok(commits)
+}
+
+
+/// The first difference between a run and a reference log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    /// Line of the reference log
+    pub line: usize,
+    /// Commits that matched before it
+    pub matched: usize,
+    /// Commit the reference expected
+    pub expected: Commit,
+    /// What the simulator did instead, or `None` if it halted
+    pub actual: Option<Commit>,
+    /// Address of the HALT instruction if the simulator halted
+    pub halt_addr: Option<usize>,
+    /// Last matching commits, oldest first
+    pub context: Vec<Commit>,
+}
+
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Mismatch at line {} of the reference after {} matching commits",
+            self.line,
+            self.matched
+        )?;
+        for commit in self.context.iter() {
+            writeln!(f, "    {}", commit)?;
+        }
+        writeln!(f, "expected:")?;
+        writeln!(f, "    {}", self.expected)?;
+        writeln!(f, "actual:")?;
+        match (self.actual, self.halt_addr) {
+            (Some(commit), _) => write!(f, "    {}", commit),
+            (None, Some(addr)) => write!(f, "    HALT at {:#0x}", addr),
+            (None, None) => write!(f, "    nothing"),
+        }
+    }
+}
+
+
+/// Indicates the `rest` of the reference are the instructions that were
+/// ahead of HALT in the pipeline of the halted `sim`.
+///
+/// The cycle accurate model stops as soon as HALT reaches EX, so the two
+/// instructions ahead of it never retire.
+fn in_flight<S: Simulator>(sim: &S, rest: &[(usize, Commit)]) -> bool {
+    let pl = match sim.pipeline() {
+        Some(pl) => pl,
+        None => return false,
+    };
+    let in_flight: Vec<u32> = [
+        (pl.mem_wb.bubble, pl.mem_wb.pc),
+        (pl.ex_mem.bubble, pl.ex_mem.pc),
+    ]
+        .iter()
+        .filter(|&&(bubble, _)| !bubble)
+        .map(|&(_, pc)| pc)
+        .collect();
+
+    rest.len() <= in_flight.len() &&
+        rest.iter().zip(in_flight).all(|(&(_, c), pc)| c.pc == pc)
+}
+
+
+/// Runs `sim`, which executes `insns`, comparing each commit with the
+/// commits of a reference log read by `read_log`.
+///
+/// Reference commits before the first one at the current program counter,
+/// such as those of a boot ROM, are skipped. Returns the number of commits
+/// compared if the run matched the log until either ended, or the first
+/// mismatch with up to `context` matching commits before it. Instructions
+/// the cycle accurate model leaves unfinished at HALT aren't compared.
+pub fn diff<S: Simulator>(
+    sim: &mut S,
+    insns: &dyn InstructionMemory,
+    reference: &[(usize, Commit)],
+    context: usize,
+) -> Result<usize, Box<Mismatch>> {
+    let start = sim.registers().pc.read();
+    let reference = match reference.iter().position(|&(_, c)| c.pc == start) {
+        Some(i) => &reference[i..],
+        None => &reference[reference.len()..],
+    };
+
+    let mismatch = |matched: usize, actual, halt_addr, recent: &[Commit]| {
+        let (line, expected) = reference[matched];
+        Box::new(Mismatch {
+            line,
+            matched,
+            expected,
+            actual,
+            halt_addr,
+            context: recent.to_vec(),
+        })
+    };
+
+    let mut tracker = CommitTracker::new(insns);
+    let mut matched = 0;
+    let mut recent: VecDeque<Commit> = VecDeque::new();
+    while matched < reference.len() {
+        if let Some(addr) = sim.halt_addr() {
+            if in_flight(sim, &reference[matched..]) {
+                return Ok(matched);
+            }
+            let recent = recent.make_contiguous();
+            return Err(mismatch(matched, None, Some(addr), recent));
+        }
+        sim.step();
+
+        let events = sim.events().to_vec();
+        for commit in events.iter().filter_map(|e| tracker.event(e)) {
+            if commit != reference[matched].1 {
+                let recent = recent.make_contiguous();
+                return Err(mismatch(matched, Some(commit), None, recent));
+            }
+            matched += 1;
+            if context > 0 {
+                if recent.len() == context {
+                    recent.pop_front();
+                }
+                recent.push_back(commit);
+            }
+            if matched == reference.len() {
+                break;
+            }
+        }
+    }
+
+    Ok(matched)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use consts;
+    use ia_simulator::IaSimulator;
+    use memory::data::DataMemory;
+    use memory::instruction::TestInstructionMemory;
+    use register::RegisterFile;
+
+    #[test]
+    fn format() {
+        let commit = Commit {
+            pc: 0x80000004,
+            raw_insn: 0x00b50633,
+            reg_write: Some((12, 7)),
+            ..Commit::default()
+        };
+        assert_eq!(
+            commit.to_string(),
+            "core   0: 3 0x80000004 (0x00b50633) x12 0x00000007"
+        );
+
+        let commit = Commit {
+            pc: 0x10,
+            raw_insn: 0x00a10023,
+            mem_write: Some((0x100, 1, 0x5)),
+            ..Commit::default()
+        };
+        assert_eq!(
+            commit.to_string(),
+            "core   0: 3 0x00000010 (0x00a10023) mem 0x00000100 0x05"
+        );
+        assert_eq!(Commit::parse(&commit.to_string()), Some(commit));
+    }
+
+    #[test]
+    fn parse_spike() {
+        let load = "core   0: 3 0x80000010 (0x0002a303) x6  0x00000001 \
+                    mem 0x80001000";
+        assert_eq!(
+            Commit::parse(load),
+            Some(Commit {
+                pc: 0x80000010,
+                raw_insn: 0x0002a303,
+                reg_write: Some((6, 1)),
+                mem_read: Some(0x80001000),
+                mem_write: None,
+            })
+        );
+
+        // CSR and x0 writes are ignored
+        let csrw = "core   0: 3 0x80000000 (0x30529073) c773_mtvec 0x80000004";
+        assert_eq!(Commit::parse(csrw).unwrap().reg_write, None);
+        let nop = "core   0: 3 0x80000008 (0x00000013) x0  0x00000000";
+        assert_eq!(Commit::parse(nop).unwrap().reg_write, None);
+
+        // Disassembly and exceptions aren't commits
+        let disassembly = "core   0: 0x00001000 (0x00000297) auipc   t0, 0x0";
+        assert_eq!(Commit::parse(disassembly), None);
+        let exception = "core   0: exception trap_illegal_instruction, \
+                         epc 0x80000000";
+        assert_eq!(Commit::parse(exception), None);
+    }
+
+    #[test]
+    fn mismatch() {
+        let insns = TestInstructionMemory::new(vec![
+            0x00_50_00_93, // addi x1, x0, 5
+            0x00_10_81_33, // add x2, x1, x1
+            0x04_20_20_23, // sw x2, 64(x0)
+            consts::HALT,
+        ]);
+        let new = || {
+            let mem = DataMemory::new(1024);
+            IaSimulator::new(&insns, mem, RegisterFile::new(0x0))
+        };
+        let log = "core   0: 3 0x00001000 (0x00000297) x5  0x00001000\n\
+                   core   0: 3 0x00000000 (0x00500093) x1  0x00000005\n\
+                   core   0: 3 0x00000004 (0x00108133) x2  0x0000000a\n\
+                   core   0: 3 0x00000008 (0x04202023) mem 0x00000040 \
+                   0x0000000a\n";
+        let reference = read_log(log.as_bytes()).unwrap();
+        assert_eq!(reference.len(), 4);
+
+        // The boot ROM entry is skipped
+        assert_eq!(diff(&mut new(), &insns, &reference, 1), Ok(3));
+
+        let wrong = log.replace("x2  0x0000000a", "x2  0x0000000b");
+        let reference = read_log(wrong.as_bytes()).unwrap();
+        let mismatch = diff(&mut new(), &insns, &reference, 1).unwrap_err();
+        assert_eq!((mismatch.line, mismatch.matched), (3, 1));
+        assert_eq!(mismatch.actual.unwrap().reg_write, Some((2, 10)));
+        assert_eq!(
+            mismatch.to_string(),
+            "Mismatch at line 3 of the reference after 1 matching commits\n\
+             \x20   core   0: 3 0x00000000 (0x00500093) x1  0x00000005\n\
+             expected:\n\
+             \x20   core   0: 3 0x00000004 (0x00108133) x2  0x0000000b\n\
+             actual:\n\
+             \x20   core   0: 3 0x00000004 (0x00108133) x2  0x0000000a"
+        );
+
+        // So is running out of instructions
+        let longer = format!("{}{}", log, log.lines().nth(1).unwrap());
+        let reference = read_log(longer.as_bytes()).unwrap();
+        let mismatch = diff(&mut new(), &insns, &reference, 0).unwrap_err();
+        assert_eq!(mismatch.halt_addr, Some(0xc));
+        assert!(mismatch.context.is_empty());
+    }
+}
@@ -0,0 +1,539 @@
+//! Runs programs built for the official RISC-V test suites.
+//!
+//! riscv-tests and riscv-arch-test programs report to the host through the
+//! `tohost` symbol, as on Spike: writing 1 passes, and writing
+//! `(n << 1) | 1` fails test `n`. The host would answer requests through
+//! `fromhost`, but the tests only ever ask to exit. riscv-arch-test
+//! programs also leave a signature between `begin_signature` and
+//! `end_signature`, to be compared with a reference.
+
+
+use ca_simulator::CaSimulator;
+use elf::Elf;
+use ia_simulator::IaSimulator;
+use memory::data::DataMemory;
+use memory::instruction::ElfInstructionMemory;
+use pipeline::events::Event;
+use register::RegisterFile;
+use simulator::Simulator;
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+
+/// Cycles a test may take before it's given up on.
+pub const DEFAULT_CYCLE_LIMIT: u64 = 100_000;
+
+/// Name prefix of the riscv-tests RV32I programs run by `run_suite`.
+pub const PREFIX: &str = "rv32ui-p-";
+
+/// Name prefix of local stand-ins for riscv-tests programs, also run by
+/// `run_suite`.
+///
+/// `tests/riscv_tests` holds a few of these, hand-written against a local
+/// copy of the test environment. They aren't the upstream programs.
+pub const LOCAL_PREFIX: &str = "local-rv32ui-";
+
+/// Tests that fail on both models by design, by name without the prefix,
+/// with the reason.
+pub const EXPECTED_FAILURES: &[(&str, &str)] = &[
+    (
+        "fence_i",
+        "stores go to data memory only, so the code they write is never \
+         fetched",
+    ),
+];
+
+
+/// How a test program ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    /// Failed test number `n`
+    Fail(u32),
+    /// Wrote a request other than exit to `tohost`
+    Request(u32),
+    /// Reached the simulator's HALT instruction at the address
+    Halt(usize),
+    /// Didn't finish within the cycle limit
+    Timeout,
+}
+
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(n) => write!(f, "FAIL #{}", n),
+            Outcome::Request(value) => write!(f, "REQUEST {:#x}", value),
+            Outcome::Halt(addr) => write!(f, "HALT {:#x}", addr),
+            Outcome::Timeout => write!(f, "TIMEOUT"),
+        }
+    }
+}
+
+
+/// A test program and the locations of its host interface.
+pub struct TestProgram {
+    name: String,
+    insns: ElfInstructionMemory,
+    tohost: u32,
+    fromhost: Option<u32>,
+    /// Start and end of the signature region
+    signature: Option<(u32, u32)>,
+}
+
+
+impl TestProgram {
+    /// Reads the test program at `path`, named after the file.
+    pub fn open(path: &Path) -> io::Result<TestProgram> {
+        let name = path.file_name()
+            .map_or(path.as_os_str(), |name| name)
+            .to_string_lossy()
+            .into_owned();
+
+        TestProgram::new(name, &Elf::open(path)?)
+    }
+
+    /// Constructs a new `TestProgram` from an executable.
+    ///
+    /// Returns an `InvalidData` error if it has no `tohost` symbol.
+    pub fn new(name: String, elf: &Elf) -> io::Result<TestProgram> {
+        let insns = ElfInstructionMemory::new(elf);
+        let symbol = |name: &str| insns.symbols().get(name).cloned();
+        let tohost = symbol("tohost").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: no tohost symbol", name),
+            )
+        })?;
+        let fromhost = symbol("fromhost");
+        let signature = match (symbol("begin_signature"),
+                               symbol("end_signature")) {
+            (Some(begin), Some(end)) if begin <= end => Some((begin, end)),
+            _ => None,
+        };
+
+        Ok(TestProgram {
+            name,
+            insns,
+            tohost,
+            fromhost,
+            signature,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn instructions(&self) -> &ElfInstructionMemory {
+        &self.insns
+    }
+
+    /// Returns the address of `tohost`, offset like the image.
+    pub fn tohost(&self) -> u32 {
+        self.tohost
+    }
+
+    /// Returns the address of `fromhost`, if the program has one.
+    pub fn fromhost(&self) -> Option<u32> {
+        self.fromhost
+    }
+
+    /// Constructs data memory just large enough for the image, with the
+    /// image loaded.
+    pub fn data_memory(&self) -> DataMemory {
+        let mut mem = DataMemory::new(self.insns.words().len());
+        self.load(&mut mem);
+
+        mem
+    }
+
+    /// Loads the image into `mem`, which must be large enough.
+    pub fn load(&self, mem: &mut DataMemory) {
+        for (i, &word) in self.insns.words().iter().enumerate() {
+            mem.write(i * 4, 4, word).unwrap();
+        }
+    }
+
+    /// Returns registers that start execution at the entry point.
+    pub fn registers(&self) -> RegisterFile {
+        RegisterFile::new(self.insns.entry())
+    }
+
+    /// Runs `sim` until the program writes `tohost`, for at most `limit`
+    /// cycles in total.
+    pub fn run<S: Simulator>(&self, sim: &mut S, limit: u64) -> Outcome {
+        while sim.clock() < limit {
+            let halt_addr = sim.step();
+            for event in sim.events() {
+                if let Event::MemAccess {
+                    addr,
+                    write: true,
+                    value,
+                    ..
+                } = *event
+                {
+                    if addr == self.tohost && value != 0 {
+                        return exit_outcome(value);
+                    }
+                }
+            }
+            if let Some(addr) = halt_addr {
+                return Outcome::Halt(addr);
+            }
+        }
+
+        Outcome::Timeout
+    }
+
+    /// Returns the words of the signature region in `mem`, once the
+    /// program has run, if the program has one.
+    ///
+    /// Buffered stores are drained first.
+    pub fn signature(&self, mem: &mut DataMemory) -> Option<Vec<u32>> {
+        let (begin, end) = self.signature?;
+        mem.drain_stores();
+
+        let words = (begin..end)
+            .step_by(4)
+            .map(|addr| mem.read(addr as usize, 4).unwrap())
+            .collect();
+
+        Some(words)
+    }
+}
+
+
+/// Returns the outcome of the exit request `value` written to `tohost`.
+fn exit_outcome(value: u32) -> Outcome {
+    match value {
+        1 => Outcome::Pass,
+        v if v & 1 == 1 => Outcome::Fail(v >> 1),
+        v => Outcome::Request(v),
+    }
+}
+
+
+/// Writes a signature in the format of riscv-arch-test reference files,
+/// one word per line as eight hex digits.
+pub fn write_signature<W: Write>(mut w: W, words: &[u32]) -> io::Result<()> {
+    for word in words {
+        writeln!(w, "{:08x}", word)?;
+    }
+
+    Ok(())
+}
+
+
+/// Returns the riscv-tests RV32I programs and local stand-ins in `dir`, by
+/// name.
+///
+/// Files with an extension, like the `.dump` disassemblies riscv-tests
+/// builds alongside, are skipped.
+pub fn find(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_test = path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                test_name(name).is_some() && !name.contains('.')
+            });
+        if is_test && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+
+/// Returns the name of the test program `file_name` without its prefix,
+/// e.g., `add` for `rv32ui-p-add`.
+fn test_name(file_name: &str) -> Option<&str> {
+    file_name
+        .strip_prefix(PREFIX)
+        .or_else(|| file_name.strip_prefix(LOCAL_PREFIX))
+}
+
+
+/// Returns why the test program `file_name` is expected to fail, if it is.
+pub fn expected_failure(file_name: &str) -> Option<&'static str> {
+    let name = test_name(file_name)?;
+    EXPECTED_FAILURES
+        .iter()
+        .find(|&&(failing, _)| failing == name)
+        .map(|&(_, reason)| reason)
+}
+
+
+/// Outcomes of a test program on both models.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub name: String,
+    pub ia: Outcome,
+    pub ca: Outcome,
+}
+
+
+/// Pass/fail matrix of a test suite.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Matrix {
+    pub rows: Vec<Row>,
+}
+
+
+impl Matrix {
+    /// Returns the number of tests passed by the IA and CA models.
+    pub fn passed(&self) -> (usize, usize) {
+        let count = |outcome: fn(&Row) -> Outcome| {
+            self.rows
+                .iter()
+                .filter(|row| outcome(row) == Outcome::Pass)
+                .count()
+        };
+
+        (count(|row| row.ia), count(|row| row.ca))
+    }
+
+    /// Indicates every test passed on both models.
+    pub fn all_passed(&self) -> bool {
+        self.passed() == (self.rows.len(), self.rows.len())
+    }
+}
+
+
+impl fmt::Display for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.rows
+            .iter()
+            .map(|row| row.name.len())
+            .chain(Some("passed".len()))
+            .max()
+            .unwrap();
+
+        let line = |f: &mut fmt::Formatter, name: &str, ia: &str, ca: &str| {
+            writeln!(f, "{:<w$}  {:<12}  {}", name, ia, ca, w = width)
+        };
+
+        line(f, "test", "IA", "CA")?;
+        for row in &self.rows {
+            line(f, &row.name, &row.ia.to_string(), &row.ca.to_string())?;
+        }
+        let (ia, ca) = self.passed();
+        let total = self.rows.len();
+        let ia = format!("{}/{}", ia, total);
+        let ca = format!("{}/{}", ca, total);
+        write!(f, "{:<w$}  {:<12}  {}", "passed", ia, ca, w = width)?;
+
+        // Explain the failures that are by design
+        for row in &self.rows {
+            if row.ia == Outcome::Pass && row.ca == Outcome::Pass {
+                continue;
+            }
+            if let Some(reason) = expected_failure(&row.name) {
+                write!(f, "\n{} is expected to fail: {}", row.name, reason)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Runs each riscv-tests RV32I program in `dir` on both models.
+///
+/// `configure` sets up the CA model, e.g., its branch unit, before each
+/// run.
+pub fn run_suite<F>(
+    dir: &Path,
+    limit: u64,
+    mut configure: F,
+) -> io::Result<Matrix>
+where
+    F: FnMut(&mut CaSimulator),
+{
+    let mut matrix = Matrix::default();
+    for path in find(dir)? {
+        let program = TestProgram::open(&path)?;
+        let insns = program.instructions();
+
+        let (data, regs) = (program.data_memory(), program.registers());
+        let mut ia = IaSimulator::new(insns, data, regs);
+        let ia = program.run(&mut ia, limit);
+
+        let (data, regs) = (program.data_memory(), program.registers());
+        let mut ca = CaSimulator::new(insns, data, regs);
+        configure(&mut ca);
+        let ca = program.run(&mut ca, limit);
+
+        matrix.rows.push(Row {
+            name: program.name().to_string(),
+            ia,
+            ca,
+        });
+    }
+
+    Ok(matrix)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use consts;
+    use elf;
+
+    const TOHOST: u32 = 0x8000_0040;
+
+    /// Builds a program that stores `a0` to tohost, after a trap if
+    /// `trap`, then spins.
+    fn program(a0: u32, trap: bool) -> TestProgram {
+        let mut words = vec![
+            0x00_00_02_97, // auipc t0, 0
+            0x02_c2_82_93, // addi t0, t0, 44: trap handler
+            0x30_52_90_73, // csrw mtvec, t0
+            0x00_00_05_13 | a0 << 20, // li a0, a0
+            0x00_00_00_13, // nop, or the trapping instruction
+            0x00_00_00_13, // nop
+            0x00_00_00_13, // nop
+            0x00_00_00_13, // nop
+            0x00_00_00_13, // nop
+            0x00_00_00_13, // nop
+            0x00_00_00_13, // nop
+            0x00_00_03_17, // handler: auipc t1, 0
+            0x00_a3_2a_23, // sw a0, 20(t1): tohost
+            0x00_00_00_6f, // j .
+        ];
+        if trap {
+            words[4] = 0;
+        }
+        words.resize(16, 0);
+        words.push(0); // tohost
+        words.push(0); // begin_signature
+        words.push(a0);
+
+        let symbols = [
+            ("tohost", TOHOST),
+            ("begin_signature", TOHOST + 4),
+            ("end_signature", TOHOST + 12),
+        ];
+        let bytes = elf::build(0x8000_0000, 0x8000_0000, &words, 0, &symbols);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        TestProgram::new(String::from("t"), &elf).unwrap()
+    }
+
+    fn run_both(program: &TestProgram) -> (Outcome, Outcome) {
+        let insns = program.instructions();
+        let (data, regs) = (program.data_memory(), program.registers());
+        let mut ia = IaSimulator::new(insns, data, regs);
+        let (data, regs) = (program.data_memory(), program.registers());
+        let mut ca = CaSimulator::new(insns, data, regs);
+
+        (program.run(&mut ia, 1000), program.run(&mut ca, 1000))
+    }
+
+    #[test]
+    fn outcomes() {
+        let pass = program(1, false);
+        assert_eq!(pass.tohost(), 0x40);
+        assert_eq!(run_both(&pass), (Outcome::Pass, Outcome::Pass));
+
+        let fail = program(7, false);
+        assert_eq!(run_both(&fail), (Outcome::Fail(3), Outcome::Fail(3)));
+
+        let request = program(0x100, false);
+        let expected = Outcome::Request(0x100);
+        assert_eq!(run_both(&request), (expected, expected));
+
+        // An illegal instruction traps to the handler
+        let trap = program(1, true);
+        assert_eq!(run_both(&trap), (Outcome::Pass, Outcome::Pass));
+    }
+
+    #[test]
+    fn timeout_and_halt() {
+        let program = program(0, false);
+        let insns = program.instructions();
+        let (data, regs) = (program.data_memory(), program.registers());
+        let mut ia = IaSimulator::new(insns, data, regs);
+        assert_eq!(program.run(&mut ia, 500), Outcome::Timeout);
+        assert_eq!(ia.clock(), 500);
+
+        // A program of just HALT
+        let insns = ElfInstructionMemory::new(&Elf {
+            entry: 0x8000_0000,
+            segments: vec![elf::Segment {
+                addr: 0x8000_0000,
+                data: consts::HALT.to_le_bytes().to_vec(),
+                mem_size: 4,
+            }],
+            symbols: Default::default(),
+        });
+        let mem = program.data_memory();
+        let mut ca = CaSimulator::new(&insns, mem, RegisterFile::new(0));
+        assert_eq!(program.run(&mut ca, 100), Outcome::Halt(0));
+    }
+
+    #[test]
+    fn signature() {
+        let program = program(0x2a, false);
+        let mut ia = IaSimulator::new(
+            program.instructions(),
+            program.data_memory(),
+            program.registers(),
+        );
+        program.run(&mut ia, 100);
+
+        let words = program.signature(ia.memory_mut()).unwrap();
+        assert_eq!(words, vec![0, 0x2a]);
+
+        let mut text = Vec::new();
+        write_signature(&mut text, &words).unwrap();
+        assert_eq!(text, b"00000000\n0000002a\n");
+    }
+
+    #[test]
+    fn matrix() {
+        let matrix = Matrix {
+            rows: vec![
+                Row {
+                    name: String::from("rv32ui-p-add"),
+                    ia: Outcome::Pass,
+                    ca: Outcome::Pass,
+                },
+                Row {
+                    name: String::from("rv32ui-p-fence_i"),
+                    ia: Outcome::Fail(2),
+                    ca: Outcome::Timeout,
+                },
+            ],
+        };
+        assert_eq!(matrix.passed(), (1, 1));
+        assert!(!matrix.all_passed());
+        assert_eq!(
+            matrix.to_string(),
+            "test              IA            CA\n\
+             rv32ui-p-add      pass          pass\n\
+             rv32ui-p-fence_i  FAIL #2       TIMEOUT\n\
+             passed            1/2           1/2\n\
+             rv32ui-p-fence_i is expected to fail: stores go to data \
+             memory only, so the code they write is never fetched"
+        );
+    }
+
+    #[test]
+    fn expected_failures() {
+        let reason = expected_failure("rv32ui-p-fence_i").unwrap();
+        assert_eq!(expected_failure("local-rv32ui-fence_i"), Some(reason));
+        assert_eq!(expected_failure("rv32ui-p-add"), None);
+        assert_eq!(expected_failure("fence_i"), None);
+    }
+}
@@ -12,6 +12,11 @@ pub const BYTE_SIZE: usize = 1;
 /// A canonical RISC-V NOP, encoded as ADDI x0, x0, 0.
 pub const NOP: u32 = 0x13;
 
+/// Opcodes of branches and jumps, which the fetch stage predecodes.
+pub const BRANCH_OPCODE: u32 = 0b110_0011;
+pub const JAL_OPCODE: u32 = 0b110_1111;
+pub const JALR_OPCODE: u32 = 0b110_0111;
+
 // Masks to isolate specific parts of the instruction using logical AND (&)
 pub const FUNCT7_MASK: u32 = 0xfe000000;
 pub const FUNCT3_MASK: u32 = 0x7000;
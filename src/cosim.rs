@@ -0,0 +1,432 @@
+//! Lockstep co-simulation of the cycle accurate model against the
+//! instruction accurate one.
+//!
+//! `Lockstep` clocks a `CaSimulator`, and every time it retires an
+//! instruction in WB, executes the next instruction on an `IaSimulator` of
+//! the same program and compares what the two did: the instruction's
+//! address, the register it wrote and the memory it wrote. The first
+//! difference stops the run with a `Divergence`, which describes both
+//! instructions, the CA pipeline and the instructions retired before.
+//!
+//! The models must start in the same state, with their own copies of data
+//! memory. Devices whose behaviour depends on timing, such as a disk with
+//! a latency, see different cycle counts in the two models and can make
+//! them diverge.
+
+
+use ca_simulator::CaSimulator;
+use ia_simulator::IaSimulator;
+use instruction::Instruction;
+use memory::instruction::InstructionMemory;
+use pipeline::Pipeline;
+use pipeline::events::Event;
+use simulator::Simulator;
+
+use std::collections::VecDeque;
+use std::fmt;
+
+
+/// Retirements kept for a `Divergence` by default.
+pub const DEFAULT_HISTORY: usize = 16;
+
+/// Most IA steps taken for one instruction, allowing for traps, which
+/// don't retire anything.
+const MAX_IA_STEPS: usize = 16;
+
+
+/// What an instruction did, as seen when it retired.
+#[derive(Clone, Copy, Debug)]
+pub struct Retirement {
+    pub pc: u32,
+    pub insn: Instruction,
+    /// Register written and its new value
+    pub reg_write: Option<(u32, u32)>,
+    /// Address, size and value of a store
+    pub mem_write: Option<(u32, usize, u32)>,
+}
+
+
+impl Retirement {
+    /// Indicates both retirements did the same.
+    fn matches(&self, other: &Retirement) -> bool {
+        self.pc == other.pc && self.reg_write == other.reg_write &&
+            self.mem_write == other.mem_write
+    }
+}
+
+
+impl fmt::Display for Retirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#0x}: {}", self.pc, self.insn)?;
+        if let Some((rd, value)) = self.reg_write {
+            write!(f, "  x{} <- {:#0x}", rd, value)?;
+        }
+        if let Some((addr, size, value)) = self.mem_write {
+            write!(f, "  mem[{:#0x}; {}] <- {:#0x}", addr, size, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// The first difference found between the models.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// CA clock cycle the difference showed in
+    pub cycle: u64,
+    /// Instructions that matched before it
+    pub matched: u64,
+    /// Instruction the CA model retired, or `None` if it halted
+    pub ca: Option<Retirement>,
+    /// Instruction the IA model executed, or `None` if it halted or
+    /// didn't retire anything
+    pub ia: Option<Retirement>,
+    /// Address each model halted at, if either did
+    pub halt_addrs: (Option<usize>, Option<usize>),
+    /// CA pipeline registers at the end of the cycle
+    pub pipeline: Pipeline,
+    /// Last matching retirements, oldest first
+    pub recent: Vec<Retirement>,
+}
+
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Models diverged in cycle {} after {} matching instructions:",
+            self.cycle,
+            self.matched
+        )?;
+        let models = [
+            ("CA", self.ca, self.halt_addrs.0),
+            ("IA", self.ia, self.halt_addrs.1),
+        ];
+        for &(model, retirement, halt_addr) in models.iter() {
+            match (retirement, halt_addr) {
+                (Some(r), _) => writeln!(f, "    {}  {}", model, r)?,
+                (None, Some(addr)) => {
+                    writeln!(f, "    {}  halted at {:#0x}", model, addr)?
+                }
+                (None, None) => writeln!(f, "    {}  nothing retired", model)?,
+            }
+        }
+
+        writeln!(f, "CA pipeline:")?;
+        let pl = &self.pipeline;
+        let registers = [
+            ("IF/ID", pl.if_id.bubble, pl.if_id.pc,
+             Instruction::new(pl.if_id.raw_insn)),
+            ("ID/EX", pl.id_ex.bubble, pl.id_ex.pc, pl.id_ex.insn),
+            ("EX/MEM", pl.ex_mem.bubble, pl.ex_mem.pc, pl.ex_mem.insn),
+            ("MEM/WB", pl.mem_wb.bubble, pl.mem_wb.pc, pl.mem_wb.insn),
+        ];
+        for &(name, bubble, pc, insn) in registers.iter() {
+            match bubble {
+                true => writeln!(f, "    {:<7}bubble", name)?,
+                false => writeln!(f, "    {:<7}{:#0x}: {}", name, pc, insn)?,
+            }
+        }
+
+        write!(f, "Last {} retirements:", self.recent.len())?;
+        for r in self.recent.iter() {
+            write!(f, "\n    {}", r)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Runs the cycle accurate model in lockstep with the instruction accurate
+/// one.
+pub struct Lockstep<'a> {
+    insns: &'a dyn InstructionMemory,
+    ca: CaSimulator<'a>,
+    ia: IaSimulator<'a>,
+    /// Retirements kept in `recent`
+    history: usize,
+    recent: VecDeque<Retirement>,
+    /// Stores made by CA instructions that haven't retired yet, in order
+    stores: VecDeque<(u32, (u32, usize, u32))>,
+    matched: u64,
+}
+
+
+impl<'a> Lockstep<'a> {
+    /// Constructs a new `Lockstep` comparing `ca` against `ia`, which both
+    /// execute `insns`.
+    pub fn new(
+        insns: &'a dyn InstructionMemory,
+        ca: CaSimulator<'a>,
+        ia: IaSimulator<'a>,
+    ) -> Lockstep<'a> {
+        Lockstep {
+            insns,
+            ca,
+            ia,
+            history: DEFAULT_HISTORY,
+            recent: VecDeque::new(),
+            stores: VecDeque::new(),
+            matched: 0,
+        }
+    }
+
+    /// Sets how many of the last retirements a `Divergence` lists.
+    pub fn set_history(&mut self, history: usize) {
+        self.history = history;
+        while self.recent.len() > history {
+            self.recent.pop_front();
+        }
+    }
+
+    pub fn ca(&self) -> &CaSimulator<'a> {
+        &self.ca
+    }
+
+    pub fn ia(&self) -> &IaSimulator<'a> {
+        &self.ia
+    }
+
+    /// Returns the number of instructions that matched so far.
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// Simulates a CA clock cycle and checks the instruction it retired,
+    /// if any.
+    ///
+    /// Returns the address of the HALT instruction once both models have
+    /// reached it.
+    pub fn step(&mut self) -> Result<Option<usize>, Box<Divergence>> {
+        if let Some(addr) = self.ca.halt_addr() {
+            return Ok(Some(addr));
+        }
+
+        let halt_addr = self.ca.step();
+        let events = self.ca.events().to_vec();
+        for event in events.iter() {
+            if let Event::MemAccess {
+                pc,
+                addr,
+                size,
+                write: true,
+                value,
+            } = *event
+            {
+                self.stores.push_back((pc, (addr, size, value)));
+            }
+        }
+
+        if let Some((pc, reg_write)) = retired(&events) {
+            // Stores are made in MEM, before the instruction retires
+            let mem_write = match self.stores.front() {
+                Some(&(store_pc, store)) if store_pc == pc => {
+                    self.stores.pop_front();
+                    Some(store)
+                }
+                _ => None,
+            };
+            let ca = self.retirement(pc, reg_write, mem_write);
+            let ia = self.next_ia();
+            match ia {
+                Some(ref ia) if ia.matches(&ca) => self.agree(ca),
+                _ => return Err(self.divergence(Some(ca), ia)),
+            }
+        }
+
+        match halt_addr {
+            Some(addr) => self.finish(addr).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Runs both models to HALT.
+    pub fn run(&mut self) -> Result<usize, Box<Divergence>> {
+        loop {
+            if let Some(addr) = self.step()? {
+                return Ok(addr);
+            }
+        }
+    }
+
+    fn retirement(
+        &self,
+        pc: u32,
+        reg_write: Option<(u32, u32)>,
+        mem_write: Option<(u32, usize, u32)>,
+    ) -> Retirement {
+        Retirement {
+            pc,
+            insn: Instruction::new(self.insns.read(pc as usize)),
+            reg_write,
+            mem_write,
+        }
+    }
+
+    /// Executes IA instructions until one retires, returning what it did,
+    /// or `None` if the model halted or kept trapping.
+    fn next_ia(&mut self) -> Option<Retirement> {
+        for _ in 0..MAX_IA_STEPS {
+            if self.ia.step().is_some() {
+                return None;
+            }
+            let events = self.ia.events();
+            let (pc, reg_write) = match retired(events) {
+                Some(retired) => retired,
+                None => continue,
+            };
+            let mem_write = events
+                .iter()
+                .filter_map(|e| match *e {
+                    Event::MemAccess {
+                        addr,
+                        size,
+                        write: true,
+                        value,
+                        ..
+                    } => Some((addr, size, value)),
+                    _ => None,
+                })
+                .next();
+
+            return Some(self.retirement(pc, reg_write, mem_write));
+        }
+
+        None
+    }
+
+    /// Records a matching retirement.
+    fn agree(&mut self, retirement: Retirement) {
+        self.matched += 1;
+        if self.history == 0 {
+            return;
+        }
+        if self.recent.len() == self.history {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(retirement);
+    }
+
+    /// Runs the IA model to HALT after the CA model halted at `addr`.
+    ///
+    /// The CA model halts with the instructions before HALT still in the
+    /// pipeline, so the IA model may have to execute a few more.
+    fn finish(&mut self, addr: usize) -> Result<usize, Box<Divergence>> {
+        for _ in 0..MAX_IA_STEPS {
+            if let Some(ia_addr) = self.ia.step() {
+                if ia_addr == addr {
+                    return Ok(addr);
+                }
+                break;
+            }
+        }
+
+        Err(self.divergence(None, None))
+    }
+
+    fn divergence(
+        &self,
+        ca: Option<Retirement>,
+        ia: Option<Retirement>,
+    ) -> Box<Divergence> {
+        Box::new(Divergence {
+            cycle: self.ca.clock() - 1,
+            matched: self.matched,
+            ca,
+            ia,
+            halt_addrs: (self.ca.halt_addr(), self.ia.halt_addr()),
+            pipeline: *self.ca.pipeline().unwrap(),
+            recent: self.recent.iter().cloned().collect(),
+        })
+    }
+}
+
+
+/// Returns the address and register write of the instruction retired with
+/// `events`, if one retired.
+fn retired(events: &[Event]) -> Option<(u32, Option<(u32, u32)>)> {
+    let mut pc = None;
+    let mut reg_write = None;
+    for event in events {
+        match *event {
+            Event::Retire { pc: retired, .. } => pc = Some(retired),
+            Event::RegWrite { rd, value, .. } => reg_write = Some((rd, value)),
+            _ => (),
+        }
+    }
+
+    pc.map(|pc| (pc, reg_write))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use consts;
+    use hazards::{HazardPolicy, HazardUnit};
+    use memory::data::DataMemory;
+    use memory::instruction::TestInstructionMemory;
+    use register::RegisterFile;
+
+    fn program() -> TestInstructionMemory {
+        let mut insns = vec![
+            0x00_50_00_93, // addi x1, x0, 5
+            0x00_10_81_33, // add x2, x1, x1
+            0x04_20_20_23, // sw x2, 64(x0)
+        ];
+        insns.extend_from_slice(&[consts::NOP; 3]);
+        insns.push(consts::HALT);
+        insns.extend_from_slice(&[consts::NOP; 3]);
+
+        TestInstructionMemory::new(insns)
+    }
+
+    fn lockstep<'a>(insns: &'a TestInstructionMemory, policy: HazardPolicy)
+        -> Lockstep<'a>
+    {
+        let mem = DataMemory::new(1024);
+        let mut ca = CaSimulator::new(insns, mem, RegisterFile::new(0x0));
+        ca.set_hazard_unit(HazardUnit::new(policy));
+        let mem = DataMemory::new(1024);
+        let ia = IaSimulator::new(insns, mem, RegisterFile::new(0x0));
+
+        Lockstep::new(insns, ca, ia)
+    }
+
+    #[test]
+    fn models_agree() {
+        let insns = program();
+        let mut lockstep = lockstep(&insns, HazardPolicy::Forwarding);
+        assert_eq!(lockstep.run().unwrap(), 0x18);
+        // The last two NOPs are still in the pipeline at HALT
+        assert_eq!(lockstep.matched(), 4);
+        assert_eq!(lockstep.ia().memory().read(64, 4), Ok(10));
+    }
+
+    #[test]
+    fn divergence() {
+        let insns = program();
+        let mut lockstep = lockstep(&insns, HazardPolicy::NoInterlock);
+        lockstep.set_history(1);
+        let divergence = lockstep.run().unwrap_err();
+
+        // The add reads x1 before the addi writes it back
+        assert_eq!(divergence.matched, 1);
+        assert_eq!(divergence.ca.unwrap().reg_write, Some((2, 0)));
+        assert_eq!(divergence.ia.unwrap().reg_write, Some((2, 10)));
+        let report = divergence.to_string();
+        assert!(report.starts_with(&format!(
+            "Models diverged in cycle {} after 1 matching instructions:\n\
+             \x20   CA  0x4: add x2, x1, x1  x2 <- 0x0\n\
+             \x20   IA  0x4: add x2, x1, x1  x2 <- 0xa\n\
+             CA pipeline:\n",
+            divergence.cycle
+        )));
+        assert!(report.contains("    EX/MEM 0xc: addi x0, x0, 0\n"));
+        assert!(report.ends_with("Last 1 retirements:\n\
+                                  \x20   0x0: addi x1, x0, 5  x1 <- 0x5"));
+    }
+}
@@ -0,0 +1,233 @@
+//! Machine-mode control and status registers.
+
+
+use checkpoint::{Checkpoint, Reader, Writer};
+use memory::pmp::{self, Pmp};
+use trap::{Exception, Trap};
+
+use std::io;
+
+
+// CSR addresses (see RISC-V Privileged Architecture V1.10, table 2.5)
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPADDR0: u32 = 0x3b0;
+pub const MHARTID: u32 = 0xf14;
+
+/// MXL = 32 bits, extensions = I.
+const MISA_RV32I: u32 = 0x4000_0100;
+
+// Fields of mstatus
+const MSTATUS_MIE: u32 = 1 << 3;
+const MSTATUS_MPIE: u32 = 1 << 7;
+const MSTATUS_MPP: u32 = 0b11 << 11;
+
+
+/// Machine-mode CSRs, including the physical memory protection unit.
+#[derive(Clone, Copy, Debug)]
+pub struct CsrFile {
+    pub mstatus: u32,
+    pub mtvec: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub pmp: Pmp,
+}
+
+
+impl CsrFile {
+    /// Constructs a new `CsrFile`.
+    pub fn new() -> CsrFile {
+        CsrFile {
+            mstatus: MSTATUS_MPP, // only machine mode is implemented
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            pmp: Pmp::new(),
+        }
+    }
+
+    /// Reads CSR `addr`.
+    ///
+    /// Unimplemented CSRs raise an illegal instruction exception.
+    pub fn read(&self, addr: u32) -> Result<u32, Exception> {
+        let value = match addr {
+            MSTATUS => self.mstatus,
+            MISA => MISA_RV32I,
+            MIE | MIP => 0, // no interrupt sources
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MHARTID => 0,
+            a if is_pmpcfg(a) => self.pmp.read_cfg((a - PMPCFG0) as usize),
+            a if is_pmpaddr(a) => self.pmp.read_addr((a - PMPADDR0) as usize),
+            _ => return Err(Exception::IllegalInstruction),
+        };
+
+        Ok(value)
+    }
+
+    /// Writes `value` to CSR `addr`.
+    ///
+    /// Writes to read-only or unimplemented CSRs raise an illegal instruction
+    /// exception. Writes to WARL fields keep only the legal bits.
+    pub fn write(&mut self, addr: u32, value: u32) -> Result<(), Exception> {
+        if (addr >> 10) == 0b11 {
+            return Err(Exception::IllegalInstruction); // read-only space
+        }
+
+        match addr {
+            MSTATUS => {
+                let mask = MSTATUS_MIE | MSTATUS_MPIE;
+                self.mstatus = (value & mask) | MSTATUS_MPP;
+            }
+            MISA => {} // fixed ISA, writes ignored
+            MIE | MIP => {}
+            MTVEC => self.mtvec = value & !0x3, // direct mode only
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !0x3,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            a if is_pmpcfg(a) => {
+                self.pmp.write_cfg((a - PMPCFG0) as usize, value)
+            }
+            a if is_pmpaddr(a) => {
+                self.pmp.write_addr((a - PMPADDR0) as usize, value)
+            }
+            _ => return Err(Exception::IllegalInstruction),
+        }
+
+        Ok(())
+    }
+
+    /// Takes a trap raised by the instruction at `pc`.
+    ///
+    /// Returns the address of the trap handler.
+    pub fn trap(&mut self, pc: u32, trap: Trap) -> u32 {
+        let mie = self.mstatus & MSTATUS_MIE;
+        self.mstatus = (mie << 4) | MSTATUS_MPP; // MPIE <- MIE, MIE <- 0
+        self.mepc = pc;
+        self.mcause = trap.cause.code();
+        self.mtval = trap.tval;
+
+        self.mtvec
+    }
+
+    /// Returns from a trap handler (MRET).
+    ///
+    /// Returns the address to resume execution at.
+    pub fn trap_return(&mut self) -> u32 {
+        let mpie = self.mstatus & MSTATUS_MPIE;
+        self.mstatus = (mpie >> 4) | MSTATUS_MPIE | MSTATUS_MPP;
+
+        self.mepc
+    }
+}
+
+
+impl Default for CsrFile {
+    fn default() -> CsrFile {
+        CsrFile::new()
+    }
+}
+
+
+impl Checkpoint for CsrFile {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.mstatus);
+        w.u32(self.mtvec);
+        w.u32(self.mscratch);
+        w.u32(self.mepc);
+        w.u32(self.mcause);
+        w.u32(self.mtval);
+        self.pmp.save(w);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.mstatus = r.u32()?;
+        self.mtvec = r.u32()?;
+        self.mscratch = r.u32()?;
+        self.mepc = r.u32()?;
+        self.mcause = r.u32()?;
+        self.mtval = r.u32()?;
+
+        self.pmp.restore(r)
+    }
+}
+
+
+/// Indicates `addr` is one of pmpcfg0-pmpcfg3.
+fn is_pmpcfg(addr: u32) -> bool {
+    addr >= PMPCFG0 && addr < PMPCFG0 + (pmp::NUM_ENTRIES / 4) as u32
+}
+
+
+/// Indicates `addr` is one of pmpaddr0-pmpaddr15.
+fn is_pmpaddr(addr: u32) -> bool {
+    addr >= PMPADDR0 && addr < PMPADDR0 + pmp::NUM_ENTRIES as u32
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write() {
+        let mut csr = CsrFile::new();
+        csr.write(MSCRATCH, 0xdeadbeef).unwrap();
+        assert_eq!(csr.read(MSCRATCH), Ok(0xdeadbeef));
+        csr.write(MTVEC, 0x103).unwrap();
+        assert_eq!(csr.read(MTVEC), Ok(0x100));
+        csr.write(PMPADDR0 + 15, 0x1234).unwrap();
+        assert_eq!(csr.read(PMPADDR0 + 15), Ok(0x1234));
+        csr.write(MIE, 0x888).unwrap();
+        assert_eq!(csr.read(MIE), Ok(0));
+    }
+
+    #[test]
+    fn illegal_access() {
+        let mut csr = CsrFile::new();
+        assert_eq!(csr.read(0x7c0), Err(Exception::IllegalInstruction));
+        assert_eq!(
+            csr.write(MHARTID, 1),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(
+            csr.write(PMPCFG0 + 4, 1),
+            Err(Exception::IllegalInstruction)
+        );
+    }
+
+    #[test]
+    fn trap_and_return() {
+        let mut csr = CsrFile::new();
+        csr.write(MTVEC, 0x200).unwrap();
+        csr.write(MSTATUS, MSTATUS_MIE).unwrap();
+
+        let trap = Trap::new(Exception::StoreAccessFault, 0x400);
+        assert_eq!(csr.trap(0x1c, trap), 0x200);
+        assert_eq!(csr.mepc, 0x1c);
+        assert_eq!(csr.mcause, 7);
+        assert_eq!(csr.mtval, 0x400);
+        assert_eq!(csr.mstatus & MSTATUS_MIE, 0);
+        assert_eq!(csr.mstatus & MSTATUS_MPIE, MSTATUS_MPIE);
+
+        assert_eq!(csr.trap_return(), 0x1c);
+        assert_eq!(csr.mstatus & MSTATUS_MIE, MSTATUS_MIE);
+    }
+
+}
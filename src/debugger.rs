@@ -0,0 +1,1174 @@
+//! Interactive debugger for either simulator.
+//!
+//! `Debugger` drives a `Simulator` from a command shell, reading one command
+//! per line:
+//!
+//! ```text
+//! b LOC [if COND]   break before the instruction at LOC, an address or
+//!                   symbol, optionally only when COND holds
+//! b if COND         break before any instruction when COND holds
+//! watch ADDR        stop when the word at ADDR changes
+//! rwatch ADDR       stop when the word at ADDR is read
+//! wwatch ADDR       stop when the word at ADDR is written
+//! d N               delete breakpoint or watchpoint N
+//! i                 list breakpoints and watchpoints
+//! s [N]             step N instructions (default 1)
+//! cycle [N]         step N clock cycles (default 1)
+//! c                 continue to a breakpoint, watchpoint or HALT
+//! rs [N]            step back N instructions (default 1)
+//! rcycle [N]        step back N clock cycles (default 1)
+//! rc                continue backwards to the last breakpoint or
+//!                   watchpoint hit
+//! who LOC           show the last write to LOC, a register or the word
+//!                   at an address
+//! r                 print the registers
+//! p REG             print a register, by ABI or architectural name
+//! x ADDR [N]        examine N words of data memory (default 4)
+//! l [LOC]           disassemble around LOC (default: the current
+//!                   instruction)
+//! pipe              print the pipeline registers
+//! reset             reset the processor, keeping data memory
+//! save FILE         save a checkpoint of the machine to FILE
+//! load FILE         restore the machine from the checkpoint FILE
+//! q                 quit
+//! ```
+//!
+//! Conditions compare a register with a value, e.g. `a0 == 3`, using `==`,
+//! `!=`, `<`, `<=`, `>` or `>=` on unsigned values. An empty line repeats
+//! the last command.
+//!
+//! The current instruction is the next one to complete. In the cycle
+//! accurate model that's the oldest instruction in the pipeline, so an
+//! instruction step runs until the next one retires, and a breakpoint stops
+//! when the instruction reaches MEM/WB, before its writeback.
+//!
+//! Going backwards is limited to the history recorded since the debugger
+//! started, or since the last `reset` or `load`, and to the last
+//! `history::DEFAULT_MAX_CHECKPOINTS` checkpoints. Going back and then
+//! forward again discards the history after the point gone back to.
+
+
+use checkpoint::{self, Checkpoint};
+use history::{self, History, Location};
+use instruction::Instruction;
+use memory::data::DataMemory;
+use memory::instruction::InstructionMemory;
+use pipeline::events::Event;
+use register::{self, ABI_NAMES};
+use simulator::Simulator;
+use tui::parse_number;
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+
+/// Instructions disassembled either side of the current one.
+const LIST_CONTEXT: u32 = 4;
+
+const HELP: &str = "\
+b LOC [if COND]   break before the instruction at LOC (address or symbol)
+b if COND         break when COND holds, e.g. a0 == 3
+watch ADDR        stop when the word at ADDR changes
+rwatch ADDR       stop when the word at ADDR is read
+wwatch ADDR       stop when the word at ADDR is written
+d N               delete breakpoint or watchpoint N
+i                 list breakpoints and watchpoints
+s [N]             step N instructions
+cycle [N]         step N clock cycles
+c                 continue
+rs [N]            step back N instructions
+rcycle [N]        step back N clock cycles
+rc                continue backwards
+who LOC           show the last write to a register or address
+r                 print the registers
+p REG             print a register
+x ADDR [N]        examine N words of data memory
+l [LOC]           disassemble around LOC
+pipe              print the pipeline registers
+reset             reset the processor, keeping data memory
+save FILE         save a checkpoint of the machine
+load FILE         restore the machine from a checkpoint
+q                 quit";
+
+
+/// A comparison of a register with a value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+
+impl Compare {
+    fn by_symbol(symbol: &str) -> Option<Compare> {
+        match symbol {
+            "==" => Some(Compare::Eq),
+            "!=" => Some(Compare::Ne),
+            "<" => Some(Compare::Lt),
+            "<=" => Some(Compare::Le),
+            ">" => Some(Compare::Gt),
+            ">=" => Some(Compare::Ge),
+            _ => None,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Compare::Eq => "==",
+            Compare::Ne => "!=",
+            Compare::Lt => "<",
+            Compare::Le => "<=",
+            Compare::Gt => ">",
+            Compare::Ge => ">=",
+        }
+    }
+
+    fn holds(self, a: u32, b: u32) -> bool {
+        match self {
+            Compare::Eq => a == b,
+            Compare::Ne => a != b,
+            Compare::Lt => a < b,
+            Compare::Le => a <= b,
+            Compare::Gt => a > b,
+            Compare::Ge => a >= b,
+        }
+    }
+}
+
+
+/// A condition on a general purpose register.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Condition {
+    reg: usize,
+    compare: Compare,
+    value: u32,
+}
+
+
+/// Stops before an instruction.
+struct Breakpoint {
+    number: usize,
+    /// Address of the instruction, or `None` for any instruction
+    addr: Option<u32>,
+    condition: Option<Condition>,
+}
+
+
+/// What a watchpoint stops on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Watch {
+    Read,
+    Write,
+    Change,
+}
+
+
+/// Stops on an access to a word of data memory.
+struct Watchpoint {
+    number: usize,
+    addr: u32,
+    watch: Watch,
+    /// Last value of the word, for change watchpoints
+    value: Option<u32>,
+}
+
+
+/// When to stop a run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Until {
+    /// After this many more cycles
+    Cycles(u64),
+    /// After this many more instructions retire
+    Retired(u64),
+    /// At a breakpoint, watchpoint or HALT
+    Stopped,
+}
+
+
+/// An interactive debugger driving a simulator.
+pub struct Debugger<'a, S, R: BufRead, W: Write>
+where
+    S: Simulator + Checkpoint,
+{
+    sim: S,
+    insns: &'a dyn InstructionMemory,
+    input: R,
+    out: W,
+    /// Addresses by symbol name
+    symbols: BTreeMap<String, u32>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_number: usize,
+    /// Fetch order of the last instruction to reach MEM/WB
+    last_id: u64,
+    last_command: String,
+    history: History,
+}
+
+
+impl<'a, S, R: BufRead, W: Write> Debugger<'a, S, R, W>
+where
+    S: Simulator + Checkpoint,
+{
+    /// Constructs a new `Debugger` of `sim`, which executes `insns`,
+    /// reading commands from `input` and writing to `out`.
+    pub fn new(
+        sim: S,
+        insns: &'a dyn InstructionMemory,
+        input: R,
+        out: W,
+    ) -> Debugger<'a, S, R, W> {
+        Debugger {
+            sim,
+            insns,
+            input,
+            out,
+            symbols: BTreeMap::new(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_number: 1,
+            last_id: 0,
+            last_command: String::new(),
+            history: History::new(history::DEFAULT_INTERVAL),
+        }
+    }
+
+    /// Sets the symbols that can be used as locations.
+    pub fn set_symbols(&mut self, symbols: BTreeMap<String, u32>) {
+        self.symbols = symbols;
+    }
+
+    pub fn simulator(&self) -> &S {
+        &self.sim
+    }
+
+    /// Flushes and returns the output.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    /// Reads and carries out commands until `q` or the end of the input.
+    pub fn run(&mut self) -> io::Result<()> {
+        self.history.start(&self.sim);
+        let location = self.location();
+        writeln!(self.out, "{}", location)?;
+
+        loop {
+            write!(self.out, "(debug) ")?;
+            self.out.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                writeln!(self.out)?;
+                return Ok(());
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            } else {
+                self.last_command = line.clone();
+            }
+            if !self.command(&line)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Carries out a command, returning whether to read another.
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.first() {
+            Some(&command) => command,
+            None => return Ok(true),
+        };
+        let args = &words[1..];
+        let count = match args.first().map(|n| parse_number(n)) {
+            None => Some(1),
+            Some(Some(n)) if n > 0 => Some(n),
+            Some(_) => None,
+        };
+
+        match (command, count) {
+            ("b", _) | ("break", _) => self.add_breakpoint(args)?,
+            ("watch", _) => self.add_watchpoint(args, Watch::Change)?,
+            ("rwatch", _) => self.add_watchpoint(args, Watch::Read)?,
+            ("wwatch", _) => self.add_watchpoint(args, Watch::Write)?,
+            ("d", _) | ("delete", _) => self.delete(args)?,
+            ("i", _) | ("info", _) => self.info()?,
+            ("s", Some(n)) | ("step", Some(n)) => {
+                self.resume(Until::Retired(n))?
+            }
+            ("cycle", Some(n)) => self.resume(Until::Cycles(n))?,
+            ("c", _) | ("continue", _) => self.resume(Until::Stopped)?,
+            ("rs", Some(n)) | ("reverse-step", Some(n)) => {
+                let clock = self.sim.clock();
+                let target = self.history.instructions_back(clock, n as usize);
+                self.go_back(target)?
+            }
+            ("rcycle", Some(n)) => {
+                let target = self.sim
+                    .clock()
+                    .saturating_sub(n)
+                    .max(self.history.start_clock());
+                self.go_back(target)?
+            }
+            ("rc", _) | ("reverse-continue", _) => self.reverse_continue()?,
+            ("who", _) => self.who(args)?,
+            ("r", _) | ("regs", _) => self.print_registers()?,
+            ("p", _) | ("print", _) => self.print_register(args)?,
+            ("x", _) => self.examine(args)?,
+            ("l", _) | ("list", _) => self.list(args)?,
+            ("pipe", _) | ("pipeline", _) => self.print_pipeline()?,
+            ("reset", _) => {
+                self.sim.reset();
+                self.history.start(&self.sim);
+                self.last_id = 0;
+                self.update_watched_values();
+                let location = self.location();
+                writeln!(self.out, "{}", location)?;
+            }
+            ("save", _) => self.save(args)?,
+            ("load", _) => self.load(args)?,
+            ("h", _) | ("help", _) => writeln!(self.out, "{}", HELP)?,
+            ("q", _) | ("quit", _) => return Ok(false),
+            _ => writeln!(self.out, "unknown command: {}", line)?,
+        }
+
+        Ok(true)
+    }
+
+    /// Parses an address or symbol.
+    fn parse_location(&self, text: &str) -> Option<u32> {
+        match parse_number(text) {
+            Some(addr) => Some(addr as u32),
+            None => self.symbols.get(text).cloned(),
+        }
+    }
+
+    /// Parses a condition of the form `REG OP VALUE`.
+    fn parse_condition(&self, words: &[&str]) -> Option<Condition> {
+        if words.len() != 3 {
+            return None;
+        }
+
+        Some(Condition {
+            reg: register::gpr_by_name(words[0])?,
+            compare: Compare::by_symbol(words[1])?,
+            value: parse_value(words[2])?,
+        })
+    }
+
+    fn add_breakpoint(&mut self, args: &[&str]) -> io::Result<()> {
+        let (addr, condition) = match args.iter().position(|&a| a == "if") {
+            Some(i) => (&args[..i], Some(&args[i + 1..])),
+            None => (args, None),
+        };
+        let addr = match addr {
+            [] if condition.is_some() => None,
+            [loc] => match self.parse_location(loc) {
+                Some(addr) => Some(addr),
+                None => {
+                    return writeln!(self.out, "unknown location: {}", loc)
+                }
+            },
+            _ => return writeln!(self.out, "usage: b LOC [if COND]"),
+        };
+        let condition = match condition.map(|c| self.parse_condition(c)) {
+            None => None,
+            Some(Some(condition)) => Some(condition),
+            Some(None) => {
+                return writeln!(self.out, "invalid condition, e.g. a0 == 3")
+            }
+        };
+
+        let breakpoint = Breakpoint {
+            number: self.next_number,
+            addr,
+            condition,
+        };
+        self.next_number += 1;
+        let description = describe_breakpoint(&breakpoint);
+        writeln!(self.out, "{}", description)?;
+        self.breakpoints.push(breakpoint);
+
+        Ok(())
+    }
+
+    fn add_watchpoint(
+        &mut self,
+        args: &[&str],
+        watch: Watch,
+    ) -> io::Result<()> {
+        let addr = match args {
+            [addr] => parse_number(addr),
+            _ => None,
+        };
+        let addr = match addr {
+            Some(addr) => addr as u32 & !0x3,
+            None => return writeln!(self.out, "usage: watch ADDR"),
+        };
+        if !self.sim.memory().contains(addr as usize, 4) {
+            return writeln!(self.out, "cannot access memory at {:#x}", addr);
+        }
+
+        let watchpoint = Watchpoint {
+            number: self.next_number,
+            addr,
+            watch,
+            value: read_word(self.sim.memory(), addr as usize),
+        };
+        self.next_number += 1;
+        writeln!(self.out, "{}", describe_watchpoint(&watchpoint))?;
+        self.watchpoints.push(watchpoint);
+
+        Ok(())
+    }
+
+    fn delete(&mut self, args: &[&str]) -> io::Result<()> {
+        let number = match args {
+            [n] => parse_number(n).map(|n| n as usize),
+            _ => None,
+        };
+        let number = match number {
+            Some(number) => number,
+            None => return writeln!(self.out, "usage: d N"),
+        };
+
+        let breakpoints = self.breakpoints.len();
+        let watchpoints = self.watchpoints.len();
+        self.breakpoints.retain(|b| b.number != number);
+        self.watchpoints.retain(|w| w.number != number);
+        if self.breakpoints.len() == breakpoints &&
+            self.watchpoints.len() == watchpoints
+        {
+            writeln!(self.out, "no breakpoint or watchpoint {}", number)?;
+        }
+
+        Ok(())
+    }
+
+    fn info(&mut self) -> io::Result<()> {
+        let mut lines: Vec<(usize, String)> = self.breakpoints
+            .iter()
+            .map(|b| (b.number, describe_breakpoint(b)))
+            .chain(
+                self.watchpoints
+                    .iter()
+                    .map(|w| (w.number, describe_watchpoint(w))),
+            )
+            .collect();
+        lines.sort();
+
+        if lines.is_empty() {
+            writeln!(self.out, "no breakpoints or watchpoints")?;
+        }
+        for (_, line) in lines {
+            writeln!(self.out, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs until `until`, a breakpoint, a watchpoint or HALT.
+    fn resume(&mut self, mut until: Until) -> io::Result<()> {
+        if let Some(addr) = self.sim.halt_addr() {
+            return writeln!(self.out, "halted at {:#x}", addr);
+        }
+
+        loop {
+            let halt_addr = self.sim.step();
+            self.history.record(&self.sim);
+            let retired = self.sim
+                .events()
+                .iter()
+                .filter(|e| matches!(**e, Event::Retire { .. }))
+                .count() as u64;
+
+            if let Some(addr) = halt_addr {
+                let clock = self.sim.clock();
+                return writeln!(
+                    self.out,
+                    "halted at {:#x} (cycle {})",
+                    addr,
+                    clock
+                );
+            }
+
+            let mut stopped = false;
+            for message in self.check_watchpoints() {
+                writeln!(self.out, "{}", message)?;
+                stopped = true;
+            }
+            if let Some(message) = self.check_breakpoints() {
+                writeln!(self.out, "{}", message)?;
+                stopped = true;
+            }
+
+            until = match until {
+                Until::Cycles(n) => Until::Cycles(n - 1),
+                Until::Retired(n) => Until::Retired(n.saturating_sub(retired)),
+                Until::Stopped => Until::Stopped,
+            };
+            if stopped || until == Until::Cycles(0) ||
+                until == Until::Retired(0)
+            {
+                break;
+            }
+        }
+
+        let location = self.location();
+        writeln!(self.out, "{}", location)
+    }
+
+    /// Returns the address of the instruction that became the current one
+    /// in the last cycle, if any.
+    fn arrival(&mut self) -> Option<u32> {
+        let mem_wb = match self.sim.pipeline() {
+            Some(pipeline) => pipeline.mem_wb,
+            None => return Some(self.sim.registers().pc.read()),
+        };
+
+        if mem_wb.bubble || mem_wb.id == self.last_id {
+            return None;
+        }
+        self.last_id = mem_wb.id;
+
+        Some(mem_wb.pc)
+    }
+
+    /// Returns a message for the first breakpoint hit in the last cycle.
+    fn check_breakpoints(&mut self) -> Option<String> {
+        let pc = self.arrival()?;
+        let reg = self.sim.registers();
+
+        self.breakpoints
+            .iter()
+            .find(|b| {
+                b.addr.is_none_or(|addr| addr == pc) &&
+                    b.condition.is_none_or(|c| {
+                        c.compare.holds(reg.gpr[c.reg].read(), c.value)
+                    })
+            })
+            .map(|b| format!("breakpoint {} at {:#x}", b.number, pc))
+    }
+
+    /// Returns messages for the watchpoints hit in the last cycle.
+    fn check_watchpoints(&mut self) -> Vec<String> {
+        let mut messages = Vec::new();
+
+        for w in self.watchpoints.iter_mut() {
+            if w.watch == Watch::Change {
+                let value = read_word(self.sim.memory(), w.addr as usize);
+                if value != w.value {
+                    messages.push(format!(
+                        "watchpoint {}: {:#x} changed from {} to {}",
+                        w.number,
+                        w.addr,
+                        format_word(w.value),
+                        format_word(value)
+                    ));
+                    w.value = value;
+                }
+                continue;
+            }
+
+            for event in self.sim.events() {
+                if let Event::MemAccess {
+                    pc,
+                    addr,
+                    size,
+                    write,
+                    value,
+                } = *event
+                {
+                    let overlaps = addr < w.addr + 4 &&
+                        w.addr < addr + size as u32;
+                    if overlaps && write == (w.watch == Watch::Write) {
+                        messages.push(format!(
+                            "watchpoint {}: {} {:#x} = {:#x} (pc {:#x})",
+                            w.number,
+                            if write { "write" } else { "read" },
+                            addr,
+                            value,
+                            pc
+                        ));
+                    }
+                }
+            }
+        }
+
+        messages
+    }
+
+    /// Saves a checkpoint of the machine.
+    fn save(&mut self, args: &[&str]) -> io::Result<()> {
+        let path = match args {
+            [path] => path,
+            _ => return writeln!(self.out, "usage: save FILE"),
+        };
+
+        match checkpoint::save_file(&self.sim, path) {
+            Ok(()) => writeln!(
+                self.out,
+                "saved cycle {} to {}",
+                self.sim.clock(),
+                path
+            ),
+            Err(e) => writeln!(self.out, "cannot save {}: {}", path, e),
+        }
+    }
+
+    /// Restores the machine from a checkpoint.
+    ///
+    /// Breakpoints and watchpoints are kept. A failed restore may leave the
+    /// machine partially restored.
+    fn load(&mut self, args: &[&str]) -> io::Result<()> {
+        let path = match args {
+            [path] => path,
+            _ => return writeln!(self.out, "usage: load FILE"),
+        };
+
+        if let Err(e) = checkpoint::restore_file(&mut self.sim, path) {
+            return writeln!(self.out, "cannot load {}: {}", path, e);
+        }
+        self.history.start(&self.sim);
+        self.resync();
+        let location = self.location();
+        writeln!(self.out, "{}", location)
+    }
+
+    /// Catches up with a simulator that has been restored or rewound.
+    fn resync(&mut self) {
+        // The current instruction has already arrived
+        self.last_id = self.sim.pipeline().map_or(0, |pl| pl.mem_wb.id);
+        self.update_watched_values();
+    }
+
+    /// Goes back to the earlier clock `clock`.
+    fn go_back(&mut self, clock: u64) -> io::Result<()> {
+        if clock == self.sim.clock() {
+            return writeln!(self.out, "at the start of the history");
+        }
+        if let Err(e) = self.history.rewind(&mut self.sim, clock) {
+            return writeln!(self.out, "cannot go back: {}", e);
+        }
+        self.resync();
+        let location = self.location();
+        writeln!(self.out, "{}", location)
+    }
+
+    /// Goes back to the last cycle a breakpoint or watchpoint was hit in,
+    /// or to the start of the history.
+    ///
+    /// The history is searched from the newest checkpoint backwards,
+    /// replaying the cycles after each one.
+    fn reverse_continue(&mut self) -> io::Result<()> {
+        let now = self.sim.clock();
+        let mut end = now;
+        let mut hit = None;
+
+        while hit.is_none() {
+            let start = match self.history.restore_before(&mut self.sim, end)
+            {
+                Ok(Some(start)) => start,
+                Ok(None) => break,
+                Err(e) => {
+                    return writeln!(self.out, "cannot go back: {}", e)
+                }
+            };
+            self.resync();
+            while self.sim.clock() < end {
+                if self.sim.step().is_some() {
+                    break;
+                }
+                let mut messages = self.check_watchpoints();
+                messages.extend(self.check_breakpoints());
+                if !messages.is_empty() && self.sim.clock() < now {
+                    hit = Some((self.sim.clock(), messages));
+                }
+            }
+            end = start;
+        }
+
+        let (clock, messages) = match hit {
+            Some(hit) => hit,
+            None => {
+                let start = self.history.start_clock();
+                writeln!(self.out, "no stop since cycle {}", start)?;
+                (start, Vec::new())
+            }
+        };
+        if let Err(e) = self.history.rewind(&mut self.sim, clock) {
+            return writeln!(self.out, "cannot go back: {}", e);
+        }
+        self.resync();
+        for message in messages {
+            writeln!(self.out, "{}", message)?;
+        }
+        let location = self.location();
+        writeln!(self.out, "{}", location)
+    }
+
+    /// Shows the last write to a register or word of data memory.
+    fn who(&mut self, args: &[&str]) -> io::Result<()> {
+        let location = match args {
+            [name] => match register::gpr_by_name(name) {
+                Some(i) => Some(Location::Register(i as u32)),
+                None => parse_number(name).map(|addr| Location::Memory {
+                    addr: addr as u32 & !0x3,
+                    size: 4,
+                }),
+            },
+            _ => None,
+        };
+        let location = match location {
+            Some(location) => location,
+            None => return writeln!(self.out, "usage: who REG|ADDR"),
+        };
+
+        let clock = self.sim.clock();
+        let write = match self.history.last_write(location, clock) {
+            Some(write) => write,
+            None => {
+                let start = self.history.start_clock();
+                return writeln!(
+                    self.out,
+                    "no writes to {} since cycle {}",
+                    describe_location(location),
+                    start
+                );
+            }
+        };
+        let insn = self.disassemble(write.pc);
+        writeln!(
+            self.out,
+            "cycle {}: {} wrote {:#x} to {}",
+            write.cycle,
+            insn,
+            write.value,
+            describe_location(write.location)
+        )
+    }
+
+    /// Reads the words watched for changes again, after a reset or a
+    /// restore.
+    fn update_watched_values(&mut self) {
+        for w in self.watchpoints.iter_mut() {
+            w.value = read_word(self.sim.memory(), w.addr as usize);
+        }
+    }
+
+    /// Describes the clock cycle and the current instruction.
+    fn location(&self) -> String {
+        let pc = self.sim.current_pc();
+        format!("cycle {}: {}", self.sim.clock(), self.disassemble(pc))
+    }
+
+    /// Disassembles the instruction at `addr`.
+    fn disassemble(&self, addr: u32) -> String {
+        if addr as usize >= self.insns.size() ||
+            !addr.is_multiple_of(4)
+        {
+            return format!("{:#x}: <no instruction>", addr);
+        }
+        let insn = Instruction::new(self.insns.read(addr as usize));
+
+        format!("{:#x}: {}", addr, insn)
+    }
+
+    fn print_registers(&mut self) -> io::Result<()> {
+        let reg = self.sim.registers();
+        writeln!(self.out, "pc   {:#010x}", reg.pc.read())?;
+        for row in 0..8 {
+            let line: Vec<String> = (4 * row..4 * row + 4)
+                .map(|i| {
+                    format!("{:<4} {:#010x}", ABI_NAMES[i], reg.gpr[i].read())
+                })
+                .collect();
+            writeln!(self.out, "{}", line.join("  "))?;
+        }
+
+        Ok(())
+    }
+
+    fn print_register(&mut self, args: &[&str]) -> io::Result<()> {
+        let reg = self.sim.registers();
+        let (name, value) = match args {
+            ["pc"] => (String::from("pc"), reg.pc.read()),
+            [name] => match register::gpr_by_name(name) {
+                Some(i) => {
+                    (format!("{} (x{})", ABI_NAMES[i], i), reg.gpr[i].read())
+                }
+                None => return writeln!(self.out, "unknown register {}", name),
+            },
+            _ => return writeln!(self.out, "usage: p REG"),
+        };
+
+        writeln!(self.out, "{} = {:#x} ({})", name, value, value as i32)
+    }
+
+    fn examine(&mut self, args: &[&str]) -> io::Result<()> {
+        let (addr, words) = match args {
+            [addr] => (parse_number(addr), Some(4)),
+            [addr, n] => (parse_number(addr), parse_number(n)),
+            _ => (None, None),
+        };
+        let (addr, words) = match (addr, words) {
+            (Some(addr), Some(words)) => {
+                (addr as usize & !0x3, words as usize)
+            }
+            _ => return writeln!(self.out, "usage: x ADDR [N]"),
+        };
+        let mem = self.sim.memory();
+        if !words.checked_mul(4).is_some_and(|len| mem.contains(addr, len)) {
+            return writeln!(self.out, "cannot access memory at {:#x}", addr);
+        }
+
+        for row in 0..words.div_ceil(4) {
+            let start = addr + 16 * row;
+            let line: Vec<String> = (0..4.min(words - 4 * row))
+                .map(|i| {
+                    format_word(read_word(self.sim.memory(), start + 4 * i))
+                })
+                .collect();
+            writeln!(self.out, "{:#010x}: {}", start, line.join(" "))?;
+        }
+
+        Ok(())
+    }
+
+    fn list(&mut self, args: &[&str]) -> io::Result<()> {
+        let pc = self.sim.current_pc();
+        let center = match args {
+            [] => pc,
+            [loc] => match self.parse_location(loc) {
+                Some(addr) => addr & !0x3,
+                None => {
+                    return writeln!(self.out, "unknown location: {}", loc)
+                }
+            },
+            _ => return writeln!(self.out, "usage: l [LOC]"),
+        };
+
+        let first = center.saturating_sub(4 * LIST_CONTEXT);
+        let last = center.saturating_add(4 * LIST_CONTEXT);
+        for addr in (first..last + 1).step_by(4) {
+            if addr as usize >= self.insns.size() {
+                break;
+            }
+            for (name, _) in self.symbols.iter().filter(|s| *s.1 == addr) {
+                writeln!(self.out, "<{}>:", name)?;
+            }
+            let marker = if addr == pc { "=>" } else { "  " };
+            let line = self.disassemble(addr);
+            writeln!(self.out, "{} {}", marker, line)?;
+        }
+
+        Ok(())
+    }
+
+    fn print_pipeline(&mut self) -> io::Result<()> {
+        let pipeline = match self.sim.pipeline() {
+            Some(pipeline) => *pipeline,
+            None => return writeln!(self.out, "this model has no pipeline"),
+        };
+
+        let registers = [
+            ("IF/ID", pipeline.if_id.bubble, pipeline.if_id.pc),
+            ("ID/EX", pipeline.id_ex.bubble, pipeline.id_ex.pc),
+            ("EX/MEM", pipeline.ex_mem.bubble, pipeline.ex_mem.pc),
+            ("MEM/WB", pipeline.mem_wb.bubble, pipeline.mem_wb.pc),
+        ];
+        for &(name, bubble, pc) in registers.iter() {
+            if bubble {
+                writeln!(self.out, "{:<7}bubble", name)?;
+            } else {
+                let line = self.disassemble(pc);
+                writeln!(self.out, "{:<7}{}", name, line)?;
+            }
+        }
+        writeln!(
+            self.out,
+            "EX/MEM alu_result = {:#x}, MEM/WB mem_result = {:#x}",
+            pipeline.ex_mem.alu_result,
+            pipeline.mem_wb.mem_result
+        )
+    }
+}
+
+
+/// Describes a breakpoint, e.g. `breakpoint 1 at 0x1c if a0 == 0x3`.
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    let mut text = format!("breakpoint {}", breakpoint.number);
+    if let Some(addr) = breakpoint.addr {
+        text.push_str(&format!(" at {:#x}", addr));
+    }
+    if let Some(c) = breakpoint.condition {
+        text.push_str(&format!(
+            " if {} {} {:#x}",
+            ABI_NAMES[c.reg],
+            c.compare.symbol(),
+            c.value
+        ));
+    }
+
+    text
+}
+
+
+/// Describes a watchpoint, e.g. `watchpoint 2 on writes to 0x100`.
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let what = match watchpoint.watch {
+        Watch::Read => "reads of",
+        Watch::Write => "writes to",
+        Watch::Change => "changes to",
+    };
+
+    format!(
+        "watchpoint {} on {} {:#x}",
+        watchpoint.number,
+        what,
+        watchpoint.addr
+    )
+}
+
+
+/// Describes a register or memory location, e.g. `a0 (x10)` or `0x100`.
+fn describe_location(location: Location) -> String {
+    match location {
+        Location::Register(i) => format!("{} (x{})", ABI_NAMES[i as usize], i),
+        Location::Memory { addr, .. } => format!("{:#x}", addr),
+    }
+}
+
+
+/// Reads the word at `addr`, or `None` outside memory.
+fn read_word(mem: &DataMemory, addr: usize) -> Option<u32> {
+    match mem.contains(addr, 4) {
+        true => mem.read(addr, 4).ok(),
+        false => None,
+    }
+}
+
+
+/// Formats a word of memory, or `????????` if it can't be read.
+fn format_word(value: Option<u32>) -> String {
+    match value {
+        Some(value) => format!("{:#010x}", value),
+        None => String::from("????????"),
+    }
+}
+
+
+/// Parses a register value, which may be negative.
+fn parse_value(text: &str) -> Option<u32> {
+    match text.strip_prefix('-') {
+        Some(magnitude) => {
+            parse_number(magnitude).map(|n| (n as u32).wrapping_neg())
+        }
+        None => parse_number(text).map(|n| n as u32),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ca_simulator::CaSimulator;
+    use consts;
+    use ia_simulator::IaSimulator;
+    use memory::data::DataMemory;
+    use memory::instruction::TestInstructionMemory;
+    use register::RegisterFile;
+
+    /// Stores 1, 2 and 3 to 0x100 in a loop, then halts at 0x18.
+    fn program() -> TestInstructionMemory {
+        let mut insns = vec![
+            0x00_15_05_13, // loop: addi x10, x10, 1
+            0x10_a0_20_23, // sw x10, 256(x0)
+            0xfe_b5_4c_e3, // blt x10, x11, loop
+        ];
+        insns.extend_from_slice(&[consts::NOP; 3]);
+        insns.push(consts::HALT);
+        insns.extend_from_slice(&[consts::NOP; 3]);
+
+        TestInstructionMemory::new(insns)
+    }
+
+    fn registers() -> RegisterFile {
+        let mut registers = RegisterFile::new(0x0);
+        registers.gpr[11].write(3);
+
+        registers
+    }
+
+    /// Runs `sim` commanded by `input`, returning the output.
+    fn debug<S>(sim: S, insns: &TestInstructionMemory, input: &str)
+        -> String
+    where
+        S: Simulator + Checkpoint,
+    {
+        let mut debugger = Debugger::new(sim, insns, input.as_bytes(),
+                                         Vec::new());
+        let mut symbols = BTreeMap::new();
+        symbols.insert(String::from("loop"), 0x0);
+        debugger.set_symbols(symbols);
+        debugger.run().unwrap();
+
+        String::from_utf8(debugger.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn breakpoints() {
+        let insns = program();
+        let sim = IaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, "b loop if a0 == 2\nc\np a0\nc\n");
+
+        assert!(out.contains("breakpoint 1 at 0x0 if a0 == 0x2\n"));
+        assert!(out.contains("breakpoint 1 at 0x0\n\
+                              cycle 6: 0x0: addi x10, x10, 1\n"));
+        assert!(out.contains("a0 (x10) = 0x2 (2)\n"));
+        assert!(out.contains("halted at 0x18 (cycle 13)\n"));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let insns = program();
+        let input = "wwatch 0x100\nwatch 0x100\nc\nd 1\nc\nd 2\nc\n";
+
+        let sim = IaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, input);
+        assert!(out.contains("watchpoint 1: write 0x100 = 0x1 (pc 0x4)\n\
+                              watchpoint 2: 0x100 changed from 0x00000000 \
+                              to 0x00000001\n\
+                              cycle 2: 0x8: blt x10, x11, -8\n"));
+        assert!(out.contains("watchpoint 2: 0x100 changed from 0x00000001 \
+                              to 0x00000002\n\
+                              cycle 5: 0x8: blt x10, x11, -8\n"));
+        assert!(out.contains("halted at 0x18 (cycle 13)\n"));
+
+        // The store is the oldest instruction after it writes memory
+        let sim = CaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, input);
+        assert!(out.contains("watchpoint 1: write 0x100 = 0x1 (pc 0x4)\n\
+                              watchpoint 2: 0x100 changed from 0x00000000 \
+                              to 0x00000001\n\
+                              cycle 5: 0x4: sw x10, 256(x0)\n"));
+        assert!(out.contains("halted at 0x18 (cycle 21)\n"));
+    }
+
+    #[test]
+    fn steps() {
+        let insns = program();
+        let sim = CaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, "s\n\ncycle 2\npipe\nx 0x100 1\nq\n");
+
+        // The first instruction retires in cycle 4, and the taken branch
+        // flushes the instructions after it
+        assert!(out.contains("(debug) cycle 5: 0x4: sw x10, 256(x0)\n\
+                              (debug) cycle 6: 0x8: blt x10, x11, -8\n\
+                              (debug) cycle 8: 0x0: addi x10, x10, 1\n"));
+        assert!(out.contains("IF/ID  0x4: sw x10, 256(x0)\n\
+                              ID/EX  0x0: addi x10, x10, 1\n\
+                              EX/MEM bubble\n\
+                              MEM/WB bubble\n"));
+        assert!(out.contains("0x00000100: 0x00000001\n"));
+    }
+
+    #[test]
+    fn memory_bounds() {
+        let insns = program();
+        let input = "x 0x1000\nx 0xff8 4\nx 0xff8 2\nwatch 0x1000\n\
+                     rwatch 0xfffffffc\nq\n";
+
+        let sim = IaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, input);
+        assert!(out.contains("cannot access memory at 0x1000\n\
+                              (debug) cannot access memory at 0xff8\n\
+                              (debug) 0x00000ff8: 0x00000000 0x00000000\n\
+                              (debug) cannot access memory at 0x1000\n\
+                              (debug) cannot access memory at 0xfffffffc\n"));
+    }
+
+    #[test]
+    fn undecodable_words() {
+        let mut words = vec![0xff_ff_ff_ff, 0x00_00_00_00];
+        words.extend_from_slice(&[consts::NOP; 3]);
+        words.push(consts::HALT);
+        let insns = TestInstructionMemory::new(words);
+
+        let sim = IaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, "l 0x0\nq\n");
+        assert!(out.contains("\n=> 0x0: .word 0xffffffff\n"));
+        assert!(out.contains("\n   0x4: .word 0x00000000\n"));
+        assert!(out.contains("\n   0x8: addi x0, x0, 0\n"));
+    }
+
+    #[test]
+    fn checkpoints() {
+        let insns = program();
+        let path = ::std::env::temp_dir().join(format!(
+            "casim-debugger-checkpoints-{}.ckpt",
+            ::std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let input = format!(
+            "b loop if a0 == 1\nc\nsave {0}\nc\nx 0x100 1\nload {0}\n\
+             x 0x100 1\nd 1\nc\nload /nonexistent\n",
+            path
+        );
+
+        let sim = CaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, &input);
+        ::std::fs::remove_file(path).unwrap();
+        assert!(out.contains(&format!("saved cycle 10 to {}\n", path)));
+        assert!(out.contains("0x00000100: 0x00000003\n\
+                              (debug) cycle 10: 0x0: addi x10, x10, 1\n\
+                              (debug) 0x00000100: 0x00000001\n"));
+        assert!(out.contains("halted at 0x18 (cycle 21)\n"));
+        assert!(out.contains("cannot load /nonexistent"));
+    }
+
+    #[test]
+    fn reverse() {
+        let insns = program();
+        let input = "wwatch 0x100\nc\nc\nc\nrs\nrc\nrcycle 2\nwho a0\n\
+                     who 0x102\nwho a1\nrc\nrc\nrs\n";
+
+        let sim = IaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, input);
+        assert!(out.contains("cycle 8: 0x8: blt x10, x11, -8\n\
+                              (debug) cycle 7: 0x4: sw x10, 256(x0)\n\
+                              (debug) watchpoint 1: write 0x100 = 0x2 \
+                              (pc 0x4)\n\
+                              cycle 5: 0x8: blt x10, x11, -8\n\
+                              (debug) cycle 3: 0x0: addi x10, x10, 1\n"));
+        assert!(out.contains("cycle 0: 0x0: addi x10, x10, 1 wrote 0x1 to \
+                              a0 (x10)\n\
+                              (debug) cycle 1: 0x4: sw x10, 256(x0) wrote \
+                              0x1 to 0x100\n\
+                              (debug) no writes to a1 (x11) since cycle 0\n"));
+        assert!(out.contains("no stop since cycle 0\n\
+                              cycle 0: 0x0: addi x10, x10, 1\n\
+                              (debug) at the start of the history\n"));
+
+        // Going back one instruction makes the one that retired last the
+        // current instruction again
+        let sim = CaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let out = debug(sim, &insns, input);
+        assert!(out.contains("cycle 17: 0x4: sw x10, 256(x0)\n\
+                              (debug) cycle 13: 0x0: addi x10, x10, 1\n\
+                              (debug) watchpoint 1: write 0x100 = 0x2 \
+                              (pc 0x4)\n\
+                              cycle 11: 0x4: sw x10, 256(x0)\n"));
+        assert!(out.contains("cycle 4: 0x4: sw x10, 256(x0) wrote 0x1 to \
+                              0x100\n"));
+    }
+}
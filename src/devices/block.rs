@@ -0,0 +1,526 @@
+//! Memory-mapped block storage device backed by a host image file.
+//!
+//! Register map (byte offsets from the device base):
+//!
+//! | Offset  | Name     | Access | Description                              |
+//! |---------|----------|--------|------------------------------------------|
+//! | `0x000` | SECTOR   | RW     | Sector number for the next command       |
+//! | `0x004` | DMA_ADDR | RW     | RAM address for DMA commands             |
+//! | `0x008` | COMMAND  | RW     | Write to start a command; reads last one |
+//! | `0x00c` | STATUS   | R      | `BUSY` and `ERROR` flags                 |
+//! | `0x010` | SECTORS  | R      | Number of sectors in the image           |
+//! | `0x200` | BUFFER   | RW     | One sector of data                       |
+//!
+//! A command runs for the configured read or write latency, during which
+//! STATUS reads `BUSY`, and then transfers a sector between the image and
+//! either the buffer or RAM. With a latency of N cycles, STATUS is idle
+//! again N cycles after the command is written, or 1 with no latency.
+//! Commands written while busy are ignored, and DMA commands that don't fit
+//! in RAM fail without transferring anything.
+
+
+use checkpoint::{self, Reader, Writer};
+use memory::mmio::{Device, Dma};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+
+/// Default base address of the block device.
+pub const BASE: usize = 0x1000_0000;
+
+/// Bytes per sector.
+pub const SECTOR_SIZE: usize = 512;
+
+// Register offsets
+pub const SECTOR: usize = 0x000;
+pub const DMA_ADDR: usize = 0x004;
+pub const COMMAND: usize = 0x008;
+pub const STATUS: usize = 0x00c;
+pub const SECTORS: usize = 0x010;
+pub const BUFFER: usize = 0x200;
+
+// Commands
+pub const CMD_READ: u32 = 1;
+pub const CMD_WRITE: u32 = 2;
+pub const CMD_READ_DMA: u32 = 3;
+pub const CMD_WRITE_DMA: u32 = 4;
+
+// Status flags
+pub const BUSY: u32 = 0x1;
+pub const ERROR: u32 = 0x2;
+
+
+/// How writes reach the backing image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMode {
+    /// Writes go straight to the image file
+    WriteThrough,
+    /// Writes are kept in memory and the image file is never modified
+    CopyOnWrite,
+}
+
+
+/// A command that is waiting for its latency to elapse.
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    command: u32,
+    sector: u32,
+    dma_addr: u32,
+    cycles_left: u32,
+}
+
+
+/// A block device backed by a host image file.
+pub struct BlockDevice {
+    image: File,
+    mode: WriteMode,
+    sectors: u32,
+    overlay: HashMap<u32, Vec<u8>>,
+    read_latency: u32,
+    write_latency: u32,
+    sector: u32,
+    dma_addr: u32,
+    command: u32,
+    status: u32,
+    buffer: [u8; SECTOR_SIZE],
+    pending: Option<Pending>,
+}
+
+
+impl BlockDevice {
+    /// Opens the image file at `path`.
+    ///
+    /// The image is opened read-only in copy-on-write mode. A partial last
+    /// sector is ignored.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        mode: WriteMode,
+    ) -> io::Result<BlockDevice> {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(mode == WriteMode::WriteThrough)
+            .open(path)?;
+        let sectors = (image.metadata()?.len() / SECTOR_SIZE as u64) as u32;
+
+        Ok(BlockDevice {
+            image,
+            mode,
+            sectors,
+            overlay: HashMap::new(),
+            read_latency: 0,
+            write_latency: 0,
+            sector: 0,
+            dma_addr: 0,
+            command: 0,
+            status: 0,
+            buffer: [0; SECTOR_SIZE],
+            pending: None,
+        })
+    }
+
+    /// Sets the number of cycles reads and writes take to complete.
+    pub fn set_latency(&mut self, read: u32, write: u32) {
+        self.read_latency = read;
+        self.write_latency = write;
+    }
+
+    /// Starts `command` on the current sector.
+    fn start(&mut self, command: u32) {
+        if self.status & BUSY != 0 {
+            warn!("Block device: command {} ignored while busy", command);
+            return;
+        }
+
+        let cycles_left = match command {
+            CMD_READ | CMD_READ_DMA => self.read_latency,
+            CMD_WRITE | CMD_WRITE_DMA => self.write_latency,
+            _ => {
+                warn!("Block device: unknown command {}", command);
+                self.status = ERROR;
+                return;
+            }
+        };
+
+        debug!("Block device: command {} sector {}", command, self.sector);
+        self.command = command;
+        self.status = BUSY;
+        self.pending = Some(Pending {
+            command,
+            sector: self.sector,
+            dma_addr: self.dma_addr,
+            cycles_left,
+        });
+    }
+
+    /// Completes a command, returning false if it failed.
+    fn complete(&mut self, pending: Pending, dma: &mut Dma) -> bool {
+        if pending.sector >= self.sectors {
+            warn!("Block device: sector {} out of range", pending.sector);
+            return false;
+        }
+
+        // Check the whole transfer first, so a failed one leaves RAM as is
+        let addr = pending.dma_addr as usize;
+        let dma_command = pending.command == CMD_READ_DMA ||
+            pending.command == CMD_WRITE_DMA;
+        if dma_command && !dma.contains(addr, SECTOR_SIZE) {
+            warn!("Block device: DMA at {:#0x} outside RAM", addr);
+            return false;
+        }
+
+        let result = match pending.command {
+            CMD_READ => self.read_sector(pending.sector).map(|data| {
+                self.buffer.copy_from_slice(&data);
+            }),
+            CMD_READ_DMA => self.read_sector(pending.sector).map(|data| {
+                for (i, &byte) in data.iter().enumerate() {
+                    dma.write_byte(addr + i, byte);
+                }
+            }),
+            CMD_WRITE => {
+                let data = self.buffer.to_vec();
+                self.write_sector(pending.sector, data)
+            }
+            _ => {
+                let data = (0..SECTOR_SIZE)
+                    .map(|i| dma.read_byte(addr + i).unwrap())
+                    .collect();
+                self.write_sector(pending.sector, data)
+            }
+        };
+
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Block device: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Reads sector `n` from the overlay or the image.
+    fn read_sector(&mut self, n: u32) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.overlay.get(&n) {
+            return Ok(data.clone());
+        }
+
+        let mut data = vec![0; SECTOR_SIZE];
+        self.image.seek(SeekFrom::Start(n as u64 * SECTOR_SIZE as u64))?;
+        self.image.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Writes sector `n` to the overlay or the image.
+    fn write_sector(&mut self, n: u32, data: Vec<u8>) -> io::Result<()> {
+        match self.mode {
+            WriteMode::CopyOnWrite => {
+                self.overlay.insert(n, data);
+                Ok(())
+            }
+            WriteMode::WriteThrough => {
+                let offset = n as u64 * SECTOR_SIZE as u64;
+                self.image.seek(SeekFrom::Start(offset))?;
+                self.image.write_all(&data)
+            }
+        }
+    }
+}
+
+
+impl Device for BlockDevice {
+    fn size(&self) -> usize {
+        BUFFER + SECTOR_SIZE
+    }
+
+    fn read(&self, offset: usize, size: usize) -> u32 {
+        if offset >= BUFFER {
+            let start = offset - BUFFER;
+            return (0..size).fold(0, |value, i| {
+                value | (self.buffer[start + i] as u32) << (8 * i)
+            });
+        }
+
+        let value = match offset & !0x3 {
+            SECTOR => self.sector,
+            DMA_ADDR => self.dma_addr,
+            COMMAND => self.command,
+            STATUS => self.status,
+            SECTORS => self.sectors,
+            _ => 0,
+        };
+
+        let mask = ((1u64 << (8 * size)) - 1) as u32;
+        (value >> (8 * (offset & 0x3))) & mask
+    }
+
+    fn write(&mut self, offset: usize, size: usize, data: u32) {
+        if offset >= BUFFER {
+            let start = offset - BUFFER;
+            for i in 0..size {
+                self.buffer[start + i] = (data >> (8 * i)) as u8;
+            }
+            return;
+        }
+
+        match offset & !0x3 {
+            SECTOR => self.sector = data,
+            DMA_ADDR => self.dma_addr = data,
+            COMMAND => self.start(data),
+            _ => {} // read-only or unmapped
+        }
+    }
+
+    fn tick(&mut self, dma: &mut Dma) {
+        let mut pending = match self.pending {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        // The command completes on its last cycle, or the first with no
+        // latency
+        pending.cycles_left = pending.cycles_left.saturating_sub(1);
+        if pending.cycles_left > 0 {
+            self.pending = Some(pending);
+            return;
+        }
+
+        self.pending = None;
+        self.status = match self.complete(pending, dma) {
+            true => 0,
+            false => ERROR,
+        };
+        debug!("Block device: command {} done", pending.command);
+    }
+
+    /// Saves the registers, the buffer, any pending command and the sectors
+    /// written in copy-on-write mode. Sectors written through to the image
+    /// are not saved.
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.sectors as usize);
+        w.u32(self.sector);
+        w.u32(self.dma_addr);
+        w.u32(self.command);
+        w.u32(self.status);
+        w.bytes(&self.buffer);
+        w.option(self.pending, |w, pending| {
+            w.u32(pending.command);
+            w.u32(pending.sector);
+            w.u32(pending.dma_addr);
+            w.u32(pending.cycles_left);
+        });
+
+        let mut overlay: Vec<_> = self.overlay.iter().collect();
+        overlay.sort_by_key(|&(&n, _)| n);
+        w.usize(overlay.len());
+        for (&n, data) in overlay {
+            w.u32(n);
+            w.bytes(data);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_usize("disk sectors", self.sectors as usize)?;
+        self.sector = r.u32()?;
+        self.dma_addr = r.u32()?;
+        self.command = r.u32()?;
+        self.status = r.u32()?;
+        let buffer = r.bytes()?;
+        if buffer.len() != SECTOR_SIZE {
+            return Err(checkpoint::invalid("invalid sector buffer"));
+        }
+        self.buffer.copy_from_slice(buffer);
+        self.pending = r.option(|r| {
+            Ok(Pending {
+                command: r.u32()?,
+                sector: r.u32()?,
+                dma_addr: r.u32()?,
+                cycles_left: r.u32()?,
+            })
+        })?;
+
+        self.overlay.clear();
+        for _ in 0..r.usize()? {
+            let n = r.u32()?;
+            let data = r.bytes()?;
+            if data.len() != SECTOR_SIZE {
+                return Err(checkpoint::invalid("invalid overlay sector"));
+            }
+            self.overlay.insert(n, data.to_vec());
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memory::data::DataMemory;
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Creates a two-sector image where sector `n` is filled with `n + 1`.
+    fn image(name: &str) -> PathBuf {
+        let path = env::temp_dir()
+            .join(format!("riscv-block-unit-{}-{}.bin", name, process::id()));
+        let mut contents = vec![1u8; SECTOR_SIZE];
+        contents.extend_from_slice(&[2u8; SECTOR_SIZE]);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Opens `path` in copy-on-write mode.
+    fn open(path: &Path) -> BlockDevice {
+        BlockDevice::open(path, WriteMode::CopyOnWrite).unwrap()
+    }
+
+    /// Ticks `dev` until it's idle, returning the number of ticks.
+    fn wait(dev: &mut BlockDevice, ram: &mut [u32]) -> u32 {
+        let mut ticks = 0;
+        while dev.read(STATUS, 4) & BUSY != 0 {
+            dev.tick(&mut Dma::new(ram));
+            ticks += 1;
+        }
+        ticks
+    }
+
+    #[test]
+    fn read_into_buffer() {
+        let path = image("read");
+        let mut dev = open(&path);
+        let mut ram = [0u32; 4];
+        assert_eq!(dev.read(SECTORS, 4), 2);
+
+        dev.write(SECTOR, 4, 1);
+        dev.write(COMMAND, 4, CMD_READ);
+        assert_eq!(wait(&mut dev, &mut ram), 1);
+        assert_eq!(dev.read(STATUS, 4), 0);
+        assert_eq!(dev.read(BUFFER + SECTOR_SIZE - 4, 4), 0x02020202);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn latency() {
+        let path = image("latency");
+        let mut dev = open(&path);
+        let mut ram = [0u32; 4];
+        dev.set_latency(5, 10);
+
+        dev.write(COMMAND, 4, CMD_READ);
+        assert_eq!(dev.read(STATUS, 4), BUSY);
+        dev.write(COMMAND, 4, CMD_WRITE); // ignored while busy
+        assert_eq!(dev.read(COMMAND, 4), CMD_READ);
+        assert_eq!(wait(&mut dev, &mut ram), 5);
+
+        dev.write(COMMAND, 4, CMD_WRITE);
+        assert_eq!(wait(&mut dev, &mut ram), 10);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// STATUS reads idle exactly `latency` cycles after the command.
+    #[test]
+    fn exact_cycles() {
+        let path = image("exact");
+        let status = |mem: &DataMemory| mem.read(BASE + STATUS, 4);
+
+        for &latency in [0, 1, 2, 7].iter() {
+            let mut dev = open(&path);
+            dev.set_latency(latency, latency);
+            let mut mem = DataMemory::new(4);
+            mem.attach(BASE, Box::new(dev));
+
+            mem.write(BASE + COMMAND, 4, CMD_READ).unwrap();
+            for _ in 1..latency {
+                mem.tick();
+                assert_eq!(status(&mem), Ok(BUSY));
+            }
+            mem.tick();
+            assert_eq!(status(&mem), Ok(0));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dma_write_copy_on_write() {
+        let path = image("dma");
+        let mut dev = open(&path);
+        let mut ram = [0xabababab_u32; SECTOR_SIZE / 4];
+
+        dev.write(SECTOR, 4, 0);
+        dev.write(COMMAND, 4, CMD_WRITE_DMA);
+        wait(&mut dev, &mut ram);
+        assert_eq!(dev.read(STATUS, 4), 0);
+
+        // Read back through the overlay, but the image is untouched
+        dev.write(COMMAND, 4, CMD_READ);
+        wait(&mut dev, &mut ram);
+        assert_eq!(dev.read(BUFFER, 4), 0xabababab);
+        assert_eq!(fs::read(&path).unwrap()[0], 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let path = image("errors");
+        let mut dev = open(&path);
+        let mut ram = [0u32; 4];
+
+        dev.write(COMMAND, 4, 9); // unknown command
+        assert_eq!(dev.read(STATUS, 4), ERROR);
+
+        dev.write(DMA_ADDR, 4, 0x8); // sector doesn't fit in RAM
+        dev.write(COMMAND, 4, CMD_READ_DMA);
+        wait(&mut dev, &mut ram);
+        assert_eq!(dev.read(STATUS, 4), ERROR);
+        assert_eq!(ram, [0; 4]); // nothing transferred
+
+        dev.write(SECTOR, 4, 2); // past the end of the image
+        dev.write(COMMAND, 4, CMD_READ);
+        wait(&mut dev, &mut ram);
+        assert_eq!(dev.read(STATUS, 4), ERROR);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint() {
+        let path = image("checkpoint");
+        let mut dev = open(&path);
+        let mut ram = [0xcdcdcdcd_u32; SECTOR_SIZE / 4];
+        dev.set_latency(4, 4);
+
+        // Save with a written sector in the overlay and a read pending
+        dev.write(SECTOR, 4, 1);
+        dev.write(COMMAND, 4, CMD_WRITE_DMA);
+        wait(&mut dev, &mut ram);
+        dev.write(COMMAND, 4, CMD_READ);
+        dev.tick(&mut Dma::new(&mut ram));
+        let mut w = Writer::new();
+        dev.save(&mut w);
+        let saved = w.into_bytes();
+
+        let mut restored = open(&path);
+        restored.set_latency(4, 4);
+        let mut r = Reader::new(&saved).unwrap();
+        restored.restore(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(restored.read(STATUS, 4), BUSY);
+        assert_eq!(wait(&mut restored, &mut ram), 3);
+        assert_eq!(restored.read(BUFFER, 4), 0xcdcdcdcd);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+}
@@ -0,0 +1,119 @@
+//! Memory-mapped text console.
+//!
+//! Register map (byte offsets from the device base):
+//!
+//! | Offset  | Name | Access | Description                              |
+//! |---------|------|--------|------------------------------------------|
+//! | `0x000` | TX   | W      | Write to output the low byte; reads zero |
+//!
+//! Characters go straight to a host writer such as stdout, or to a
+//! `Capture` to be read back after the run.
+
+
+use memory::mmio::Device;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+
+/// Default base address of the console.
+pub const BASE: usize = 0x3000_0000;
+
+// Register offsets
+pub const TX: usize = 0x000;
+
+/// Size in bytes of the address window.
+const SIZE: usize = 4;
+
+
+/// A write-only character device.
+pub struct Console {
+    output: Box<dyn Write>,
+}
+
+
+impl Console {
+    /// Constructs a new `Console` writing to `output`.
+    pub fn new(output: Box<dyn Write>) -> Console {
+        Console { output }
+    }
+}
+
+
+impl Device for Console {
+    fn size(&self) -> usize {
+        SIZE
+    }
+
+    fn read(&self, _offset: usize, _size: usize) -> u32 {
+        0
+    }
+
+    fn write(&mut self, offset: usize, _size: usize, data: u32) {
+        if offset == TX {
+            // The program has no way to learn of a host error
+            let _ = self.output.write_all(&[data as u8]);
+            let _ = self.output.flush();
+        }
+    }
+}
+
+
+/// Console output collected in memory.
+///
+/// Clones share the same buffer, so one can be given to a `Console` and
+/// another read after the run.
+#[derive(Clone, Default)]
+pub struct Capture {
+    buf: Rc<RefCell<Vec<u8>>>,
+}
+
+
+impl Capture {
+    /// Constructs a new, empty `Capture`.
+    pub fn new() -> Capture {
+        Capture::default()
+    }
+
+    /// Returns the output so far, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.buf.borrow()).into_owned()
+    }
+}
+
+
+impl Write for Capture {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buf.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memory::data::DataMemory;
+
+    #[test]
+    fn capture() {
+        let capture = Capture::new();
+        let mut mem = DataMemory::new(16);
+        mem.attach(BASE, Box::new(Console::new(Box::new(capture.clone()))));
+
+        for &c in b"hi\n" {
+            mem.write(BASE + TX, 1, c as u32).unwrap();
+        }
+        // Only the low byte is output
+        mem.write(BASE + TX, 4, 0x1234_5621).unwrap();
+        assert_eq!(mem.read(BASE + TX, 4).unwrap(), 0);
+
+        assert_eq!(capture.text(), "hi\n!");
+    }
+}
@@ -0,0 +1,323 @@
+//! Memory-mapped framebuffer.
+//!
+//! Register map (byte offsets from the device base):
+//!
+//! | Offset   | Name    | Access | Description                             |
+//! |----------|---------|--------|-----------------------------------------|
+//! | `0x000`  | WIDTH   | R      | Width in pixels                         |
+//! | `0x004`  | HEIGHT  | R      | Height in pixels                        |
+//! | `0x008`  | FORMAT  | R      | Pixel format (see `PixelFormat`)        |
+//! | `0x00c`  | COMMIT  | RW     | Write to commit a frame; reads count    |
+//! | `0x400`  | PALETTE | RW     | 256 `0x00RRGGBB` words for 8-bit pixels |
+//! | `0x1000` | PIXELS  | RW     | Pixel data, row by row from top left    |
+//!
+//! Committing a frame writes it to the output directory, if one is set, as
+//! `frame_NNNN.ppm` (or `.png`).
+
+
+use checkpoint::{self, Reader, Writer};
+use devices::image;
+use memory::mmio::Device;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+
+
+/// Default base address of the framebuffer.
+pub const BASE: usize = 0x2000_0000;
+
+// Register offsets
+pub const WIDTH: usize = 0x000;
+pub const HEIGHT: usize = 0x004;
+pub const FORMAT: usize = 0x008;
+pub const COMMIT: usize = 0x00c;
+pub const PALETTE: usize = 0x400;
+pub const PIXELS: usize = 0x1000;
+
+/// Number of palette entries.
+const PALETTE_SIZE: usize = 256;
+
+
+/// Layout of a pixel in framebuffer memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// 16-bit `rrrrrggg_gggbbbbb`
+    Rgb565 = 0,
+    /// Bytes R, G, B, A in increasing address order; alpha is ignored
+    Rgba8888 = 1,
+    /// 8-bit index into the palette
+    Palette8 = 2,
+}
+
+
+impl PixelFormat {
+    /// Returns the number of bytes per pixel.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgba8888 => 4,
+            PixelFormat::Palette8 => 1,
+        }
+    }
+}
+
+
+/// Image file format for committed frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+}
+
+
+/// A memory-mapped framebuffer.
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    palette: [u32; PALETTE_SIZE],
+    pixels: Vec<u8>,
+    frames: u32,
+    output: Option<(PathBuf, ImageFormat)>,
+}
+
+
+impl Framebuffer {
+    /// Constructs a new `Framebuffer`, initially black.
+    pub fn new(
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+    ) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            format,
+            palette: [0; PALETTE_SIZE],
+            pixels: vec![0; width * height * format.bytes_per_pixel()],
+            frames: 0,
+            output: None,
+        }
+    }
+
+    /// Writes committed frames as `format` images into `dir`.
+    ///
+    /// The directory is created if it doesn't exist.
+    pub fn set_output(&mut self, dir: PathBuf, format: ImageFormat) {
+        self.output = Some((dir, format));
+    }
+
+    /// Returns the number of committed frames.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Returns the current contents as packed 8-bit RGB.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let bpp = self.format.bytes_per_pixel();
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+
+        for pixel in self.pixels.chunks(bpp) {
+            let (r, g, b) = match self.format {
+                PixelFormat::Rgb565 => {
+                    let value = pixel[0] as u32 | (pixel[1] as u32) << 8;
+                    let r = (value >> 11) & 0x1f;
+                    let g = (value >> 5) & 0x3f;
+                    let b = value & 0x1f;
+                    // Replicate high bits so full intensity maps to 255
+                    let r = (r << 3) | (r >> 2);
+                    let g = (g << 2) | (g >> 4);
+                    let b = (b << 3) | (b >> 2);
+                    (r, g, b)
+                }
+                PixelFormat::Rgba8888 => {
+                    (pixel[0] as u32, pixel[1] as u32, pixel[2] as u32)
+                }
+                PixelFormat::Palette8 => {
+                    let color = self.palette[pixel[0] as usize];
+                    ((color >> 16) & 0xff, (color >> 8) & 0xff, color & 0xff)
+                }
+            };
+            rgb.extend_from_slice(&[r as u8, g as u8, b as u8]);
+        }
+
+        rgb
+    }
+
+    /// Commits the current frame, writing it out if an output is set.
+    fn commit(&mut self) -> io::Result<()> {
+        let frame = self.frames;
+        self.frames += 1;
+        debug!("Framebuffer: committed frame {}", frame);
+
+        let (dir, format) = match self.output {
+            Some((ref dir, format)) => (dir, format),
+            None => return Ok(()),
+        };
+
+        fs::create_dir_all(dir)?;
+        let extension = match format {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png",
+        };
+        let path = dir.join(format!("frame_{:04}.{}", frame, extension));
+        let mut out = BufWriter::new(File::create(path)?);
+        let rgb = self.to_rgb();
+
+        match format {
+            ImageFormat::Ppm => {
+                image::write_ppm(&mut out, self.width, self.height, &rgb)
+            }
+            ImageFormat::Png => {
+                image::write_png(&mut out, self.width, self.height, &rgb)
+            }
+        }
+    }
+}
+
+
+impl Device for Framebuffer {
+    fn size(&self) -> usize {
+        PIXELS + self.pixels.len()
+    }
+
+    fn read(&self, offset: usize, size: usize) -> u32 {
+        if offset >= PIXELS {
+            return read_bytes(&self.pixels, offset - PIXELS, size);
+        }
+
+        let value = match offset & !0x3 {
+            WIDTH => self.width as u32,
+            HEIGHT => self.height as u32,
+            FORMAT => self.format as u32,
+            COMMIT => self.frames,
+            o if is_palette(o) => self.palette[(o - PALETTE) / 4],
+            _ => 0,
+        };
+
+        let mask = ((1u64 << (8 * size)) - 1) as u32;
+        (value >> (8 * (offset & 0x3))) & mask
+    }
+
+    fn write(&mut self, offset: usize, size: usize, data: u32) {
+        if offset >= PIXELS {
+            write_bytes(&mut self.pixels, offset - PIXELS, size, data);
+            return;
+        }
+
+        match offset & !0x3 {
+            COMMIT => {
+                if let Err(e) = self.commit() {
+                    error!("Framebuffer: failed to write frame: {}", e);
+                }
+            }
+            o if is_palette(o) => {
+                let entry = (o - PALETTE) / 4;
+                let mut bytes = self.palette[entry].to_le_bytes();
+                write_bytes(&mut bytes, offset & 0x3, size, data);
+                self.palette[entry] = u32::from_le_bytes(bytes);
+            }
+            _ => {} // read-only or unmapped
+        }
+    }
+
+    /// Saves the palette, pixels and frame count. Frames already written
+    /// to the output directory are not saved.
+    fn save(&self, w: &mut Writer) {
+        for &color in self.palette.iter() {
+            w.u32(color);
+        }
+        w.bytes(&self.pixels);
+        w.u32(self.frames);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        for color in self.palette.iter_mut() {
+            *color = r.u32()?;
+        }
+        let pixels = r.bytes()?;
+        if pixels.len() != self.pixels.len() {
+            return Err(checkpoint::invalid("framebuffer size mismatch"));
+        }
+        self.pixels.copy_from_slice(pixels);
+        self.frames = r.u32()?;
+
+        Ok(())
+    }
+}
+
+
+/// Indicates `offset` is within the palette registers.
+fn is_palette(offset: usize) -> bool {
+    (PALETTE..PALETTE + 4 * PALETTE_SIZE).contains(&offset)
+}
+
+
+/// Reads `size` little-endian bytes at `offset` in `buf`.
+fn read_bytes(buf: &[u8], offset: usize, size: usize) -> u32 {
+    (0..size).fold(0, |value, i| value | (buf[offset + i] as u32) << (8 * i))
+}
+
+
+/// Writes the lower `size` bytes of `data` little-endian at `offset`.
+fn write_bytes(buf: &mut [u8], offset: usize, size: usize, data: u32) {
+    for i in 0..size {
+        buf[offset + i] = (data >> (8 * i)) as u8;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let mut fb = Framebuffer::new(320, 240, PixelFormat::Palette8);
+        assert_eq!(fb.size(), PIXELS + 320 * 240);
+        assert_eq!(fb.read(WIDTH, 4), 320);
+        assert_eq!(fb.read(HEIGHT, 4), 240);
+        assert_eq!(fb.read(FORMAT, 4), 2);
+
+        fb.write(WIDTH, 4, 1);
+        assert_eq!(fb.read(WIDTH, 4), 320);
+
+        fb.write(COMMIT, 4, 1);
+        fb.write(COMMIT, 1, 1);
+        assert_eq!(fb.read(COMMIT, 4), 2);
+        assert_eq!(fb.frames(), 2);
+    }
+
+    #[test]
+    fn rgb565() {
+        let mut fb = Framebuffer::new(3, 1, PixelFormat::Rgb565);
+        fb.write(PIXELS, 2, 0xf800); // red
+        fb.write(PIXELS + 2, 2, 0x07e0); // green
+        fb.write(PIXELS + 4, 2, 0x0010); // half blue
+        assert_eq!(fb.read(PIXELS, 4), 0x07e0f800);
+        assert_eq!(fb.to_rgb(), vec![255, 0, 0, 0, 255, 0, 0, 0, 0x84]);
+    }
+
+    #[test]
+    fn rgba8888() {
+        let mut fb = Framebuffer::new(1, 2, PixelFormat::Rgba8888);
+        fb.write(PIXELS, 4, 0xff332211);
+        fb.write(PIXELS + 4, 1, 0x80);
+        assert_eq!(fb.to_rgb(), vec![0x11, 0x22, 0x33, 0x80, 0, 0]);
+    }
+
+    #[test]
+    fn palette8() {
+        let mut fb = Framebuffer::new(2, 2, PixelFormat::Palette8);
+        fb.write(PALETTE + 4, 4, 0x00abcdef);
+        fb.write(PALETTE + 8 + 2, 1, 0x12); // byte write into entry 2
+        fb.write(PIXELS, 4, 0x00020100);
+        assert_eq!(fb.read(PALETTE + 4, 2), 0xcdef);
+        assert_eq!(
+            fb.to_rgb(),
+            vec![0, 0, 0, 0xab, 0xcd, 0xef, 0x12, 0, 0, 0, 0, 0]
+        );
+    }
+
+}
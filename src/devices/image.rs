@@ -0,0 +1,163 @@
+//! Minimal image encoders for frame dumps.
+//!
+//! Both encoders take packed 8-bit RGB pixel data, row by row from the top
+//! left. PNG images are written uncompressed (zlib stored blocks), which
+//! keeps the encoder small and its output byte-for-byte reproducible.
+
+
+use std::io::{self, Write};
+
+
+/// PNG file signature.
+const PNG_SIGNATURE: [u8; 8] =
+    [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Largest payload of a deflate stored block.
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+
+/// Writes a binary PPM (P6) image.
+pub fn write_ppm<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> io::Result<()> {
+    assert_eq!(rgb.len(), width * height * 3);
+
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+    out.write_all(rgb)
+}
+
+
+/// Writes a truecolor PNG image.
+pub fn write_png<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> io::Result<()> {
+    assert_eq!(rgb.len(), width * height * 3);
+
+    out.write_all(&PNG_SIGNATURE)?;
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB, no interlace
+    write_chunk(out, b"IHDR", &ihdr)?;
+
+    // Each scanline is prefixed with filter type 0 (none)
+    let mut scanlines = Vec::with_capacity(height * (1 + width * 3));
+    for row in rgb.chunks(width * 3) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&scanlines))?;
+
+    write_chunk(out, b"IEND", &[])
+}
+
+
+/// Writes a PNG chunk with its length and CRC.
+fn write_chunk<W: Write>(
+    out: &mut W,
+    kind: &[u8; 4],
+    data: &[u8],
+) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+
+    let crc = crc32(crc32(0, kind), data);
+    out.write_all(&crc.to_be_bytes())
+}
+
+
+/// Wraps `data` in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01]; // deflate, 32K window, no dict
+
+    let nblocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    for i in 0..nblocks {
+        let start = i * MAX_STORED_BLOCK;
+        let end = (start + MAX_STORED_BLOCK).min(data.len());
+        let len = (end - start) as u16;
+
+        stream.push((i + 1 == nblocks) as u8); // BFINAL, BTYPE=00
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(&data[start..end]);
+    }
+
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+
+/// Continues CRC-32 (ISO 3309) `crc` over `data`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+
+    !crc
+}
+
+
+/// Computes the Adler-32 checksum of `data`.
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+
+    (b << 16) | a
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppm() {
+        let mut out = Vec::new();
+        write_ppm(&mut out, 2, 1, &[255, 0, 0, 0, 0, 255]).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff".to_vec());
+    }
+
+    #[test]
+    fn png() {
+        let mut out = Vec::new();
+        write_png(&mut out, 1, 1, &[0xff, 0x80, 0x00]).unwrap();
+
+        assert_eq!(&out[..8], &PNG_SIGNATURE);
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(&out[out.len() - 8..out.len() - 4], b"IEND");
+        // IEND's CRC is fixed
+        assert_eq!(&out[out.len() - 4..], &[0xae, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn zlib_splits_large_data() {
+        let data = vec![7u8; MAX_STORED_BLOCK + 1];
+        let stream = zlib_stored(&data);
+        // Header, two 5-byte block headers, data and checksum
+        assert_eq!(stream.len(), 2 + 5 + 5 + data.len() + 4);
+        assert_eq!(stream[2], 0);
+        assert_eq!(stream[2 + 5 + MAX_STORED_BLOCK], 1);
+    }
+
+}
@@ -0,0 +1,6 @@
+//! Memory-mapped peripheral devices.
+
+pub mod block;
+pub mod console;
+pub mod framebuffer;
+pub mod image;
@@ -0,0 +1,333 @@
+//! Minimal reader for 32-bit little-endian RISC-V ELF executables.
+//!
+//! Reads only what's needed to run a statically linked program: the
+//! loadable segments, the entry point and the symbol table.
+
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+
+/// Program header type of a loadable segment
+const PT_LOAD: u32 = 1;
+/// Section header type of a symbol table
+const SHT_SYMTAB: u32 = 2;
+
+// Symbol types that don't name an address in the program
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const STB_GLOBAL: u8 = 1;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+
+/// A segment loaded into memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    /// Virtual address of the first byte
+    pub addr: u32,
+    /// Contents from the file, followed by `mem_size - data.len()` zeros
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+}
+
+
+/// An executable's loadable contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    /// Addresses by symbol name
+    ///
+    /// Global symbols take precedence over local ones of the same name.
+    pub symbols: BTreeMap<String, u32>,
+}
+
+
+impl Elf {
+    /// Reads the executable at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Elf> {
+        Elf::parse(&fs::read(path)?)
+    }
+
+    /// Parses an executable.
+    ///
+    /// Returns an `InvalidData` error if `bytes` isn't a 32-bit
+    /// little-endian RISC-V ELF file, or is truncated.
+    pub fn parse(bytes: &[u8]) -> io::Result<Elf> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(invalid("not an ELF file"));
+        }
+        let header = slice(bytes, 0, EHDR_SIZE)?;
+        if header[4] != CLASS_32 || header[5] != DATA_LITTLE_ENDIAN {
+            return Err(invalid("not a 32-bit little-endian ELF file"));
+        }
+        if u16_at(header, 18) != MACHINE_RISCV {
+            return Err(invalid("not a RISC-V ELF file"));
+        }
+
+        let entry = u32_at(header, 24);
+        let segments = read_segments(bytes, header)?;
+        let symbols = read_symbols(bytes, header)?;
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    /// Returns the address of the symbol `name`.
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).cloned()
+    }
+}
+
+
+/// Reads the loadable segments from the program header table.
+fn read_segments(bytes: &[u8], header: &[u8]) -> io::Result<Vec<Segment>> {
+    let offset = u32_at(header, 28) as usize;
+    let count = u16_at(header, 44) as usize;
+    let mut segments = Vec::new();
+
+    for i in 0..count {
+        let phdr = slice(bytes, offset + i * PHDR_SIZE, PHDR_SIZE)?;
+        if u32_at(phdr, 0) != PT_LOAD {
+            continue;
+        }
+        let file_size = u32_at(phdr, 16) as usize;
+        let mem_size = u32_at(phdr, 20);
+        if file_size > mem_size as usize {
+            return Err(invalid("segment larger in the file than in memory"));
+        }
+        let data = slice(bytes, u32_at(phdr, 4) as usize, file_size)?;
+        segments.push(Segment {
+            addr: u32_at(phdr, 8),
+            data: data.to_vec(),
+            mem_size,
+        });
+    }
+
+    Ok(segments)
+}
+
+
+/// Reads the named symbols from the symbol table, if there is one.
+fn read_symbols(
+    bytes: &[u8],
+    header: &[u8],
+) -> io::Result<BTreeMap<String, u32>> {
+    let offset = u32_at(header, 32) as usize;
+    let count = u16_at(header, 48) as usize;
+    let section = |i: usize| slice(bytes, offset + i * SHDR_SIZE, SHDR_SIZE);
+    let mut symbols = BTreeMap::new();
+
+    for i in 0..count {
+        let symtab = section(i)?;
+        if u32_at(symtab, 4) != SHT_SYMTAB {
+            continue;
+        }
+        let strtab = section(u32_at(symtab, 24) as usize)?;
+        let strings = slice(
+            bytes,
+            u32_at(strtab, 16) as usize,
+            u32_at(strtab, 20) as usize,
+        )?;
+        let table = slice(
+            bytes,
+            u32_at(symtab, 16) as usize,
+            u32_at(symtab, 20) as usize,
+        )?;
+
+        for sym in table.chunks(SYM_SIZE).filter(|s| s.len() == SYM_SIZE) {
+            let info = sym[12];
+            let kind = info & 0xf;
+            // Skip undefined symbols and those that aren't addresses
+            if u16_at(sym, 14) == 0 || kind == STT_SECTION || kind == STT_FILE
+            {
+                continue;
+            }
+            // Skip assembler temporary labels, too
+            let name = string_at(strings, u32_at(sym, 0) as usize)?;
+            if name.is_empty() || name.starts_with(".L") {
+                continue;
+            }
+            let value = u32_at(sym, 4);
+            if info >> 4 == STB_GLOBAL {
+                symbols.insert(name, value);
+            } else {
+                symbols.entry(name).or_insert(value);
+            }
+        }
+    }
+
+    Ok(symbols)
+}
+
+
+/// Returns `len` bytes of `bytes` at `offset`.
+fn slice(bytes: &[u8], offset: usize, len: usize) -> io::Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or_else(|| invalid("truncated ELF file"))
+}
+
+
+/// Returns the NUL-terminated string at `offset` in a string table.
+fn string_at(strings: &[u8], offset: usize) -> io::Result<String> {
+    let rest = strings
+        .get(offset..)
+        .ok_or_else(|| invalid("symbol name out of range"))?;
+    let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+
+    Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+
+    u32::from_le_bytes(word)
+}
+
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+/// Builds minimal ELF executables, for tests.
+///
+/// The image has one loadable segment and a symbol table.
+#[cfg(test)]
+pub fn build(
+    addr: u32,
+    entry: u32,
+    words: &[u32],
+    bss: u32,
+    symbols: &[(&str, u32)],
+) -> Vec<u8> {
+    let push16 =
+        |v: &mut Vec<u8>, x: u16| v.extend_from_slice(&x.to_le_bytes());
+    let push32 =
+        |v: &mut Vec<u8>, x: u32| v.extend_from_slice(&x.to_le_bytes());
+
+    let data: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let mut strings = vec![0u8];
+    let mut syms = vec![0u8; SYM_SIZE];
+    for &(name, value) in symbols {
+        push32(&mut syms, strings.len() as u32);
+        push32(&mut syms, value);
+        push32(&mut syms, 0);
+        syms.push(STB_GLOBAL << 4);
+        syms.push(0);
+        push16(&mut syms, 1);
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+    }
+
+    let data_offset = EHDR_SIZE + PHDR_SIZE;
+    let syms_offset = data_offset + data.len();
+    let strings_offset = syms_offset + syms.len();
+    let shdr_offset = strings_offset + strings.len();
+
+    let mut elf = Vec::new();
+    elf.extend_from_slice(MAGIC);
+    elf.extend_from_slice(&[CLASS_32, DATA_LITTLE_ENDIAN, 1]);
+    elf.resize(16, 0);
+    push16(&mut elf, 2); // executable
+    push16(&mut elf, MACHINE_RISCV);
+    push32(&mut elf, 1);
+    push32(&mut elf, entry);
+    push32(&mut elf, EHDR_SIZE as u32);
+    push32(&mut elf, shdr_offset as u32);
+    push32(&mut elf, 0);
+    for &half in &[EHDR_SIZE, PHDR_SIZE, 1, SHDR_SIZE, 3, 0] {
+        push16(&mut elf, half as u16);
+    }
+
+    let size = data.len() as u32;
+    for &word in &[PT_LOAD, data_offset as u32, addr, addr, size] {
+        push32(&mut elf, word);
+    }
+    for &word in &[size + bss, 7, 4] {
+        push32(&mut elf, word);
+    }
+    elf.extend_from_slice(&data);
+    elf.extend_from_slice(&syms);
+    elf.extend_from_slice(&strings);
+
+    // Null section, symbol table and its string table
+    elf.resize(elf.len() + SHDR_SIZE, 0);
+    let tables = [
+        (SHT_SYMTAB, syms_offset, syms.len(), 2),
+        (3, strings_offset, strings.len(), 0),
+    ];
+    for &(kind, offset, size, link) in &tables {
+        let fields = [0, kind, 0, 0, offset as u32, size as u32, link, 0];
+        for &field in &fields {
+            push32(&mut elf, field);
+        }
+        push32(&mut elf, 1);
+        push32(&mut elf, if kind == SHT_SYMTAB { SYM_SIZE as u32 } else { 0 });
+    }
+
+    elf
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let words = [0x00_10_00_93, 0x00_00_00_6f];
+        let symbols = [("_start", 0x8000_0000), ("tohost", 0x8000_0008)];
+        let bytes = build(0x8000_0000, 0x8000_0000, &words, 8, &symbols);
+
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.entry, 0x8000_0000);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].addr, 0x8000_0000);
+        assert_eq!(elf.segments[0].data, &bytes[84..92]);
+        assert_eq!(elf.segments[0].mem_size, 16);
+        assert_eq!(elf.symbol("tohost"), Some(0x8000_0008));
+        assert_eq!(elf.symbol("_start"), Some(0x8000_0000));
+        assert_eq!(elf.symbol("fromhost"), None);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let bytes = build(0, 0, &[0x13], 0, &[]);
+        let error = |bytes: &[u8]| Elf::parse(bytes).unwrap_err().kind();
+
+        assert_eq!(error(b"casim"), io::ErrorKind::InvalidData);
+        assert_eq!(error(&bytes[..60]), io::ErrorKind::InvalidData);
+
+        let mut riscv64 = bytes.clone();
+        riscv64[4] = 2;
+        assert_eq!(error(&riscv64), io::ErrorKind::InvalidData);
+
+        let mut arm = bytes;
+        arm[18] = 40;
+        assert_eq!(error(&arm), io::ErrorKind::InvalidData);
+    }
+}
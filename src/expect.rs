@@ -0,0 +1,646 @@
+//! Expected final states of test programs.
+//!
+//! Expectations for a disassembly program are read from a sidecar file of
+//! the same name with the extension `.expect`, or else from lines of the
+//! program itself starting with `# expect:`. There is one directive per
+//! line, and `#` starts a comment:
+//!
+//! ```text
+//! memory 8192            # data memory size in words (default 1024)
+//! halt 0x4c0             # address of the HALT reached
+//! x5 = 0x5a5             # final value of a register, e.g., a0 or x10
+//! mem 0x5dc = 0x5a5 -1   # final words from an address on
+//! cycles 300..400        # bounds on the cycle count, either may be left out
+//! cycles ca ..500        # bounds for one model only
+//! output "sorted\n"      # text written to the console
+//! ```
+//!
+//! Every program must reach HALT, within the largest cycle bound if there
+//! is one. The console is attached at its default base address.
+
+
+use ca_simulator::CaSimulator;
+use devices::console::{self, Capture, Console};
+use ia_simulator::IaSimulator;
+use memory::data::DataMemory;
+use memory::instruction::DisassemblyInstructionMemory;
+use register::{self, RegisterFile};
+use simulator::Simulator;
+use tui::parse_number;
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+/// Data memory size in words unless a program asks for another.
+pub const DEFAULT_MEMORY_WORDS: usize = 1024;
+
+/// Cycles a program may take without a cycle bound before it's given up on.
+pub const DEFAULT_CYCLE_LIMIT: u64 = 1_000_000;
+
+/// Extension of sidecar expectations files.
+pub const EXTENSION: &str = "expect";
+
+/// Extension of the programs found by `discover`.
+pub const PROGRAM_EXTENSION: &str = "txt";
+
+/// Start of expectations embedded in a program.
+const EMBEDDED: &str = "# expect:";
+
+
+/// A simulation model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Ia,
+    Ca,
+}
+
+
+/// Both models, in the order their tests are run.
+pub const MODELS: [Model; 2] = [Model::Ia, Model::Ca];
+
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Model::Ia => write!(f, "ia"),
+            Model::Ca => write!(f, "ca"),
+        }
+    }
+}
+
+
+/// Bounds on the number of cycles a run takes, both inclusive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CycleBounds {
+    /// Model the bounds apply to, or `None` for both
+    pub model: Option<Model>,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+
+impl CycleBounds {
+    /// Indicates the bounds apply to runs of `model`.
+    pub fn applies_to(&self, model: Model) -> bool {
+        self.model.is_none_or(|m| m == model)
+    }
+
+    /// Indicates `cycles` is within the bounds.
+    pub fn contains(&self, cycles: u64) -> bool {
+        self.min.is_none_or(|min| cycles >= min) &&
+            self.max.is_none_or(|max| cycles <= max)
+    }
+}
+
+
+impl fmt::Display for CycleBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(min) = self.min {
+            write!(f, "{}", min)?;
+        }
+        write!(f, "..")?;
+        if let Some(max) = self.max {
+            write!(f, "{}", max)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// The expected final state of a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expectations {
+    pub memory_words: usize,
+    pub halt: Option<usize>,
+    /// Register numbers and values
+    pub registers: Vec<(usize, u32)>,
+    /// Start addresses and the words from there on
+    pub memory: Vec<(usize, Vec<u32>)>,
+    pub cycles: Vec<CycleBounds>,
+    pub output: Option<String>,
+}
+
+
+impl Expectations {
+    /// Constructs new `Expectations` that only require reaching HALT.
+    pub fn new() -> Expectations {
+        Expectations {
+            memory_words: DEFAULT_MEMORY_WORDS,
+            halt: None,
+            registers: Vec::new(),
+            memory: Vec::new(),
+            cycles: Vec::new(),
+            output: None,
+        }
+    }
+
+    /// Parses the directives in an expectations file.
+    ///
+    /// Errors name the offending line.
+    pub fn parse(text: &str) -> Result<Expectations, String> {
+        let mut expectations = Expectations::new();
+        for (i, line) in text.lines().enumerate() {
+            expectations
+                .parse_line(line)
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+
+        Ok(expectations)
+    }
+
+    /// Parses the directives embedded in a program, if it has any.
+    pub fn embedded(source: &str) -> Result<Option<Expectations>, String> {
+        let mut expectations = Expectations::new();
+        let mut found = false;
+        for (i, line) in source.lines().enumerate() {
+            if let Some(directive) = line.trim_start().strip_prefix(EMBEDDED) {
+                found = true;
+                expectations
+                    .parse_line(directive)
+                    .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            }
+        }
+
+        Ok(if found { Some(expectations) } else { None })
+    }
+
+    /// Reads the expectations for the program at `path`, if it has any.
+    ///
+    /// A sidecar file takes precedence over embedded directives. Parse
+    /// errors are returned as `InvalidData` errors.
+    pub fn for_program(path: &Path) -> io::Result<Option<Expectations>> {
+        let invalid = |file: &Path, e: String| {
+            let msg = format!("{}: {}", file.display(), e);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        };
+
+        let sidecar = path.with_extension(EXTENSION);
+        if sidecar.is_file() {
+            let text = fs::read_to_string(&sidecar)?;
+            return Expectations::parse(&text)
+                .map(Some)
+                .map_err(|e| invalid(&sidecar, e));
+        }
+
+        let source = fs::read_to_string(path)?;
+        Expectations::embedded(&source).map_err(|e| invalid(path, e))
+    }
+
+    /// Parses one directive into `self`.
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let (keyword, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+
+        match keyword {
+            "memory" => {
+                self.memory_words = parse_number(rest)
+                    .ok_or_else(|| format!("invalid size {}", rest))?
+                    as usize;
+            }
+            "halt" => {
+                let addr = parse_value(rest)?;
+                self.halt = Some(addr as usize);
+            }
+            "mem" => {
+                let (addr, words) = split_assignment(rest)?;
+                let addr = parse_value(addr)? as usize;
+                if !addr.is_multiple_of(4) {
+                    return Err(format!("unaligned address {:#x}", addr));
+                }
+                let words = words
+                    .split_whitespace()
+                    .map(parse_value)
+                    .collect::<Result<Vec<u32>, String>>()?;
+                if words.is_empty() {
+                    return Err(String::from("mem needs at least one word"));
+                }
+                self.memory.push((addr, words));
+            }
+            "cycles" => self.cycles.push(parse_cycles(rest)?),
+            "output" => self.output = Some(parse_string(rest)?),
+            _ => {
+                let (name, value) = split_assignment(line)?;
+                let reg = register::gpr_by_name(name)
+                    .ok_or_else(|| format!("unknown directive {}", name))?;
+                self.registers.push((reg, parse_value(value)?));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of cycles to run `model` for.
+    pub fn cycle_limit(&self, model: Model) -> u64 {
+        self.cycles
+            .iter()
+            .filter(|bounds| bounds.applies_to(model))
+            .filter_map(|bounds| bounds.max)
+            .max()
+            .map_or(DEFAULT_CYCLE_LIMIT, |max| max + 1)
+    }
+
+    /// Checks the final state of `sim`, a run of `model`, and the text it
+    /// wrote to the console.
+    ///
+    /// Returns a description of each difference. Stores still buffered
+    /// are drained first.
+    pub fn check<S: Simulator>(
+        &self,
+        model: Model,
+        sim: &mut S,
+        output: &str,
+    ) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        match (sim.halt_addr(), self.halt) {
+            (None, _) => {
+                errors.push(format!(
+                    "didn't halt within {} cycles",
+                    sim.clock()
+                ));
+                return errors;
+            }
+            (Some(addr), Some(expected)) if addr != expected => {
+                errors.push(format!(
+                    "halted at {:#x} instead of {:#x}",
+                    addr,
+                    expected
+                ));
+            }
+            _ => (),
+        }
+
+        for &(reg, expected) in &self.registers {
+            let value = sim.registers().gpr[reg].read();
+            if value != expected {
+                errors.push(format!(
+                    "x{} is {:#x} instead of {:#x}",
+                    reg,
+                    value,
+                    expected
+                ));
+            }
+        }
+
+        sim.memory_mut().drain_stores();
+        let size = self.memory_words * 4;
+        for &(start, ref words) in &self.memory {
+            for (i, &expected) in words.iter().enumerate() {
+                let addr = start + 4 * i;
+                if addr + 4 > size {
+                    errors.push(format!("mem[{:#x}] is out of range", addr));
+                    break;
+                }
+                let value = sim.memory().read(addr, 4).unwrap();
+                if value != expected {
+                    errors.push(format!(
+                        "mem[{:#x}] is {:#x} instead of {:#x}",
+                        addr,
+                        value,
+                        expected
+                    ));
+                }
+            }
+        }
+
+        let cycles = sim.clock();
+        for bounds in &self.cycles {
+            if bounds.applies_to(model) && !bounds.contains(cycles) {
+                errors.push(format!(
+                    "took {} cycles instead of {}",
+                    cycles,
+                    bounds
+                ));
+            }
+        }
+
+        if let Some(ref expected) = self.output {
+            if output != expected {
+                errors.push(format!(
+                    "output {:?} instead of {:?}",
+                    output,
+                    expected
+                ));
+            }
+        }
+
+        errors
+    }
+}
+
+
+impl Default for Expectations {
+    fn default() -> Expectations {
+        Expectations::new()
+    }
+}
+
+
+/// A program with expectations.
+#[derive(Clone, Debug)]
+pub struct Case {
+    /// Path relative to the directory searched, without the extension
+    pub name: String,
+    pub path: PathBuf,
+    pub expectations: Expectations,
+}
+
+
+impl Case {
+    /// Runs the program on `model` and checks its final state.
+    ///
+    /// Returns a description of each difference from the expectations.
+    pub fn run(&self, model: Model) -> Result<(), Vec<String>> {
+        let f = File::open(&self.path).map_err(|e| vec![e.to_string()])?;
+        let insns = DisassemblyInstructionMemory::new(&f);
+        let capture = Capture::new();
+        let mut data_memory = DataMemory::new(self.expectations.memory_words);
+        let console = Console::new(Box::new(capture.clone()));
+        data_memory.attach(console::BASE, Box::new(console));
+        let reg = RegisterFile::new(0x0);
+        let limit = self.expectations.cycle_limit(model);
+
+        let errors = match model {
+            Model::Ia => {
+                let mut sim = IaSimulator::new(&insns, data_memory, reg);
+                sim.step_cycles(limit);
+                self.expectations.check(model, &mut sim, &capture.text())
+            }
+            Model::Ca => {
+                let mut sim = CaSimulator::new(&insns, data_memory, reg);
+                sim.step_cycles(limit);
+                self.expectations.check(model, &mut sim, &capture.text())
+            }
+        };
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+
+/// Finds the programs with expectations under `dir`, sorted by name.
+///
+/// Programs are disassembly files, searched for in subdirectories too.
+pub fn discover(dir: &Path) -> io::Result<Vec<Case>> {
+    let mut cases = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if path.extension().is_none_or(|ext| ext != PROGRAM_EXTENSION) {
+                continue;
+            }
+            if let Some(expectations) = Expectations::for_program(&path)? {
+                let name = path
+                    .strip_prefix(dir)
+                    .unwrap_or(&path)
+                    .with_extension("")
+                    .to_string_lossy()
+                    .into_owned();
+                cases.push(Case {
+                    name,
+                    path,
+                    expectations,
+                });
+            }
+        }
+    }
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(cases)
+}
+
+
+/// Returns `line` up to a `#` that isn't inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+
+    line
+}
+
+
+/// Splits `lhs = rhs` into its trimmed sides.
+fn split_assignment(text: &str) -> Result<(&str, &str), String> {
+    let i = text
+        .find('=')
+        .ok_or_else(|| format!("expected '=' in {}", text))?;
+
+    Ok((text[..i].trim(), text[i + 1..].trim()))
+}
+
+
+/// Parses a 32-bit value, which may be negative.
+fn parse_value(text: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid value {}", text);
+    match text.strip_prefix('-') {
+        Some(magnitude) => match parse_number(magnitude) {
+            Some(n) if n <= 1 << 31 => Ok((n as u32).wrapping_neg()),
+            _ => Err(invalid()),
+        },
+        None => match parse_number(text) {
+            Some(n) if n <= u32::MAX as u64 => Ok(n as u32),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+
+/// Parses `[ia|ca] [MIN]..[MAX]`.
+fn parse_cycles(text: &str) -> Result<CycleBounds, String> {
+    let (model, range) = match text.split_at(text.len().min(2)) {
+        ("ia", rest) => (Some(Model::Ia), rest.trim()),
+        ("ca", rest) => (Some(Model::Ca), rest.trim()),
+        _ => (None, text),
+    };
+    let invalid = || format!("invalid cycle bounds {}", text);
+    let i = range.find("..").ok_or_else(invalid)?;
+    let bound = |s: &str| match s.trim() {
+        "" => Ok(None),
+        s => parse_number(s).map(Some).ok_or_else(invalid),
+    };
+
+    Ok(CycleBounds {
+        model,
+        min: bound(&range[..i])?,
+        max: bound(&range[i + 2..])?,
+    })
+}
+
+
+/// Parses a double-quoted string with `\n`, `\t`, `\0`, `\\` and `\"`
+/// escapes.
+fn parse_string(text: &str) -> Result<String, String> {
+    let invalid = || format!("invalid string {}", text);
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        string.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            _ => return Err(invalid()),
+        });
+    }
+
+    Ok(string)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use consts::HALT;
+    use memory::instruction::TestInstructionMemory;
+
+    #[test]
+    fn parse() {
+        let text = "\
+            # Comments and blank lines are ignored\n\
+            \n\
+            memory 64\n\
+            halt 0x10\n\
+            a0 = 42  # x10\n\
+            x31 = -1\n\
+            mem 0x8 = 0x1 2\n\
+            cycles 5..\n\
+            cycles ca ..20\n\
+            output \"# \\\"hi\\\"\\n\"\n";
+        let expectations = Expectations::parse(text).unwrap();
+
+        assert_eq!(expectations.memory_words, 64);
+        assert_eq!(expectations.halt, Some(0x10));
+        assert_eq!(expectations.registers, [(10, 42), (31, 0xffff_ffff)]);
+        assert_eq!(expectations.memory, [(8, vec![1, 2])]);
+        assert_eq!(
+            expectations.cycles,
+            [
+                CycleBounds { model: None, min: Some(5), max: None },
+                CycleBounds {
+                    model: Some(Model::Ca),
+                    min: None,
+                    max: Some(20),
+                },
+            ]
+        );
+        assert_eq!(expectations.output, Some(String::from("# \"hi\"\n")));
+        assert_eq!(expectations.cycle_limit(Model::Ia), DEFAULT_CYCLE_LIMIT);
+        assert_eq!(expectations.cycle_limit(Model::Ca), 21);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text| Expectations::parse(text).unwrap_err();
+
+        assert_eq!(error("halt 4\nx32 = 1"), "line 2: unknown directive x32");
+        assert_eq!(error("x1 = 0x1_0000"), "line 1: invalid value 0x1_0000");
+        let below = "x1 = -2147483649";
+        assert_eq!(error(below), "line 1: invalid value -2147483649");
+        assert_eq!(error("mem 0x2 = 1"), "line 1: unaligned address 0x2");
+        assert_eq!(error("cycles 10"), "line 1: invalid cycle bounds 10");
+        assert_eq!(error(r#"output "\q""#), r#"line 1: invalid string "\q""#);
+    }
+
+    #[test]
+    fn embedded() {
+        let source = "\
+            # expect: halt 0x8\n\
+            # An ordinary comment\n\
+                   0:    00 10 00 93    addi x1 , x0 , 1\n\
+              # expect: ra = 1\n";
+        let expectations = Expectations::embedded(source).unwrap().unwrap();
+        assert_eq!(expectations.halt, Some(8));
+        assert_eq!(expectations.registers, [(1, 1)]);
+
+        assert_eq!(Expectations::embedded("# halt 0x8").unwrap(), None);
+    }
+
+    #[test]
+    fn check() {
+        // addi x1, x0, 5; sw x1, 4(x0); sb x1, TX(console); HALT
+        let insns = TestInstructionMemory::new(vec![
+            0x00500093,
+            0x00102223,
+            0x30000137,
+            0x00110023,
+            HALT,
+            HALT,
+            HALT,
+            HALT,
+        ]);
+        let capture = Capture::new();
+        let mut mem = DataMemory::new(4);
+        let console = Console::new(Box::new(capture.clone()));
+        mem.attach(console::BASE, Box::new(console));
+        let mut sim = CaSimulator::new(&insns, mem, RegisterFile::new(0));
+        sim.step_cycles(100);
+
+        let mut expectations = Expectations::new();
+        expectations.memory_words = 4;
+        expectations.halt = Some(0x10);
+        expectations.registers = vec![(1, 5)];
+        expectations.memory = vec![(0x4, vec![5])];
+        expectations.output = Some(String::from("\u{5}"));
+        assert!(
+            expectations
+                .check(Model::Ca, &mut sim, &capture.text())
+                .is_empty()
+        );
+
+        expectations.halt = Some(0x14);
+        expectations.registers = vec![(1, 6)];
+        expectations.memory = vec![(0x8, vec![0, 0, 0])];
+        expectations.cycles = vec![
+            CycleBounds { model: Some(Model::Ca), min: None, max: Some(1) },
+            CycleBounds { model: Some(Model::Ia), min: None, max: Some(1) },
+        ];
+        expectations.output = Some(String::new());
+        let cycles = sim.clock();
+        assert_eq!(
+            expectations.check(Model::Ca, &mut sim, &capture.text()),
+            [
+                String::from("halted at 0x10 instead of 0x14"),
+                String::from("x1 is 0x5 instead of 0x6"),
+                String::from("mem[0x10] is out of range"),
+                format!("took {} cycles instead of ..1", cycles),
+                String::from("output \"\\u{5}\" instead of \"\""),
+            ]
+        );
+    }
+}
@@ -0,0 +1,924 @@
+//! Differential fuzzing of the CA model against the IA model.
+//!
+//! `Generator` builds random RV32I programs biased toward what the hazard
+//! unit has to get right: instructions reading the register the one before
+//! wrote, loads followed by a use of the loaded value, and branches close
+//! together. Every access stays within data memory and all control flow
+//! goes forward, so each program reaches the HALT at its end, which is
+//! preceded by NOPs for the CA pipeline to drain.
+//!
+//! `compare` runs a program on both models and lists the differences in
+//! their final architectural state. `minimize` shrinks a failing program
+//! by delta debugging, and `fuzz` puts the three together.
+
+
+use ca_simulator::CaSimulator;
+use consts::{BRANCH_OPCODE, HALT, JALR_OPCODE, JAL_OPCODE, NOP};
+use ia_simulator::IaSimulator;
+use instruction::Instruction;
+use memory::data::DataMemory;
+use memory::instruction::TestInstructionMemory;
+use register::RegisterFile;
+use simulator::Simulator;
+
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+
+/// Ops in a generated program unless set otherwise.
+pub const DEFAULT_LENGTH: usize = 64;
+
+/// Data memory size in words unless set otherwise.
+pub const DEFAULT_MEMORY_WORDS: usize = 1024;
+
+// Opcodes of the generated instructions
+const LOAD: u32 = 0b000_0011;
+const OP_IMM: u32 = 0b001_0011;
+const AUIPC: u32 = 0b001_0111;
+const STORE: u32 = 0b010_0011;
+const OP: u32 = 0b011_0011;
+const LUI: u32 = 0b011_0111;
+const FENCE: u32 = 0x0ff0_000f;
+
+/// Registers written, x1 to x15; a small set makes dependencies common.
+const REGISTERS: u32 = 15;
+
+/// Most ops a branch or jump skips.
+const MAX_SKIP: u32 = 8;
+
+/// Bytes of data memory reachable with a positive 12-bit offset from x0.
+const MAX_WINDOW: usize = 2048;
+
+/// NOPs before the HALT, as the CA model halts with the instructions
+/// ahead of it unfinished.
+const DRAIN: usize = 4;
+
+/// HALTs after the program, as the CA model fetches past the first.
+const PADDING: usize = 4;
+
+
+/// A xorshift random number generator, so that a seed repeats a run.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+
+impl Rng {
+    /// Constructs a new `Rng` from `seed`.
+    pub fn new(seed: u64) -> Rng {
+        // Scramble the seed (SplitMix64) so nearby seeds give unrelated
+        // streams; xorshift needs a nonzero state
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+        Rng { state: (z ^ (z >> 31)) | 1 }
+    }
+
+    /// Returns the next 32 random bits.
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+
+        (self.state >> 32) as u32
+    }
+
+    /// Returns a number below `n`.
+    pub fn below(&mut self, n: u32) -> u32 {
+        self.next_u32() % n
+    }
+
+    /// Returns true with a probability of `percent` in 100.
+    pub fn chance(&mut self, percent: u32) -> bool {
+        self.below(100) < percent
+    }
+}
+
+
+/// One or two instructions kept together when minimizing.
+///
+/// Branch and jump offsets are filled in when the program is encoded, so
+/// removing ops leaves every target within the program.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Insn(u32),
+    /// Instructions that only make sense together, e.g., an `andi` keeping
+    /// an address within data memory and the access using it
+    Pair(u32, u32),
+    /// A branch or JAL skipping the next `n` ops
+    Jump(u32, usize),
+    /// An `auipc` and a `jalr` from its result skipping the next `n` ops
+    JumpReg(u32, u32, usize),
+}
+
+
+impl Op {
+    /// Returns the number of instructions.
+    fn len(&self) -> usize {
+        match *self {
+            Op::Insn(_) | Op::Jump(..) => 1,
+            Op::Pair(..) | Op::JumpReg(..) => 2,
+        }
+    }
+}
+
+
+/// A generated program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    ops: Vec<Op>,
+    memory_words: usize,
+}
+
+
+impl Program {
+    /// Constructs a new `Program` for a data memory of `memory_words`.
+    pub fn new(ops: Vec<Op>, memory_words: usize) -> Program {
+        Program { ops, memory_words }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn memory_words(&self) -> usize {
+        self.memory_words
+    }
+
+    /// Encodes the program, ending with NOPs and HALT.
+    pub fn words(&self) -> Vec<u32> {
+        let mut addrs = Vec::with_capacity(self.ops.len() + 1);
+        let mut addr = 0;
+        for op in &self.ops {
+            addrs.push(addr);
+            addr += 4 * op.len() as i32;
+        }
+        addrs.push(addr);
+        let offset = |i: usize, skip: usize| {
+            addrs[(i + 1 + skip).min(self.ops.len())] - addrs[i]
+        };
+
+        let mut words = Vec::with_capacity(addrs.len() + DRAIN);
+        for (i, &op) in self.ops.iter().enumerate() {
+            match op {
+                Op::Insn(insn) => words.push(insn),
+                Op::Pair(first, second) => {
+                    words.push(first);
+                    words.push(second);
+                }
+                Op::Jump(insn, skip) => {
+                    let offset = offset(i, skip);
+                    words.push(match insn & 0x7f {
+                        BRANCH_OPCODE => insn | b_imm(offset),
+                        _ => insn | j_imm(offset),
+                    });
+                }
+                Op::JumpReg(auipc, jalr, skip) => {
+                    words.push(auipc);
+                    words.push(jalr | (offset(i, skip) as u32) << 20);
+                }
+            }
+        }
+        words.extend_from_slice(&[NOP; DRAIN]);
+        words.push(HALT);
+
+        words
+    }
+
+    /// Returns an instruction memory holding the program.
+    pub fn instructions(&self) -> TestInstructionMemory {
+        let mut words = self.words();
+        words.extend_from_slice(&[HALT; PADDING]);
+
+        TestInstructionMemory::new(words)
+    }
+
+    /// Writes the program in the format `DisassemblyInstructionMemory`
+    /// reads.
+    pub fn write_disassembly<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "Disassembly of section .text:\n")?;
+        writeln!(w, "0 <_start>:")?;
+
+        let mut words = self.words();
+        words.extend_from_slice(&[NOP; PADDING]);
+        for (i, &word) in words.iter().enumerate() {
+            let b = word.to_be_bytes();
+            writeln!(
+                w,
+                "{:8x}:    {:02x} {:02x} {:02x} {:02x}    {}",
+                4 * i,
+                b[0],
+                b[1],
+                b[2],
+                b[3],
+                Instruction::new(word)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the most cycles a run should take.
+    ///
+    /// Control flow only goes forward, so each instruction runs once.
+    fn cycle_limit(&self) -> u64 {
+        1000 + 100 * self.words().len() as u64
+    }
+
+    /// Runs the program on the IA model.
+    pub fn run_ia(&self) -> State {
+        let insns = self.instructions();
+        let mut sim = IaSimulator::new(
+            &insns,
+            DataMemory::new(self.memory_words),
+            RegisterFile::new(0),
+        );
+        sim.step_cycles(self.cycle_limit());
+
+        State::of(&mut sim, self.memory_words)
+    }
+
+    /// Runs the program on the CA model, set up by `configure`.
+    pub fn run_ca<F>(&self, configure: &F) -> State
+    where
+        F: Fn(&mut CaSimulator),
+    {
+        let insns = self.instructions();
+        let mut sim = CaSimulator::new(
+            &insns,
+            DataMemory::new(self.memory_words),
+            RegisterFile::new(0),
+        );
+        configure(&mut sim);
+        sim.step_cycles(self.cycle_limit());
+
+        State::of(&mut sim, self.memory_words)
+    }
+}
+
+
+/// The final architectural state of a run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    pub halt_addr: Option<usize>,
+    pub gpr: [u32; 32],
+    /// Data memory, word by word
+    pub memory: Vec<u32>,
+}
+
+
+impl State {
+    /// Captures the state of `sim`, with `memory_words` of data memory.
+    ///
+    /// Stores still buffered are drained first.
+    pub fn of<S: Simulator>(sim: &mut S, memory_words: usize) -> State {
+        sim.memory_mut().drain_stores();
+        let mut gpr = [0; 32];
+        for (value, reg) in gpr.iter_mut().zip(sim.registers().gpr.iter()) {
+            *value = reg.read();
+        }
+        let memory = (0..memory_words)
+            .map(|i| sim.memory().read(4 * i, 4).unwrap())
+            .collect();
+
+        State {
+            halt_addr: sim.halt_addr(),
+            gpr,
+            memory,
+        }
+    }
+
+    /// Describes how `self`, from the CA model, differs from `ia`.
+    pub fn differences(&self, ia: &State) -> Vec<String> {
+        let mut differences = Vec::new();
+        let hex = |addr: Option<usize>| match addr {
+            Some(addr) => format!("{:#x}", addr),
+            None => String::from("none"),
+        };
+
+        if self.halt_addr != ia.halt_addr {
+            differences.push(format!(
+                "halt: CA {} IA {}",
+                hex(self.halt_addr),
+                hex(ia.halt_addr)
+            ));
+        }
+        for (i, (ca, ia)) in self.gpr.iter().zip(ia.gpr.iter()).enumerate() {
+            if ca != ia {
+                differences.push(format!("x{}: CA {:#x} IA {:#x}", i, ca, ia));
+            }
+        }
+        let words = self.memory.iter().zip(ia.memory.iter());
+        for (i, (ca, ia)) in words.enumerate() {
+            if ca != ia {
+                differences.push(format!(
+                    "mem[{:#x}]: CA {:#x} IA {:#x}",
+                    4 * i,
+                    ca,
+                    ia
+                ));
+            }
+        }
+
+        differences
+    }
+
+    /// Returns directives for `expect` requiring this state.
+    ///
+    /// Registers and memory words that are zero are left out.
+    pub fn expectations(&self) -> Vec<String> {
+        let mut directives = Vec::new();
+        if self.memory.len() != DEFAULT_MEMORY_WORDS {
+            directives.push(format!("memory {}", self.memory.len()));
+        }
+        if let Some(addr) = self.halt_addr {
+            directives.push(format!("halt {:#x}", addr));
+        }
+        for (i, &value) in self.gpr.iter().enumerate().skip(1) {
+            if value != 0 {
+                directives.push(format!("x{} = {:#x}", i, value));
+            }
+        }
+        for (i, &value) in self.memory.iter().enumerate() {
+            if value != 0 {
+                directives.push(format!("mem {:#x} = {:#x}", 4 * i, value));
+            }
+        }
+
+        directives
+    }
+}
+
+
+/// Builds random programs.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    length: usize,
+    memory_words: usize,
+}
+
+
+impl Generator {
+    /// Constructs a new `Generator` with the default length and memory.
+    pub fn new() -> Generator {
+        Generator {
+            length: DEFAULT_LENGTH,
+            memory_words: DEFAULT_MEMORY_WORDS,
+        }
+    }
+
+    /// Sets the number of ops in a program.
+    pub fn set_length(&mut self, length: usize) {
+        self.length = length;
+    }
+
+    /// Sets the size of data memory accesses stay within.
+    ///
+    /// Panics if there isn't at least one word.
+    pub fn set_memory_words(&mut self, memory_words: usize) {
+        assert!(memory_words > 0, "data memory must hold a word");
+        self.memory_words = memory_words;
+    }
+
+    /// Builds a program from the random numbers of `rng`.
+    pub fn generate(&self, rng: &mut Rng) -> Program {
+        let mut builder = Builder::new(rng, self.memory_words);
+        let ops = (0..self.length).map(|_| builder.op()).collect();
+
+        Program::new(ops, self.memory_words)
+    }
+}
+
+
+impl Default for Generator {
+    fn default() -> Generator {
+        Generator::new()
+    }
+}
+
+
+/// State kept between the ops of a program being generated.
+struct Builder<'a> {
+    rng: &'a mut Rng,
+    /// Bytes of data memory accessed through x0
+    window: usize,
+    /// Registers written lately, latest last
+    written: Vec<u32>,
+    /// Register loaded by the last op
+    loaded: Option<u32>,
+    /// Register the current op should read, if it reads any
+    load_use: Option<u32>,
+    /// The last op was a branch or jump
+    branched: bool,
+    /// Addresses accessed lately
+    addrs: Vec<usize>,
+}
+
+
+impl<'a> Builder<'a> {
+    fn new(rng: &'a mut Rng, memory_words: usize) -> Builder<'a> {
+        Builder {
+            rng,
+            window: MAX_WINDOW.min(4 * memory_words),
+            written: Vec::new(),
+            loaded: None,
+            load_use: None,
+            branched: false,
+            addrs: Vec::new(),
+        }
+    }
+
+    /// Returns a random op.
+    fn op(&mut self) -> Op {
+        self.load_use = self.loaded.take();
+        let branch_weight = if self.branched { 40 } else { 10 };
+        let weights = [24, 24, 4, 12, 12, branch_weight, 3, 3, 2];
+        let total: u32 = weights.iter().sum();
+        let mut pick = self.rng.below(total);
+        let kind = weights
+            .iter()
+            .position(|&weight| {
+                let found = pick < weight;
+                pick = pick.saturating_sub(weight);
+                found
+            })
+            .unwrap();
+
+        self.branched = (5..=7).contains(&kind);
+        match kind {
+            0 => self.alu_imm(),
+            1 => self.alu_reg(),
+            2 => self.upper(),
+            3 => self.load(),
+            4 => self.store(),
+            5 => self.branch(),
+            6 => self.jal(),
+            7 => self.jalr(),
+            _ => Op::Insn(FENCE),
+        }
+    }
+
+    /// Returns a register to read, most likely one written just before.
+    fn source(&mut self) -> u32 {
+        if let Some(reg) = self.load_use.take() {
+            if self.rng.chance(70) {
+                return reg;
+            }
+        }
+        if !self.written.is_empty() && self.rng.chance(60) {
+            let n = self.written.len() as u32;
+            // Favour the latest
+            let back = self.rng.below(n).min(self.rng.below(n));
+            return self.written[(n - 1 - back) as usize];
+        }
+        if self.rng.chance(5) {
+            return 0;
+        }
+
+        self.register()
+    }
+
+    /// Returns a register to write.
+    fn dest(&mut self) -> u32 {
+        let reg = self.register();
+        self.written.push(reg);
+        if self.written.len() > 3 {
+            self.written.remove(0);
+        }
+
+        reg
+    }
+
+    fn register(&mut self) -> u32 {
+        1 + self.rng.below(REGISTERS)
+    }
+
+    /// Returns an address aligned to `size` within the window, likely one
+    /// accessed before.
+    fn address(&mut self, size: usize) -> usize {
+        let addr = if !self.addrs.is_empty() && self.rng.chance(50) {
+            let i = self.rng.below(self.addrs.len() as u32) as usize;
+            self.addrs[i] & !(size - 1)
+        } else {
+            let slots = (self.window / size) as u32;
+            size * self.rng.below(slots) as usize
+        };
+        self.addrs.push(addr & !3);
+        if self.addrs.len() > 4 {
+            self.addrs.remove(0);
+        }
+
+        addr
+    }
+
+    /// Returns an `andi` confining a register to the window, and the
+    /// register it writes.
+    fn confine(&mut self) -> (u32, u32) {
+        let span: i32 = 1 << (usize::BITS - 1 - self.window.leading_zeros());
+        let mask = (span - 1) & !3;
+        let src = self.source();
+        let base = self.dest();
+
+        (i_type(OP_IMM, 0b111, base, src, mask), base)
+    }
+
+    fn alu_imm(&mut self) -> Op {
+        let funct3 = self.rng.below(8);
+        let imm = match funct3 {
+            0b001 => self.rng.below(32) as i32,
+            0b101 => (self.rng.below(2) << 10 | self.rng.below(32)) as i32,
+            _ => self.rng.below(4096) as i32 - 2048,
+        };
+        let rs1 = self.source();
+
+        Op::Insn(i_type(OP_IMM, funct3, self.dest(), rs1, imm))
+    }
+
+    fn alu_reg(&mut self) -> Op {
+        let (funct7, funct3) = match self.rng.below(10) {
+            8 => (0b010_0000, 0b000), // sub
+            9 => (0b010_0000, 0b101), // sra
+            n => (0, n % 8),
+        };
+        let rs1 = self.source();
+        let rs2 = self.source();
+
+        Op::Insn(r_type(funct7, funct3, self.dest(), rs1, rs2))
+    }
+
+    fn upper(&mut self) -> Op {
+        let opcode = if self.rng.chance(50) { LUI } else { AUIPC };
+        let imm = self.rng.next_u32() & 0xfffff;
+
+        Op::Insn(imm << 12 | self.dest() << 7 | opcode)
+    }
+
+    fn load(&mut self) -> Op {
+        let funct3 = [0b000, 0b001, 0b010, 0b100, 0b101][self.rng.below(5)
+            as usize];
+        let size = 1 << (funct3 & 0b11);
+        let op = if self.rng.chance(50) {
+            let addr = self.address(size) as i32;
+            Op::Insn(i_type(LOAD, funct3, self.dest(), 0, addr))
+        } else {
+            let (andi, base) = self.confine();
+            Op::Pair(andi, i_type(LOAD, funct3, self.dest(), base, 0))
+        };
+        self.loaded = self.written.last().cloned();
+
+        op
+    }
+
+    fn store(&mut self) -> Op {
+        let funct3 = self.rng.below(3);
+        let size = 1 << funct3;
+        if self.rng.chance(50) {
+            let addr = self.address(size) as i32;
+            let rs2 = self.source();
+            Op::Insn(s_type(funct3, 0, rs2, addr))
+        } else {
+            let (andi, base) = self.confine();
+            let rs2 = self.source();
+            Op::Pair(andi, s_type(funct3, base, rs2, 0))
+        }
+    }
+
+    fn branch(&mut self) -> Op {
+        let funct3 = [0b000, 0b001, 0b100, 0b101, 0b110, 0b111][self
+            .rng
+            .below(6) as usize];
+        let rs1 = self.source();
+        let rs2 = self.source();
+        let insn = rs2 << 20 | rs1 << 15 | funct3 << 12 | BRANCH_OPCODE;
+
+        Op::Jump(insn, self.skip())
+    }
+
+    fn jal(&mut self) -> Op {
+        let rd = if self.rng.chance(50) { self.dest() } else { 0 };
+
+        Op::Jump(rd << 7 | JAL_OPCODE, self.skip())
+    }
+
+    fn jalr(&mut self) -> Op {
+        let base = self.dest();
+        let rd = if self.rng.chance(50) { self.dest() } else { 0 };
+        let auipc = base << 7 | AUIPC;
+
+        Op::JumpReg(auipc, i_type(JALR_OPCODE, 0, rd, base, 0), self.skip())
+    }
+
+    fn skip(&mut self) -> usize {
+        self.rng.below(MAX_SKIP + 1) as usize
+    }
+}
+
+
+fn r_type(funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | OP
+}
+
+
+fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+    (imm as u32 & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+
+fn s_type(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 |
+        (imm & 0x1f) << 7 | STORE
+}
+
+
+/// Returns the immediate bits of a branch to `offset`.
+fn b_imm(offset: i32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 12 & 0x1) << 31 | (imm >> 5 & 0x3f) << 25 |
+        (imm >> 1 & 0xf) << 8 | (imm >> 11 & 0x1) << 7
+}
+
+
+/// Returns the immediate bits of a JAL to `offset`.
+fn j_imm(offset: i32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 20 & 0x1) << 31 | (imm >> 1 & 0x3ff) << 21 |
+        (imm >> 11 & 0x1) << 20 | (imm >> 12 & 0xff) << 12
+}
+
+
+/// Runs `program` on both models and describes the differences in their
+/// final states.
+///
+/// `configure` sets up the CA model. A panic in either model is a
+/// difference, too.
+pub fn compare<F>(program: &Program, configure: &F) -> Vec<String>
+where
+    F: Fn(&mut CaSimulator),
+{
+    let run = || program.run_ca(configure).differences(&program.run_ia());
+    match panic::catch_unwind(AssertUnwindSafe(run)) {
+        Ok(differences) => differences,
+        Err(_) => vec![String::from("panicked")],
+    }
+}
+
+
+/// Shrinks `program` by delta debugging while `fails` holds.
+///
+/// Removes chunks of ops, halving their size whenever none can be removed,
+/// until no single op can be.
+pub fn minimize<P>(program: &Program, mut fails: P) -> Program
+where
+    P: FnMut(&Program) -> bool,
+{
+    let mut ops = program.ops.clone();
+    let mut chunks = 2;
+
+    while ops.len() >= 2 {
+        let size = ops.len().div_ceil(chunks);
+        let mut removed = false;
+        for start in (0..ops.len()).step_by(size) {
+            let mut candidate = ops.clone();
+            candidate.drain(start..(start + size).min(ops.len()));
+            let candidate = Program::new(candidate, program.memory_words);
+            if fails(&candidate) {
+                ops = candidate.ops;
+                chunks = (chunks - 1).max(2);
+                removed = true;
+                break;
+            }
+        }
+        if !removed {
+            if chunks >= ops.len() {
+                break;
+            }
+            chunks = (2 * chunks).min(ops.len());
+        }
+    }
+
+    Program::new(ops, program.memory_words)
+}
+
+
+/// A program the models disagree on.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    /// Seed the program was generated from
+    pub seed: u64,
+    pub program: Program,
+    pub minimized: Program,
+    /// Differences the minimized program shows
+    pub differences: Vec<String>,
+}
+
+
+/// Compares `count` programs, generated from consecutive seeds starting
+/// at `seed`, and minimizes the first the models disagree on.
+pub fn fuzz<F>(
+    generator: &Generator,
+    seed: u64,
+    count: u64,
+    configure: F,
+) -> Option<Failure>
+where
+    F: Fn(&mut CaSimulator),
+{
+    for seed in (0..count).map(|i| seed.wrapping_add(i)) {
+        let program = generator.generate(&mut Rng::new(seed));
+        if compare(&program, &configure).is_empty() {
+            continue;
+        }
+        debug!("Programs from seed {} differ, minimizing", seed);
+
+        let minimized = minimize(&program, |candidate| {
+            !compare(candidate, &configure).is_empty()
+        });
+        let differences = compare(&minimized, &configure);
+        return Some(Failure {
+            seed,
+            program,
+            minimized,
+            differences,
+        });
+    }
+
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hazards::{HazardPolicy, HazardUnit};
+    use instruction::{Function, Opcode};
+
+    #[test]
+    fn rng() {
+        let mut a = Rng::new(1);
+        let mut b = Rng::new(1);
+        let mut c = Rng::new(2);
+        let a: Vec<u32> = (0..8).map(|_| a.next_u32()).collect();
+        let b: Vec<u32> = (0..8).map(|_| b.next_u32()).collect();
+        let c: Vec<u32> = (0..8).map(|_| c.next_u32()).collect();
+
+        assert_eq!(a, b);
+        assert!(a != c);
+    }
+
+    #[test]
+    fn generated_programs_are_valid() {
+        let mut generator = Generator::new();
+        generator.set_length(200);
+        generator.set_memory_words(64);
+
+        for seed in 0..20 {
+            let program = generator.generate(&mut Rng::new(seed));
+            assert_eq!(program.ops().len(), 200);
+            let words = program.words();
+            let halt_addr = 4 * (words.len() - 1);
+
+            for (i, &word) in words.iter().enumerate() {
+                let insn = Instruction::new(word);
+                let pc = 4 * i as i64;
+                let imm = insn.fields.imm.unwrap_or(0) as i32 as i64;
+                match insn.opcode {
+                    // Forward, and no further than the HALT
+                    Opcode::Branch | Opcode::Jal => {
+                        assert!(imm >= 4 && pc + imm <= halt_addr as i64);
+                    }
+                    Opcode::Load | Opcode::Store => {
+                        let rs1 = insn.fields.rs1.unwrap();
+                        assert!(rs1 == 0 || imm == 0);
+                        assert!((0..256).contains(&imm));
+                    }
+                    _ => (),
+                }
+            }
+            assert_eq!(words[words.len() - 1], HALT);
+
+            // Both models reach the HALT
+            let ia = program.run_ia();
+            assert_eq!(ia.halt_addr, Some(halt_addr));
+            assert_eq!(program.run_ca(&|_: &mut CaSimulator| ()), ia);
+        }
+    }
+
+    #[test]
+    fn dependencies() {
+        let program = Generator::new().generate(&mut Rng::new(7));
+        let insns: Vec<Instruction> =
+            program.words().into_iter().map(Instruction::new).collect();
+        let reads = |insn: &Instruction, rd: u32| {
+            insn.fields.rs1 == Some(rd) || insn.fields.rs2 == Some(rd)
+        };
+
+        let mut back_to_back = 0;
+        let mut load_use = 0;
+        for pair in insns.windows(2) {
+            match pair[0].fields.rd {
+                Some(rd) if rd != 0 && reads(&pair[1], rd) => {
+                    back_to_back += 1;
+                    if pair[0].opcode == Opcode::Load {
+                        load_use += 1;
+                    }
+                }
+                _ => (),
+            }
+        }
+        let branches = insns
+            .windows(2)
+            .filter(|pair| {
+                pair.iter().all(|insn| insn.opcode == Opcode::Branch)
+            })
+            .count();
+
+        assert!(back_to_back >= 16, "{} back-to-back", back_to_back);
+        assert!(load_use >= 2, "{} load-use", load_use);
+        assert!(branches >= 1, "{} adjacent branches", branches);
+    }
+
+    #[test]
+    fn jumps_survive_removal() {
+        // addi x1, x0, 1; beq x0, x0 over 2; addi x1, x1, 2; addi x1, x1, 4
+        let addi = |imm| i_type(OP_IMM, 0, 1, 1, imm);
+        let beq = BRANCH_OPCODE;
+        let program = Program::new(
+            vec![
+                Op::Insn(i_type(OP_IMM, 0, 1, 0, 1)),
+                Op::Jump(beq, 2),
+                Op::Insn(addi(2)),
+                Op::Insn(addi(4)),
+                Op::Insn(addi(8)),
+            ],
+            16,
+        );
+        assert_eq!(program.run_ia().gpr[1], 9);
+
+        // Removing a skipped op moves the target to the end
+        let shorter = Program::new(
+            vec![program.ops[0], program.ops[1], program.ops[2]],
+            16,
+        );
+        let words = shorter.words();
+        assert_eq!(Instruction::new(words[1]).fields.imm, Some(8));
+        assert_eq!(words[3], NOP);
+        assert_eq!(Instruction::new(words[7]).function, Function::Halt);
+        assert_eq!(shorter.run_ia().gpr[1], 1);
+    }
+
+    #[test]
+    fn minimize_to_failing_ops() {
+        let ops: Vec<Op> = (0..40).map(|i| Op::Insn(NOP | i << 20)).collect();
+        let program = Program::new(ops.clone(), 16);
+        let needed = [ops[3], ops[17], ops[30]];
+
+        let mut tries = 0;
+        let minimized = minimize(&program, |candidate| {
+            tries += 1;
+            needed.iter().all(|op| candidate.ops().contains(op))
+        });
+
+        assert_eq!(minimized.ops(), needed);
+        assert!(tries < 100, "{} tries", tries);
+    }
+
+    #[test]
+    fn finds_missing_interlock() {
+        let failure = fuzz(&Generator::new(), 0, 100, |ca: &mut CaSimulator| {
+            ca.set_hazard_unit(HazardUnit::new(HazardPolicy::NoInterlock));
+        }).expect("no difference found");
+
+        assert!(!failure.differences.is_empty());
+        assert!(failure.minimized.ops().len() <= 4);
+        assert!(failure.minimized.ops().len() < failure.program.ops().len());
+    }
+
+    #[test]
+    fn disassembly_and_expectations() {
+        let program = Program::new(
+            vec![
+                Op::Insn(i_type(OP_IMM, 0, 5, 0, 0x5a5)),
+                Op::Insn(s_type(0b010, 0, 5, 8)),
+            ],
+            16,
+        );
+        let mut text = Vec::new();
+        program.write_disassembly(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines[2], "0 <_start>:");
+        assert_eq!(lines[3], "       0:    5a 50 02 93    addi x5, x0, 1445");
+        assert_eq!(lines[4], "       4:    00 50 24 23    sw x5, 8(x0)");
+        assert_eq!(lines[5], "       8:    00 00 00 13    addi x0, x0, 0");
+        assert_eq!(lines[9], "      18:    00 00 00 3f    halt");
+        assert_eq!(
+            program.run_ia().expectations(),
+            ["memory 16", "halt 0x18", "x5 = 0x5a5", "mem 0x8 = 0x5a5"]
+        );
+    }
+}
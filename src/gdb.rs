@@ -0,0 +1,628 @@
+//! GDB remote serial protocol stub.
+//!
+//! `GdbStub` serves one GDB connection, so `riscv32-unknown-elf-gdb` can
+//! debug a program running in either simulator:
+//!
+//! ```text
+//! (gdb) target remote :1234
+//! ```
+//!
+//! It reads and writes the general purpose registers and the PC, reads
+//! instruction and data memory and writes data memory, single-steps and
+//! continues, and supports software and hardware breakpoints and write,
+//! read and access watchpoints. GDB reads the RV32I register layout from
+//! the target description.
+//!
+//! The PC is the address of the current instruction, as in the debugger:
+//! in the cycle accurate model that's the oldest instruction in the
+//! pipeline, and writing it empties the pipeline. Instruction and data
+//! memory are both mapped from address zero, so reads below the end of the
+//! program are served from instruction memory, which can't be written.
+//! HALT is reported as the program exiting with status 0. Given the
+//! connection with `set_interrupt`, a continuing program stops when GDB
+//! sends an interrupt (Ctrl-C).
+
+
+use memory::instruction::InstructionMemory;
+use pipeline::events::Event;
+use register::ABI_NAMES;
+use simulator::Simulator;
+
+use std::io::{self, BufRead, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+
+/// Most data bytes in a packet.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Byte GDB sends to interrupt a running program.
+const INTERRUPT: u8 = 0x03;
+
+/// Cycles between polls for an interrupt while continuing.
+const INTERRUPT_INTERVAL: u64 = 1024;
+
+/// Number of the PC in the target description.
+const PC_REGNUM: usize = 32;
+
+
+/// What set a breakpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Breakpoint {
+    Software,
+    Hardware,
+}
+
+
+/// The accesses a watchpoint stops on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Watch {
+    Write,
+    Read,
+    Access,
+}
+
+
+impl Watch {
+    /// Returns the stop reason reported for the watchpoint.
+    fn reason(self) -> &'static str {
+        match self {
+            Watch::Write => "watch",
+            Watch::Read => "rwatch",
+            Watch::Access => "awatch",
+        }
+    }
+}
+
+
+/// A watchpoint on `len` bytes from `addr`.
+struct Watchpoint {
+    watch: Watch,
+    addr: u32,
+    len: u32,
+}
+
+
+/// A connection that can be read without blocking, to poll for interrupts
+/// while the program runs.
+pub trait Interruptible {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+
+impl Interruptible for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+
+#[cfg(unix)]
+impl Interruptible for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+
+/// Serves the GDB remote serial protocol for a simulator.
+pub struct GdbStub<'a, S: Simulator, R: BufRead, W: Write> {
+    sim: S,
+    insns: &'a dyn InstructionMemory,
+    input: R,
+    out: W,
+    /// Acknowledge packets, until GDB turns it off
+    ack: bool,
+    breakpoints: Vec<(u32, Breakpoint)>,
+    watchpoints: Vec<Watchpoint>,
+    /// The connection `input` reads from, polled for interrupts
+    interrupt: Option<Box<dyn Interruptible>>,
+}
+
+
+impl<'a, S: Simulator, R: BufRead, W: Write> GdbStub<'a, S, R, W> {
+    /// Constructs a new `GdbStub` of `sim`, which executes `insns`, reading
+    /// packets from `input` and replying to `out`.
+    pub fn new(
+        sim: S,
+        insns: &'a dyn InstructionMemory,
+        input: R,
+        out: W,
+    ) -> GdbStub<'a, S, R, W> {
+        GdbStub {
+            sim,
+            insns,
+            input,
+            out,
+            ack: true,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            interrupt: None,
+        }
+    }
+
+    /// Sets the connection `input` reads from, so continuing can be
+    /// interrupted.
+    pub fn set_interrupt(
+        &mut self,
+        interrupt: Option<Box<dyn Interruptible>>,
+    ) {
+        self.interrupt = interrupt;
+    }
+
+    pub fn simulator(&self) -> &S {
+        &self.sim
+    }
+
+    /// Serves packets until GDB detaches, kills the program or disconnects.
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match packet.as_str() {
+                "D" => return self.send("OK"),
+                "k" => return Ok(()),
+                _ => {
+                    let reply = self.reply(&packet);
+                    self.send(&reply)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next packet, or `None` when the connection is closed.
+    ///
+    /// Acknowledgements and interrupts between packets are ignored.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut buf = Vec::new();
+            self.input.read_until(b'$', &mut buf)?;
+            if buf.last() != Some(&b'$') {
+                return Ok(None);
+            }
+
+            buf.clear();
+            self.input.read_until(b'#', &mut buf)?;
+            if buf.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut sum = [0; 2];
+            self.input.read_exact(&mut sum)?;
+
+            let sum = u8::from_str_radix(&String::from_utf8_lossy(&sum), 16);
+            let valid = sum == Ok(checksum(&buf));
+            if self.ack {
+                self.out.write_all(if valid { b"+" } else { b"-" })?;
+                self.out.flush()?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&buf).into_owned()));
+            }
+        }
+    }
+
+    /// Sends a packet holding `data`.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.out, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+        self.out.flush()
+    }
+
+    /// Carries out a packet, returning the reply.
+    fn reply(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;\
+                 QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            self.ack = false;
+            ok()
+        } else if let Some(annex) =
+            packet.strip_prefix("qXfer:features:read:")
+        {
+            read_features(annex)
+        } else if packet == "qAttached" {
+            String::from("1")
+        } else if packet.starts_with('H') {
+            ok()
+        } else if packet == "?" {
+            String::from("S05")
+        } else if packet == "g" {
+            (0..PC_REGNUM + 1)
+                .map(|i| hex_word(self.read_register(i)))
+                .collect()
+        } else if let Some(values) = packet.strip_prefix('G') {
+            self.write_registers(values)
+        } else if let Some(regnum) = packet.strip_prefix('p') {
+            match parse_hex(regnum).map(|i| i as usize) {
+                Some(i) if i <= PC_REGNUM => hex_word(self.read_register(i)),
+                _ => error(),
+            }
+        } else if let Some(assignment) = packet.strip_prefix('P') {
+            self.write_register(assignment)
+        } else if let Some(range) = packet.strip_prefix('m') {
+            self.read_memory(range)
+        } else if let Some(write) = packet.strip_prefix('M') {
+            self.write_memory(write)
+        } else if packet.starts_with('Z') || packet.starts_with('z') {
+            self.set_point(packet)
+        } else if packet == "s" {
+            self.resume(true)
+        } else if packet == "c" {
+            self.resume(false)
+        } else {
+            String::new() // unsupported
+        }
+    }
+
+    /// Reads general purpose register `i`, or the PC.
+    fn read_register(&self, i: usize) -> u32 {
+        if i == PC_REGNUM {
+            return self.sim.current_pc();
+        }
+
+        self.sim.registers().gpr[i].read()
+    }
+
+    /// Writes general purpose register `i`, or the PC.
+    fn set_register(&mut self, i: usize, value: u32) {
+        if i == PC_REGNUM {
+            self.sim.set_pc(value);
+        } else {
+            self.sim.registers_mut().gpr[i].write(value);
+        }
+    }
+
+    /// Writes all registers from the hex values of a `G` packet.
+    fn write_registers(&mut self, values: &str) -> String {
+        if values.len() != 8 * (PC_REGNUM + 1) {
+            return error();
+        }
+        let values: Option<Vec<u32>> = (0..PC_REGNUM + 1)
+            .map(|i| parse_word(&values[8 * i..8 * i + 8]))
+            .collect();
+        let values = match values {
+            Some(values) => values,
+            None => return error(),
+        };
+
+        // Only empty the pipeline if the PC changes
+        for (i, &value) in values.iter().enumerate() {
+            if i != PC_REGNUM || value != self.read_register(i) {
+                self.set_register(i, value);
+            }
+        }
+
+        ok()
+    }
+
+    /// Writes a register from a `P` packet of the form `n=value`.
+    fn write_register(&mut self, assignment: &str) -> String {
+        let mut parts = assignment.splitn(2, '=');
+        let regnum = parts.next().and_then(parse_hex);
+        let value = parts.next().and_then(parse_word);
+        match (regnum, value) {
+            (Some(i), Some(value)) if i as usize <= PC_REGNUM => {
+                self.set_register(i as usize, value);
+                ok()
+            }
+            _ => error(),
+        }
+    }
+
+    /// Reads memory for an `m` packet of the form `addr,len`.
+    fn read_memory(&self, range: &str) -> String {
+        let (addr, len) = match parse_range(range) {
+            Some(range) => range,
+            None => return error(),
+        };
+
+        let mut data = String::new();
+        for addr in addr..addr.saturating_add(len.min(PACKET_SIZE as u32 / 2))
+        {
+            let mem = self.sim.memory();
+            let byte = if (addr as usize) < self.insns.size() {
+                let word = self.insns.read(addr as usize & !0x3);
+                Some(word >> (8 * (addr & 0x3)))
+            } else if mem.contains(addr as usize, 1) {
+                mem.read(addr as usize, 1).ok()
+            } else {
+                None
+            };
+            match byte {
+                Some(byte) => data.push_str(&format!("{:02x}", byte as u8)),
+                None if data.is_empty() => return error(),
+                None => break,
+            }
+        }
+
+        data
+    }
+
+    /// Writes data memory for an `M` packet of the form `addr,len:bytes`.
+    fn write_memory(&mut self, write: &str) -> String {
+        let mut parts = write.splitn(2, ':');
+        let range = parts.next().and_then(parse_range);
+        let bytes = parts.next().and_then(parse_bytes);
+        let (addr, bytes) = match (range, bytes) {
+            (Some((addr, len)), Some(b)) if b.len() == len as usize => {
+                (addr as usize, b)
+            }
+            _ => return error(),
+        };
+        if addr < self.insns.size() {
+            return error(); // instruction memory is read-only
+        }
+        if !self.sim.memory().contains(addr, bytes.len()) {
+            return error();
+        }
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            let mem = self.sim.memory_mut();
+            if mem.write(addr + i, 1, byte as u32).is_err() {
+                return error();
+            }
+        }
+
+        ok()
+    }
+
+    /// Inserts or removes a breakpoint or watchpoint for a `Z` or `z`
+    /// packet of the form `Ztype,addr,kind`.
+    fn set_point(&mut self, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let fields: Vec<&str> = packet[1..].split(',').collect();
+        let (kind, addr, len) = match fields[..] {
+            [kind, addr, len] => (kind, parse_hex(addr), parse_hex(len)),
+            _ => return error(),
+        };
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return error(),
+        };
+
+        let watch = match kind {
+            "0" | "1" => {
+                let breakpoint = if kind == "0" {
+                    Breakpoint::Software
+                } else {
+                    Breakpoint::Hardware
+                };
+                self.breakpoints.retain(|&b| b != (addr, breakpoint));
+                if insert {
+                    self.breakpoints.push((addr, breakpoint));
+                }
+                return ok();
+            }
+            "2" => Watch::Write,
+            "3" => Watch::Read,
+            "4" => Watch::Access,
+            _ => return String::new(), // unsupported
+        };
+        self.watchpoints.retain(|w| {
+            (w.watch, w.addr, w.len) != (watch, addr, len)
+        });
+        if insert {
+            self.watchpoints.push(Watchpoint { watch, addr, len });
+        }
+
+        ok()
+    }
+
+    /// Steps one instruction, or continues until a breakpoint, watchpoint,
+    /// interrupt or HALT, returning the stop reply.
+    fn resume(&mut self, step: bool) -> String {
+        let mut cycles = 0;
+        loop {
+            if self.sim.step().is_some() {
+                return String::from("W00");
+            }
+            cycles += 1;
+            if !step && cycles % INTERRUPT_INTERVAL == 0 && self.interrupted()
+            {
+                return String::from("S02");
+            }
+
+            if let Some(reply) = self.check_watchpoints() {
+                return reply;
+            }
+
+            // A new instruction is current once the last one completed
+            let completed = self.sim.pipeline().is_none() ||
+                self.sim
+                    .events()
+                    .iter()
+                    .any(|e| matches!(*e, Event::Retire { .. }));
+            if !completed {
+                continue;
+            }
+            if step {
+                return String::from("S05");
+            }
+
+            let pc = self.sim.current_pc();
+            let hit = self.breakpoints.iter().find(|b| b.0 == pc);
+            match hit {
+                Some(&(_, Breakpoint::Software)) => {
+                    return String::from("T05swbreak:;")
+                }
+                Some(&(_, Breakpoint::Hardware)) => {
+                    return String::from("T05hwbreak:;")
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Indicates GDB sent an interrupt, without waiting for input.
+    fn interrupted(&mut self) -> bool {
+        let interrupt = match self.interrupt {
+            Some(ref interrupt) => interrupt,
+            None => return false,
+        };
+        if interrupt.set_nonblocking(true).is_err() {
+            return false;
+        }
+        // Buffered input is returned without reading the connection
+        let byte = match self.input.fill_buf() {
+            Ok(buf) => buf.first().cloned(),
+            Err(_) => None,
+        };
+        interrupt.set_nonblocking(false).expect("error configuring socket");
+
+        if byte == Some(INTERRUPT) {
+            self.input.consume(1);
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns the stop reply for the first watchpoint hit in the last
+    /// cycle, if any.
+    fn check_watchpoints(&self) -> Option<String> {
+        for event in self.sim.events() {
+            let (addr, size, write) = match *event {
+                Event::MemAccess {
+                    addr, size, write, ..
+                } => (addr, size as u32, write),
+                _ => continue,
+            };
+
+            let hit = self.watchpoints.iter().find(|w| {
+                let overlaps = addr < w.addr.saturating_add(w.len) &&
+                    w.addr < addr + size;
+                overlaps &&
+                    match w.watch {
+                        Watch::Write => write,
+                        Watch::Read => !write,
+                        Watch::Access => true,
+                    }
+            });
+            if let Some(w) = hit {
+                return Some(format!("T05{}:{:x};", w.watch.reason(), addr));
+            }
+        }
+
+        None
+    }
+}
+
+
+/// Returns the modulo 256 sum of `data`.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+
+fn ok() -> String {
+    String::from("OK")
+}
+
+
+fn error() -> String {
+    String::from("E01")
+}
+
+
+/// Returns the RV32I target description.
+pub fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>riscv:rv32</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for (i, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" | "s0" => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n",
+            name,
+            kind,
+            i
+        ));
+    }
+    xml.push_str(&format!(
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n\
+         </feature>\n\
+         </target>\n",
+        PC_REGNUM
+    ));
+
+    xml
+}
+
+
+/// Reads part of the target description for an annex of the form
+/// `target.xml:offset,length`.
+fn read_features(annex: &str) -> String {
+    let range = annex.strip_prefix("target.xml:").and_then(parse_range);
+    let (offset, len) = match range {
+        Some((offset, len)) => (offset as usize, len as usize),
+        None => return error(),
+    };
+
+    let xml = target_xml();
+    if offset >= xml.len() {
+        return String::from("l");
+    }
+    let end = xml.len().min(offset + len);
+    let more = if end < xml.len() { "m" } else { "l" };
+
+    format!("{}{}", more, &xml[offset..end])
+}
+
+
+/// Parses a hex number.
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+
+/// Parses a range of the form `addr,len` in hex.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let mut parts = text.splitn(2, ',');
+    match (parts.next(), parts.next()) {
+        (Some(addr), Some(len)) => Some((parse_hex(addr)?, parse_hex(len)?)),
+        _ => None,
+    }
+}
+
+
+/// Parses pairs of hex digits.
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok())
+        .collect()
+}
+
+
+/// Parses a little endian word of 8 hex digits.
+fn parse_word(text: &str) -> Option<u32> {
+    match parse_bytes(text) {
+        Some(ref bytes) if bytes.len() == 4 => Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |word, &byte| word << 8 | byte as u32),
+        ),
+        _ => None,
+    }
+}
+
+
+/// Formats a word as 8 hex digits, little endian.
+fn hex_word(word: u32) -> String {
+    word.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,10 +1,151 @@
 //! Data hazard detection.
 
 
-use consts::{RS1_MASK, RS1_SHIFT, RS2_MASK, RS2_SHIFT};
+use checkpoint::{Checkpoint, Reader, Writer};
+use consts::{BRANCH_OPCODE, OPCODE_MASK, RS1_MASK, RS1_SHIFT, RS2_MASK,
+             RS2_SHIFT};
 use instruction::Instruction;
 use pipeline::Pipeline;
 
+use std::fmt;
+use std::io;
+
+
+/// How the pipeline handles read-after-write data hazards.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HazardPolicy {
+    /// Forward results to EX, stalling only for load-use hazards
+    #[default]
+    Forwarding,
+    /// Stall in ID until every source register has reached writeback
+    StallOnly,
+    /// Neither forward nor stall, so hazards read stale registers
+    ///
+    /// Programs must separate dependent instructions with NOPs.
+    NoInterlock,
+}
+
+
+/// Data hazard counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HazardStats {
+    /// Cycles ID stalled waiting for a source register
+    pub stall_cycles: u64,
+    /// Source registers read before their new value was written
+    pub violations: u64,
+}
+
+
+/// A register read that got a stale value under `HazardPolicy::NoInterlock`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HazardViolation {
+    /// Address of the instruction reading the register
+    pub pc: u32,
+    /// Register read
+    pub reg: u32,
+    /// Address of the instruction yet to write the register
+    pub producer_pc: u32,
+    pub clock: u64,
+}
+
+
+impl fmt::Display for HazardViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#0x} reads stale x{} before {:#0x} writes it (clock {})",
+            self.pc,
+            self.reg,
+            self.producer_pc,
+            self.clock
+        )
+    }
+}
+
+
+/// Applies a `HazardPolicy` and records its cost.
+#[derive(Clone, Debug, Default)]
+pub struct HazardUnit {
+    policy: HazardPolicy,
+    violations: Vec<HazardViolation>,
+    pub stats: HazardStats,
+}
+
+
+impl HazardUnit {
+    /// Constructs a new `HazardUnit`.
+    pub fn new(policy: HazardPolicy) -> HazardUnit {
+        HazardUnit {
+            policy,
+            violations: Vec::new(),
+            stats: HazardStats::default(),
+        }
+    }
+
+    /// Returns the hazard policy.
+    pub fn policy(&self) -> HazardPolicy {
+        self.policy
+    }
+
+    /// Returns the stale register reads seen, in program order.
+    pub fn violations(&self) -> &[HazardViolation] {
+        &self.violations
+    }
+
+    /// Records that the instruction at `pc` read a stale `reg`.
+    pub fn violation(
+        &mut self,
+        pc: u32,
+        reg: u32,
+        producer_pc: u32,
+        clk: u64,
+    ) {
+        let violation = HazardViolation {
+            pc,
+            reg,
+            producer_pc,
+            clock: clk,
+        };
+        warn!("Hazard violation: {}", violation);
+
+        self.stats.violations += 1;
+        self.violations.push(violation);
+    }
+}
+
+
+impl Checkpoint for HazardUnit {
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.policy as usize);
+        w.u64(self.stats.stall_cycles);
+        w.u64(self.stats.violations);
+        w.usize(self.violations.len());
+        for violation in self.violations.iter() {
+            w.u32(violation.pc);
+            w.u32(violation.reg);
+            w.u32(violation.producer_pc);
+            w.u64(violation.clock);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_usize("hazard policy", self.policy as usize)?;
+        self.stats.stall_cycles = r.u64()?;
+        self.stats.violations = r.u64()?;
+        self.violations.clear();
+        for _ in 0..r.usize()? {
+            self.violations.push(HazardViolation {
+                pc: r.u32()?,
+                reg: r.u32()?,
+                producer_pc: r.u32()?,
+                clock: r.u64()?,
+            });
+        }
+
+        Ok(())
+    }
+}
+
 
 /// Indicates ALU src1 should be forwarded from the previous ALU result.
 ///
@@ -53,17 +194,83 @@ pub fn mem_hazard_src2(pl: &Pipeline) -> bool {
 
 
 /// Indicates a load-use hazard that will require a pipeline stall.
+///
+/// CSR reads also complete in the MEM stage and are treated like loads.
 pub fn load_hazard(pl: &Pipeline) -> bool {
     // Before decode stage, rs1 and rs2 need to be extracted manually
     let if_id_rs1 = Some((pl.if_id.raw_insn & RS1_MASK) >> RS1_SHIFT);
     let if_id_rs2 = Some((pl.if_id.raw_insn & RS2_MASK) >> RS2_SHIFT);
 
-    pl.id_ex.insn.semantics.mem_read &&
+    pl.id_ex.insn.semantics.mem_to_reg &&
         ((pl.id_ex.insn.fields.rd == if_id_rs1) ||
              (pl.id_ex.insn.fields.rd == if_id_rs2))
 }
 
 
+/// Indicates a branch resolved in ID needs a result that isn't ready yet.
+///
+/// The branch stalls one cycle behind an ALU instruction, and two behind a
+/// load. See Patterson & Hennessy pg 319.
+pub fn branch_hazard(pl: &Pipeline) -> bool {
+    if pl.if_id.raw_insn & OPCODE_MASK != BRANCH_OPCODE {
+        return false;
+    }
+
+    let if_id_rs1 = Some((pl.if_id.raw_insn & RS1_MASK) >> RS1_SHIFT);
+    let if_id_rs2 = Some((pl.if_id.raw_insn & RS2_MASK) >> RS2_SHIFT);
+    let writes_src = |insn: &Instruction| {
+        insn.semantics.reg_write && (insn.fields.rd != Some(0)) &&
+            ((insn.fields.rd == if_id_rs1) || (insn.fields.rd == if_id_rs2))
+    };
+
+    writes_src(&pl.id_ex.insn) ||
+        (pl.ex_mem.insn.semantics.mem_to_reg && writes_src(&pl.ex_mem.insn))
+}
+
+
+/// Indicates `insn` in ID reads a register not yet written back.
+///
+/// Returns the register and the address of the instruction producing it.
+/// Without forwarding, `insn` must wait until the producer reaches WB, where
+/// the register file is written before it is read.
+pub fn raw_hazard(insn: &Instruction, pl: &Pipeline) -> Option<(u32, u32)> {
+    let producers = [
+        (&pl.id_ex.insn, pl.id_ex.pc),
+        (&pl.ex_mem.insn, pl.ex_mem.pc),
+    ];
+
+    for &(producer, pc) in producers.iter() {
+        if !producer.semantics.reg_write || producer.fields.rd == Some(0) {
+            continue;
+        }
+        for &src in [insn.fields.rs1, insn.fields.rs2].iter() {
+            match src {
+                Some(reg) if src == producer.fields.rd => {
+                    return Some((reg, pc))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    None
+}
+
+
+/// Indicates a branch in ID should take src1 from the EX/MEM ALU result.
+pub fn id_hazard_src1(insn: &Instruction, pl: &Pipeline) -> bool {
+    insn.fields.rs1 != Some(0) && pl.ex_mem.insn.semantics.reg_write &&
+        (pl.ex_mem.insn.fields.rd == insn.fields.rs1)
+}
+
+
+/// Indicates a branch in ID should take src2 from the EX/MEM ALU result.
+pub fn id_hazard_src2(insn: &Instruction, pl: &Pipeline) -> bool {
+    insn.fields.rs2 != Some(0) && pl.ex_mem.insn.semantics.reg_write &&
+        (pl.ex_mem.insn.fields.rd == insn.fields.rs2)
+}
+
+
 /// Indicates src1 register was just written to and should be forwarded.
 ///
 /// See Patterson & Hennessy pg 301.
@@ -0,0 +1,419 @@
+//! Execution history for reverse debugging.
+//!
+//! `History` follows a simulator as it runs forward, taking a checkpoint
+//! every `interval` cycles and logging the PC of each retired instruction
+//! and every register and memory write. Going back to an earlier cycle
+//! restores the last checkpoint before it and replays the cycles in
+//! between, which is exact because the simulators are deterministic. The
+//! log answers which instruction last wrote a register or address without
+//! replaying anything.
+//!
+//! Only the last `max_checkpoints` checkpoints are kept. Older ones are
+//! dropped with the log entries before them, which moves the start of the
+//! history forward, so a long run takes bounded memory.
+
+
+use checkpoint::{self, Checkpoint};
+use pipeline::events::Event;
+use simulator::Simulator;
+
+use std::collections::VecDeque;
+use std::io;
+
+
+/// Cycles between checkpoints used by `Debugger`.
+pub const DEFAULT_INTERVAL: u64 = 1000;
+
+/// Checkpoints kept unless set otherwise.
+pub const DEFAULT_MAX_CHECKPOINTS: usize = 100;
+
+
+/// A register or memory location written by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    /// General purpose register `x<n>`
+    Register(u32),
+    /// `size` bytes of data memory at `addr`
+    Memory { addr: u32, size: usize },
+}
+
+
+impl Location {
+    /// Indicates the location overlaps `other`.
+    pub fn overlaps(&self, other: &Location) -> bool {
+        match (*self, *other) {
+            (Location::Register(a), Location::Register(b)) => a == b,
+            (
+                Location::Memory { addr: a, size: m },
+                Location::Memory { addr: b, size: n },
+            ) => {
+                let (a, b) = (a as u64, b as u64);
+                a < b + n as u64 && b < a + m as u64
+            }
+            _ => false,
+        }
+    }
+}
+
+
+/// A logged register or memory write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Write {
+    /// Cycle the write happened in, counting from zero
+    pub cycle: u64,
+    /// Address of the instruction that wrote
+    pub pc: u32,
+    pub location: Location,
+    pub value: u32,
+}
+
+
+/// Checkpoints and a change log of a simulator's run.
+pub struct History {
+    interval: u64,
+    max_checkpoints: Option<usize>,
+    /// Clock and checkpoint, oldest first
+    checkpoints: VecDeque<(u64, Vec<u8>)>,
+    /// Clock after each cycle an instruction retired in
+    retirements: VecDeque<u64>,
+    writes: VecDeque<Write>,
+    /// Clock of the last cycle recorded
+    clock: u64,
+}
+
+
+impl History {
+    /// Constructs a new `History` taking a checkpoint every `interval`
+    /// cycles and keeping `DEFAULT_MAX_CHECKPOINTS` of them.
+    ///
+    /// Recording starts with `start`.
+    pub fn new(interval: u64) -> History {
+        assert!(interval > 0, "Checkpoint interval must be positive");
+
+        History {
+            interval,
+            max_checkpoints: Some(DEFAULT_MAX_CHECKPOINTS),
+            checkpoints: VecDeque::new(),
+            retirements: VecDeque::new(),
+            writes: VecDeque::new(),
+            clock: 0,
+        }
+    }
+
+    /// Sets the number of checkpoints kept, or `None` to keep everything.
+    ///
+    /// The history then goes back between `max - 1` and `max` checkpoint
+    /// intervals.
+    pub fn set_max_checkpoints(&mut self, max: Option<usize>) {
+        assert!(max != Some(0), "At least one checkpoint must be kept");
+        self.max_checkpoints = max;
+        self.trim();
+    }
+
+    /// Discards the history and starts recording from the current state of
+    /// `sim`.
+    pub fn start<S: Simulator + Checkpoint>(&mut self, sim: &S) {
+        self.clock = sim.clock();
+        self.checkpoints.clear();
+        self.checkpoints.push_back((self.clock, checkpoint::save(sim)));
+        self.retirements.clear();
+        self.writes.clear();
+    }
+
+    /// Returns the earliest clock that can be gone back to.
+    pub fn start_clock(&self) -> u64 {
+        self.checkpoints.front().map_or(0, |&(clock, _)| clock)
+    }
+
+    /// Records the cycle `sim` just simulated.
+    ///
+    /// Cycles simulated after HALT are ignored.
+    pub fn record<S: Simulator + Checkpoint>(&mut self, sim: &S) {
+        if sim.clock() <= self.clock {
+            return;
+        }
+        self.clock = sim.clock();
+        let cycle = self.clock - 1;
+
+        for event in sim.events() {
+            let (pc, location, value) = match *event {
+                Event::RegWrite { pc, rd, value } => {
+                    (pc, Location::Register(rd), value)
+                }
+                Event::MemAccess {
+                    pc,
+                    addr,
+                    size,
+                    write: true,
+                    value,
+                } => (pc, Location::Memory { addr, size }, value),
+                Event::Retire { .. } => {
+                    self.retirements.push_back(self.clock);
+                    continue;
+                }
+                _ => continue,
+            };
+            self.writes.push_back(Write {
+                cycle,
+                pc,
+                location,
+                value,
+            });
+        }
+
+        if self.clock.is_multiple_of(self.interval) {
+            self.checkpoints.push_back((self.clock, checkpoint::save(sim)));
+            self.trim();
+        }
+    }
+
+    /// Drops the oldest checkpoints past the limit and the log entries
+    /// before the new start of the history.
+    fn trim(&mut self) {
+        let max = match self.max_checkpoints {
+            Some(max) => max,
+            None => return,
+        };
+        if self.checkpoints.len() <= max {
+            return;
+        }
+        let excess = self.checkpoints.len() - max;
+        self.checkpoints.drain(..excess);
+
+        let start = self.start_clock();
+        while self.retirements.front().is_some_and(|&r| r < start) {
+            self.retirements.pop_front();
+        }
+        while self.writes.front().is_some_and(|w| w.cycle < start) {
+            self.writes.pop_front();
+        }
+    }
+
+    /// Returns the clock `n` instructions before `clock`.
+    ///
+    /// That is the clock right after the retirement preceding the last `n`
+    /// retirements up to `clock`, or the start of the history if fewer
+    /// instructions retired.
+    pub fn instructions_back(&self, clock: u64, n: usize) -> u64 {
+        self.retirements
+            .iter()
+            .rev()
+            .filter(|&&retired| retired <= clock)
+            .nth(n)
+            .cloned()
+            .unwrap_or_else(|| self.start_clock())
+    }
+
+    /// Returns the last write overlapping `location` before `clock`.
+    pub fn last_write(&self, location: Location, clock: u64) -> Option<Write> {
+        self.writes
+            .iter()
+            .rev()
+            .find(|w| w.cycle < clock && w.location.overlaps(&location))
+            .cloned()
+    }
+
+    /// Restores the last checkpoint taken before `clock` into `sim`.
+    ///
+    /// Returns the clock of the checkpoint, or `None` if `clock` is at or
+    /// before the start of the history. The history itself is kept.
+    pub fn restore_before<S: Simulator + Checkpoint>(
+        &self,
+        sim: &mut S,
+        clock: u64,
+    ) -> io::Result<Option<u64>> {
+        let (start, saved) = match self.checkpoints
+            .iter()
+            .rev()
+            .find(|&&(start, _)| start < clock)
+        {
+            Some(&(start, ref saved)) => (start, saved),
+            None => return Ok(None),
+        };
+        checkpoint::restore(sim, saved)?;
+
+        Ok(Some(start))
+    }
+
+    /// Takes `sim` back to `clock`, which must not be before the start of
+    /// the history or after the current clock.
+    ///
+    /// Everything recorded after `clock` is discarded, and recorded again
+    /// as the simulator runs forward.
+    pub fn rewind<S: Simulator + Checkpoint>(
+        &mut self,
+        sim: &mut S,
+        clock: u64,
+    ) -> io::Result<()> {
+        assert!(
+            clock >= self.start_clock() && clock <= self.clock,
+            "Cycle {} outside the history",
+            clock
+        );
+
+        let i = self.checkpoints
+            .iter()
+            .rposition(|&(start, _)| start <= clock)
+            .unwrap();
+        self.checkpoints.truncate(i + 1);
+        let start = self.checkpoints[i].0;
+        checkpoint::restore(sim, &self.checkpoints[i].1)?;
+
+        self.clock = start;
+        self.retirements.retain(|&retired| retired <= start);
+        self.writes.retain(|w| w.cycle < start);
+        while sim.clock() < clock {
+            sim.step();
+            self.record(sim);
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ca_simulator::CaSimulator;
+    use consts;
+    use ia_simulator::IaSimulator;
+    use memory::data::DataMemory;
+    use memory::instruction::TestInstructionMemory;
+    use register::RegisterFile;
+
+    /// Stores 1 to 8 to 0x100 in a loop, then halts at 0x18.
+    fn program() -> TestInstructionMemory {
+        let mut insns = vec![
+            0x00_15_05_13, // loop: addi x10, x10, 1
+            0x10_a0_20_23, // sw x10, 256(x0)
+            0xfe_b5_4c_e3, // blt x10, x11, loop
+        ];
+        insns.extend_from_slice(&[consts::NOP; 3]);
+        insns.push(consts::HALT);
+        insns.extend_from_slice(&[consts::NOP; 3]);
+
+        TestInstructionMemory::new(insns)
+    }
+
+    fn registers() -> RegisterFile {
+        let mut registers = RegisterFile::new(0x0);
+        registers.gpr[11].write(8);
+
+        registers
+    }
+
+    /// Runs `sim` to HALT, then goes back to every cycle and checks the
+    /// state matches the forward run.
+    fn rewind_everywhere<S: Simulator + Checkpoint>(mut sim: S) {
+        let mut history = History::new(7);
+        history.start(&sim);
+        let mut states = vec![checkpoint::save(&sim)];
+        while sim.halt_addr().is_none() {
+            sim.step();
+            history.record(&sim);
+            states.push(checkpoint::save(&sim));
+        }
+
+        for clock in (0..states.len() as u64).rev() {
+            history.rewind(&mut sim, clock).unwrap();
+            assert_eq!(checkpoint::save(&sim), states[clock as usize]);
+        }
+
+        // The history is recorded again going forward
+        while sim.halt_addr().is_none() {
+            sim.step();
+            history.record(&sim);
+        }
+        assert_eq!(checkpoint::save(&sim), *states.last().unwrap());
+        let store = Location::Memory {
+            addr: 0x102,
+            size: 1,
+        };
+        let last = history.last_write(store, sim.clock()).unwrap();
+        assert_eq!((last.pc, last.value), (0x4, 8));
+    }
+
+    #[test]
+    fn ia_rewind() {
+        let insns = program();
+        let sim = IaSimulator::new(&insns, DataMemory::new(1024), registers());
+        rewind_everywhere(sim);
+    }
+
+    #[test]
+    fn ca_rewind() {
+        let insns = program();
+        let sim = CaSimulator::new(&insns, DataMemory::new(1024), registers());
+        rewind_everywhere(sim);
+    }
+
+    /// Tests that a long run keeps a bounded window of history that can
+    /// still be gone back through.
+    #[test]
+    fn bounded() {
+        let insns = TestInstructionMemory::new(vec![
+            0x00_15_05_13, // loop: addi x10, x10, 1
+            0x10_a0_20_23, // sw x10, 256(x0)
+            0xff_9f_f0_6f, // jal x0, loop
+        ]);
+        let mut sim =
+            CaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let mut history = History::new(10);
+        history.set_max_checkpoints(Some(3));
+        history.start(&sim);
+        for _ in 0..10_000 {
+            sim.step();
+            history.record(&sim);
+        }
+
+        assert_eq!(history.checkpoints.len(), 3);
+        assert_eq!(history.start_clock(), 9980);
+        assert!(history.retirements.iter().all(|&r| r >= 9980));
+        assert!(history.writes.iter().all(|w| w.cycle >= 9980));
+        assert!(history.writes.len() <= 2 * 20);
+
+        let x10 = Location::Register(10);
+        let last = history.last_write(x10, 10_000).unwrap();
+        assert!(last.cycle >= 9980);
+        history.rewind(&mut sim, 9980).unwrap();
+        assert_eq!(sim.clock(), 9980);
+        let clock = history.instructions_back(9980, 1);
+        assert_eq!(clock, 9980);
+
+        // Keeping everything again
+        history.set_max_checkpoints(None);
+        for _ in 0..100 {
+            sim.step();
+            history.record(&sim);
+        }
+        assert_eq!(history.checkpoints.len(), 11);
+    }
+
+    #[test]
+    fn log() {
+        let insns = program();
+        let mut sim =
+            IaSimulator::new(&insns, DataMemory::new(1024), registers());
+        let mut history = History::new(100);
+        history.start(&sim);
+        for _ in 0..6 {
+            sim.step();
+            history.record(&sim);
+        }
+
+        // addi, sw, blt, addi, sw, blt
+        assert_eq!(history.instructions_back(6, 1), 5);
+        assert_eq!(history.instructions_back(6, 5), 1);
+        assert_eq!(history.instructions_back(6, 6), 0);
+        assert_eq!(history.instructions_back(4, 2), 2);
+
+        let x10 = Location::Register(10);
+        let write = history.last_write(x10, 6).unwrap();
+        assert_eq!((write.cycle, write.pc, write.value), (3, 0x0, 2));
+        let write = history.last_write(x10, 3).unwrap();
+        assert_eq!((write.cycle, write.value), (0, 1));
+        assert!(history.last_write(Location::Register(12), 6).is_none());
+    }
+}
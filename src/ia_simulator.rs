@@ -1,13 +1,20 @@
 //! Single cycle instruction accurate RISC-V 32I simulator.
 
 
+use checkpoint::{Checkpoint, Reader, Writer};
 use consts;
 use instruction::{Function, Opcode};
 use memory::data::DataMemory;
 use memory::instruction::InstructionMemory;
+use pipeline::events::{Event, Observer};
 use register::RegisterFile;
+use simulator::Simulator;
 use stages::{insn_fetch, insn_decode, reg_read, execute, access_memory,
-             reg_writeback};
+             access_csr, reg_writeback};
+use trap::Trap;
+
+use std::io;
+use std::mem::{replace, swap};
 
 
 /// Runs a single cycle instruction accurate RISC-V 32I simulator.
@@ -15,39 +22,160 @@ use stages::{insn_fetch, insn_decode, reg_read, execute, access_memory,
 /// Returns the PC address of the HALT instruction.
 ///
 pub fn run(
-    insns: &InstructionMemory,
-    mut mem: &mut DataMemory,
-    mut reg: &mut RegisterFile,
+    insns: &dyn InstructionMemory,
+    mem: &mut DataMemory,
+    reg: &mut RegisterFile,
 ) -> usize {
-    // Clock is used to aid debugging only
-    let mut clock: u64 = 0;
+    let mut sim = IaSimulator::new(
+        insns,
+        replace(mem, DataMemory::new(0)),
+        replace(reg, RegisterFile::new(0)),
+    );
+
+    let halt_addr = sim.run();
+
+    swap(mem, sim.memory_mut());
+    swap(reg, sim.registers_mut());
+
+    halt_addr
+}
+
+
+/// A single cycle instruction accurate RISC-V 32I simulator.
+pub struct IaSimulator<'a> {
+    insns: &'a dyn InstructionMemory,
+    mem: DataMemory,
+    reg: RegisterFile,
+
+    /// Program counter after a reset
+    reset_pc: u32,
+    clock: u64,
+    halt_addr: Option<usize>,
+
+    /// Events of the last instruction
+    events: Vec<Event>,
+    observer: Option<&'a mut dyn Observer>,
+}
+
+
+impl<'a> IaSimulator<'a> {
+    /// Constructs a new `IaSimulator` executing `insns`.
+    ///
+    /// The program counter of `reg` is also the reset vector.
+    pub fn new(
+        insns: &'a dyn InstructionMemory,
+        mem: DataMemory,
+        reg: RegisterFile,
+    ) -> IaSimulator<'a> {
+        IaSimulator {
+            insns,
+            mem,
+            reset_pc: reg.pc.read(),
+            reg,
+            clock: 0,
+            halt_addr: None,
+            events: Vec::new(),
+            observer: None,
+        }
+    }
+
+    /// Passes the events of each instruction to `observer` after it
+    /// completes.
+    ///
+    /// There is no pipeline, so every stage is reported empty and the
+    /// state isn't reported.
+    pub fn set_observer(&mut self, observer: &'a mut dyn Observer) {
+        self.observer = Some(observer);
+    }
+
+    /// Executes the instruction at the program counter.
+    ///
+    /// Returns the PC address of the instruction if it was HALT.
+    fn execute_insn(&mut self) -> Option<usize> {
+        let clock = self.clock;
+        let reg = &mut self.reg;
+        let mem = &mut self.mem;
+        let events = &mut self.events;
+
+        mem.tick();
 
-    loop {
         // Read and increment program counter
         let pc = reg.pc.read();
         reg.pc.write(pc + consts::WORD_SIZE as u32);
 
         // IF: Instruction fetch
-        let raw_insn = insn_fetch(insns, pc, clock);
+        let raw_insn = match insn_fetch(self.insns, &reg.csr.pmp, pc, clock) {
+            Ok(raw_insn) => raw_insn,
+            Err(trap) => {
+                take_trap(reg, pc, trap, clock);
+                return None;
+            }
+        };
 
         // ID: Instruction decode and register file read
-        let mut insn = insn_decode(raw_insn, clock);
-        let (rs1, rs2) = reg_read(&insn, &reg, clock);
+        let mut insn = match insn_decode(raw_insn, clock) {
+            Ok(insn) => insn,
+            Err(trap) => {
+                take_trap(reg, pc, trap, clock);
+                return None;
+            }
+        };
+        let (rs1, rs2) = reg_read(&insn, reg, clock);
 
         // EX: Execution or address calculation
-        let alu_result = execute(&mut insn, rs1, rs2, clock);
+        let alu_result = execute(&mut insn, pc, rs1, rs2, clock);
 
-        // MEM: Data memory access
-        let mem_result =
-            access_memory(&insn, &mut mem, alu_result, rs2, clock);
+        // MEM: Data memory or CSR access
+        let mem_result = match insn.opcode {
+            Opcode::System => {
+                access_csr(&insn, &mut reg.csr, alu_result, clock)
+            }
+            _ => {
+                let pmp = &reg.csr.pmp;
+                access_memory(&insn, mem, pmp, alu_result, rs2, clock)
+            }
+        };
+        let mem_result = match mem_result {
+            Ok(mem_result) => mem_result,
+            Err(trap) => {
+                take_trap(reg, pc, trap, clock);
+                return None;
+            }
+        };
+
+        if insn.semantics.mem_read || insn.semantics.mem_write {
+            events.push(Event::MemAccess {
+                pc,
+                addr: alu_result as u32,
+                size: insn.semantics.mem_size,
+                write: insn.semantics.mem_write,
+                value: if insn.semantics.mem_write {
+                    rs2 as u32
+                } else {
+                    mem_result
+                },
+            });
+        }
 
         // WB: Write result back to register
-        reg_writeback(pc, &insn, &mut reg, alu_result, mem_result, clock);
+        reg_writeback(pc, &insn, reg, alu_result, mem_result, clock);
 
         if insn.function == Function::Halt {
             info!("Halt: {:#0x} (clock {}), exiting...", pc, clock);
-            return pc as usize;
+            return Some(pc as usize);
+        }
+
+        match insn.fields.rd {
+            Some(rd) if insn.semantics.reg_write && rd != 0 => {
+                let value = reg.gpr[rd as usize].read();
+                events.push(Event::RegWrite { pc, rd, value });
+            }
+            _ => (),
         }
+        events.push(Event::Retire {
+            pc,
+            function: insn.function,
+        });
 
         // Modify program counter for branch or jump
         if insn.semantics.branch &&
@@ -55,7 +183,7 @@ pub fn run(
         {
             let imm = insn.fields.imm.unwrap() as i32;
             let npc = match insn.opcode {
-                Opcode::Jalr => alu_result & 0xfffe, // LSB -> 0
+                Opcode::Jalr => alu_result & !1, // LSB -> 0
                 _ => (pc as i32) + imm,
             };
 
@@ -64,7 +192,104 @@ pub fn run(
             reg.pc.write(npc as u32);
         }
 
-        clock += 1;
+        if insn.function == Function::Mret {
+            let npc = reg.csr.trap_return();
+            trace!("Mret: {:#0x} -> {:#0x} (clock {})", pc, npc, clock);
+            reg.pc.write(npc);
+        }
+
+        None
     }
+}
+
+
+impl<'a> Simulator for IaSimulator<'a> {
+    fn step(&mut self) -> Option<usize> {
+        if self.halt_addr.is_some() {
+            return self.halt_addr;
+        }
+
+        self.events.clear();
+        self.halt_addr = self.execute_insn();
+        if let Some(ref mut observer) = self.observer {
+            for event in self.events.iter() {
+                observer.event(self.clock, event);
+            }
+            observer.cycle(self.clock, &[None; 5]);
+        }
+        self.clock += 1;
+
+        self.halt_addr
+    }
+
+    fn reset(&mut self) {
+        self.reg = RegisterFile::new(self.reset_pc);
+        self.clock = 0;
+        self.halt_addr = None;
+        self.events.clear();
+    }
+
+    fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    fn halt_addr(&self) -> Option<usize> {
+        self.halt_addr
+    }
+
+    fn registers(&self) -> &RegisterFile {
+        &self.reg
+    }
+
+    fn registers_mut(&mut self) -> &mut RegisterFile {
+        &mut self.reg
+    }
+
+    fn memory(&self) -> &DataMemory {
+        &self.mem
+    }
+
+    fn memory_mut(&mut self) -> &mut DataMemory {
+        &mut self.mem
+    }
+
+    fn events(&self) -> &[Event] {
+        &self.events
+    }
+}
+
+
+/// Saves the registers, data memory and clock. The events of the last
+/// instruction are not saved.
+impl<'a> Checkpoint for IaSimulator<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.str("ia");
+        w.usize(self.insns.size());
+        w.u32(self.reset_pc);
+        w.u64(self.clock);
+        w.option(self.halt_addr, |w, addr| w.usize(addr));
+        self.reg.save(w);
+        self.mem.save(w);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_str("model", "ia")?;
+        r.expect_usize("program size", self.insns.size())?;
+        self.reset_pc = r.u32()?;
+        self.clock = r.u64()?;
+        self.halt_addr = r.option(|r| r.usize())?;
+        self.reg.restore(r)?;
+        self.mem.restore(r)?;
+        self.events.clear();
+
+        Ok(())
+    }
+}
+
 
+/// Takes a trap raised by the instruction at `pc` and redirects to mtvec.
+fn take_trap(reg: &mut RegisterFile, pc: u32, trap: Trap, clk: u64) {
+    let npc = reg.csr.trap(pc, trap);
+    debug!("Trap: {:?} at {:#0x} -> {:#0x} (clock {})", trap, pc, npc, clk);
+    reg.pc.write(npc);
 }
@@ -8,15 +8,13 @@ use instruction::{Instruction, Opcode};
 pub fn gen(insn: &Instruction) -> Option<u32> {
     let shamt = match insn.opcode {
         Opcode::Lui | Opcode::AuiPc => 0,
+        Opcode::System => 0, // CSR addresses are unsigned
         Opcode::Jal | Opcode::Jalr => 12,
         Opcode::Branch => 19,
         _ => 20,
     };
 
-    match insn.fields.imm {
-        Some(v) => Some((((v as i32) << shamt) >> shamt) as u32),
-        None => None,
-    }
+    insn.fields.imm.map(|v| (((v as i32) << shamt) >> shamt) as u32)
 }
 
 
@@ -1,5 +1,10 @@
 //! Decodes RISC-V 32I instructions.
 
+#![allow(
+    clippy::field_reassign_with_default,
+    clippy::match_like_matches_macro,
+)]
+
 
 use alu::AluSrc;
 use consts::*;
@@ -17,12 +22,25 @@ pub fn decode(insn: &mut Instruction) {
         Format::U => parse_type_u(insn.value),
         Format::J => parse_type_j(insn.value),
     };
-    insn.fields.imm = immediates::gen(&insn);
+    insn.fields.imm = immediates::gen(insn);
     insn.function = insn_to_fn(insn);
+    if is_csr_imm(insn.function) {
+        // rs1 holds a 5-bit immediate (zimm) rather than a register index
+        insn.fields.rs1 = None;
+    }
     insn.semantics = insn_to_semantics(insn);
 }
 
 
+/// Indicates a CSR instruction that takes its source operand from zimm.
+fn is_csr_imm(function: Function) -> bool {
+    match function {
+        Function::Csrrwi | Function::Csrrsi | Function::Csrrci => true,
+        _ => false,
+    }
+}
+
+
 /// Determines an instruction's mnemonic, e.g., JAL, XOR, or SRA
 fn insn_to_fn(insn: &Instruction) -> Function {
     // Check opcode-only functions
@@ -32,6 +50,7 @@ fn insn_to_fn(insn: &Instruction) -> Function {
         Opcode::Jal => Function::Jal,
         Opcode::Jalr => Function::Jalr,
         Opcode::Halt => Function::Halt,
+        Opcode::Illegal => Function::Illegal,
         _ => Function::Addi, // Signal opcode didn't match
     };
 
@@ -42,6 +61,19 @@ fn insn_to_fn(insn: &Instruction) -> Function {
     let bit30 = (insn.value & BIT30_MASK) >> BIT30_SHIFT;
     let funct3 = insn.fields.funct3.unwrap();
 
+    // Bits 31:25 other than bit 30 select extensions, e.g., M's MUL
+    let funct7 = (insn.value & FUNCT7_MASK) >> FUNCT7_SHIFT;
+    let funct7_ok = match (insn.opcode, funct3) {
+        (Opcode::Op, 0b000) |
+        (Opcode::Op, 0b101) |
+        (Opcode::OpImm, 0b101) => funct7 & !0b010_0000 == 0,
+        (Opcode::Op, _) | (Opcode::OpImm, 0b001) => funct7 == 0,
+        _ => true,
+    };
+    if !funct7_ok {
+        return Function::Illegal;
+    }
+
     // Check rest of functions
     match (insn.opcode, funct3, bit30) {
         (Opcode::Branch, 0b000, _) => Function::Beq,
@@ -77,7 +109,23 @@ fn insn_to_fn(insn: &Instruction) -> Function {
         (Opcode::Op, 0b101, 0b1) => Function::Sra,
         (Opcode::Op, 0b110, _) => Function::Or,
         (Opcode::Op, 0b111, _) => Function::And,
-        _ => panic!("Failed to decode instruction {:#0x}", insn.value),
+        (Opcode::MiscMem, 0b000, _) => Function::Fence,
+        (Opcode::MiscMem, 0b001, _) => Function::FenceI,
+        (Opcode::System, 0b000, _) => {
+            match insn.fields.imm.unwrap() {
+                0x000 => Function::Ecall,
+                0x001 => Function::Ebreak,
+                0x302 => Function::Mret,
+                _ => Function::Illegal,
+            }
+        }
+        (Opcode::System, 0b001, _) => Function::Csrrw,
+        (Opcode::System, 0b010, _) => Function::Csrrs,
+        (Opcode::System, 0b011, _) => Function::Csrrc,
+        (Opcode::System, 0b101, _) => Function::Csrrwi,
+        (Opcode::System, 0b110, _) => Function::Csrrsi,
+        (Opcode::System, 0b111, _) => Function::Csrrci,
+        _ => Function::Illegal,
     }
 }
 
@@ -88,12 +136,20 @@ fn insn_to_semantics(insn: &Instruction) -> Semantics {
 
     let mut semantics = Semantics::default();
 
+    if insn.function == Function::Illegal {
+        return semantics; // Traps in decode without side effects
+    }
+
     semantics.branch = match insn.opcode {
         Opcode::Branch | Opcode::Jal | Opcode::Jalr => true,
         _ => false,
     };
     semantics.mem_read = insn.opcode == Opcode::Load;
-    semantics.mem_to_reg = insn.opcode == Opcode::Load;
+    // CSR reads complete in the MEM stage, so they are written back like loads
+    semantics.mem_to_reg = match insn.opcode {
+        Opcode::Load | Opcode::System => true,
+        _ => false,
+    };
     semantics.alu_op = match (insn.opcode, insn.function) {
         (Opcode::Load, _) => Add,
         (Opcode::Store, _) => Add,
@@ -124,17 +180,27 @@ fn insn_to_semantics(insn: &Instruction) -> Semantics {
         (Opcode::Op, Function::Sll) => ShiftLeft,
         (Opcode::Op, Function::Srl) => ShiftRightLogical,
         (Opcode::Op, Function::Sra) => ShiftRightArithmetic,
+        (Opcode::MiscMem, _) |
+        (Opcode::System, _) |
         (Opcode::Halt, _) |
-        (Opcode::Lui, _) => Add,
+        (Opcode::Lui, _) |
+        (Opcode::AuiPc, _) => Add,
         _ => panic!("ALU semanics for {:?} not implemented", insn.function),
     };
     semantics.mem_write = insn.opcode == Opcode::Store;
     semantics.alu_src = match insn.opcode {
-        Opcode::Branch | Opcode::Op | Opcode::Jal => AluSrc::Reg,
+        Opcode::Branch | Opcode::Op | Opcode::Jal | Opcode::System => {
+            AluSrc::Reg
+        }
         _ => AluSrc::Imm,
     };
-    semantics.reg_write = match insn.opcode {
-        Opcode::Branch | Opcode::Store => false,
+    semantics.reg_write = match (insn.opcode, insn.function) {
+        (Opcode::Branch, _) |
+        (Opcode::Store, _) |
+        (Opcode::MiscMem, _) |
+        (_, Function::Ecall) |
+        (_, Function::Ebreak) |
+        (_, Function::Mret) => false,
         _ => true,
     };
     semantics.mem_size = match insn.function {
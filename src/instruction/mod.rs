@@ -1,9 +1,14 @@
 //! Instruction decode stage.
 
+// Opcode literals group bits like the spec's opcode map, e.g., 0b11_000_11
+#![allow(clippy::unusual_byte_groupings)]
+
 
 use alu::{AluOp, AluSrc};
 use consts;
 
+use std::fmt;
+
 pub mod decoder;
 
 
@@ -65,6 +70,61 @@ impl Default for Instruction {
 }
 
 
+impl fmt::Display for Instruction {
+    /// Formats the instruction in assembler syntax, e.g. `addi x10, x10, 1`.
+    ///
+    /// Branch and jump offsets are relative to the instruction's address.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = format!("{:?}", self.function).to_lowercase();
+        let rd = self.fields.rd.unwrap_or(0);
+        let rs1 = self.fields.rs1.unwrap_or(0);
+        let rs2 = self.fields.rs2.unwrap_or(0);
+        let imm = self.fields.imm.unwrap_or(0) as i32;
+        let zimm = (self.value & consts::RS1_MASK) >> consts::RS1_SHIFT;
+
+        match self.function {
+            Function::FenceI => write!(f, "fence.i"),
+            Function::Fence |
+            Function::Ecall |
+            Function::Ebreak |
+            Function::Mret |
+            Function::Halt => write!(f, "{}", name),
+            Function::Illegal => write!(f, ".word {:#010x}", self.value),
+            Function::Lui | Function::AuiPc => {
+                write!(f, "{} x{}, {:#x}", name, rd, imm as u32 >> 12)
+            }
+            Function::Jal => write!(f, "{} x{}, {}", name, rd, imm),
+            Function::Slli | Function::Srli | Function::Srai => {
+                write!(f, "{} x{}, x{}, {}", name, rd, rs1, imm & 0x1f)
+            }
+            Function::Csrrw | Function::Csrrs | Function::Csrrc => {
+                write!(f, "{} x{}, {:#x}, x{}", name, rd, imm, rs1)
+            }
+            Function::Csrrwi | Function::Csrrsi | Function::Csrrci => {
+                write!(f, "{} x{}, {:#x}, {}", name, rd, imm, zimm)
+            }
+            _ => {
+                match self.opcode {
+                    Opcode::Jalr | Opcode::Load => {
+                        write!(f, "{} x{}, {}(x{})", name, rd, imm, rs1)
+                    }
+                    Opcode::Store => {
+                        write!(f, "{} x{}, {}(x{})", name, rs2, imm, rs1)
+                    }
+                    Opcode::Branch => {
+                        write!(f, "{} x{}, x{}, {}", name, rs1, rs2, imm)
+                    }
+                    Opcode::Op => {
+                        write!(f, "{} x{}, x{}, x{}", name, rd, rs1, rs2)
+                    }
+                    _ => write!(f, "{} x{}, x{}, {}", name, rd, rs1, imm),
+                }
+            }
+        }
+    }
+}
+
+
 /// Extracts the opcode from a raw instruction integer.
 fn int_to_opcode(insn: u32) -> Opcode {
     let opcode = insn & consts::OPCODE_MASK;
@@ -78,8 +138,10 @@ fn int_to_opcode(insn: u32) -> Opcode {
         0b01_000_11 => Opcode::Store,
         0b01_100_11 => Opcode::Op,
         0b00_100_11 => Opcode::OpImm,
+        0b00_011_11 => Opcode::MiscMem,
+        0b11_100_11 => Opcode::System,
         0b01_111_11 => Opcode::Halt,
-        _ => panic!("Unknown opcode {:#09b}", opcode),
+        _ => Opcode::Illegal,
     }
 }
 
@@ -96,7 +158,10 @@ fn opcode_to_format(opcode: Opcode) -> Format {
         Opcode::Store => Format::S,
         Opcode::Op => Format::R,
         Opcode::OpImm => Format::I,
+        Opcode::MiscMem => Format::I,
+        Opcode::System => Format::I,
         Opcode::Halt => Format::U,  // Do minimal parsing; Halt has no format
+        Opcode::Illegal => Format::U,
     }
 }
 
@@ -126,7 +191,10 @@ pub enum Opcode {
     Store,
     Op,
     OpImm,
+    MiscMem,
+    System,
     Halt,
+    Illegal,
 }
 
 
@@ -143,7 +211,7 @@ pub enum Format {
 
 
 /// RISC-V 32I mnemonics.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Function {
     /// Load upper immediate
     Lui,
@@ -225,11 +293,93 @@ pub enum Function {
     Or,
     /// Logical And
     And,
+    // Memory ordering
+    /// Order memory accesses
+    Fence,
+    /// Synchronize instruction and data streams
+    FenceI,
+    // Environment and trap-return instructions
+    /// Environment call
+    Ecall,
+    /// Environment breakpoint
+    Ebreak,
+    /// Machine-mode trap return
+    Mret,
+    // Control and status register instructions
+    /// Atomic read/write CSR
+    Csrrw,
+    /// Atomic read and set bits in CSR
+    Csrrs,
+    /// Atomic read and clear bits in CSR
+    Csrrc,
+    /// Atomic read/write CSR (immediate)
+    Csrrwi,
+    /// Atomic read and set bits in CSR (immediate)
+    Csrrsi,
+    /// Atomic read and clear bits in CSR (immediate)
+    Csrrci,
     /// Halt simulator
     Halt,
+    /// Unknown or unimplemented encoding
+    Illegal,
 }
 
 
+/// Every function, in declaration order.
+pub const FUNCTIONS: [Function; 50] = [
+    Function::Lui,
+    Function::AuiPc,
+    Function::Jal,
+    Function::Jalr,
+    Function::Beq,
+    Function::Bne,
+    Function::Blt,
+    Function::Bge,
+    Function::Bltu,
+    Function::Bgeu,
+    Function::Lb,
+    Function::Lh,
+    Function::Lw,
+    Function::Lbu,
+    Function::Lhu,
+    Function::Sb,
+    Function::Sh,
+    Function::Sw,
+    Function::Addi,
+    Function::Slti,
+    Function::Sltiu,
+    Function::Xori,
+    Function::Ori,
+    Function::Andi,
+    Function::Slli,
+    Function::Srli,
+    Function::Srai,
+    Function::Add,
+    Function::Sub,
+    Function::Sll,
+    Function::Slt,
+    Function::Sltu,
+    Function::Xor,
+    Function::Srl,
+    Function::Sra,
+    Function::Or,
+    Function::And,
+    Function::Fence,
+    Function::FenceI,
+    Function::Ecall,
+    Function::Ebreak,
+    Function::Mret,
+    Function::Csrrw,
+    Function::Csrrs,
+    Function::Csrrc,
+    Function::Csrrwi,
+    Function::Csrrsi,
+    Function::Csrrci,
+    Function::Halt,
+    Function::Illegal,
+];
+
+
 /// Control unit semantics
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Semantics {
@@ -264,4 +414,85 @@ mod tests {
         assert!(insn.semantics.reg_write);
     }
 
+    #[test]
+    fn csr() {
+        // csrrs x10, mcause, x0
+        let insn = Instruction::new(0x34_20_25_73);
+        assert_eq!(insn.function, Function::Csrrs);
+        assert_eq!(insn.fields.imm, Some(0x342));
+        assert_eq!(insn.fields.rs1, Some(0));
+        assert!(insn.semantics.mem_to_reg);
+        assert!(insn.semantics.reg_write);
+
+        // csrrwi x0, pmpcfg0, 0x1f: rs1 field holds zimm, not a register
+        let insn = Instruction::new(0x3a_0f_d0_73);
+        assert_eq!(insn.function, Function::Csrrwi);
+        assert_eq!(insn.fields.rs1, None);
+
+        // mret
+        let insn = Instruction::new(0x30_20_00_73);
+        assert_eq!(insn.function, Function::Mret);
+        assert!(!insn.semantics.reg_write);
+    }
+
+    #[test]
+    fn fence() {
+        // fence rw, rw
+        let insn = Instruction::new(0x03_30_00_0f);
+        assert_eq!(insn.opcode, Opcode::MiscMem);
+        assert_eq!(insn.function, Function::Fence);
+        assert!(!insn.semantics.reg_write);
+        assert!(!insn.semantics.mem_read && !insn.semantics.mem_write);
+
+        // fence.i
+        let insn = Instruction::new(0x00_00_10_0f);
+        assert_eq!(insn.function, Function::FenceI);
+    }
+
+    #[test]
+    fn illegal() {
+        let function = |value| Instruction::new(value).function;
+        assert_eq!(function(0x00_15_05_13), Function::Addi);
+        assert_eq!(function(0x30_20_00_73), Function::Mret);
+        assert_eq!(function(0x00_00_10_0f), Function::FenceI);
+        assert_eq!(function(consts::HALT), Function::Halt);
+        assert_eq!(function(0x0), Function::Illegal); // all zeros
+        assert_eq!(function(0xff_ff_ff_ff), Function::Illegal);
+        assert_eq!(function(0x10_50_00_73), Function::Illegal); // wfi
+        assert_eq!(function(0x00_05_35_03), Function::Illegal); // ld
+        assert_eq!(function(0x00_00_20_07), Function::Illegal); // flw
+        assert_eq!(function(0x02_c5_85_33), Function::Illegal); // mul
+        assert_eq!(function(0x40_15_95_13), Function::Illegal); // slli
+
+        let insn = Instruction::new(0x10_50_00_73);
+        assert!(!insn.semantics.reg_write);
+        assert!(!insn.semantics.mem_read && !insn.semantics.mem_write);
+        assert_eq!(format!("{}", insn), ".word 0x10500073");
+    }
+
+    #[test]
+    fn display() {
+        let text = |value| format!("{}", Instruction::new(value));
+
+        assert_eq!(text(0x00_15_05_13), "addi x10, x10, 1");
+        assert_eq!(text(0xa5_a5_a4_b7), "lui x9, 0xa5a5a");
+        assert_eq!(text(0x40_30_81_33), "sub x2, x1, x3");
+        assert_eq!(text(0x5d_c0_23_03), "lw x6, 1500(x0)");
+        assert_eq!(text(0x5c_50_2e_23), "sw x5, 1500(x0)");
+        assert_eq!(text(0xfe_a5_de_e3), "bge x11, x10, -4");
+        assert_eq!(text(0x40_75_d5_13), "srai x10, x11, 7");
+        assert_eq!(text(0x00_00_80_67), "jalr x0, 0(x1)");
+        assert_eq!(text(0x34_20_25_73), "csrrs x10, 0x342, x0");
+        assert_eq!(text(0x00_00_10_0f), "fence.i");
+        assert_eq!(text(consts::HALT), "halt");
+    }
+
+    #[test]
+    fn functions_in_order() {
+        for (i, &function) in FUNCTIONS.iter().enumerate() {
+            assert_eq!(function as usize, i);
+        }
+        assert_eq!(FUNCTIONS[FUNCTIONS.len() - 1], Function::Illegal);
+    }
+
 }
@@ -3,15 +3,33 @@
 
 pub mod alu;
 pub mod ca_simulator;
+pub mod checkpoint;
+pub mod commit_log;
+pub mod compliance;
 pub mod consts;
+pub mod cosim;
+pub mod csr;
+pub mod debugger;
+pub mod devices;
+pub mod elf;
+pub mod expect;
+pub mod fuzz;
+pub mod gdb;
 pub mod hazards;
+pub mod history;
 pub mod immediates;
 pub mod ia_simulator;
 pub mod instruction;
 pub mod memory;
 pub mod pipeline;
+pub mod predictor;
+pub mod profile;
 pub mod register;
+pub mod simulator;
 pub mod stages;
+pub mod stats;
+pub mod trap;
+pub mod tui;
 
 #[macro_use]
 extern crate log;
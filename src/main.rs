@@ -4,13 +4,45 @@
 extern crate env_logger;
 extern crate riscv_5stage_simulator;
 
-use riscv_5stage_simulator::ca_simulator;
+use riscv_5stage_simulator::ca_simulator::{self, CaSimulator};
+use riscv_5stage_simulator::checkpoint::{self, Checkpoint};
+use riscv_5stage_simulator::commit_log::{self, CommitLog};
+use riscv_5stage_simulator::compliance::{self, Outcome, TestProgram};
+use riscv_5stage_simulator::cosim::{self, Lockstep};
+use riscv_5stage_simulator::debugger::Debugger;
+use riscv_5stage_simulator::devices::block::{self, BlockDevice, WriteMode};
+use riscv_5stage_simulator::devices::console::{self, Console};
+use riscv_5stage_simulator::devices::framebuffer::{self, Framebuffer,
+                                                   ImageFormat, PixelFormat};
+use riscv_5stage_simulator::fuzz;
+use riscv_5stage_simulator::gdb::GdbStub;
+use riscv_5stage_simulator::hazards::{HazardPolicy, HazardUnit};
+use riscv_5stage_simulator::ia_simulator::IaSimulator;
 use riscv_5stage_simulator::memory::data::DataMemory;
-use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::memory::instruction::{DisassemblyInstructionMemory,
+                                                 InstructionMemory};
+use riscv_5stage_simulator::memory::store_buffer::{DrainPolicy, StoreBuffer};
+use riscv_5stage_simulator::pipeline::chart::PipelineChart;
+use riscv_5stage_simulator::pipeline::events::{JsonLinesSink, Observer};
+use riscv_5stage_simulator::pipeline::kanata::KanataWriter;
+use riscv_5stage_simulator::pipeline::vcd::{self, SignalGroup, VcdWriter};
+use riscv_5stage_simulator::predictor::{self, BranchResolution, BranchStats,
+                                        BranchUnit};
+use riscv_5stage_simulator::predictor::btb::Btb;
+use riscv_5stage_simulator::predictor::ras::ReturnAddressStack;
+use riscv_5stage_simulator::profile::Profile;
 use riscv_5stage_simulator::register::RegisterFile;
+use riscv_5stage_simulator::simulator::Simulator;
+use riscv_5stage_simulator::tui::Tui;
 
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 
 
 const LOGO: &str = "
@@ -40,6 +72,606 @@ rrrrrrrrrrrrrrrrrrrrrr  rrrrrrrrrrrrrrrrrrrrrr
 
 ";
 
+const OPTIONS: &str = "
+Commands:
+    diff REFERENCE              Compare the run with the Spike style commit
+                                log REFERENCE, stopping at the first
+                                mismatch
+    test                        Run the riscv-tests or riscv-arch-test ELF
+                                program <filename> until it writes tohost
+    riscv-tests                 Run the rv32ui-p-* and local-rv32ui-*
+                                programs in the directory <filename> on
+                                both models and print a pass/fail matrix
+    fuzz                        Compare the final state of random programs
+                                on both models, and minimize the first
+                                that differs into a test program written
+                                to [filename] or stdout
+
+Options:
+    --model MODEL               Simulation model (MODEL: ca (default), ia);
+                                the IA model doesn't take the pipeline,
+                                branch and hazard options
+    --framebuffer WxH[:FORMAT]  Attach a framebuffer at 0x20000000
+                                (FORMAT: rgb565 (default), rgba8888,
+                                palette8)
+    --frame-dir DIR             Write committed frames to DIR (default:
+                                frames)
+    --png                       Write frames as PNG instead of PPM
+    --disk IMAGE                Attach a block device at 0x10000000 backed
+                                by IMAGE (copy-on-write)
+    --disk-write-through        Write disk changes back to IMAGE
+    --disk-latency R,W          Disk read and write latency in cycles
+    --console                   Attach a console at 0x30000000 that
+                                writes to stdout
+    --write-latency N           Extra cycles each store occupies memory
+    --store-buffer DEPTH        Buffer up to DEPTH stores in the MEM stage
+    --drain POLICY              Store buffer drain policy (POLICY: eager
+                                (default), lazy, watermark:N)
+    --write-combining           Merge stores to the same word in the
+                                store buffer
+    --predictor NAME            Branch predictor (NAME: not-taken
+                                (default), taken, btfn, 1-bit, 2-bit,
+                                gshare, tournament, or all to compare
+                                every predictor)
+    --btb N[,WAYS[,TAG_BITS]]   Predict branch and jump targets with an
+                                N-entry branch target buffer (default:
+                                direct mapped, full tags)
+    --ras DEPTH                 Predict returns with a return address
+                                stack of DEPTH entries
+    --resolve STAGE             Resolve branches and JAL in STAGE (STAGE:
+                                mem (default), id)
+    --hazards POLICY            Data hazard handling (POLICY: forwarding
+                                (default), stall, no-interlock)
+    --commit-log FILE           Write a Spike style commit log to FILE
+    --events FILE               Write pipeline events to FILE as JSON
+                                lines
+    --kanata FILE               Write a Kanata pipeline log to FILE for
+                                the Konata viewer
+    --chart FIRST:LAST          Print a pipeline chart of cycles FIRST to
+                                LAST
+    --vcd FILE                  Write a VCD waveform of the pipeline to
+                                FILE
+    --vcd-signals GROUPS        Comma separated signal groups to dump
+                                (GROUPS: pc, insn, control, forward,
+                                hazard, regs; default: all)
+    --tui                       Step through the run in an interactive
+                                terminal UI
+    --debug                     Debug the program in an interactive
+                                command shell
+    --gdb PORT|PATH             Serve GDB's remote protocol on localhost
+                                PORT or the Unix socket PATH
+    --restore FILE              Start --debug or --gdb from the checkpoint
+                                FILE, saved with the same options
+    --stats-json FILE           Write run statistics to FILE as JSON
+    --stats-csv FILE            Write run statistics to FILE as CSV
+    --profile FILE              Write execution counts and cycles by
+                                instruction, basic block, loop and symbol,
+                                and an annotated listing, to FILE
+    --cosim                     Check the CA model against the IA model
+                                instruction by instruction
+    --cosim-history N           Retirements listed when the models
+                                diverge (default: 16)
+    --signature FILE            Write the signature region of a test
+                                program to FILE
+    --max-cycles N              Give up on a test program after N cycles
+                                (default: 100000)
+    --seed N                    Seed of the first fuzzed program
+                                (default: 0)
+    --count N                   Programs to fuzz (default: 1000)
+    --length N                  Operations, of one or two instructions, in
+                                a fuzzed program (default: 64)
+";
+
+
+/// Command line options.
+struct Options {
+    filename: String,
+    /// Simulate the instruction accurate model
+    ia: bool,
+    framebuffer: Option<(usize, usize, PixelFormat)>,
+    frame_dir: PathBuf,
+    frame_format: ImageFormat,
+    disk: Option<PathBuf>,
+    disk_mode: WriteMode,
+    disk_latency: (u32, u32),
+    console: bool,
+    write_latency: u32,
+    store_buffer: Option<usize>,
+    drain_policy: DrainPolicy,
+    write_combining: bool,
+    predictor: String,
+    btb: Option<(usize, usize, u32)>,
+    ras: Option<usize>,
+    resolution: BranchResolution,
+    hazard_policy: HazardPolicy,
+    commit_log: Option<PathBuf>,
+    events: Option<PathBuf>,
+    kanata: Option<PathBuf>,
+    chart: Option<(u64, u64)>,
+    vcd: Option<PathBuf>,
+    vcd_signals: Vec<SignalGroup>,
+    tui: bool,
+    debug: bool,
+    gdb: Option<String>,
+    restore: Option<PathBuf>,
+    stats_json: Option<PathBuf>,
+    stats_csv: Option<PathBuf>,
+    profile: Option<PathBuf>,
+    cosim: bool,
+    cosim_history: usize,
+    /// Reference commit log to compare with
+    diff: Option<PathBuf>,
+    /// Run a test program
+    test: bool,
+    /// Run a directory of riscv-tests programs
+    riscv_tests: bool,
+    signature: Option<PathBuf>,
+    max_cycles: u64,
+    /// Fuzz the CA model against the IA model
+    fuzz: bool,
+    seed: u64,
+    count: u64,
+    length: usize,
+}
+
+
+/// Options only the CA model uses, so `--model ia` rejects them.
+const CA_OPTIONS: [&str; 12] = [
+    "--events",
+    "--kanata",
+    "--chart",
+    "--vcd",
+    "--tui",
+    "--stats-json",
+    "--stats-csv",
+    "--hazards",
+    "--predictor",
+    "--btb",
+    "--ras",
+    "--resolve",
+];
+
+
+/// Parses command line arguments, not including the program name.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut filename = None;
+    let mut ia = false;
+    let mut framebuffer = None;
+    let mut frame_dir = PathBuf::from("frames");
+    let mut frame_format = ImageFormat::Ppm;
+    let mut disk = None;
+    let mut disk_mode = WriteMode::CopyOnWrite;
+    let mut disk_latency = (0, 0);
+    let mut console = false;
+    let mut write_latency = 0;
+    let mut store_buffer = None;
+    let mut drain_policy = DrainPolicy::Eager;
+    let mut write_combining = false;
+    let mut predictor = String::from("not-taken");
+    let mut btb = None;
+    let mut ras = None;
+    let mut resolution = BranchResolution::Memory;
+    let mut hazard_policy = HazardPolicy::Forwarding;
+    let mut commit_log = None;
+    let mut events = None;
+    let mut kanata = None;
+    let mut chart = None;
+    let mut vcd = None;
+    let mut vcd_signals = vcd::GROUPS.to_vec();
+    let mut tui = false;
+    let mut debug = false;
+    let mut gdb = None;
+    let mut restore = None;
+    let mut stats_json = None;
+    let mut stats_csv = None;
+    let mut profile = None;
+    let mut cosim = false;
+    let mut cosim_history = cosim::DEFAULT_HISTORY;
+    let mut signature = None;
+    let mut max_cycles = compliance::DEFAULT_CYCLE_LIMIT;
+    let mut seed = 0;
+    let mut count = 1000;
+    let mut length = fuzz::DEFAULT_LENGTH;
+    let mut ca_option = None;
+
+    let mut args = args.iter();
+    let mut diff = None;
+    let mut test = false;
+    let mut riscv_tests = false;
+    let mut fuzz = false;
+    match args.as_slice().first().map(|a| a.as_str()) {
+        Some("diff") => {
+            args.next();
+            let value = args.next().ok_or("diff needs a reference log")?;
+            diff = Some(PathBuf::from(value));
+        }
+        Some("test") => {
+            args.next();
+            test = true;
+        }
+        Some("riscv-tests") => {
+            args.next();
+            riscv_tests = true;
+        }
+        Some("fuzz") => {
+            args.next();
+            fuzz = true;
+        }
+        _ => (),
+    }
+
+    while let Some(arg) = args.next() {
+        if ca_option.is_none() && CA_OPTIONS.contains(&arg.as_str()) {
+            ca_option = Some(arg.clone());
+        }
+        match arg.as_str() {
+            "--model" => {
+                let value = args.next().ok_or("--model needs a value")?;
+                ia = match value.as_str() {
+                    "ca" => false,
+                    "ia" => true,
+                    _ => return Err(format!("unknown model {}", value)),
+                };
+            }
+            "--framebuffer" => {
+                let value = args.next().ok_or("--framebuffer needs a value")?;
+                framebuffer = Some(parse_framebuffer(value)?);
+            }
+            "--frame-dir" => {
+                let value = args.next().ok_or("--frame-dir needs a value")?;
+                frame_dir = PathBuf::from(value);
+            }
+            "--png" => frame_format = ImageFormat::Png,
+            "--disk" => {
+                let value = args.next().ok_or("--disk needs a value")?;
+                disk = Some(PathBuf::from(value));
+            }
+            "--disk-write-through" => disk_mode = WriteMode::WriteThrough,
+            "--disk-latency" => {
+                let value = args.next().ok_or("--disk-latency needs a value")?;
+                disk_latency = parse_latency(value)?;
+            }
+            "--console" => console = true,
+            "--write-latency" => {
+                let value =
+                    args.next().ok_or("--write-latency needs a value")?;
+                write_latency = value.parse().map_err(|_| {
+                    format!("invalid write latency {}", value)
+                })?;
+            }
+            "--store-buffer" => {
+                let value = args.next().ok_or("--store-buffer needs a value")?;
+                match value.parse() {
+                    Ok(depth) if depth > 0 => store_buffer = Some(depth),
+                    _ => {
+                        return Err(format!("invalid store buffer {}", value))
+                    }
+                }
+            }
+            "--drain" => {
+                let value = args.next().ok_or("--drain needs a value")?;
+                drain_policy = parse_drain_policy(value)?;
+            }
+            "--write-combining" => write_combining = true,
+            "--predictor" => {
+                let value = args.next().ok_or("--predictor needs a value")?;
+                if value != "all" && predictor::by_name(value).is_none() {
+                    return Err(format!("unknown predictor {}", value));
+                }
+                predictor = value.to_string();
+            }
+            "--btb" => {
+                let value = args.next().ok_or("--btb needs a value")?;
+                btb = Some(parse_btb(value)?);
+            }
+            "--ras" => {
+                let value = args.next().ok_or("--ras needs a value")?;
+                match value.parse() {
+                    Ok(depth) if depth > 0 => ras = Some(depth),
+                    _ => return Err(format!("invalid RAS depth {}", value)),
+                }
+            }
+            "--resolve" => {
+                let value = args.next().ok_or("--resolve needs a value")?;
+                resolution = match value.as_str() {
+                    "mem" => BranchResolution::Memory,
+                    "id" => BranchResolution::Decode,
+                    _ => return Err(format!("invalid stage {}", value)),
+                };
+            }
+            "--hazards" => {
+                let value = args.next().ok_or("--hazards needs a value")?;
+                hazard_policy = match value.as_str() {
+                    "forwarding" => HazardPolicy::Forwarding,
+                    "stall" => HazardPolicy::StallOnly,
+                    "no-interlock" => HazardPolicy::NoInterlock,
+                    _ => return Err(format!("unknown policy {}", value)),
+                };
+            }
+            "--commit-log" => {
+                let value = args.next().ok_or("--commit-log needs a value")?;
+                commit_log = Some(PathBuf::from(value));
+            }
+            "--events" => {
+                let value = args.next().ok_or("--events needs a value")?;
+                events = Some(PathBuf::from(value));
+            }
+            "--kanata" => {
+                let value = args.next().ok_or("--kanata needs a value")?;
+                kanata = Some(PathBuf::from(value));
+            }
+            "--chart" => {
+                let value = args.next().ok_or("--chart needs a value")?;
+                chart = Some(parse_window(value)?);
+            }
+            "--vcd" => {
+                let value = args.next().ok_or("--vcd needs a value")?;
+                vcd = Some(PathBuf::from(value));
+            }
+            "--vcd-signals" => {
+                let value = args.next().ok_or("--vcd-signals needs a value")?;
+                vcd_signals = parse_signal_groups(value)?;
+            }
+            "--tui" => tui = true,
+            "--debug" => debug = true,
+            "--gdb" => {
+                let value = args.next().ok_or("--gdb needs a value")?;
+                gdb = Some(value.to_string());
+            }
+            "--restore" => {
+                let value = args.next().ok_or("--restore needs a value")?;
+                restore = Some(PathBuf::from(value));
+            }
+            "--stats-json" => {
+                let value = args.next().ok_or("--stats-json needs a value")?;
+                stats_json = Some(PathBuf::from(value));
+            }
+            "--stats-csv" => {
+                let value = args.next().ok_or("--stats-csv needs a value")?;
+                stats_csv = Some(PathBuf::from(value));
+            }
+            "--profile" => {
+                let value = args.next().ok_or("--profile needs a value")?;
+                profile = Some(PathBuf::from(value));
+            }
+            "--cosim" => cosim = true,
+            "--cosim-history" => {
+                let value =
+                    args.next().ok_or("--cosim-history needs a value")?;
+                cosim_history = value
+                    .parse()
+                    .map_err(|_| format!("invalid history {}", value))?;
+            }
+            "--signature" => {
+                let value = args.next().ok_or("--signature needs a value")?;
+                signature = Some(PathBuf::from(value));
+            }
+            "--max-cycles" => {
+                let value = args.next().ok_or("--max-cycles needs a value")?;
+                max_cycles = value
+                    .parse()
+                    .map_err(|_| format!("invalid cycle limit {}", value))?;
+            }
+            "--seed" => {
+                let value = args.next().ok_or("--seed needs a value")?;
+                seed = value
+                    .parse()
+                    .map_err(|_| format!("invalid seed {}", value))?;
+            }
+            "--count" => {
+                let value = args.next().ok_or("--count needs a value")?;
+                count = value
+                    .parse()
+                    .map_err(|_| format!("invalid count {}", value))?;
+            }
+            "--length" => {
+                let value = args.next().ok_or("--length needs a value")?;
+                length = value
+                    .parse()
+                    .map_err(|_| format!("invalid length {}", value))?;
+            }
+            a if a.starts_with("--") => {
+                return Err(format!("unknown option {}", a));
+            }
+            a => filename = Some(a.to_string()),
+        }
+    }
+
+    if let (true, Some(option)) = (ia, ca_option) {
+        return Err(format!("{} can't be combined with --model ia", option));
+    }
+    if restore.is_some() && !debug && gdb.is_none() {
+        return Err(String::from("--restore needs --debug or --gdb"));
+    }
+    if diff.is_some() && (cosim || debug || gdb.is_some()) {
+        return Err(String::from(
+            "diff can't be combined with --cosim, --debug or --gdb",
+        ));
+    }
+    if (test || riscv_tests) &&
+        (cosim || debug || gdb.is_some() || predictor == "all")
+    {
+        return Err(String::from(
+            "test and riscv-tests can't be combined with --cosim, --debug, \
+             --gdb or --predictor all",
+        ));
+    }
+    if fuzz && (ia || cosim || debug || gdb.is_some() || predictor == "all")
+    {
+        return Err(String::from(
+            "fuzz can't be combined with --model ia, --cosim, --debug, \
+             --gdb or --predictor all",
+        ));
+    }
+    if profile.is_some() &&
+        (riscv_tests || fuzz || diff.is_some() || cosim || debug ||
+             gdb.is_some() || predictor == "all")
+    {
+        return Err(String::from(
+            "--profile can't be combined with riscv-tests, fuzz, diff, \
+             --cosim, --debug, --gdb or --predictor all",
+        ));
+    }
+    if signature.is_some() && !test {
+        return Err(String::from("--signature needs the test command"));
+    }
+    if cosim && (ia || debug || gdb.is_some()) {
+        return Err(String::from(
+            "--cosim can't be combined with --model ia, --debug or --gdb",
+        ));
+    }
+
+    Ok(Options {
+        filename: match filename {
+            Some(filename) => filename,
+            None if fuzz => String::new(),
+            None => return Err(String::from("missing filename")),
+        },
+        ia,
+        framebuffer,
+        frame_dir,
+        frame_format,
+        disk,
+        disk_mode,
+        disk_latency,
+        console,
+        write_latency,
+        store_buffer,
+        drain_policy,
+        write_combining,
+        predictor,
+        btb,
+        ras,
+        resolution,
+        hazard_policy,
+        commit_log,
+        events,
+        kanata,
+        chart,
+        vcd,
+        vcd_signals,
+        tui,
+        debug,
+        gdb,
+        restore,
+        stats_json,
+        stats_csv,
+        profile,
+        cosim,
+        cosim_history,
+        diff,
+        test,
+        riscv_tests,
+        signature,
+        max_cycles,
+        fuzz,
+        seed,
+        count,
+        length,
+    })
+}
+
+
+/// Parses a framebuffer geometry of the form `WxH[:FORMAT]`.
+fn parse_framebuffer(
+    value: &str,
+) -> Result<(usize, usize, PixelFormat), String> {
+    let invalid = || format!("invalid framebuffer {}", value);
+
+    let mut parts = value.splitn(2, ':');
+    let geometry = parts.next().unwrap();
+    let format = match parts.next() {
+        None | Some("rgb565") => PixelFormat::Rgb565,
+        Some("rgba8888") => PixelFormat::Rgba8888,
+        Some("palette8") => PixelFormat::Palette8,
+        Some(_) => return Err(invalid()),
+    };
+
+    let mut dims = geometry.splitn(2, 'x').map(|d| d.parse::<usize>());
+    match (dims.next(), dims.next()) {
+        (Some(Ok(w)), Some(Ok(h))) if w > 0 && h > 0 => Ok((w, h, format)),
+        _ => Err(invalid()),
+    }
+}
+
+
+/// Parses read and write latencies of the form `R,W`.
+fn parse_latency(value: &str) -> Result<(u32, u32), String> {
+    let mut cycles = value.splitn(2, ',').map(|c| c.parse::<u32>());
+    match (cycles.next(), cycles.next()) {
+        (Some(Ok(read)), Some(Ok(write))) => Ok((read, write)),
+        _ => Err(format!("invalid disk latency {}", value)),
+    }
+}
+
+
+/// Parses a window of cycles of the form `FIRST:LAST`.
+fn parse_window(value: &str) -> Result<(u64, u64), String> {
+    let mut cycles = value.splitn(2, ':').map(|c| c.parse::<u64>());
+    match (cycles.next(), cycles.next()) {
+        (Some(Ok(first)), Some(Ok(last))) if first <= last => {
+            Ok((first, last))
+        }
+        _ => Err(format!("invalid cycles {}", value)),
+    }
+}
+
+
+/// Parses a comma separated list of VCD signal groups.
+fn parse_signal_groups(value: &str) -> Result<Vec<SignalGroup>, String> {
+    value
+        .split(',')
+        .map(|name| {
+            SignalGroup::by_name(name)
+                .ok_or_else(|| format!("unknown signal group {}", name))
+        })
+        .collect()
+}
+
+
+/// Parses a BTB geometry of the form `N[,WAYS[,TAG_BITS]]`.
+fn parse_btb(value: &str) -> Result<(usize, usize, u32), String> {
+    let invalid = || format!("invalid BTB {}", value);
+
+    let fields: Vec<&str> = value.split(',').collect();
+    if fields.len() > 3 {
+        return Err(invalid());
+    }
+    let field = |i: usize, default: usize| match fields.get(i) {
+        Some(f) => f.parse::<usize>().map_err(|_| invalid()),
+        None => Ok(default),
+    };
+
+    let entries = field(0, 0)?;
+    let ways = field(1, 1)?;
+    let tag_bits = field(2, 32)? as u32;
+    if ways == 0 || !entries.is_multiple_of(ways) ||
+        !(entries / ways).is_power_of_two()
+    {
+        return Err(invalid());
+    }
+
+    Ok((entries, ways, tag_bits))
+}
+
+
+/// Parses a store buffer drain policy.
+fn parse_drain_policy(value: &str) -> Result<DrainPolicy, String> {
+    let invalid = || format!("invalid drain policy {}", value);
+
+    match value {
+        "eager" => Ok(DrainPolicy::Eager),
+        "lazy" => Ok(DrainPolicy::Lazy),
+        _ if value.starts_with("watermark:") => {
+            match value["watermark:".len()..].parse() {
+                Ok(n) if n > 0 => Ok(DrainPolicy::Watermark(n)),
+                _ => Err(invalid()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
 
 fn main() {
     env_logger::init().unwrap();
@@ -47,22 +679,651 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     let program_name = &args[0];
 
-    let instructions: DisassemblyInstructionMemory;
-    let mut data_memory = DataMemory::new(8192);
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            println!(
+                "Usage: {} [diff REFERENCE | test | riscv-tests | fuzz] \
+                 [options] <filename>",
+                program_name
+            );
+            println!("{}", OPTIONS);
+            std::process::exit(1);
+        }
+    };
+
+    println!("{}", LOGO);
+
+    if options.test {
+        run_test(&options);
+        return;
+    }
+    if options.riscv_tests {
+        run_riscv_tests(&options);
+        return;
+    }
+    if options.fuzz {
+        run_fuzz(&options);
+        return;
+    }
+
+    let f = File::open(&options.filename).expect("error opening file");
+    let instructions = DisassemblyInstructionMemory::new(&f);
+
+    // Compare every predictor on a fresh machine
+    if options.predictor == "all" {
+        for name in predictor::NAMES.iter() {
+            let mut data_memory = new_data_memory(&options, true);
+            let mut registers = RegisterFile::new(0x0);
+            let mut branch_unit = new_branch_unit(&options, name);
+            let mut hazard_unit = HazardUnit::new(options.hazard_policy);
+            ca_simulator::run_with_units(
+                &instructions,
+                &mut data_memory,
+                &mut registers,
+                &mut branch_unit,
+                &mut hazard_unit,
+            );
+            print_branch_stats(name, &branch_unit.stats);
+        }
+        return;
+    }
+
+    if options.debug || options.gdb.is_some() || options.diff.is_some() {
+        let data_memory = new_data_memory(&options, true);
+        let registers = RegisterFile::new(0x0);
+        if options.ia {
+            let sim = IaSimulator::new(&instructions, data_memory, registers);
+            drive(sim, &instructions, &options);
+        } else {
+            let mut sim =
+                CaSimulator::new(&instructions, data_memory, registers);
+            let branch_unit = new_branch_unit(&options, &options.predictor);
+            sim.set_branch_unit(branch_unit);
+            sim.set_hazard_unit(HazardUnit::new(options.hazard_policy));
+            drive(sim, &instructions, &options);
+        }
+        return;
+    }
+
+    if options.cosim {
+        let mut ca = CaSimulator::new(
+            &instructions,
+            new_data_memory(&options, true),
+            RegisterFile::new(0x0),
+        );
+        ca.set_branch_unit(new_branch_unit(&options, &options.predictor));
+        ca.set_hazard_unit(HazardUnit::new(options.hazard_policy));
+        let ia = IaSimulator::new(
+            &instructions,
+            new_data_memory(&options, false),
+            RegisterFile::new(0x0),
+        );
+
+        let mut lockstep = Lockstep::new(&instructions, ca, ia);
+        lockstep.set_history(options.cosim_history);
+        match lockstep.run() {
+            Ok(halt_addr) => println!(
+                "Models agreed on {} instructions, HALT at {:#0x}",
+                lockstep.matched(),
+                halt_addr
+            ),
+            Err(divergence) => {
+                println!("{}", divergence);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut data_memory = new_data_memory(&options, true);
     let mut registers = RegisterFile::new(0x0);
 
-    if let Some(filename) = args.get(1) {
-        let f = File::open(filename).expect("error opening file");
-        instructions = DisassemblyInstructionMemory::new(&f);
+    let mut commit_log = options.commit_log.as_ref().map(|path| {
+        let f = File::create(path).expect("error creating commit log");
+        CommitLog::new(&instructions, BufWriter::new(f))
+    });
+    let mut profile = options
+        .profile
+        .as_ref()
+        .map(|_| new_profile(&instructions, instructions.symbols()));
+
+    if options.ia {
+        let halt_addr = {
+            let mut observers: Vec<&mut dyn Observer> = Vec::new();
+            if let Some(ref mut commit_log) = commit_log {
+                observers.push(commit_log);
+            }
+            if let Some(ref mut profile) = profile {
+                observers.push(profile);
+            }
+            let mut sim =
+                IaSimulator::new(&instructions, data_memory, registers);
+            sim.set_observer(&mut observers);
+            sim.run()
+        };
+        if let Some(commit_log) = commit_log {
+            commit_log.into_inner().expect("error writing commit log");
+        }
+        if let Some(ref profile) = profile {
+            write_profile(&options, profile);
+        }
+        println!("Caught HALT instruction at {:#0x}, exiting...", halt_addr);
+        return;
+    }
+
+    let mut branch_unit = new_branch_unit(&options, &options.predictor);
+    let mut hazard_unit = HazardUnit::new(options.hazard_policy);
+
+    let mut sink = options.events.as_ref().map(|path| {
+        let f = File::create(path).expect("error creating event file");
+        JsonLinesSink::new(BufWriter::new(f))
+    });
+    let mut kanata = options.kanata.as_ref().map(|path| {
+        let f = File::create(path).expect("error creating Kanata log");
+        KanataWriter::new(BufWriter::new(f))
+    });
+    let mut chart = options
+        .chart
+        .map(|(first, last)| PipelineChart::new(first, last));
+    let mut vcd = options.vcd.as_ref().map(|path| {
+        let f = File::create(path).expect("error creating VCD file");
+        VcdWriter::new(BufWriter::new(f), &options.vcd_signals)
+    });
+    let stdin = io::stdin();
+    let mut tui = if options.tui {
+        Some(Tui::new(stdin.lock(), io::stdout()))
     } else {
-        println!("Usage: {} <filename>", program_name);
+        None
+    };
+
+    let stats = {
+        let mut observers: Vec<&mut dyn Observer> = Vec::new();
+        if let Some(ref mut commit_log) = commit_log {
+            observers.push(commit_log);
+        }
+        if let Some(ref mut sink) = sink {
+            observers.push(sink);
+        }
+        if let Some(ref mut kanata) = kanata {
+            observers.push(kanata);
+        }
+        if let Some(ref mut chart) = chart {
+            observers.push(chart);
+        }
+        if let Some(ref mut vcd) = vcd {
+            observers.push(vcd);
+        }
+        if let Some(ref mut tui) = tui {
+            observers.push(tui);
+        }
+        if let Some(ref mut profile) = profile {
+            observers.push(profile);
+        }
+        ca_simulator::run_with_observer(
+            &instructions,
+            &mut data_memory,
+            &mut registers,
+            &mut branch_unit,
+            &mut hazard_unit,
+            &mut observers,
+        )
+    };
+    if let Some(commit_log) = commit_log {
+        commit_log.into_inner().expect("error writing commit log");
+    }
+    if let Some(sink) = sink {
+        sink.into_inner().expect("error writing events");
+    }
+    if let Some(kanata) = kanata {
+        kanata.into_inner().expect("error writing Kanata log");
+    }
+    if let Some(vcd) = vcd {
+        vcd.into_inner().expect("error writing VCD file");
+    }
+    if let Some(tui) = tui {
+        tui.into_inner().expect("error running terminal UI");
+    }
+    if let Some(ref profile) = profile {
+        write_profile(&options, profile);
+    }
+
+    println!(
+        "Caught HALT instruction at {:#0x}, exiting...",
+        stats.halt_addr
+    );
+
+    if let Some(ref chart) = chart {
+        println!("{}", chart);
+    }
+    println!("{}", stats);
+    if let Some(ref path) = options.stats_json {
+        let json = stats.to_json() + "\n";
+        fs::write(path, json).expect("error writing statistics");
+    }
+    if let Some(ref path) = options.stats_csv {
+        fs::write(path, stats.to_csv()).expect("error writing statistics");
+    }
+
+    print_branch_stats(&options.predictor, &branch_unit.stats);
+    if options.btb.is_some() || options.ras.is_some() {
+        let stats = &branch_unit.stats;
+        println!("Branch targets:");
+        println!("    jumps:                 {}", stats.jumps);
+        println!("    jumps mispredicted:    {}", stats.jumps_mispredicted);
+        println!("    BTB hits:              {}", stats.btb_hits);
+        println!("    BTB misses:            {}", stats.btb_misses);
+        println!("    BTB aliases:           {}", stats.btb_aliases);
+        println!("    RAS overflows:         {}", stats.ras_overflows);
+        println!("    RAS underflows:        {}", stats.ras_underflows);
+    }
+
+    println!("Data hazards:");
+    println!("    stall cycles:          {}", hazard_unit.stats.stall_cycles);
+    println!("    violations:            {}", hazard_unit.stats.violations);
+    for violation in hazard_unit.violations() {
+        println!("    {}", violation);
+    }
+
+    if let Some(store_buffer) = data_memory.store_buffer() {
+        let stats = &store_buffer.stats;
+        println!("Store buffer:");
+        println!("    stores:                {}", stats.stores);
+        println!("    combined:              {}", stats.combined);
+        println!("    forwarded loads:       {}", stats.forwarded_loads);
+        println!("    full stall cycles:     {}", stats.full_stall_cycles);
+        println!("    drain stall cycles:    {}", stats.drain_stall_cycles);
+        println!("    blocking stall cycles: {}", stats.blocking_stall_cycles);
+        println!("    stalls saved:          {}", stats.stalls_saved());
+    }
+}
+
+
+/// Runs the test program given with `test` on the model in `options`.
+///
+/// Writes the signature given with `--signature`, and exits with an error
+/// status unless the program passed.
+fn run_test(options: &Options) {
+    let program = TestProgram::open(Path::new(&options.filename))
+        .expect("error loading test program");
+    let insns = program.instructions();
+    let mut data_memory = program.data_memory();
+    set_memory_timing(options, &mut data_memory);
+    let mut profile = options
+        .profile
+        .as_ref()
+        .map(|_| new_profile(insns, insns.symbols()));
+
+    let passed = if options.ia {
+        let mut sim =
+            IaSimulator::new(insns, data_memory, program.registers());
+        if let Some(ref mut profile) = profile {
+            sim.set_observer(profile);
+        }
+        finish_test(sim, &program, options)
+    } else {
+        let mut sim =
+            CaSimulator::new(insns, data_memory, program.registers());
+        sim.set_branch_unit(new_branch_unit(options, &options.predictor));
+        sim.set_hazard_unit(HazardUnit::new(options.hazard_policy));
+        if let Some(ref mut profile) = profile {
+            sim.set_observer(profile);
+        }
+        finish_test(sim, &program, options)
+    };
+
+    if let Some(ref profile) = profile {
+        write_profile(options, profile);
+    }
+    if !passed {
         std::process::exit(1);
     }
+}
 
-    println!("{}", LOGO);
 
-    let halt_addr =
-        ca_simulator::run(&instructions, &mut data_memory, &mut registers);
+/// Runs `program` on `sim` and reports how it ended.
+///
+/// Returns true if the program passed.
+fn finish_test<S: Simulator>(
+    mut sim: S,
+    program: &TestProgram,
+    options: &Options,
+) -> bool {
+    let outcome = program.run(&mut sim, options.max_cycles);
+    println!(
+        "{}: {} after {} cycles",
+        program.name(),
+        outcome,
+        sim.clock()
+    );
+
+    if let Some(ref path) = options.signature {
+        let words = program
+            .signature(sim.memory_mut())
+            .expect("test program has no signature region");
+        let f = File::create(path).expect("error creating signature file");
+        compliance::write_signature(BufWriter::new(f), &words)
+            .expect("error writing signature");
+    }
+
+    outcome == Outcome::Pass
+}
+
+
+/// Runs the riscv-tests programs in the directory given with
+/// `riscv-tests` on both models and prints the pass/fail matrix.
+///
+/// The CA model is configured by `options`. Exits with an error status
+/// unless every program passed on both.
+fn run_riscv_tests(options: &Options) {
+    let dir = Path::new(&options.filename);
+    let matrix = compliance::run_suite(dir, options.max_cycles, |ca| {
+        ca.set_branch_unit(new_branch_unit(options, &options.predictor));
+        ca.set_hazard_unit(HazardUnit::new(options.hazard_policy));
+        set_memory_timing(options, ca.memory_mut());
+    }).expect("error running riscv-tests");
+
+    if matrix.rows.is_empty() {
+        println!(
+            "No {}* or {}* programs in {}",
+            compliance::PREFIX,
+            compliance::LOCAL_PREFIX,
+            dir.display()
+        );
+        std::process::exit(1);
+    }
+    println!("{}", matrix);
+    if !matrix.all_passed() {
+        std::process::exit(1);
+    }
+}
+
+
+/// Fuzzes the CA model, configured by `options`, against the IA model.
+///
+/// The first program the models disagree on is minimized and written as a
+/// test program, expecting the IA model's final state, to the file named
+/// in `options` or stdout. Exits with an error status if there was one.
+fn run_fuzz(options: &Options) {
+    let mut generator = fuzz::Generator::new();
+    generator.set_length(options.length);
+    let configure = |ca: &mut CaSimulator| {
+        ca.set_branch_unit(new_branch_unit(options, &options.predictor));
+        ca.set_hazard_unit(HazardUnit::new(options.hazard_policy));
+        set_memory_timing(options, ca.memory_mut());
+    };
+
+    // Panics count as differences, and minimizing repeats them
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| ()));
+    let failure =
+        fuzz::fuzz(&generator, options.seed, options.count, configure);
+    std::panic::set_hook(hook);
+
+    let failure = match failure {
+        Some(failure) => failure,
+        None => {
+            println!(
+                "No differences in {} programs from seed {}",
+                options.count,
+                options.seed
+            );
+            return;
+        }
+    };
+    println!(
+        "Program from seed {} differs, minimized from {} to {} ops:",
+        failure.seed,
+        failure.program.ops().len(),
+        failure.minimized.ops().len()
+    );
+    for difference in &failure.differences {
+        println!("    {}", difference);
+    }
+
+    let mut text = format!("# Found by casim fuzz --seed {}\n", failure.seed);
+    for directive in failure.minimized.run_ia().expectations() {
+        text += &format!("# expect: {}\n", directive);
+    }
+    text += "\n";
+    let mut program = text.into_bytes();
+    failure
+        .minimized
+        .write_disassembly(&mut program)
+        .expect("error writing program");
+    if options.filename.is_empty() {
+        println!("\n{}", String::from_utf8_lossy(&program));
+    } else {
+        fs::write(&options.filename, program).expect("error writing program");
+    }
+
+    std::process::exit(1);
+}
+
+
+/// Compares `sim`, which executes `instructions`, with the reference log
+/// given with `diff`, or debugs it.
+fn drive<S: Simulator + Checkpoint>(
+    sim: S,
+    instructions: &DisassemblyInstructionMemory,
+    options: &Options,
+) {
+    match options.diff {
+        Some(ref path) => diff(sim, instructions, path),
+        None => interact(sim, instructions, options),
+    }
+}
+
+
+/// Runs `sim`, which executes `instructions`, against the commit log at
+/// `path`, exiting with an error status at the first mismatch.
+fn diff<S: Simulator>(
+    mut sim: S,
+    instructions: &DisassemblyInstructionMemory,
+    path: &Path,
+) {
+    let f = File::open(path).expect("error opening reference log");
+    let reference = commit_log::read_log(BufReader::new(f))
+        .expect("error reading reference log");
+
+    let context = commit_log::DEFAULT_CONTEXT;
+    match commit_log::diff(&mut sim, instructions, &reference, context) {
+        Ok(matched) => println!(
+            "Matched {} commits of {}",
+            matched,
+            path.display()
+        ),
+        Err(mismatch) => {
+            println!("{}", mismatch);
+            std::process::exit(1);
+        }
+    }
+}
+
+
+/// Debugs `sim`, which executes `instructions`, from GDB if `--gdb` is
+/// given, otherwise in a command shell on the terminal.
+///
+/// Starts from the checkpoint given with `--restore`, if any.
+fn interact<S: Simulator + Checkpoint>(
+    mut sim: S,
+    instructions: &DisassemblyInstructionMemory,
+    options: &Options,
+) {
+    if let Some(ref path) = options.restore {
+        checkpoint::restore_file(&mut sim, path)
+            .expect("error restoring checkpoint");
+    }
+
+    let addr = match options.gdb {
+        Some(ref addr) => addr,
+        None => {
+            let stdin = io::stdin();
+            let mut debugger =
+                Debugger::new(sim, instructions, stdin.lock(), io::stdout());
+            debugger.set_symbols(instructions.symbols().clone());
+            debugger.run().expect("error running debugger");
+            return;
+        }
+    };
+
+    let result = match addr.parse::<u16>() {
+        Ok(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))
+                .expect("error listening for GDB");
+            println!("Waiting for GDB on localhost:{}", port);
+            let (stream, _) = listener.accept().expect("error accepting GDB");
+            stream.set_nodelay(true).expect("error configuring socket");
+            let input = BufReader::new(stream.try_clone().unwrap());
+            let interrupt = Box::new(stream.try_clone().unwrap());
+            let mut stub = GdbStub::new(sim, instructions, input, stream);
+            stub.set_interrupt(Some(interrupt));
+            stub.run()
+        }
+        Err(_) => serve_unix(sim, instructions, addr),
+    };
+    result.expect("error serving GDB");
+}
+
+
+/// Serves GDB on the Unix socket `path`.
+#[cfg(unix)]
+fn serve_unix<S: Simulator>(
+    sim: S,
+    instructions: &DisassemblyInstructionMemory,
+    path: &str,
+) -> io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+    println!("Waiting for GDB on {}", path);
+    let (stream, _) = listener.accept()?;
+    let input = BufReader::new(stream.try_clone()?);
+    let interrupt = Box::new(stream.try_clone()?);
+    let mut stub = GdbStub::new(sim, instructions, input, stream);
+    stub.set_interrupt(Some(interrupt));
+    let result = stub.run();
+    fs::remove_file(path)?;
+
+    result
+}
+
+
+/// Serves GDB on the Unix socket `path`.
+#[cfg(not(unix))]
+fn serve_unix<S: Simulator>(
+    _sim: S,
+    _instructions: &DisassemblyInstructionMemory,
+    path: &str,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Unix sockets are not supported: {}", path),
+    ))
+}
+
+
+/// Constructs data memory with the devices and store buffer in `options`.
+///
+/// Without `outputs`, the framebuffer doesn't write frames, the console
+/// output is discarded and the disk image is never written, for a second
+/// copy of the machine.
+fn new_data_memory(options: &Options, outputs: bool) -> DataMemory {
+    let mut data_memory = DataMemory::new(8192);
+
+    if let Some((width, height, format)) = options.framebuffer {
+        let mut fb = Framebuffer::new(width, height, format);
+        if outputs {
+            fb.set_output(options.frame_dir.clone(), options.frame_format);
+        }
+        data_memory.attach(framebuffer::BASE, Box::new(fb));
+    }
+
+    if let Some(ref image) = options.disk {
+        let mode = match outputs {
+            true => options.disk_mode,
+            false => WriteMode::CopyOnWrite,
+        };
+        let mut disk =
+            BlockDevice::open(image, mode).expect("error opening disk image");
+        let (read, write) = options.disk_latency;
+        disk.set_latency(read, write);
+        data_memory.attach(block::BASE, Box::new(disk));
+    }
+
+    if options.console {
+        let console = match outputs {
+            true => Console::new(Box::new(io::stdout())),
+            false => Console::new(Box::new(io::sink())),
+        };
+        data_memory.attach(console::BASE, Box::new(console));
+    }
+
+    set_memory_timing(options, &mut data_memory);
+
+    data_memory
+}
+
+
+/// Constructs a new `Profile` of a simulator executing `insns`, grouped
+/// by `symbols`.
+fn new_profile<'a>(
+    insns: &'a dyn InstructionMemory,
+    symbols: &BTreeMap<String, u32>,
+) -> Profile<'a> {
+    let mut profile = Profile::new(insns);
+    profile.set_symbols(symbols);
+
+    profile
+}
+
+
+/// Writes `profile` to the file given with `--profile`.
+fn write_profile(options: &Options, profile: &Profile) {
+    if let Some(ref path) = options.profile {
+        let report = format!("{}\n", profile);
+        fs::write(path, report).expect("error writing profile");
+    }
+}
+
+
+/// Sets the write latency and store buffer in `options` on `data_memory`.
+fn set_memory_timing(options: &Options, data_memory: &mut DataMemory) {
+    data_memory.set_write_latency(options.write_latency);
+    if let Some(depth) = options.store_buffer {
+        let mut store_buffer = StoreBuffer::new(depth, options.drain_policy);
+        store_buffer.set_combining(options.write_combining);
+        data_memory.set_store_buffer(Some(store_buffer));
+    }
+}
+
+
+/// Constructs a branch unit with the named predictor and the BTB, return
+/// address stack and resolution stage in `options`.
+fn new_branch_unit(options: &Options, name: &str) -> BranchUnit {
+    let mut branch_unit = BranchUnit::new(predictor::by_name(name).unwrap());
+    branch_unit.set_resolution(options.resolution);
+
+    if let Some((entries, ways, tag_bits)) = options.btb {
+        branch_unit.set_btb(Some(Btb::new(entries, ways, tag_bits)));
+    }
+    if let Some(depth) = options.ras {
+        branch_unit.set_ras(Some(ReturnAddressStack::new(depth)));
+    }
+
+    branch_unit
+}
+
 
-    println!("Caught HALT instruction at {:#0x}, exiting...", halt_addr);
+/// Prints branch prediction statistics for the predictor `name`.
+fn print_branch_stats(name: &str, stats: &BranchStats) {
+    println!(
+        "Branch predictor {:<10}  branches: {:>6}  mispredicted: {:>6}  \
+         accuracy: {:>6.2}%  penalty cycles: {:>6}  stall cycles: {:>6}",
+        name,
+        stats.branches,
+        stats.mispredicted,
+        100.0 * stats.accuracy(),
+        stats.penalty_cycles,
+        stats.stall_cycles
+    );
 }
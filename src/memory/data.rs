@@ -1,9 +1,41 @@
 //! A read-write data memory.
 
 
+use checkpoint::{self, Checkpoint, Reader, Writer};
+use memory::mmio::{Device, Dma, MappedDevice};
+use memory::store_buffer::{PendingStore, StoreBuffer, StoreBufferStats};
+use trap::Exception;
+
+use std::io;
+
+
+/// Handling of loads and stores that aren't naturally aligned.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MisalignedPolicy {
+    /// Raise a load or store address misaligned exception
+    Trap,
+    /// Split the access into aligned words, costing a cycle per extra word
+    ///
+    /// Accesses within a word, e.g., a halfword at offset 1, cost nothing.
+    #[default]
+    Split,
+    /// Perform the access directly, costing a fixed number of extra cycles
+    Emulate(u32),
+}
+
+
 /// A read-write data memory.
+///
+/// Memory-mapped devices may be attached above the end of RAM. The
+/// pipelined model accesses memory through `load`, `store` and `fence`,
+/// which account for write latency and an optional store buffer.
 pub struct DataMemory {
     mem: Box<[u32]>,
+    misaligned_policy: MisalignedPolicy,
+    devices: Vec<MappedDevice>,
+    write_latency: u32,
+    store_buffer: Option<StoreBuffer>,
+    port_busy: u32,
 }
 
 
@@ -12,69 +44,476 @@ impl DataMemory {
     ///
     /// Allocates `nwords` * 32 bits of memory.
     pub fn new(nwords: usize) -> DataMemory {
-        DataMemory { mem: vec![0u32; nwords].into_boxed_slice() }
+        DataMemory {
+            mem: vec![0u32; nwords].into_boxed_slice(),
+            misaligned_policy: MisalignedPolicy::default(),
+            devices: Vec::new(),
+            write_latency: 0,
+            store_buffer: None,
+            port_busy: 0,
+        }
     }
 
-    /// Reads `size` (1, 2, or 4) bytes from memory.
-    pub fn read(&self, addr: usize, size: usize) -> u32 {
-        // Split byte address into word address and byte offset
-        let word_addr = addr >> 2;
-        let byte_offset = addr & 0x3;
-        let byte_offset_in_bits = 8 * byte_offset;
+    /// Attaches `device` to the address space at byte address `base`.
+    ///
+    /// Panics if the device's window overlaps RAM or another device.
+    pub fn attach(&mut self, base: usize, device: Box<dyn Device>) {
+        let mapped = MappedDevice::new(base, device);
+        let end = base + mapped.device.size();
+
+        if mapped.overlaps(0, self.mem.len() * 4) ||
+            self.devices.iter().any(|d| d.overlaps(base, end))
+        {
+            panic!("Device at {:#0x} overlaps mapped memory", base);
+        }
 
-        if word_addr >= self.mem.len() {
-            panic!("Address {:#0x} out of range", word_addr);
+        self.devices.push(mapped);
+    }
+
+    /// Advances attached devices and the store buffer by one clock cycle.
+    pub fn tick(&mut self) {
+        let mut dma = Dma::new(&mut self.mem);
+        for mapped in &mut self.devices {
+            mapped.device.tick(&mut dma);
         }
 
-        if (addr & (size - 1)) != 0 {
-            panic!("Unaligned memory access at {:#0x}", addr);
+        if self.port_busy > 0 {
+            self.port_busy -= 1;
+            return;
         }
 
-        let word = self.mem[word_addr] >> byte_offset_in_bits;
+        let write_latency = self.write_latency;
+        let written = match self.store_buffer {
+            Some(ref mut store_buffer) => store_buffer.tick(write_latency),
+            None => None,
+        };
+        if let Some(store) = written {
+            self.commit(store);
+        }
+    }
+
+    /// Sets the extra cycles a store occupies the write port for.
+    ///
+    /// Without a store buffer, the MEM stage stalls for this many cycles on
+    /// every store.
+    pub fn set_write_latency(&mut self, cycles: u32) {
+        self.write_latency = cycles;
+    }
+
+    /// Returns the extra cycles a store occupies the write port for.
+    pub fn write_latency(&self) -> u32 {
+        self.write_latency
+    }
+
+    /// Sets or removes the store buffer, first writing out pending stores.
+    pub fn set_store_buffer(&mut self, store_buffer: Option<StoreBuffer>) {
+        self.drain_stores();
+        self.store_buffer = store_buffer;
+    }
+
+    /// Returns the store buffer, if any.
+    pub fn store_buffer(&self) -> Option<&StoreBuffer> {
+        self.store_buffer.as_ref()
+    }
 
-        return match size {
-            1 => word & 0xff,   // isolate least significant byte
-            2 => word & 0xffff, // isolate least significant halfword
-            4 => word,
-            _ => panic!("Can only read 1, 2, or 4 bytes at a time"),
+    /// Loads `size` bytes for the pipelined model.
+    ///
+    /// Pending buffered stores are forwarded to the load. Returns the value
+    /// and the number of extra cycles the access takes.
+    pub fn load(
+        &mut self,
+        addr: usize,
+        size: usize,
+    ) -> Result<(u32, u32), Exception> {
+        self.check_access(addr, size, Exception::LoadAddressMisaligned)?;
+        let mut cycles = self.access_cycles(addr, size);
+
+        let device_stores = match self.store_buffer {
+            Some(ref store_buffer) => store_buffer.has_device_stores(),
+            None => return Ok((self.read(addr, size)?, cycles)),
         };
+
+        // Device registers may depend on earlier device writes
+        if self.device_index(addr, size).is_some() {
+            if device_stores {
+                let drain = self.drain_stores();
+                self.buffer_stats().drain_stall_cycles += drain as u64;
+                cycles += drain;
+            }
+            return Ok((self.read(addr, size)?, cycles));
+        }
+
+        let mut forwarded = false;
+        let value = {
+            let store_buffer = self.store_buffer.as_ref().unwrap();
+            (0..size).fold(0, |value, i| {
+                let byte = match store_buffer.forward(addr + i) {
+                    Some(byte) => {
+                        forwarded = true;
+                        byte
+                    }
+                    None => self.read_byte(addr + i),
+                };
+                value | (byte as u32) << (8 * i)
+            })
+        };
+
+        if forwarded {
+            self.buffer_stats().forwarded_loads += 1;
+        }
+        self.port_busy += 1; // the load holds the port this cycle
+
+        Ok((value, cycles))
+    }
+
+    /// Stores the lower `size` bytes of `data` for the pipelined model.
+    ///
+    /// Returns the number of extra cycles the MEM stage stalls for: the
+    /// write latency without a store buffer, or the time to free an entry
+    /// with one.
+    pub fn store(
+        &mut self,
+        addr: usize,
+        size: usize,
+        data: u32,
+    ) -> Result<u32, Exception> {
+        self.check_access(addr, size, Exception::StoreAddressMisaligned)?;
+        let blocking = self.access_cycles(addr, size) + self.write_latency;
+
+        if self.store_buffer.is_none() {
+            self.write(addr, size, data)?;
+            return Ok(blocking);
+        }
+
+        let device = self.device_index(addr, size).is_some();
+        let stores = split_store(addr, size, data, device);
+
+        let depth = self.store_buffer.as_ref().unwrap().depth();
+        let mut cycles = 0;
+        if stores.len() > depth {
+            // Too many parts to buffer, so write them once the older
+            // stores have drained
+            cycles = self.drain_stores() + blocking;
+            self.write(addr, size, data)?;
+        } else {
+            while self.store_buffer.as_ref().unwrap().free() < stores.len() {
+                cycles += self.drain_one();
+            }
+        }
+
+        let store_buffer = self.store_buffer.as_mut().unwrap();
+        if stores.len() <= depth {
+            for store in stores {
+                store_buffer.push(store);
+            }
+        }
+        store_buffer.stats.blocking_stall_cycles += blocking as u64;
+        store_buffer.stats.full_stall_cycles += cycles as u64;
+
+        Ok(cycles)
+    }
+
+    /// Orders memory accesses (FENCE) for the pipelined model.
+    ///
+    /// Returns the number of cycles spent draining the store buffer.
+    pub fn fence(&mut self) -> u32 {
+        let cycles = self.drain_stores();
+        if self.store_buffer.is_some() {
+            self.buffer_stats().drain_stall_cycles += cycles as u64;
+        }
+
+        cycles
+    }
+
+    /// Writes out every pending buffered store.
+    ///
+    /// Returns the number of cycles the writes take.
+    pub fn drain_stores(&mut self) -> u32 {
+        let mut cycles = 0;
+        while self.store_buffer.as_ref().is_some_and(|sb| !sb.is_empty()) {
+            cycles += self.drain_one();
+        }
+
+        cycles
+    }
+
+    /// Writes out the oldest buffered store while the pipeline waits.
+    ///
+    /// Returns the number of cycles the write takes.
+    fn drain_one(&mut self) -> u32 {
+        let write_latency = self.write_latency;
+        let store_buffer = self.store_buffer.as_mut().unwrap();
+        let (store, cycles) = store_buffer.pop(write_latency).unwrap();
+
+        self.commit(store);
+        self.port_busy += cycles;
+
+        cycles
+    }
+
+    /// Writes a store leaving the store buffer to memory or a device.
+    fn commit(&mut self, store: PendingStore) {
+        let offset = store.mask.trailing_zeros() as usize;
+        let size = store.mask.count_ones() as usize;
+        let addr = store.addr + offset;
+        let contiguous = store.mask >> offset == (1 << size) - 1;
+
+        if contiguous && size != 3 && is_aligned(addr, size) {
+            let data = store.data >> (8 * offset);
+            self.write(addr, size, data).expect("aligned store");
+            return;
+        }
+
+        for i in 0..4 {
+            if let Some(byte) = store.byte(i) {
+                self.write(store.addr + i, 1, byte as u32)
+                    .expect("byte store");
+            }
+        }
+    }
+
+    /// Returns the store buffer's counters.
+    fn buffer_stats(&mut self) -> &mut StoreBufferStats {
+        &mut self.store_buffer.as_mut().unwrap().stats
+    }
+
+    /// Sets how misaligned accesses are handled.
+    pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+        self.misaligned_policy = policy;
+    }
+
+    /// Returns how misaligned accesses are handled.
+    pub fn misaligned_policy(&self) -> MisalignedPolicy {
+        self.misaligned_policy
+    }
+
+    /// Reads `size` (1, 2, or 4) bytes from memory.
+    ///
+    /// Misaligned reads are handled according to the misaligned policy.
+    pub fn read(&self, addr: usize, size: usize) -> Result<u32, Exception> {
+        self.check_access(addr, size, Exception::LoadAddressMisaligned)?;
+
+        if let Some(i) = self.device_index(addr, size) {
+            let mapped = &self.devices[i];
+            return Ok(mapped.device.read(addr - mapped.base, size));
+        }
+
+        let value = (0..size).fold(0, |value, i| {
+            value | (self.read_byte(addr + i) as u32) << (8 * i)
+        });
+
+        Ok(value)
     }
 
     /// Writes the lower `size` (1, 2, or 4) bytes of `data` to memory.
-    pub fn write(&mut self, addr: usize, size: usize, data: u32) {
+    ///
+    /// Misaligned writes are handled according to the misaligned policy.
+    pub fn write(
+        &mut self,
+        addr: usize,
+        size: usize,
+        data: u32,
+    ) -> Result<(), Exception> {
+        self.check_access(addr, size, Exception::StoreAddressMisaligned)?;
+
+        if let Some(i) = self.device_index(addr, size) {
+            let mapped = &mut self.devices[i];
+            mapped.device.write(addr - mapped.base, size, data);
+            return Ok(());
+        }
+
+        for i in 0..size {
+            self.write_byte(addr + i, (data >> (8 * i)) as u8);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of extra cycles needed to complete an access.
+    ///
+    /// Naturally aligned accesses complete in a single cycle.
+    pub fn access_cycles(&self, addr: usize, size: usize) -> u32 {
+        if is_aligned(addr, size) {
+            return 0;
+        }
+
+        match self.misaligned_policy {
+            MisalignedPolicy::Trap => 0,
+            MisalignedPolicy::Split => {
+                let first_word = addr >> 2;
+                let last_word = (addr + size - 1) >> 2;
+                (last_word - first_word) as u32
+            }
+            MisalignedPolicy::Emulate(cost) => cost,
+        }
+    }
+
+    /// Validates access size, range and alignment.
+    fn check_access(
+        &self,
+        addr: usize,
+        size: usize,
+        misaligned: Exception,
+    ) -> Result<(), Exception> {
+        if size != 1 && size != 2 && size != 4 {
+            panic!("Can only access 1, 2, or 4 bytes at a time");
+        }
+
+        if !self.contains(addr, size) {
+            panic!("Address {:#0x} out of range", addr);
+        }
+
+        self.check_alignment(addr, size, misaligned)
+    }
+
+    /// Indicates the `size` bytes from `addr` on are all in memory or in one
+    /// device, so accessing them doesn't panic.
+    pub fn contains(&self, addr: usize, size: usize) -> bool {
+        match addr.checked_add(size) {
+            Some(end) => {
+                end <= self.mem.len() * 4 ||
+                    self.device_index(addr, size).is_some()
+            }
+            None => false,
+        }
+    }
+
+    /// Raises `misaligned` if the access isn't naturally aligned and the
+    /// policy is to trap.
+    pub fn check_alignment(
+        &self,
+        addr: usize,
+        size: usize,
+        misaligned: Exception,
+    ) -> Result<(), Exception> {
+        if !is_aligned(addr, size) &&
+            self.misaligned_policy == MisalignedPolicy::Trap
+        {
+            debug!("Misaligned {}-byte access at {:#0x}", size, addr);
+            return Err(misaligned);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the index of the device that `size` bytes at `addr` map to.
+    fn device_index(&self, addr: usize, size: usize) -> Option<usize> {
+        self.devices.iter().position(|d| {
+            d.contains(addr) && d.contains(addr + size - 1)
+        })
+    }
+
+    /// Reads the byte at `addr`.
+    fn read_byte(&self, addr: usize) -> u8 {
         // Split byte address into word address and byte offset
         let word_addr = addr >> 2;
-        let byte_offset = addr & 0x3;
-        let byte_offset_in_bits = 8 * byte_offset;
+        let byte_offset_in_bits = 8 * (addr & 0x3);
 
-        if word_addr >= self.mem.len() {
-            panic!("Address {:#0x} out of range", word_addr);
+        (self.mem[word_addr] >> byte_offset_in_bits) as u8
+    }
+
+    /// Writes `data` to the byte at `addr`.
+    fn write_byte(&mut self, addr: usize, data: u8) {
+        // Split byte address into word address and byte offset
+        let word_addr = addr >> 2;
+        let byte_offset_in_bits = 8 * (addr & 0x3);
+
+        let mask = 0xff << byte_offset_in_bits;
+        let masked_current_word = self.mem[word_addr] & !mask;
+        self.mem[word_addr] =
+            ((data as u32) << byte_offset_in_bits) | masked_current_word;
+    }
+}
+
+
+impl Checkpoint for DataMemory {
+    /// Saves RAM, the store buffer and each device in attachment order.
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.mem.len());
+        for &word in self.mem.iter() {
+            w.u32(word);
         }
+        w.u32(self.port_busy);
 
-        if byte_offset + size > 4 {
-            panic!("Unaligned memory access");
+        w.bool(self.store_buffer.is_some());
+        if let Some(ref store_buffer) = self.store_buffer {
+            store_buffer.save(w);
         }
 
-        let current_word = self.mem[word_addr];
-        let mask = match size {
-            1 => 0xff,
-            2 => 0xffff,
-            4 => 0xffffffff,
-            _ => panic!("Can only write 1, 2, or 4 bytes at a time"),
-        };
-        let mask = mask << byte_offset_in_bits;
-        let masked_current_word = current_word & !mask;
-        let new_word = (data << byte_offset_in_bits) | masked_current_word;
+        w.usize(self.devices.len());
+        for mapped in self.devices.iter() {
+            w.usize(mapped.base);
+            mapped.device.save(w);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_usize("memory size", self.mem.len())?;
+        for word in self.mem.iter_mut() {
+            *word = r.u32()?;
+        }
+        self.port_busy = r.u32()?;
 
-        // Write back
-        self.mem[word_addr] = new_word;
+        match (r.bool()?, self.store_buffer.as_mut()) {
+            (true, Some(store_buffer)) => store_buffer.restore(r)?,
+            (false, None) => {}
+            _ => return Err(checkpoint::invalid("store buffer mismatch")),
+        }
+
+        r.expect_usize("device count", self.devices.len())?;
+        for mapped in self.devices.iter_mut() {
+            r.expect_usize("device base", mapped.base)?;
+            mapped.device.restore(r)?;
+        }
+
+        Ok(())
     }
 }
 
 
+/// Splits a store into per-word pending stores.
+fn split_store(
+    addr: usize,
+    size: usize,
+    data: u32,
+    device: bool,
+) -> Vec<PendingStore> {
+    let mut stores: Vec<PendingStore> = Vec::with_capacity(2);
+
+    for i in 0..size {
+        let byte_addr = addr + i;
+        let word = byte_addr & !0x3;
+        let offset = byte_addr & 0x3;
+        let byte = (data >> (8 * i)) & 0xff;
+
+        if stores.last().map(|s| s.addr) != Some(word) {
+            stores.push(PendingStore {
+                addr: word,
+                data: 0,
+                mask: 0,
+                device,
+            });
+        }
+
+        let store = stores.last_mut().unwrap();
+        store.data |= byte << (8 * offset);
+        store.mask |= 1 << offset;
+    }
+
+    stores
+}
+
+
+/// Indicates `addr` is a multiple of `size`.
+fn is_aligned(addr: usize, size: usize) -> bool {
+    (addr & (size - 1)) == 0
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use memory::store_buffer::DrainPolicy;
 
     #[test]
     fn common_constructor() {
@@ -87,11 +526,11 @@ mod tests {
         let mut addr = 0x0;
         let mut size = 1;
 
-        assert_eq!(mem.read(addr, size), 0);
-        mem.write(addr, size, 0xff);
+        assert_eq!(mem.read(addr, size), Ok(0));
+        mem.write(addr, size, 0xff).unwrap();
         addr = 0x0;
         size = 4;
-        assert_eq!(mem.read(addr, size), 0xff);
+        assert_eq!(mem.read(addr, size), Ok(0xff));
     }
 
     #[test]
@@ -100,11 +539,11 @@ mod tests {
         let mut addr = 0x1;
         let mut size = 1;
 
-        assert_eq!(mem.read(addr, size), 0);
-        mem.write(addr, size, 0xff);
+        assert_eq!(mem.read(addr, size), Ok(0));
+        mem.write(addr, size, 0xff).unwrap();
         addr = 0x0;
         size = 4;
-        assert_eq!(mem.read(addr, size), 0xff00);
+        assert_eq!(mem.read(addr, size), Ok(0xff00));
     }
 
     #[test]
@@ -113,11 +552,11 @@ mod tests {
         let mut addr = 0x2;
         let mut size = 1;
 
-        assert_eq!(mem.read(addr, size), 0);
-        mem.write(addr, size, 0xff);
+        assert_eq!(mem.read(addr, size), Ok(0));
+        mem.write(addr, size, 0xff).unwrap();
         addr = 0x0;
         size = 4;
-        assert_eq!(mem.read(addr, size), 0xff0000);
+        assert_eq!(mem.read(addr, size), Ok(0xff0000));
     }
 
     #[test]
@@ -126,11 +565,11 @@ mod tests {
         let mut addr = 0x3;
         let mut size = 1;
 
-        assert_eq!(mem.read(addr, size), 0);
-        mem.write(addr, size, 0xff);
+        assert_eq!(mem.read(addr, size), Ok(0));
+        mem.write(addr, size, 0xff).unwrap();
         addr = 0x0;
         size = 4;
-        assert_eq!(mem.read(addr, size), 0xff000000);
+        assert_eq!(mem.read(addr, size), Ok(0xff000000));
     }
 
     #[test]
@@ -139,9 +578,9 @@ mod tests {
         let addr = 0x4;
         let size = 2;
 
-        assert_eq!(mem.read(addr, size), 0);
-        mem.write(addr, size, 0xf0f0);
-        assert_eq!(mem.read(addr, size), 0xf0f0);
+        assert_eq!(mem.read(addr, size), Ok(0));
+        mem.write(addr, size, 0xf0f0).unwrap();
+        assert_eq!(mem.read(addr, size), Ok(0xf0f0));
     }
 
     #[test]
@@ -150,9 +589,9 @@ mod tests {
         let addr = 0x6;
         let size = 2;
 
-        assert_eq!(mem.read(addr, size), 0);
-        mem.write(addr, size, 0xf0f0);
-        assert_eq!(mem.read(addr, size), 0xf0f0);
+        assert_eq!(mem.read(addr, size), Ok(0));
+        mem.write(addr, size, 0xf0f0).unwrap();
+        assert_eq!(mem.read(addr, size), Ok(0xf0f0));
     }
 
     #[test]
@@ -161,29 +600,240 @@ mod tests {
         let addr = 0x4;
         let size = 4;
 
-        assert_eq!(mem.read(addr, size), 0);
-        mem.write(addr, size, 0xf0f0f0f0);
-        assert_eq!(mem.read(addr, size), 0xf0f0f0f0);
+        assert_eq!(mem.read(addr, size), Ok(0));
+        mem.write(addr, size, 0xf0f0f0f0).unwrap();
+        assert_eq!(mem.read(addr, size), Ok(0xf0f0f0f0));
     }
 
     #[test]
-    #[should_panic]
     fn unaligned_halfword() {
-        let mem = DataMemory::new(2);
+        let mut mem = DataMemory::new(2);
+        mem.set_misaligned_policy(MisalignedPolicy::Trap);
         let addr = 0x3;
         let size = 2;
         // Attempt to read addrs 0x3 and 0x4, which crosses a word boundary
-        mem.read(addr, size);
+        assert_eq!(
+            mem.read(addr, size),
+            Err(Exception::LoadAddressMisaligned)
+        );
+        assert_eq!(
+            mem.write(addr, size, 0xffff),
+            Err(Exception::StoreAddressMisaligned)
+        );
     }
 
     #[test]
-    #[should_panic]
     fn unaligned_word() {
-        let mem = DataMemory::new(2);
+        let mut mem = DataMemory::new(2);
+        mem.set_misaligned_policy(MisalignedPolicy::Trap);
         let addr = 0x2;
         let size = 4;
         // Attempt to read addrs 0x2 through 0x5, which crosses a word boundary
-        mem.read(addr, size);
+        assert_eq!(
+            mem.read(addr, size),
+            Err(Exception::LoadAddressMisaligned)
+        );
+        assert_eq!(
+            mem.write(addr, size, 0xffff),
+            Err(Exception::StoreAddressMisaligned)
+        );
+    }
+
+    #[test]
+    fn unaligned_within_word() {
+        let mut mem = DataMemory::new(2);
+        mem.set_misaligned_policy(MisalignedPolicy::Trap);
+        // Halfword at 0x1 doesn't cross a word but is still misaligned
+        assert_eq!(mem.read(0x1, 2), Err(Exception::LoadAddressMisaligned));
+        assert_eq!(
+            mem.write(0x1, 2, 0xffff),
+            Err(Exception::StoreAddressMisaligned)
+        );
+    }
+
+    #[test]
+    fn default_policy() {
+        let mut mem = DataMemory::new(2);
+        // Accesses within a word complete at no cost, as they always have
+        assert_eq!(mem.write(0x1, 2, 0xffff), Ok(()));
+        assert_eq!(mem.read(0x0, 4), Ok(0x00ffff00));
+        assert_eq!(mem.access_cycles(0x1, 2), 0);
+        assert_eq!(mem.access_cycles(0x3, 2), 1);
+    }
+
+    #[test]
+    fn split_across_word() {
+        let mut mem = DataMemory::new(2);
+        mem.set_misaligned_policy(MisalignedPolicy::Split);
+        mem.write(0x2, 4, 0xaabbccdd).unwrap();
+        assert_eq!(mem.read(0x0, 4), Ok(0xccdd0000));
+        assert_eq!(mem.read(0x4, 4), Ok(0x0000aabb));
+        assert_eq!(mem.read(0x2, 4), Ok(0xaabbccdd));
+        assert_eq!(mem.read(0x3, 2), Ok(0xbbcc));
+        assert_eq!(mem.access_cycles(0x2, 4), 1);
+        assert_eq!(mem.access_cycles(0x3, 2), 1);
+        assert_eq!(mem.access_cycles(0x1, 2), 0); // within one word
+        assert_eq!(mem.access_cycles(0x4, 4), 0);
+    }
+
+    #[test]
+    fn split_across_page() {
+        // 2 pages of 4 KiB
+        let mut mem = DataMemory::new(2048);
+        mem.set_misaligned_policy(MisalignedPolicy::Split);
+        mem.write(0xffe, 4, 0x11223344).unwrap();
+        assert_eq!(mem.read(0xffc, 4), Ok(0x33440000));
+        assert_eq!(mem.read(0x1000, 4), Ok(0x00001122));
+        assert_eq!(mem.read(0xffe, 4), Ok(0x11223344));
+        assert_eq!(mem.read(0xfff, 2), Ok(0x2233));
+        assert_eq!(mem.access_cycles(0xfff, 2), 1);
+    }
+
+    #[test]
+    fn emulate() {
+        let mut mem = DataMemory::new(2048);
+        mem.set_misaligned_policy(MisalignedPolicy::Emulate(10));
+        mem.write(0xfff, 4, 0x11223344).unwrap();
+        assert_eq!(mem.read(0xfff, 4), Ok(0x11223344));
+        assert_eq!(mem.read(0x1, 2), Ok(0));
+        assert_eq!(mem.access_cycles(0xfff, 4), 10);
+        assert_eq!(mem.access_cycles(0x1, 2), 10);
+        assert_eq!(mem.access_cycles(0x1000, 4), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn split_outside_range() {
+        let mut mem = DataMemory::new(2);
+        mem.set_misaligned_policy(MisalignedPolicy::Split);
+        // Bytes 0x6 through 0x9 run past the end of memory
+        mem.read(0x6, 4).unwrap();
+    }
+
+    /// A device with a single read-write word.
+    struct Scratch(u32);
+
+    impl Device for Scratch {
+        fn size(&self) -> usize {
+            4
+        }
+
+        fn read(&self, _offset: usize, _size: usize) -> u32 {
+            self.0
+        }
+
+        fn write(&mut self, _offset: usize, _size: usize, data: u32) {
+            self.0 = data;
+        }
+    }
+
+    #[test]
+    fn device() {
+        let mut mem = DataMemory::new(2);
+        mem.attach(0x100, Box::new(Scratch(0)));
+        mem.write(0x100, 4, 0xabcd).unwrap();
+        assert_eq!(mem.read(0x100, 4), Ok(0xabcd));
+        assert_eq!(mem.read(0x0, 4), Ok(0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn device_overlaps_memory() {
+        let mut mem = DataMemory::new(2);
+        mem.attach(0x4, Box::new(Scratch(0)));
+    }
+
+    #[test]
+    fn blocking_store() {
+        let mut mem = DataMemory::new(2);
+        mem.set_write_latency(3);
+        assert_eq!(mem.store(0x0, 4, 0x1234), Ok(3));
+        assert_eq!(mem.read(0x0, 4), Ok(0x1234));
+    }
+
+    #[test]
+    fn store_buffer_forwarding() {
+        let mut mem = DataMemory::new(2);
+        mem.set_write_latency(3);
+        mem.set_store_buffer(Some(StoreBuffer::new(4, DrainPolicy::Lazy)));
+        mem.write(0x0, 4, 0x11223344).unwrap();
+
+        assert_eq!(mem.store(0x2, 2, 0xaabb), Ok(0));
+        assert_eq!(mem.read(0x0, 4), Ok(0x11223344));
+        assert_eq!(mem.load(0x0, 4), Ok((0xaabb3344, 0)));
+        assert_eq!(mem.store_buffer().unwrap().stats.forwarded_loads, 1);
+
+        assert_eq!(mem.fence(), 4);
+        assert_eq!(mem.read(0x0, 4), Ok(0xaabb3344));
+        assert!(mem.store_buffer().unwrap().is_empty());
+    }
+
+    #[test]
+    fn store_buffer_full() {
+        let mut mem = DataMemory::new(2);
+        mem.set_write_latency(1);
+        mem.set_store_buffer(Some(StoreBuffer::new(1, DrainPolicy::Lazy)));
+
+        assert_eq!(mem.store(0x0, 4, 1), Ok(0));
+        assert_eq!(mem.store(0x4, 4, 2), Ok(2));
+        assert_eq!(mem.read(0x0, 4), Ok(1));
+
+        let stats = mem.store_buffer().unwrap().stats;
+        assert_eq!(stats.full_stall_cycles, 2);
+        assert_eq!(stats.blocking_stall_cycles, 2);
+        assert_eq!(stats.stalls_saved(), 0);
+    }
+
+    #[test]
+    fn store_buffer_split_bypass() {
+        let mut mem = DataMemory::new(2);
+        mem.set_write_latency(1);
+        mem.set_store_buffer(Some(StoreBuffer::new(1, DrainPolicy::Lazy)));
+
+        // Both halves of a word-crossing store don't fit in one entry
+        assert_eq!(mem.store(0x0, 1, 0xaa), Ok(0));
+        assert_eq!(mem.store(0x2, 4, 0x44332211), Ok(4));
+        assert!(mem.store_buffer().unwrap().is_empty());
+        assert_eq!(mem.read(0x0, 4), Ok(0x221100aa));
+        assert_eq!(mem.read(0x4, 4), Ok(0x00004433));
+    }
+
+    #[test]
+    fn store_buffer_drains_when_idle() {
+        let mut mem = DataMemory::new(2);
+        mem.set_write_latency(1);
+        mem.set_store_buffer(Some(StoreBuffer::new(2, DrainPolicy::Eager)));
+
+        mem.store(0x4, 4, 0xcafe).unwrap();
+        mem.tick();
+        assert_eq!(mem.read(0x4, 4), Ok(0));
+        mem.tick();
+        assert_eq!(mem.read(0x4, 4), Ok(0xcafe));
+    }
+
+    #[test]
+    fn store_buffer_device_load_drains() {
+        let mut mem = DataMemory::new(2);
+        mem.attach(0x100, Box::new(Scratch(0)));
+        mem.set_store_buffer(Some(StoreBuffer::new(2, DrainPolicy::Lazy)));
+
+        mem.store(0x100, 4, 0xabcd).unwrap();
+        assert_eq!(mem.load(0x100, 4), Ok((0xabcd, 1)));
+        let stats = mem.store_buffer().unwrap().stats;
+        assert_eq!(stats.drain_stall_cycles, 1);
+    }
+
+    #[test]
+    fn contains() {
+        let mut mem = DataMemory::new(2);
+        mem.attach(0x100, Box::new(Scratch(0)));
+
+        assert!(mem.contains(0x4, 4));
+        assert!(!mem.contains(0x6, 4));
+        assert!(!mem.contains(0x8, 1));
+        assert!(mem.contains(0x100, 4));
+        assert!(!mem.contains(0x102, 4));
+        assert!(!mem.contains(usize::MAX, 1));
     }
 
     #[test]
@@ -194,7 +844,7 @@ mod tests {
         // Try to read memory address 0x8
         let addr = 0x8;
         let size = 1;
-        mem.read(addr, size);
+        mem.read(addr, size).unwrap();
     }
 
     #[test]
@@ -205,7 +855,7 @@ mod tests {
         // Try to write to memory address 0x8
         let addr = 0x8;
         let size = 1;
-        mem.write(addr, size, 0x1);
+        mem.write(addr, size, 0x1).unwrap();
     }
 
 }
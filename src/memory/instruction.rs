@@ -1,12 +1,15 @@
 //! Read-only instruction memory.
 //!
-//! Provides a loader for disassembler output.
+//! Provides loaders for disassembler output and ELF executables, which also
+//! read their symbols.
 
 
 use regex::{Captures, Regex};
 
 use consts::HALT;
+use elf::Elf;
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -28,16 +31,29 @@ const RE: &str = r"(?x)
     .*$
 ";
 
+/// Regex to parse symbols in disassembler output
+const SYMBOL_RE: &str = r"(?x)
+    ^(?P<addr>[[:xdigit:]]+)    # Captures (addr) <name>:
+    [[:blank:]]+
+    <(?P<name>[^>]+)>:          # Captures addr <(name)>:
+    [[:blank:]]*$
+";
+
 
 /// A read-only instruction memory trait.
 pub trait InstructionMemory {
     fn read(&self, addr: usize) -> u32;
+
+    /// Returns the size of the memory in bytes.
+    fn size(&self) -> usize;
 }
 
 
 /// Instruction memory that populates memory from disassembler output.
 pub struct DisassemblyInstructionMemory {
     mem: Vec<u32>,
+    /// Addresses by symbol name
+    symbols: BTreeMap<String, u32>,
 }
 
 
@@ -47,36 +63,50 @@ impl DisassemblyInstructionMemory {
     /// `disassembly` must be an open text file containing lines of the form:
     ///
     /// ```text
-    ///      16c:	00 15 05 13    addi x10 , x10 , 1
+    ///      16c:    00 15 05 13    addi x10 , x10 , 1
+    /// ```
+    ///
+    /// The first such matching line must have address 0. Symbols are read
+    /// from lines of the form:
+    ///
+    /// ```text
+    /// 14 <ctors_initialized>:
     /// ```
     ///
-    /// The first such matching line must have address 0.
-    /// Non-matching lines are ignored.
+    /// Other lines are ignored.
     ///
     pub fn new(disassembly: &File) -> DisassemblyInstructionMemory {
         let file = BufReader::new(disassembly);
         let mut mem = Vec::new();
+        let mut symbols = BTreeMap::new();
         let regex = Regex::new(RE).unwrap();
+        let symbol_regex = Regex::new(SYMBOL_RE).unwrap();
 
         // Load each line of disassembly into memory
         for line in file.lines() {
             let l = line.expect("failed to read line");
-            match regex.captures(&l) {
-                Some(caps) => {
-                    let addr = extract_addr(&caps).unwrap();
-                    // Test that addr matches the actual location in memory
-                    assert_eq!(addr, (mem.len() * 4) as u32);
-                    let insn = extract_insn(&caps).unwrap();
-                    mem.push(insn);
-                }
-                // Ignore lines that don't match the regex
-                None => {}
+            if let Some(caps) = symbol_regex.captures(&l) {
+                let addr = extract_addr(&caps).unwrap();
+                symbols.insert(caps["name"].to_string(), addr);
+            }
+            // Ignore lines that don't match the regex
+            if let Some(caps) = regex.captures(&l) {
+                let addr = extract_addr(&caps).unwrap();
+                // Test that addr matches the actual location in memory
+                assert_eq!(addr, (mem.len() * 4) as u32);
+                let insn = extract_insn(&caps).unwrap();
+                mem.push(insn);
             }
         }
 
         mem.push(HALT);
 
-        DisassemblyInstructionMemory { mem }
+        DisassemblyInstructionMemory { mem, symbols }
+    }
+
+    /// Returns the addresses of the symbols in the disassembly by name.
+    pub fn symbols(&self) -> &BTreeMap<String, u32> {
+        &self.symbols
     }
 }
 
@@ -100,6 +130,104 @@ impl InstructionMemory for DisassemblyInstructionMemory {
 
         self.mem[word_addr]
     }
+
+    fn size(&self) -> usize {
+        self.mem.len() * 4
+    }
+}
+
+
+/// Instruction memory that populates memory from the loadable segments of
+/// an ELF executable.
+///
+/// Memories start at address 0, so the image is moved there: addresses are
+/// offset by the lowest segment address, the base. Programs that address
+/// memory relative to the PC, like the riscv-tests environment, run
+/// unchanged.
+pub struct ElfInstructionMemory {
+    mem: Vec<u32>,
+    base: u32,
+    entry: u32,
+    /// Offset addresses by symbol name
+    symbols: BTreeMap<String, u32>,
+}
+
+
+impl ElfInstructionMemory {
+    /// Constructs a new `ElfInstructionMemory` holding the segments of
+    /// `elf`.
+    ///
+    /// Gaps between segments and uninitialized data read as zero. Symbols
+    /// outside the image are dropped.
+    pub fn new(elf: &Elf) -> ElfInstructionMemory {
+        let base = elf.segments.iter().map(|s| s.addr).min().unwrap_or(0);
+        let end = elf.segments
+            .iter()
+            .map(|s| (s.addr - base + s.mem_size) as usize)
+            .max()
+            .unwrap_or(0);
+
+        let mut mem = vec![0u32; end.div_ceil(4)];
+        for segment in &elf.segments {
+            let start = (segment.addr - base) as usize;
+            for (i, &byte) in segment.data.iter().enumerate() {
+                let addr = start + i;
+                mem[addr >> 2] |= (byte as u32) << (8 * (addr & 0x3));
+            }
+        }
+
+        let symbols = elf.symbols
+            .iter()
+            .filter(|&(_, &addr)| addr >= base && addr - base <= end as u32)
+            .map(|(name, &addr)| (name.clone(), addr - base))
+            .collect();
+
+        ElfInstructionMemory {
+            mem,
+            base,
+            entry: elf.entry.wrapping_sub(base),
+            symbols,
+        }
+    }
+
+    /// Returns the link address of address 0.
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Returns the address execution starts at.
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// Returns the addresses of the symbols in the executable by name.
+    pub fn symbols(&self) -> &BTreeMap<String, u32> {
+        &self.symbols
+    }
+
+    /// Returns the image, a word at a time, to load into data memory.
+    pub fn words(&self) -> &[u32] {
+        &self.mem
+    }
+}
+
+
+impl InstructionMemory for ElfInstructionMemory {
+    /// Reads an instruction from `InstructionMemory`.
+    ///
+    /// Addresses past the image read as zero, an illegal instruction. The
+    /// fetch stage raises an access fault for them before reading.
+    fn read(&self, addr: usize) -> u32 {
+        if addr & 0x3 != 0 {
+            panic!("Unaligned memory access at {:#0x}", addr);
+        }
+
+        self.mem.get(addr >> 2).cloned().unwrap_or(0)
+    }
+
+    fn size(&self) -> usize {
+        self.mem.len() * 4
+    }
 }
 
 
@@ -134,22 +262,26 @@ impl InstructionMemory for TestInstructionMemory {
 
         self.mem[word_addr]
     }
+
+    fn size(&self) -> usize {
+        self.mem.len() * 4
+    }
 }
 
 
 /// Extracts regex captures related to the memory address and converts to u32.
 fn extract_addr(caps: &Captures) -> Result<u32, ParseIntError> {
     let s = caps.name("addr").unwrap().as_str();
-    u32::from_str_radix(&s, 16)
+    u32::from_str_radix(s, 16)
 }
 
 
 /// Extracts regex captures related to the instruction and converts to u32.
 fn extract_insn(caps: &Captures) -> Result<u32, ParseIntError> {
     let s: String = caps.name("byte1").unwrap().as_str().to_owned() +
-        &caps.name("byte2").unwrap().as_str().to_owned() +
-        &caps.name("byte3").unwrap().as_str().to_owned() +
-        &caps.name("byte4").unwrap().as_str().to_owned();
+        caps.name("byte2").unwrap().as_str() +
+        caps.name("byte3").unwrap().as_str() +
+        caps.name("byte4").unwrap().as_str();
 
     u32::from_str_radix(&s, 16)
 }
@@ -174,6 +306,41 @@ mod tests {
         assert!(needle.captures(haystack).is_none());
     }
 
+    #[test]
+    fn symbol_regex() {
+        let haystack: &str = "1c4 <FAIL____src_ins_assembly_test_s>:";
+        let needle = Regex::new(SYMBOL_RE).unwrap();
+        let caps = needle.captures(haystack).unwrap();
+        assert_eq!("1c4", &caps["addr"]);
+        assert_eq!("FAIL____src_ins_assembly_test_s", &caps["name"]);
+
+        let haystack: &str = "     16c:	00 15 05 13    addi x10 , x10 , 1";
+        assert!(needle.captures(haystack).is_none());
+    }
+
+    #[test]
+    fn elf() {
+        use elf;
+
+        let words = [0x00_10_00_93, 0x00_00_00_6f];
+        let symbols = [
+            ("_start", 0x8000_0000),
+            ("tohost", 0x8000_0008),
+            ("__global_pointer$", 0x8000_0800),
+        ];
+        let bytes = elf::build(0x8000_0000, 0x8000_0004, &words, 8, &symbols);
+        let insns = ElfInstructionMemory::new(&Elf::parse(&bytes).unwrap());
+
+        assert_eq!(insns.base(), 0x8000_0000);
+        assert_eq!(insns.entry(), 0x4);
+        assert_eq!(insns.size(), 16);
+        assert_eq!(insns.words(), &[0x00_10_00_93, 0x00_00_00_6f, 0, 0]);
+        assert_eq!(insns.read(0x4), 0x00_00_00_6f);
+        assert_eq!(insns.read(0x100), 0);
+        assert_eq!(insns.symbols()["tohost"], 0x8);
+        assert!(!insns.symbols().contains_key("__global_pointer$"));
+    }
+
     #[test]
     fn extract_addr_from_regex_captures() {
         let haystack: &str = "     16c:	00 15 05 13    addi x10 , x10 , 1";
@@ -0,0 +1,99 @@
+//! Memory-mapped I/O.
+//!
+//! Devices are attached to `DataMemory` at a base address, and loads and
+//! stores that fall inside a device's window are forwarded to the device
+//! instead of RAM. Devices are also ticked once per clock cycle, and may
+//! access RAM directly (DMA) while ticking.
+
+
+use checkpoint::{Reader, Writer};
+
+use std::io;
+
+/// A device mapped into the data address space.
+pub trait Device {
+    /// Returns the size in bytes of the device's address window.
+    fn size(&self) -> usize;
+
+    /// Reads `size` (1, 2, or 4) bytes at `offset` into the window.
+    fn read(&self, offset: usize, size: usize) -> u32;
+
+    /// Writes the lower `size` (1, 2, or 4) bytes of `data` at `offset`.
+    fn write(&mut self, offset: usize, size: usize, data: u32);
+
+    /// Advances the device by one clock cycle.
+    fn tick(&mut self, _dma: &mut Dma) {}
+
+    /// Appends the device's state to a checkpoint.
+    ///
+    /// Devices without state that changes need not implement this.
+    fn save(&self, _w: &mut Writer) {}
+
+    /// Restores the state saved by `save`.
+    fn restore(&mut self, _r: &mut Reader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Direct memory access to RAM for devices.
+pub struct Dma<'a> {
+    ram: &'a mut [u32],
+}
+
+
+impl<'a> Dma<'a> {
+    /// Constructs a new `Dma` over `ram`.
+    pub fn new(ram: &'a mut [u32]) -> Dma<'a> {
+        Dma { ram }
+    }
+
+    /// Indicates the `len` bytes from `addr` on are all inside RAM.
+    pub fn contains(&self, addr: usize, len: usize) -> bool {
+        addr.checked_add(len)
+            .is_some_and(|end| end <= self.ram.len() * 4)
+    }
+
+    /// Reads the byte at `addr`, or `None` if it is outside RAM.
+    pub fn read_byte(&self, addr: usize) -> Option<u8> {
+        let word = *self.ram.get(addr >> 2)?;
+        Some((word >> (8 * (addr & 0x3))) as u8)
+    }
+
+    /// Writes the byte at `addr`. Returns false if it is outside RAM.
+    pub fn write_byte(&mut self, addr: usize, data: u8) -> bool {
+        let shift = 8 * (addr & 0x3);
+        match self.ram.get_mut(addr >> 2) {
+            Some(word) => {
+                *word = (*word & !(0xff << shift)) | (data as u32) << shift;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+
+/// A device attached at a base address.
+pub struct MappedDevice {
+    pub base: usize,
+    pub device: Box<dyn Device>,
+}
+
+
+impl MappedDevice {
+    /// Constructs a new `MappedDevice`.
+    pub fn new(base: usize, device: Box<dyn Device>) -> MappedDevice {
+        MappedDevice { base, device }
+    }
+
+    /// Indicates `addr` falls inside the device's window.
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr - self.base < self.device.size()
+    }
+
+    /// Indicates the device's window overlaps [`lo`, `hi`).
+    pub fn overlaps(&self, lo: usize, hi: usize) -> bool {
+        lo < self.base + self.device.size() && self.base < hi
+    }
+}
@@ -2,3 +2,6 @@
 
 pub mod data;
 pub mod instruction;
+pub mod mmio;
+pub mod pmp;
+pub mod store_buffer;
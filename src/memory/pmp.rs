@@ -0,0 +1,352 @@
+//! Physical memory protection (PMP) unit.
+//!
+//! Implements the sixteen RV32 PMP entries described in the RISC-V
+//! Privileged Architecture V1.10, section 3.6. The simulator only runs in
+//! machine mode, so an entry restricts accesses only once its lock bit is
+//! set, and accesses that match no entry are allowed.
+
+
+use checkpoint::{Checkpoint, Reader, Writer};
+
+use std::io;
+
+
+/// Number of PMP entries.
+pub const NUM_ENTRIES: usize = 16;
+
+// Fields of a pmpNcfg byte
+pub const R: u8 = 0x01;
+pub const W: u8 = 0x02;
+pub const X: u8 = 0x04;
+pub const TOR: u8 = 0x08;
+pub const NA4: u8 = 0x10;
+pub const NAPOT: u8 = 0x18;
+pub const L: u8 = 0x80;
+const A_MASK: u8 = 0x18;
+const A_SHIFT: u8 = 3;
+
+
+/// Kind of memory access being checked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+
+/// Address-matching mode of a PMP entry (the `A` field of pmpNcfg).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressMatching {
+    /// Entry disabled
+    Off,
+    /// Top of range; the previous entry's address is the bottom
+    Tor,
+    /// Naturally aligned four-byte region
+    Na4,
+    /// Naturally aligned power-of-two region, eight bytes or larger
+    Napot,
+}
+
+
+/// A physical memory protection unit with 16 entries.
+#[derive(Clone, Copy, Debug)]
+pub struct Pmp {
+    cfg: [u8; NUM_ENTRIES],
+    addr: [u32; NUM_ENTRIES],
+}
+
+
+impl Pmp {
+    /// Constructs a new `Pmp` with every entry disabled and unlocked.
+    pub fn new() -> Pmp {
+        Pmp {
+            cfg: [0; NUM_ENTRIES],
+            addr: [0; NUM_ENTRIES],
+        }
+    }
+
+    /// Reads CSR pmpcfg`n` (0-3), which packs the config of 4 entries.
+    pub fn read_cfg(&self, n: usize) -> u32 {
+        (0..4).fold(0, |value, i| {
+            value | (self.cfg[4 * n + i] as u32) << (8 * i)
+        })
+    }
+
+    /// Writes CSR pmpcfg`n` (0-3), skipping entries that are locked.
+    pub fn write_cfg(&mut self, n: usize, value: u32) {
+        for i in 0..4 {
+            let entry = 4 * n + i;
+            if self.is_locked(entry) {
+                continue;
+            }
+            self.cfg[entry] = (value >> (8 * i)) as u8;
+        }
+    }
+
+    /// Reads CSR pmpaddr`i`, which holds bits 33:2 of an address.
+    pub fn read_addr(&self, i: usize) -> u32 {
+        self.addr[i]
+    }
+
+    /// Writes CSR pmpaddr`i` unless it is locked.
+    ///
+    /// The address is also locked when the next entry is a locked TOR entry,
+    /// since it forms that entry's bottom of range.
+    pub fn write_addr(&mut self, i: usize, value: u32) {
+        let next_is_locked_tor = i + 1 < NUM_ENTRIES &&
+            self.is_locked(i + 1) &&
+            self.matching(i + 1) == AddressMatching::Tor;
+
+        if self.is_locked(i) || next_is_locked_tor {
+            return;
+        }
+
+        self.addr[i] = value;
+    }
+
+    /// Returns true if entry `i` has its lock bit set.
+    pub fn is_locked(&self, i: usize) -> bool {
+        self.cfg[i] & L != 0
+    }
+
+    /// Returns the address-matching mode of entry `i`.
+    pub fn matching(&self, i: usize) -> AddressMatching {
+        match (self.cfg[i] & A_MASK) >> A_SHIFT {
+            0 => AddressMatching::Off,
+            1 => AddressMatching::Tor,
+            2 => AddressMatching::Na4,
+            _ => AddressMatching::Napot,
+        }
+    }
+
+    /// Checks whether a machine-mode access of `size` bytes is permitted.
+    ///
+    /// The lowest-numbered entry matching any byte of the access decides the
+    /// outcome. The access fails if it is only partially contained in that
+    /// entry, or if the entry is locked and lacks the needed permission.
+    pub fn check(&self, addr: u32, size: usize, access: Access) -> bool {
+        let start = addr as u64;
+        let end = start + size as u64; // exclusive
+
+        for i in 0..NUM_ENTRIES {
+            let (lo, hi) = match self.range(i) {
+                Some(range) => range,
+                None => continue,
+            };
+
+            if end <= lo || start >= hi {
+                continue; // no byte of the access matches
+            }
+
+            if start < lo || end > hi {
+                return false; // partial match
+            }
+
+            if !self.is_locked(i) {
+                return true;
+            }
+
+            let permission = match access {
+                Access::Read => R,
+                Access::Write => W,
+                Access::Execute => X,
+            };
+
+            return self.cfg[i] & permission != 0;
+        }
+
+        true
+    }
+
+    /// Returns the byte address range [lo, hi) covered by entry `i`.
+    fn range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[i] as u64;
+
+        match self.matching(i) {
+            AddressMatching::Off => None,
+            AddressMatching::Tor => {
+                let lo = match i {
+                    0 => 0,
+                    _ => (self.addr[i - 1] as u64) << 2,
+                };
+                Some((lo, addr << 2))
+            }
+            AddressMatching::Na4 => Some((addr << 2, (addr << 2) + 4)),
+            AddressMatching::Napot => {
+                // Trailing ones encode the region size as 2^(ones + 3)
+                let ones = (!addr).trailing_zeros() as u64;
+                let size = 1u64 << (ones + 3);
+                let lo = (addr & !((1u64 << ones) - 1)) << 2;
+                Some((lo, lo + size))
+            }
+        }
+    }
+}
+
+
+impl Default for Pmp {
+    fn default() -> Pmp {
+        Pmp::new()
+    }
+}
+
+
+impl Checkpoint for Pmp {
+    fn save(&self, w: &mut Writer) {
+        for i in 0..NUM_ENTRIES {
+            w.u8(self.cfg[i]);
+            w.u32(self.addr[i]);
+        }
+    }
+
+    /// Restores every entry, including locked ones.
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        for i in 0..NUM_ENTRIES {
+            self.cfg[i] = r.u8()?;
+            self.addr[i] = r.u32()?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_allows_everything() {
+        let pmp = Pmp::new();
+        assert!(pmp.check(0x0, 4, Access::Read));
+        assert!(pmp.check(0xfffffffc, 4, Access::Write));
+        assert!(pmp.check(0x100, 4, Access::Execute));
+    }
+
+    #[test]
+    fn unlocked_entry_does_not_restrict_machine_mode() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x100 >> 2);
+        pmp.write_cfg(0, NA4 as u32);
+        assert!(pmp.check(0x100, 4, Access::Write));
+    }
+
+    #[test]
+    fn tor() {
+        let mut pmp = Pmp::new();
+        // [0x100, 0x200) read-only, locked
+        pmp.write_addr(0, 0x100 >> 2);
+        pmp.write_addr(1, 0x200 >> 2);
+        let entry1 = TOR | R | L;
+        pmp.write_cfg(0, (entry1 as u32) << 8);
+
+        assert!(pmp.check(0x100, 4, Access::Read));
+        assert!(pmp.check(0x1fc, 4, Access::Read));
+        assert!(!pmp.check(0x100, 4, Access::Write));
+        assert!(!pmp.check(0x1fc, 1, Access::Execute));
+        assert!(pmp.check(0xfc, 4, Access::Write));
+        assert!(pmp.check(0x200, 4, Access::Write));
+    }
+
+    #[test]
+    fn tor_entry_zero_starts_at_address_zero() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x80 >> 2);
+        let entry0 = TOR | L;
+        pmp.write_cfg(0, entry0 as u32);
+
+        assert!(!pmp.check(0x0, 4, Access::Read));
+        assert!(!pmp.check(0x7c, 4, Access::Read));
+        assert!(pmp.check(0x80, 4, Access::Read));
+    }
+
+    #[test]
+    fn na4() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x40 >> 2);
+        let entry0 = NA4 | W | L;
+        pmp.write_cfg(0, entry0 as u32);
+
+        assert!(!pmp.check(0x40, 4, Access::Read));
+        assert!(!pmp.check(0x43, 1, Access::Read));
+        assert!(pmp.check(0x40, 2, Access::Write));
+        assert!(pmp.check(0x44, 4, Access::Read));
+    }
+
+    #[test]
+    fn napot() {
+        let mut pmp = Pmp::new();
+        // 256-byte region at 0x400: (0x400 >> 2) | (256 / 8 - 1)
+        pmp.write_addr(0, (0x400 >> 2) | 0x1f);
+        let entry0 = NAPOT | R | L;
+        pmp.write_cfg(0, entry0 as u32);
+
+        assert!(pmp.check(0x400, 4, Access::Read));
+        assert!(pmp.check(0x4fc, 4, Access::Read));
+        assert!(!pmp.check(0x480, 2, Access::Write));
+        assert!(pmp.check(0x3fc, 4, Access::Write));
+        assert!(pmp.check(0x500, 4, Access::Write));
+    }
+
+    #[test]
+    fn napot_whole_address_space() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0xffffffff);
+        let entry0 = NAPOT | R | X | L;
+        pmp.write_cfg(0, entry0 as u32);
+
+        assert!(pmp.check(0x0, 4, Access::Execute));
+        assert!(!pmp.check(0xfffffffc, 4, Access::Write));
+    }
+
+    #[test]
+    fn lowest_entry_has_priority() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x100 >> 2);
+        pmp.write_addr(1, 0xffffffff);
+        let entry0 = NA4 | R | W | L;
+        let entry1 = NAPOT | R | L;
+        pmp.write_cfg(0, entry0 as u32 | (entry1 as u32) << 8);
+
+        assert!(pmp.check(0x100, 4, Access::Write));
+        assert!(!pmp.check(0x104, 4, Access::Write));
+    }
+
+    #[test]
+    fn partial_match_fails() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x100 >> 2);
+        let entry0 = NA4 | R | W | X;
+        pmp.write_cfg(0, entry0 as u32);
+
+        assert!(!pmp.check(0xfe, 4, Access::Read));
+    }
+
+    #[test]
+    fn lock_ignores_writes() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x100 >> 2);
+        let entry0 = NA4 | R | L;
+        pmp.write_cfg(0, entry0 as u32);
+
+        pmp.write_cfg(0, 0xffffff1f);
+        pmp.write_addr(0, 0x0);
+
+        assert_eq!(pmp.read_cfg(0), 0xffffff00 | entry0 as u32);
+        assert_eq!(pmp.read_addr(0), 0x100 >> 2);
+    }
+
+    #[test]
+    fn locked_tor_locks_previous_address() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x100 >> 2);
+        pmp.write_addr(1, 0x200 >> 2);
+        let entry1 = TOR | R | L;
+        pmp.write_cfg(0, (entry1 as u32) << 8);
+
+        pmp.write_addr(0, 0x0);
+        assert_eq!(pmp.read_addr(0), 0x100 >> 2);
+    }
+
+}
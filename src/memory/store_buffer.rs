@@ -0,0 +1,377 @@
+//! Store buffer with optional write combining.
+//!
+//! Retired stores wait in a FIFO for the data memory write port instead of
+//! stalling the MEM stage. Each buffered store occupies the port for the
+//! memory's write latency plus one cycle while it drains. Loads see pending
+//! stores through byte-wise forwarding.
+
+
+use checkpoint::{self, Checkpoint, Reader, Writer};
+
+use std::collections::VecDeque;
+use std::io;
+
+
+/// When the buffer is allowed to drain on its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DrainPolicy {
+    /// Drain whenever the write port is idle
+    Eager,
+    /// Drain only while at least this many stores are pending
+    Watermark(usize),
+    /// Drain only when the buffer is full or a FENCE executes
+    Lazy,
+}
+
+
+/// A store waiting to be written to memory.
+///
+/// Stores are kept per aligned word, with a mask of the bytes written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PendingStore {
+    /// Address of the word
+    pub addr: usize,
+    /// Bytes in word order; only bytes selected by `mask` are valid
+    pub data: u32,
+    /// Bit `i` is set if byte `i` of the word is written
+    pub mask: u8,
+    /// Set for stores to memory-mapped devices, which are never combined
+    pub device: bool,
+}
+
+
+impl PendingStore {
+    /// Returns the pending byte at offset `i` in the word, if any.
+    pub fn byte(&self, i: usize) -> Option<u8> {
+        match self.mask & (1 << i) {
+            0 => None,
+            _ => Some((self.data >> (8 * i)) as u8),
+        }
+    }
+}
+
+
+/// Store buffer counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoreBufferStats {
+    /// Stores that entered the buffer
+    pub stores: u64,
+    /// Stores merged into the youngest pending entry
+    pub combined: u64,
+    /// Loads that took at least one byte from a pending store
+    pub forwarded_loads: u64,
+    /// Cycles stalled waiting for a free entry
+    pub full_stall_cycles: u64,
+    /// Cycles stalled draining the buffer for a FENCE or device load
+    pub drain_stall_cycles: u64,
+    /// Cycles the same stores would have stalled without a buffer
+    pub blocking_stall_cycles: u64,
+}
+
+
+impl StoreBufferStats {
+    /// Returns the total cycles stalled by the buffer.
+    pub fn stall_cycles(&self) -> u64 {
+        self.full_stall_cycles + self.drain_stall_cycles
+    }
+
+    /// Returns the stall cycles saved compared with blocking stores.
+    ///
+    /// Negative if the buffer cost more than it saved.
+    pub fn stalls_saved(&self) -> i64 {
+        self.blocking_stall_cycles as i64 - self.stall_cycles() as i64
+    }
+}
+
+
+/// A FIFO store buffer.
+#[derive(Clone, Debug)]
+pub struct StoreBuffer {
+    depth: usize,
+    policy: DrainPolicy,
+    combining: bool,
+    entries: VecDeque<PendingStore>,
+    progress: u32,
+    pub stats: StoreBufferStats,
+}
+
+
+impl StoreBuffer {
+    /// Constructs a new `StoreBuffer` holding up to `depth` stores.
+    pub fn new(depth: usize, policy: DrainPolicy) -> StoreBuffer {
+        assert!(depth > 0, "Store buffer needs at least one entry");
+
+        StoreBuffer {
+            depth,
+            policy,
+            combining: false,
+            entries: VecDeque::with_capacity(depth),
+            progress: 0,
+            stats: StoreBufferStats::default(),
+        }
+    }
+
+    /// Enables merging stores to the same word as the youngest entry.
+    pub fn set_combining(&mut self, combining: bool) {
+        self.combining = combining;
+    }
+
+    /// Returns the maximum number of pending stores.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the number of pending stores.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Indicates no stores are pending.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the number of free entries.
+    pub fn free(&self) -> usize {
+        self.depth - self.entries.len()
+    }
+
+    /// Adds `store`, merging it into the youngest entry if possible.
+    ///
+    /// The caller must make sure there is a free entry.
+    pub fn push(&mut self, store: PendingStore) {
+        self.stats.stores += 1;
+
+        // The oldest entry can't change once it has started draining
+        let draining = self.entries.len() == 1 && self.progress > 0;
+        if self.combining && !store.device && !draining {
+            if let Some(youngest) = self.entries.back_mut() {
+                if youngest.addr == store.addr && !youngest.device {
+                    let mask = byte_mask(store.mask);
+                    youngest.data = (youngest.data & !mask) |
+                        (store.data & mask);
+                    youngest.mask |= store.mask;
+                    self.stats.combined += 1;
+                    return;
+                }
+            }
+        }
+
+        assert!(self.entries.len() < self.depth, "Store buffer overflow");
+        self.entries.push_back(store);
+    }
+
+    /// Returns the youngest pending value of the byte at `addr`, if any.
+    pub fn forward(&self, addr: usize) -> Option<u8> {
+        let word = addr & !0x3;
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.addr == word)
+            .filter_map(|e| e.byte(addr & 0x3))
+            .next()
+    }
+
+    /// Indicates any stores are pending for memory-mapped devices.
+    pub fn has_device_stores(&self) -> bool {
+        self.entries.iter().any(|e| e.device)
+    }
+
+    /// Advances draining by one idle cycle of the write port.
+    ///
+    /// Returns the oldest store once it has been written, which takes
+    /// `write_latency` + 1 cycles.
+    pub fn tick(&mut self, write_latency: u32) -> Option<PendingStore> {
+        let may_drain = match self.policy {
+            DrainPolicy::Eager => true,
+            DrainPolicy::Watermark(n) => self.entries.len() >= n,
+            DrainPolicy::Lazy => false,
+        };
+
+        // A store that has started draining always completes
+        if self.entries.is_empty() || (!may_drain && self.progress == 0) {
+            return None;
+        }
+
+        self.progress += 1;
+        if self.progress <= write_latency {
+            return None;
+        }
+
+        self.progress = 0;
+        self.entries.pop_front()
+    }
+
+    /// Removes the oldest store without waiting for the port.
+    ///
+    /// Returns the store and the cycles needed to finish writing it.
+    pub fn pop(&mut self, write_latency: u32) -> Option<(PendingStore, u32)> {
+        let store = self.entries.pop_front()?;
+        let cycles = write_latency + 1 - self.progress;
+        self.progress = 0;
+
+        Some((store, cycles))
+    }
+}
+
+
+impl Checkpoint for StoreBuffer {
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.depth);
+        w.usize(self.entries.len());
+        for store in self.entries.iter() {
+            w.usize(store.addr);
+            w.u32(store.data);
+            w.u8(store.mask);
+            w.bool(store.device);
+        }
+        w.u32(self.progress);
+
+        let stats = &self.stats;
+        w.u64(stats.stores);
+        w.u64(stats.combined);
+        w.u64(stats.forwarded_loads);
+        w.u64(stats.full_stall_cycles);
+        w.u64(stats.drain_stall_cycles);
+        w.u64(stats.blocking_stall_cycles);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_usize("store buffer depth", self.depth)?;
+        let n = r.usize()?;
+        if n > self.depth {
+            return Err(checkpoint::invalid("store buffer overflow"));
+        }
+        self.entries.clear();
+        for _ in 0..n {
+            self.entries.push_back(PendingStore {
+                addr: r.usize()?,
+                data: r.u32()?,
+                mask: r.u8()?,
+                device: r.bool()?,
+            });
+        }
+        self.progress = r.u32()?;
+
+        let stats = &mut self.stats;
+        stats.stores = r.u64()?;
+        stats.combined = r.u64()?;
+        stats.forwarded_loads = r.u64()?;
+        stats.full_stall_cycles = r.u64()?;
+        stats.drain_stall_cycles = r.u64()?;
+        stats.blocking_stall_cycles = r.u64()?;
+
+        Ok(())
+    }
+}
+
+
+/// Expands a byte mask to a bit mask.
+fn byte_mask(mask: u8) -> u32 {
+    (0..4).fold(0, |bits, i| match mask & (1 << i) {
+        0 => bits,
+        _ => bits | 0xff << (8 * i),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(addr: usize, data: u32, mask: u8) -> PendingStore {
+        PendingStore {
+            addr,
+            data,
+            mask,
+            device: false,
+        }
+    }
+
+    #[test]
+    fn forwarding_prefers_youngest() {
+        let mut sb = StoreBuffer::new(4, DrainPolicy::Lazy);
+        sb.push(store(0x100, 0x11223344, 0xf));
+        sb.push(store(0x100, 0x0000aa00, 0x2));
+
+        assert_eq!(sb.len(), 2);
+        assert_eq!(sb.forward(0x100), Some(0x44));
+        assert_eq!(sb.forward(0x101), Some(0xaa));
+        assert_eq!(sb.forward(0x103), Some(0x11));
+        assert_eq!(sb.forward(0x104), None);
+    }
+
+    #[test]
+    fn combining() {
+        let mut sb = StoreBuffer::new(2, DrainPolicy::Lazy);
+        sb.set_combining(true);
+        sb.push(store(0x100, 0x000000aa, 0x1));
+        sb.push(store(0x100, 0xbbbb0000, 0xc));
+        sb.push(store(0x104, 0x000000cc, 0x1));
+
+        assert_eq!(sb.len(), 2);
+        assert_eq!(sb.stats.stores, 3);
+        assert_eq!(sb.stats.combined, 1);
+        let (head, _) = sb.pop(0).unwrap();
+        assert_eq!(head, store(0x100, 0xbbbb00aa, 0xd));
+    }
+
+    #[test]
+    fn devices_are_not_combined() {
+        let mut sb = StoreBuffer::new(2, DrainPolicy::Lazy);
+        sb.set_combining(true);
+        let mut reg = store(0x1000_0008, 1, 0xf);
+        reg.device = true;
+        sb.push(reg);
+        sb.push(reg);
+
+        assert_eq!(sb.len(), 2);
+        assert!(sb.has_device_stores());
+    }
+
+    #[test]
+    fn drain_policies() {
+        let mut eager = StoreBuffer::new(4, DrainPolicy::Eager);
+        eager.push(store(0x0, 1, 0xf));
+        assert_eq!(eager.tick(1), None);
+        assert_eq!(eager.tick(1), Some(store(0x0, 1, 0xf)));
+        assert!(eager.is_empty());
+
+        let mut lazy = StoreBuffer::new(4, DrainPolicy::Lazy);
+        lazy.push(store(0x0, 1, 0xf));
+        assert_eq!(lazy.tick(0), None);
+        assert_eq!(lazy.len(), 1);
+
+        let mut watermark = StoreBuffer::new(4, DrainPolicy::Watermark(2));
+        watermark.push(store(0x0, 1, 0xf));
+        assert_eq!(watermark.tick(0), None);
+        watermark.push(store(0x4, 2, 0xf));
+        assert_eq!(watermark.tick(0), Some(store(0x0, 1, 0xf)));
+        assert_eq!(watermark.tick(0), None);
+    }
+
+    #[test]
+    fn pop_charges_remaining_cycles() {
+        let mut sb = StoreBuffer::new(4, DrainPolicy::Eager);
+        sb.push(store(0x0, 1, 0xf));
+        sb.push(store(0x4, 2, 0xf));
+        sb.tick(3);
+
+        assert_eq!(sb.pop(3), Some((store(0x0, 1, 0xf), 3)));
+        assert_eq!(sb.pop(3), Some((store(0x4, 2, 0xf), 4)));
+        assert_eq!(sb.pop(3), None);
+    }
+
+    #[test]
+    fn stalls_saved() {
+        let stats = StoreBufferStats {
+            full_stall_cycles: 4,
+            drain_stall_cycles: 2,
+            blocking_stall_cycles: 20,
+            ..StoreBufferStats::default()
+        };
+        assert_eq!(stats.stall_cycles(), 6);
+        assert_eq!(stats.stalls_saved(), 14);
+    }
+
+}
@@ -0,0 +1,169 @@
+//! ASCII pipeline charts.
+//!
+//! `PipelineChart` collects the stage each instruction occupied during a
+//! window of cycles and formats it as the familiar textbook diagram, one
+//! row per instruction and one column per cycle:
+//!
+//! ```text
+//! cycle                3   4   5   6   7   8   9
+//! 0x0: lw x6, 0(x10)   IF  ID  EX  MEM WB
+//! 0x4: add x7, x6, x6      IF  ID  ID  EX  MEM WB
+//! bubble                           EX  MEM WB
+//! ```
+//!
+//! An instruction repeated in a stage stalled there. Flushed
+//! instructions are marked `X` in the cycle after their last stage.
+
+
+use pipeline::events::{Event, Observer, Occupancy, Stage, STAGES};
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+
+/// Width of a cycle column.
+const CELL_WIDTH: usize = 4;
+
+
+/// An instruction's stages in the window.
+struct Row {
+    label: String,
+    /// Stage names by cycle
+    cells: BTreeMap<u64, &'static str>,
+    /// The row's last stage was WB
+    retiring: bool,
+    /// Cycle of the last recorded stage
+    last: u64,
+}
+
+
+/// Records an ASCII pipeline chart of cycles `first` through `last`.
+pub struct PipelineChart {
+    first: u64,
+    last: u64,
+    /// Rows by fetch order
+    rows: BTreeMap<u64, Row>,
+}
+
+
+impl PipelineChart {
+    /// Constructs a new `PipelineChart` of cycles `first` to `last`,
+    /// inclusive.
+    pub fn new(first: u64, last: u64) -> PipelineChart {
+        PipelineChart {
+            first,
+            last,
+            rows: BTreeMap::new(),
+        }
+    }
+}
+
+
+impl Observer for PipelineChart {
+    fn event(&mut self, _cycle: u64, _event: &Event) {}
+
+    fn cycle(&mut self, cycle: u64, stages: &Occupancy) {
+        // Mark what was flushed at the end of the previous cycle
+        let present: Vec<u64> =
+            stages.iter().filter_map(|s| s.map(|s| s.id)).collect();
+        for (id, row) in self.rows.iter_mut() {
+            let gone = row.last + 1 == cycle && !present.contains(id);
+            if gone && !row.retiring && cycle <= self.last {
+                row.cells.insert(cycle, "X");
+            }
+        }
+
+        if cycle < self.first || cycle > self.last {
+            return;
+        }
+
+        for (slot, stage) in stages.iter().zip(STAGES.iter()) {
+            let slot = match *slot {
+                Some(slot) => slot,
+                None => continue,
+            };
+            let row = self.rows.entry(slot.id).or_insert_with(|| Row {
+                label: slot.label(),
+                cells: BTreeMap::new(),
+                retiring: false,
+                last: cycle,
+            });
+            row.cells.insert(cycle, stage.name());
+            row.retiring = *stage == Stage::Wb;
+            row.last = cycle;
+        }
+    }
+}
+
+
+impl fmt::Display for PipelineChart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label_width = self.rows
+            .values()
+            .map(|row| row.label.len())
+            .max()
+            .unwrap_or(0)
+            .max("cycle".len());
+        let cell_width = CELL_WIDTH.max(self.last.to_string().len() + 1);
+
+        let mut header = format!("{:<1$}  ", "cycle", label_width);
+        for cycle in self.first..self.last + 1 {
+            header.push_str(&format!("{:<1$}", cycle, cell_width));
+        }
+        write!(f, "{}", header.trim_end())?;
+
+        for row in self.rows.values() {
+            let mut line = format!("{:<1$}  ", row.label, label_width);
+            for cycle in self.first..self.last + 1 {
+                let cell = row.cells.get(&cycle).cloned().unwrap_or("");
+                line.push_str(&format!("{:<1$}", cell, cell_width));
+            }
+            write!(f, "\n{}", line.trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pipeline::events::Slot;
+
+    fn slot(id: u64, pc: u32, bubble: bool) -> Option<Slot> {
+        Some(Slot {
+            id,
+            pc,
+            raw_insn: 0x00_15_05_13, // addi x10, x10, 1
+            bubble,
+        })
+    }
+
+    #[test]
+    fn chart() {
+        let mut chart = PipelineChart::new(1, 4);
+        chart.cycle(0, &[slot(1, 0x0, false), None, None, None, None]);
+        chart.cycle(1, &[slot(2, 0x4, false), slot(1, 0x0, false), None,
+                         None, None]);
+        chart.cycle(2, &[slot(3, 0x8, false), slot(2, 0x4, false),
+                         slot(1, 0x0, false), None, None]);
+        chart.cycle(3, &[slot(4, 0xc, false), None, None,
+                         slot(1, 0x0, false), None]);
+        chart.cycle(4, &[slot(5, 0x10, false), slot(4, 0xc, false), None,
+                         None, slot(1, 0x0, false)]);
+        chart.cycle(5, &[None, None, None, None, None]);
+
+        assert_eq!(
+            chart.to_string(),
+            "cycle                   1   2   3   4\n\
+             0x0: addi x10, x10, 1   ID  EX  MEM WB\n\
+             0x4: addi x10, x10, 1   IF  ID  X\n\
+             0x8: addi x10, x10, 1       IF  X\n\
+             0xc: addi x10, x10, 1           IF  ID\n\
+             0x10: addi x10, x10, 1              IF"
+        );
+    }
+
+}
@@ -0,0 +1,400 @@
+//! Typed pipeline events.
+//!
+//! Every cycle, the cycle accurate simulator reports what each stage did to
+//! an `Observer` as a sequence of `Event`s, so tools don't have to scrape
+//! the trace log. `JsonLinesSink` writes them as one JSON object per line.
+
+
+use instruction::{Function, Instruction};
+use memory::data::DataMemory;
+use pipeline::Pipeline;
+use register::RegisterFile;
+
+use std::io::{self, Write};
+
+
+/// A pipeline stage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    If,
+    Id,
+    Ex,
+    Mem,
+    Wb,
+}
+
+
+impl Stage {
+    /// Returns the stage's conventional abbreviation.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::If => "IF",
+            Stage::Id => "ID",
+            Stage::Ex => "EX",
+            Stage::Mem => "MEM",
+            Stage::Wb => "WB",
+        }
+    }
+}
+
+
+/// Why a stage stalled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StallReason {
+    /// A load or CSR read result is needed by the next instruction
+    LoadUse,
+    /// A source register is not written back yet, without forwarding
+    DataHazard,
+    /// A branch resolved in ID needs a result that isn't ready yet
+    BranchOperands,
+    /// A multi-cycle memory access holds the MEM stage
+    Memory,
+}
+
+
+impl StallReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            StallReason::LoadUse => "load-use",
+            StallReason::DataHazard => "data-hazard",
+            StallReason::BranchOperands => "branch-operands",
+            StallReason::Memory => "memory",
+        }
+    }
+}
+
+
+/// Why an instruction was flushed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushCause {
+    /// An older branch or jump went elsewhere than predicted
+    Mispredict,
+    /// An older instruction trapped
+    Trap,
+    /// An older MRET returned from a trap handler
+    TrapReturn,
+}
+
+
+impl FlushCause {
+    pub fn name(self) -> &'static str {
+        match self {
+            FlushCause::Mispredict => "mispredict",
+            FlushCause::Trap => "trap",
+            FlushCause::TrapReturn => "trap-return",
+        }
+    }
+}
+
+
+/// Something a pipeline stage did in one cycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// An instruction was fetched
+    Fetch { pc: u32, raw_insn: u32 },
+    /// An instruction was decoded and read its registers
+    Decode { pc: u32, function: Function },
+    /// Register `src` was forwarded from `from_stage` to `dst`
+    Forward {
+        pc: u32,
+        src: u32,
+        dst: Stage,
+        from_stage: Stage,
+        value: i32,
+    },
+    /// `stage` and the stages before it stalled
+    Stall { stage: Stage, reason: StallReason },
+    /// The instruction in `stage` was discarded
+    Flush {
+        pc: u32,
+        stage: Stage,
+        cause: FlushCause,
+    },
+    /// An instruction completed writeback
+    Retire { pc: u32, function: Function },
+    /// A load or store accessed data memory
+    MemAccess {
+        pc: u32,
+        addr: u32,
+        size: usize,
+        write: bool,
+        value: u32,
+    },
+    /// A register was written
+    RegWrite { pc: u32, rd: u32, value: u32 },
+}
+
+
+impl Event {
+    /// Returns the name of the event's variant.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::Fetch { .. } => "Fetch",
+            Event::Decode { .. } => "Decode",
+            Event::Forward { .. } => "Forward",
+            Event::Stall { .. } => "Stall",
+            Event::Flush { .. } => "Flush",
+            Event::Retire { .. } => "Retire",
+            Event::MemAccess { .. } => "MemAccess",
+            Event::RegWrite { .. } => "RegWrite",
+        }
+    }
+
+    /// Formats the event in `cycle` as a single line JSON object.
+    ///
+    /// Addresses are numbers, and stages, reasons and causes are strings.
+    pub fn to_json(&self, cycle: u64) -> String {
+        let fields = match *self {
+            Event::Fetch { pc, raw_insn } => {
+                format!("\"pc\":{},\"raw_insn\":{}", pc, raw_insn)
+            }
+            Event::Decode { pc, function } |
+            Event::Retire { pc, function } => {
+                format!("\"pc\":{},\"function\":\"{:?}\"", pc, function)
+            }
+            Event::Forward {
+                pc,
+                src,
+                dst,
+                from_stage,
+                value,
+            } => {
+                format!(
+                    "\"pc\":{},\"src\":{},\"dst\":\"{}\",\
+                     \"from_stage\":\"{}\",\"value\":{}",
+                    pc,
+                    src,
+                    dst.name(),
+                    from_stage.name(),
+                    value
+                )
+            }
+            Event::Stall { stage, reason } => {
+                format!(
+                    "\"stage\":\"{}\",\"reason\":\"{}\"",
+                    stage.name(),
+                    reason.name()
+                )
+            }
+            Event::Flush { pc, stage, cause } => {
+                format!(
+                    "\"pc\":{},\"stage\":\"{}\",\"cause\":\"{}\"",
+                    pc,
+                    stage.name(),
+                    cause.name()
+                )
+            }
+            Event::MemAccess {
+                pc,
+                addr,
+                size,
+                write,
+                value,
+            } => {
+                format!(
+                    "\"pc\":{},\"addr\":{},\"size\":{},\"write\":{},\
+                     \"value\":{}",
+                    pc,
+                    addr,
+                    size,
+                    write,
+                    value
+                )
+            }
+            Event::RegWrite { pc, rd, value } => {
+                format!("\"pc\":{},\"rd\":{},\"value\":{}", pc, rd, value)
+            }
+        };
+
+        format!(
+            "{{\"cycle\":{},\"event\":\"{}\",{}}}",
+            cycle,
+            self.name(),
+            fields
+        )
+    }
+}
+
+
+/// An instruction, or a bubble inserted by a stall, occupying a stage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Slot {
+    /// Fetch order, unique within a run
+    pub id: u64,
+    pub pc: u32,
+    pub raw_insn: u32,
+    pub bubble: bool,
+}
+
+
+impl Slot {
+    /// Returns the address and disassembly of the instruction, e.g.
+    /// `0x1c: addi x10, x10, 1`, or `bubble`.
+    pub fn label(&self) -> String {
+        if self.bubble {
+            return String::from("bubble");
+        }
+
+        format!("{:#0x}: {}", self.pc, Instruction::new(self.raw_insn))
+    }
+}
+
+
+/// The slots in IF, ID, EX, MEM and WB during a cycle.
+pub type Occupancy = [Option<Slot>; 5];
+
+
+/// The stages in the order of an `Occupancy`.
+pub const STAGES: [Stage; 5] =
+    [Stage::If, Stage::Id, Stage::Ex, Stage::Mem, Stage::Wb];
+
+
+/// Receives pipeline events.
+pub trait Observer {
+    /// Called for each event, in the order stages ran within `cycle`.
+    fn event(&mut self, cycle: u64, event: &Event);
+
+    /// Called at the end of each cycle with what each stage held.
+    ///
+    /// Stages holding a bubble inserted by a flush, or nothing yet, are
+    /// `None`. A flushed instruction is last reported in the cycle it is
+    /// flushed in. The cycles of a multi-cycle memory access hold IF
+    /// through MEM as in the cycle that started it, with a bubble in WB.
+    fn cycle(&mut self, _cycle: u64, _stages: &Occupancy) {}
+
+    /// Called after `cycle` with the pipeline registers written in the
+    /// cycle, and the register file and data memory at its end.
+    fn state(
+        &mut self,
+        _cycle: u64,
+        _pipeline: &Pipeline,
+        _reg: &RegisterFile,
+        _mem: &DataMemory,
+    ) {
+    }
+}
+
+
+/// Collects events in memory.
+impl Observer for Vec<(u64, Event)> {
+    fn event(&mut self, cycle: u64, event: &Event) {
+        self.push((cycle, *event));
+    }
+}
+
+
+/// Passes events on to several observers.
+impl Observer for Vec<&mut dyn Observer> {
+    fn event(&mut self, cycle: u64, event: &Event) {
+        for observer in self.iter_mut() {
+            observer.event(cycle, event);
+        }
+    }
+
+    fn cycle(&mut self, cycle: u64, stages: &Occupancy) {
+        for observer in self.iter_mut() {
+            observer.cycle(cycle, stages);
+        }
+    }
+
+    fn state(
+        &mut self,
+        cycle: u64,
+        pipeline: &Pipeline,
+        reg: &RegisterFile,
+        mem: &DataMemory,
+    ) {
+        for observer in self.iter_mut() {
+            observer.state(cycle, pipeline, reg, mem);
+        }
+    }
+}
+
+
+/// Writes events as JSON lines.
+pub struct JsonLinesSink<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+
+impl<W: Write> JsonLinesSink<W> {
+    /// Constructs a new `JsonLinesSink` writing to `out`.
+    pub fn new(out: W) -> JsonLinesSink<W> {
+        JsonLinesSink { out, error: None }
+    }
+
+    /// Flushes and returns the writer, or the first error writing to it.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+
+impl<W: Write> Observer for JsonLinesSink<W> {
+    fn event(&mut self, cycle: u64, event: &Event) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", event.to_json(cycle)) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let event = Event::Forward {
+            pc: 0x4,
+            src: 2,
+            dst: Stage::Ex,
+            from_stage: Stage::Mem,
+            value: -1,
+        };
+        assert_eq!(
+            event.to_json(7),
+            "{\"cycle\":7,\"event\":\"Forward\",\"pc\":4,\"src\":2,\
+             \"dst\":\"EX\",\"from_stage\":\"MEM\",\"value\":-1}"
+        );
+
+        let event = Event::Retire {
+            pc: 0x8,
+            function: Function::Addi,
+        };
+        assert_eq!(
+            event.to_json(9),
+            "{\"cycle\":9,\"event\":\"Retire\",\"pc\":8,\"function\":\"Addi\"}"
+        );
+    }
+
+    #[test]
+    fn json_lines_sink() {
+        let mut sink = JsonLinesSink::new(Vec::new());
+        sink.event(
+            1,
+            &Event::Stall {
+                stage: Stage::Id,
+                reason: StallReason::LoadUse,
+            },
+        );
+        sink.event(2, &Event::Fetch { pc: 0, raw_insn: 0x13 });
+
+        let out = String::from_utf8(sink.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            out,
+            "{\"cycle\":1,\"event\":\"Stall\",\"stage\":\"ID\",\
+             \"reason\":\"load-use\"}\n\
+             {\"cycle\":2,\"event\":\"Fetch\",\"pc\":0,\"raw_insn\":19}\n"
+        );
+    }
+
+}
@@ -0,0 +1,285 @@
+//! Kanata pipeline logs.
+//!
+//! `KanataWriter` records which stage every instruction occupied in each
+//! cycle in the Kanata log format read by the Konata pipeline viewer.
+//! Instructions are labeled with their disassembly, stall bubbles are
+//! labeled `bubble` with the reason for the stall, and flushed instructions
+//! are marked as flushed rather than retired.
+
+
+use pipeline::events::{Event, FlushCause, Observer, Occupancy, Stage,
+                       StallReason, STAGES};
+
+use std::io::{self, Write};
+
+
+/// An instruction or bubble that is in the pipeline.
+struct InFlight {
+    /// Fetch order
+    id: u64,
+    /// Sequential id in the log
+    log_id: u64,
+    stage: Stage,
+    bubble: bool,
+    /// Why the instruction is flushed at the end of the current cycle
+    flush: Option<FlushCause>,
+}
+
+
+/// Writes pipeline occupancy as a Kanata log.
+pub struct KanataWriter<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+    /// Cycle of the last log entries
+    cycle: Option<u64>,
+    next_log_id: u64,
+    retired: u64,
+    in_flight: Vec<InFlight>,
+    /// Flushes reported in the current cycle
+    flushes: Vec<(Stage, FlushCause)>,
+    /// Reason for the last stall, given to the bubble it inserts
+    stall: Option<StallReason>,
+}
+
+
+impl<W: Write> KanataWriter<W> {
+    /// Constructs a new `KanataWriter` writing to `out`.
+    pub fn new(out: W) -> KanataWriter<W> {
+        KanataWriter {
+            out,
+            error: None,
+            cycle: None,
+            next_log_id: 0,
+            retired: 0,
+            in_flight: Vec::new(),
+            flushes: Vec::new(),
+            stall: None,
+        }
+    }
+
+    /// Flushes and returns the writer, or the first error writing to it.
+    ///
+    /// Instructions still in the pipeline are left unfinished in the log.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    /// Writes the log entries of `cycle`.
+    fn write_cycle(
+        &mut self,
+        cycle: u64,
+        stages: &Occupancy,
+    ) -> io::Result<()> {
+        match self.cycle {
+            None => {
+                writeln!(self.out, "Kanata\t0004")?;
+                writeln!(self.out, "C=\t{}", cycle)?;
+            }
+            Some(last) if cycle > last => {
+                writeln!(self.out, "C\t{}", cycle - last)?;
+            }
+            Some(_) => (),
+        }
+        self.cycle = Some(cycle);
+
+        // Move or finish what was in the pipeline
+        let mut in_flight = Vec::new();
+        for mut insn in self.in_flight.drain(..) {
+            let stage = stages
+                .iter()
+                .zip(STAGES.iter())
+                .find(|&(slot, _)| slot.map(|s| s.id) == Some(insn.id))
+                .map(|(_, &stage)| stage);
+            let log_id = insn.log_id;
+            if stage == Some(insn.stage) {
+                in_flight.push(insn);
+                continue;
+            }
+            writeln!(self.out, "E\t{}\t0\t{}", log_id, insn.stage.name())?;
+            match stage {
+                Some(stage) => {
+                    writeln!(self.out, "S\t{}\t0\t{}", log_id, stage.name())?;
+                    insn.stage = stage;
+                    in_flight.push(insn);
+                }
+                None if insn.stage == Stage::Wb && !insn.bubble => {
+                    writeln!(self.out, "R\t{}\t{}\t0", log_id, self.retired)?;
+                    self.retired += 1;
+                }
+                None => {
+                    if let Some(cause) = insn.flush {
+                        writeln!(
+                            self.out,
+                            "L\t{}\t1\tflushed: {}",
+                            log_id,
+                            cause.name()
+                        )?;
+                    }
+                    writeln!(self.out, "R\t{}\t0\t1", log_id)?;
+                }
+            }
+        }
+
+        // Start what entered the pipeline
+        for (slot, &stage) in stages.iter().zip(STAGES.iter()) {
+            let slot = match *slot {
+                Some(slot) => slot,
+                None => continue,
+            };
+            if in_flight.iter().any(|insn| insn.id == slot.id) {
+                continue;
+            }
+
+            let log_id = self.next_log_id;
+            self.next_log_id += 1;
+            writeln!(self.out, "I\t{}\t{}\t0", log_id, slot.id)?;
+            match (slot.bubble, self.stall) {
+                (true, Some(reason)) => writeln!(
+                    self.out,
+                    "L\t{}\t0\tbubble ({})",
+                    log_id,
+                    reason.name()
+                )?,
+                _ => {
+                    writeln!(self.out, "L\t{}\t0\t{}", log_id, slot.label())?
+                }
+            }
+            writeln!(self.out, "S\t{}\t0\t{}", log_id, stage.name())?;
+            in_flight.push(InFlight {
+                id: slot.id,
+                log_id,
+                stage,
+                bubble: slot.bubble,
+                flush: None,
+            });
+        }
+
+        for &(stage, cause) in self.flushes.iter() {
+            let flushed = in_flight.iter_mut().find(|i| i.stage == stage);
+            if let Some(insn) = flushed {
+                insn.flush = Some(cause);
+            }
+        }
+        self.flushes.clear();
+        self.in_flight = in_flight;
+
+        Ok(())
+    }
+}
+
+
+impl<W: Write> Observer for KanataWriter<W> {
+    fn event(&mut self, _cycle: u64, event: &Event) {
+        match *event {
+            Event::Stall { reason, .. } => self.stall = Some(reason),
+            Event::Flush { stage, cause, .. } => {
+                self.flushes.push((stage, cause))
+            }
+            _ => (),
+        }
+    }
+
+    fn cycle(&mut self, cycle: u64, stages: &Occupancy) {
+        if self.error.is_none() {
+            if let Err(e) = self.write_cycle(cycle, stages) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pipeline::events::Slot;
+
+    fn slot(id: u64, pc: u32, bubble: bool) -> Option<Slot> {
+        Some(Slot {
+            id,
+            pc,
+            raw_insn: 0x00_15_05_13, // addi x10, x10, 1
+            bubble,
+        })
+    }
+
+    #[test]
+    fn log() {
+        let mut writer = KanataWriter::new(Vec::new());
+        writer.cycle(3, &[slot(1, 0x0, false), None, None, None, None]);
+        writer.cycle(4, &[slot(2, 0x4, false), slot(1, 0x0, false), None,
+                          None, None]);
+        writer.event(
+            5,
+            &Event::Stall {
+                stage: Stage::Id,
+                reason: StallReason::LoadUse,
+            },
+        );
+        writer.cycle(5, &[None, slot(2, 0x4, false), slot(1, 0x0, false),
+                          None, None]);
+        writer.cycle(6, &[None, slot(2, 0x4, false), slot(3, 0x0, true),
+                          slot(1, 0x0, false), None]);
+        writer.event(
+            7,
+            &Event::Flush {
+                pc: 0x4,
+                stage: Stage::Ex,
+                cause: FlushCause::Mispredict,
+            },
+        );
+        writer.cycle(7, &[None, None, slot(2, 0x4, false), slot(3, 0x0, true),
+                          slot(1, 0x0, false)]);
+        writer.cycle(9, &[None, None, None, None, None]);
+
+        let log = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let expected = [
+            "Kanata\t0004",
+            "C=\t3",
+            "I\t0\t1\t0",
+            "L\t0\t0\t0x0: addi x10, x10, 1",
+            "S\t0\t0\tIF",
+            "C\t1",
+            "E\t0\t0\tIF",
+            "S\t0\t0\tID",
+            "I\t1\t2\t0",
+            "L\t1\t0\t0x4: addi x10, x10, 1",
+            "S\t1\t0\tIF",
+            "C\t1",
+            "E\t0\t0\tID",
+            "S\t0\t0\tEX",
+            "E\t1\t0\tIF",
+            "S\t1\t0\tID",
+            "C\t1",
+            "E\t0\t0\tEX",
+            "S\t0\t0\tMEM",
+            "I\t2\t3\t0",
+            "L\t2\t0\tbubble (load-use)",
+            "S\t2\t0\tEX",
+            "C\t1",
+            "E\t0\t0\tMEM",
+            "S\t0\t0\tWB",
+            "E\t1\t0\tID",
+            "S\t1\t0\tEX",
+            "E\t2\t0\tEX",
+            "S\t2\t0\tMEM",
+            "C\t2",
+            "E\t0\t0\tWB",
+            "R\t0\t0\t0",
+            "E\t1\t0\tEX",
+            "L\t1\t1\tflushed: mispredict",
+            "R\t1\t0\t1",
+            "E\t2\t0\tMEM",
+            "R\t2\t0\t1",
+            "",
+        ];
+        assert_eq!(log, expected.join("\n"));
+    }
+
+}
@@ -1,9 +1,17 @@
 //! Pipeline definition.
 
 
+use checkpoint::{self, Checkpoint, Reader, Writer};
 use instruction::Instruction;
+use trap::{Exception, Trap};
 
+use std::io;
+
+pub mod chart;
+pub mod events;
+pub mod kanata;
 pub mod stages;
+pub mod vcd;
 
 
 /// Pipeline holding four inter-stage registers
@@ -28,6 +36,13 @@ impl Pipeline {
 }
 
 
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline::new()
+    }
+}
+
+
 /// Pipeline register between instruction fetch and instruction decode stages.
 #[derive(Clone, Copy, Debug)]
 pub struct IfIdRegister {
@@ -36,6 +51,23 @@ pub struct IfIdRegister {
 
     /// Raw instruction
     pub raw_insn: u32,
+
+    /// Predicted address of the next instruction
+    pub npc: u32,
+
+    /// Global branch history the prediction was made with
+    pub history: u32,
+
+    /// Exception raised while fetching the instruction
+    pub trap: Option<Trap>,
+
+    /// Holds a NOP inserted by a stall or flush rather than an instruction
+    pub bubble: bool,
+
+    /// Fetch order of the instruction, or of the bubble inserted by a stall
+    ///
+    /// Zero for flushed instructions and the initial NOPs.
+    pub id: u64,
 }
 
 
@@ -44,18 +76,37 @@ impl IfIdRegister {
         IfIdRegister {
             pc: 0,
             raw_insn: 0x00_00_00_13, // NOP
+            npc: 0,
+            history: 0,
+            trap: None,
+            bubble: true,
+            id: 0,
         }
     }
 }
 
 
+impl Default for IfIdRegister {
+    fn default() -> IfIdRegister {
+        IfIdRegister::new()
+    }
+}
+
+
 /// Pipeline register between instruction decode and execution stages.
 #[derive(Clone, Copy, Debug)]
 pub struct IdExRegister {
     pub pc: u32,
+    pub npc: u32,
+    pub history: u32,
+    /// Next address of a branch or jump already resolved in ID
+    pub early_npc: Option<u32>,
     pub insn: Instruction,
     pub rs1: i32,
     pub rs2: i32,
+    pub trap: Option<Trap>,
+    pub bubble: bool,
+    pub id: u64,
 }
 
 
@@ -63,22 +114,41 @@ impl IdExRegister {
     pub fn new() -> IdExRegister {
         IdExRegister {
             pc: 0,
+            npc: 0,
+            history: 0,
+            early_npc: None,
             insn: Instruction::default(),
             rs1: 0,
             rs2: 0,
+            trap: None,
+            bubble: true,
+            id: 0,
         }
     }
 }
 
 
+impl Default for IdExRegister {
+    fn default() -> IdExRegister {
+        IdExRegister::new()
+    }
+}
+
+
 /// Pipeline register between execution and memory stages.
 #[derive(Clone, Copy, Debug)]
 pub struct ExMemRegister {
     pub pc: u32,
+    pub npc: u32,
+    pub history: u32,
+    pub early_npc: Option<u32>,
     pub insn: Instruction,
     pub alu_result: i32,
     pub rs2: i32,
     pub halt_addr: Option<usize>,
+    pub trap: Option<Trap>,
+    pub bubble: bool,
+    pub id: u64,
 }
 
 
@@ -86,15 +156,28 @@ impl ExMemRegister {
     pub fn new() -> ExMemRegister {
         ExMemRegister {
             pc: 0,
+            npc: 0,
+            history: 0,
+            early_npc: None,
             insn: Instruction::default(),
             alu_result: 0,
             rs2: 0,
             halt_addr: None,
+            trap: None,
+            bubble: true,
+            id: 0,
         }
     }
 }
 
 
+impl Default for ExMemRegister {
+    fn default() -> ExMemRegister {
+        ExMemRegister::new()
+    }
+}
+
+
 /// Pipeline register between memory and writeback stages.
 #[derive(Clone, Copy, Debug)]
 pub struct MemWbRegister {
@@ -102,6 +185,8 @@ pub struct MemWbRegister {
     pub insn: Instruction,
     pub alu_result: i32,
     pub mem_result: u32,
+    pub bubble: bool,
+    pub id: u64,
 }
 
 
@@ -112,6 +197,137 @@ impl MemWbRegister {
             insn: Instruction::default(),
             alu_result: 0,
             mem_result: 0,
+            bubble: true,
+            id: 0,
         }
     }
 }
+
+
+impl Default for MemWbRegister {
+    fn default() -> MemWbRegister {
+        MemWbRegister::new()
+    }
+}
+
+
+/// Saves the four pipeline registers.
+///
+/// Instructions are saved in their encoded form and decoded on restore.
+impl Checkpoint for Pipeline {
+    fn save(&self, w: &mut Writer) {
+        let if_id = &self.if_id;
+        w.u32(if_id.pc);
+        w.u32(if_id.raw_insn);
+        w.u32(if_id.npc);
+        w.u32(if_id.history);
+        save_trap(w, if_id.trap);
+        w.bool(if_id.bubble);
+        w.u64(if_id.id);
+
+        let id_ex = &self.id_ex;
+        w.u32(id_ex.pc);
+        w.u32(id_ex.npc);
+        w.u32(id_ex.history);
+        w.option(id_ex.early_npc, |w, npc| w.u32(npc));
+        w.u32(id_ex.insn.as_u32());
+        w.u32(id_ex.rs1 as u32);
+        w.u32(id_ex.rs2 as u32);
+        save_trap(w, id_ex.trap);
+        w.bool(id_ex.bubble);
+        w.u64(id_ex.id);
+
+        let ex_mem = &self.ex_mem;
+        w.u32(ex_mem.pc);
+        w.u32(ex_mem.npc);
+        w.u32(ex_mem.history);
+        w.option(ex_mem.early_npc, |w, npc| w.u32(npc));
+        w.u32(ex_mem.insn.as_u32());
+        w.u32(ex_mem.alu_result as u32);
+        w.u32(ex_mem.rs2 as u32);
+        w.option(ex_mem.halt_addr, |w, addr| w.usize(addr));
+        save_trap(w, ex_mem.trap);
+        w.bool(ex_mem.bubble);
+        w.u64(ex_mem.id);
+
+        let mem_wb = &self.mem_wb;
+        w.u32(mem_wb.pc);
+        w.u32(mem_wb.insn.as_u32());
+        w.u32(mem_wb.alu_result as u32);
+        w.u32(mem_wb.mem_result);
+        w.bool(mem_wb.bubble);
+        w.u64(mem_wb.id);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.if_id = IfIdRegister {
+            pc: r.u32()?,
+            raw_insn: r.u32()?,
+            npc: r.u32()?,
+            history: r.u32()?,
+            trap: restore_trap(r)?,
+            bubble: r.bool()?,
+            id: r.u64()?,
+        };
+
+        self.id_ex = IdExRegister {
+            pc: r.u32()?,
+            npc: r.u32()?,
+            history: r.u32()?,
+            early_npc: r.option(|r| r.u32())?,
+            insn: Instruction::new(r.u32()?),
+            rs1: r.u32()? as i32,
+            rs2: r.u32()? as i32,
+            trap: restore_trap(r)?,
+            bubble: r.bool()?,
+            id: r.u64()?,
+        };
+
+        self.ex_mem = ExMemRegister {
+            pc: r.u32()?,
+            npc: r.u32()?,
+            history: r.u32()?,
+            early_npc: r.option(|r| r.u32())?,
+            insn: Instruction::new(r.u32()?),
+            alu_result: r.u32()? as i32,
+            rs2: r.u32()? as i32,
+            halt_addr: r.option(|r| r.usize())?,
+            trap: restore_trap(r)?,
+            bubble: r.bool()?,
+            id: r.u64()?,
+        };
+
+        self.mem_wb = MemWbRegister {
+            pc: r.u32()?,
+            insn: Instruction::new(r.u32()?),
+            alu_result: r.u32()? as i32,
+            mem_result: r.u32()?,
+            bubble: r.bool()?,
+            id: r.u64()?,
+        };
+
+        Ok(())
+    }
+}
+
+
+/// Appends an exception held in a pipeline register to a checkpoint.
+fn save_trap(w: &mut Writer, trap: Option<Trap>) {
+    w.option(trap, |w, trap| {
+        w.u32(trap.cause.code());
+        w.u32(trap.tval);
+    });
+}
+
+
+/// Restores an exception saved by `save_trap`.
+fn restore_trap(r: &mut Reader) -> io::Result<Option<Trap>> {
+    r.option(|r| {
+        let code = r.u32()?;
+        let cause = Exception::from_code(code).ok_or_else(|| {
+            checkpoint::invalid(&format!("invalid exception {}", code))
+        })?;
+
+        Ok(Trap::new(cause, r.u32()?))
+    })
+}
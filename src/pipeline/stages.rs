@@ -2,73 +2,170 @@
 
 
 use consts;
-use hazards;
+use hazards::{self, HazardPolicy, HazardUnit};
 use instruction::{Function, Instruction, Opcode};
 use memory::data::DataMemory;
 use memory::instruction::InstructionMemory;
 use pipeline::Pipeline;
+use pipeline::events::{Event, FlushCause, Stage};
+use predictor::{BranchResolution, BranchUnit};
 use register::RegisterFile;
 use stages;
+use trap::Trap;
+
+
+/// Instructions flushed when a branch resolved in MEM was mispredicted.
+pub const MEM_BRANCH_PENALTY: u64 = 3;
+
+/// Instructions flushed when a branch resolved in ID was mispredicted.
+pub const ID_BRANCH_PENALTY: u64 = 1;
 
 
 /// IF: Instruction fetch from memory.
 pub fn insn_fetch(
     write_pipeline: &mut Pipeline,
-    insns: &InstructionMemory,
+    insns: &dyn InstructionMemory,
     reg: &mut RegisterFile,
+    branch_unit: &mut BranchUnit,
+    events: &mut Vec<Event>,
     _clk: u64,
 ) {
-    // Read and increment program counter
     let pc = reg.pc.read();
-    let npc = pc + consts::WORD_SIZE as u32;
-    reg.pc.write(npc);
 
     // IF: Instruction fetch
-    let raw_insn = stages::insn_fetch(insns, pc, _clk);
+    let (raw_insn, trap) =
+        match stages::insn_fetch(insns, &reg.csr.pmp, pc, _clk) {
+            Ok(raw_insn) => (raw_insn, None),
+            // A fetch fault travels down the pipeline as a NOP until MEM
+            Err(trap) => (consts::NOP, Some(trap)),
+        };
+
+    // Predict and update program counter
+    let history = branch_unit.predictor().history();
+    let npc = branch_unit.predict(pc, raw_insn);
+    if npc != pc + consts::WORD_SIZE as u32 {
+        trace!("Predict: {:#0x} -> {:#0x} (clock {})", pc, npc, _clk);
+    }
+    reg.pc.write(npc);
 
     write_pipeline.if_id.pc = pc;
     write_pipeline.if_id.raw_insn = raw_insn;
+    write_pipeline.if_id.npc = npc;
+    write_pipeline.if_id.history = history;
+    write_pipeline.if_id.trap = trap;
+    write_pipeline.if_id.bubble = false;
+
+    events.push(Event::Fetch { pc, raw_insn });
 }
 
 
 /// ID: Instruction decode and register read
+///
+/// With `BranchResolution::Decode`, conditional branches and JAL are also
+/// resolved here, and a misprediction flushes the instruction in IF and
+/// undoes its return address stack update.
 pub fn insn_decode(
     read_pipeline: &Pipeline,
     write_pipeline: &mut Pipeline,
     reg: &mut RegisterFile,
+    branch_unit: &mut BranchUnit,
+    hazard_unit: &mut HazardUnit,
+    events: &mut Vec<Event>,
     _clk: u64,
 ) {
     // ID: Instruction decode and register file read
     let raw_insn = read_pipeline.if_id.raw_insn;
-    let insn = stages::insn_decode(raw_insn, _clk);
+    let (insn, trap) = match stages::insn_decode(raw_insn, _clk) {
+        Ok(insn) => (insn, read_pipeline.if_id.trap),
+        // An illegal instruction travels down the pipeline as a NOP until MEM
+        Err(trap) => (Instruction::default(), Some(trap)),
+    };
 
     write_pipeline.id_ex.pc = read_pipeline.if_id.pc;
+    write_pipeline.id_ex.npc = read_pipeline.if_id.npc;
+    write_pipeline.id_ex.history = read_pipeline.if_id.history;
     write_pipeline.id_ex.insn = insn;
+    write_pipeline.id_ex.trap = trap;
+    write_pipeline.id_ex.bubble = read_pipeline.if_id.bubble;
+    write_pipeline.id_ex.id = read_pipeline.if_id.id;
+
+    let pc = read_pipeline.if_id.pc;
+    if !read_pipeline.if_id.bubble {
+        events.push(Event::Decode {
+            pc,
+            function: insn.function,
+        });
+    }
 
     // Do register forwarding (see Patterson & Hennessy pg 301)
     // Note: Had to also add logic to not try to forward writes to x0.
     let rs1: i32;
     if hazards::reg_hazard_src1(&insn, write_pipeline) {
-        rs1 = match write_pipeline.mem_wb.insn.semantics.mem_read {
+        rs1 = match write_pipeline.mem_wb.insn.semantics.mem_to_reg {
             true => write_pipeline.mem_wb.mem_result as i32,
             false => write_pipeline.mem_wb.alu_result,
         };
+        events.push(forward(pc, insn.fields.rs1, Stage::Id, Stage::Wb, rs1));
     } else {
         rs1 = reg.gpr[insn.fields.rs1.unwrap_or(0) as usize].read() as i32;
     }
 
     let rs2: i32;
     if hazards::reg_hazard_src2(&insn, write_pipeline) {
-        rs2 = match write_pipeline.mem_wb.insn.semantics.mem_read {
+        rs2 = match write_pipeline.mem_wb.insn.semantics.mem_to_reg {
             true => write_pipeline.mem_wb.mem_result as i32,
             false => write_pipeline.mem_wb.alu_result,
         };
+        events.push(forward(pc, insn.fields.rs2, Stage::Id, Stage::Wb, rs2));
     } else {
         rs2 = reg.gpr[insn.fields.rs2.unwrap_or(0) as usize].read() as i32;
     }
 
     write_pipeline.id_ex.rs1 = rs1;
     write_pipeline.id_ex.rs2 = rs2;
+    write_pipeline.id_ex.early_npc = None;
+
+    let early = insn.opcode == Opcode::Branch || insn.opcode == Opcode::Jal;
+    if branch_unit.resolution() != BranchResolution::Decode || !early ||
+        read_pipeline.if_id.trap.is_some()
+    {
+        return;
+    }
+
+    let forwarding = hazard_unit.policy() == HazardPolicy::Forwarding;
+    if hazard_unit.policy() == HazardPolicy::NoInterlock {
+        if let Some((reg, producer_pc)) =
+            hazards::raw_hazard(&insn, read_pipeline)
+        {
+            hazard_unit.violation(pc, reg, producer_pc, _clk);
+        }
+    }
+
+    // Forward ALU results from EX/MEM into the branch comparator
+    let mut rs1 = rs1;
+    if forwarding && hazards::id_hazard_src1(&insn, read_pipeline) {
+        rs1 = ex_mem_result(read_pipeline);
+        events.push(forward(pc, insn.fields.rs1, Stage::Id, Stage::Mem, rs1));
+    }
+    let mut rs2 = rs2;
+    if forwarding && hazards::id_hazard_src2(&insn, read_pipeline) {
+        rs2 = ex_mem_result(read_pipeline);
+        events.push(forward(pc, insn.fields.rs2, Stage::Id, Stage::Mem, rs2));
+    }
+
+    let mut cmp_insn = insn;
+    let cmp = stages::execute(&mut cmp_insn, pc, rs1, rs2, _clk);
+    let npc = resolve_branch(&insn, pc, cmp, _clk);
+    write_pipeline.id_ex.early_npc = Some(npc);
+
+    if npc != read_pipeline.if_id.npc {
+        reg.pc.write(npc);
+        branch_unit.flush_fetch();
+
+        // Mispredicted branch or jump - flush IF
+        trace!("Jump (ID): {:#0x} -> {:#0x} (clock {})", pc, npc, _clk);
+        flush(write_pipeline, Stage::If, FlushCause::Mispredict, events);
+    }
 }
 
 
@@ -76,23 +173,32 @@ pub fn insn_decode(
 pub fn execute(
     read_pipeline: &Pipeline,
     write_pipeline: &mut Pipeline,
+    hazard_unit: &mut HazardUnit,
+    events: &mut Vec<Event>,
     _clk: u64,
 ) {
     let pc = read_pipeline.id_ex.pc;
     let mut insn = read_pipeline.id_ex.insn;
+    let forwarding = hazard_unit.policy() == HazardPolicy::Forwarding;
+
+    if hazard_unit.policy() == HazardPolicy::NoInterlock {
+        check_stale_operands(read_pipeline, hazard_unit, _clk);
+    }
 
     // ALU src1 mux
     let rs1: i32;
-    if hazards::ex_hazard_src1(&read_pipeline) {
-        rs1 = read_pipeline.ex_mem.alu_result;
+    if forwarding && hazards::ex_hazard_src1(read_pipeline) {
+        rs1 = ex_mem_result(read_pipeline);
+        let src = insn.fields.rs1;
+        events.push(forward(pc, src, Stage::Ex, Stage::Mem, rs1));
         trace!(
             "Hazard: rs1 = {} forwarded from EX/MEM ALU result (clock {})",
             rs1,
             _clk
         );
-    } else if hazards::mem_hazard_src1(&read_pipeline) {
+    } else if forwarding && hazards::mem_hazard_src1(read_pipeline) {
         let desc: &str;
-        if read_pipeline.mem_wb.insn.semantics.mem_read {
+        if read_pipeline.mem_wb.insn.semantics.mem_to_reg {
             rs1 = read_pipeline.mem_wb.mem_result as i32;
             desc = "data memory";
         } else {
@@ -105,22 +211,26 @@ pub fn execute(
             desc,
             _clk
         );
+        let src = insn.fields.rs1;
+        events.push(forward(pc, src, Stage::Ex, Stage::Wb, rs1));
     } else {
         rs1 = read_pipeline.id_ex.rs1;
     }
 
     // ALU src2 mux
     let rs2: i32;
-    if hazards::ex_hazard_src2(&read_pipeline) {
-        rs2 = read_pipeline.ex_mem.alu_result;
+    if forwarding && hazards::ex_hazard_src2(read_pipeline) {
+        rs2 = ex_mem_result(read_pipeline);
+        let src = insn.fields.rs2;
+        events.push(forward(pc, src, Stage::Ex, Stage::Mem, rs2));
         trace!(
             "Hazard: rs2 = {} forwarded from EX/MEM ALU result (clock {})",
             rs2,
             _clk
         );
-    } else if hazards::mem_hazard_src2(&read_pipeline) {
+    } else if forwarding && hazards::mem_hazard_src2(read_pipeline) {
         let desc: &str;
-        if read_pipeline.mem_wb.insn.semantics.mem_read {
+        if read_pipeline.mem_wb.insn.semantics.mem_to_reg {
             rs2 = read_pipeline.mem_wb.mem_result as i32;
             desc = "data memory";
         } else {
@@ -133,69 +243,331 @@ pub fn execute(
             desc,
             _clk
         );
+        let src = insn.fields.rs2;
+        events.push(forward(pc, src, Stage::Ex, Stage::Wb, rs2));
     } else {
         rs2 = read_pipeline.id_ex.rs2;
     }
 
-    let alu_result = stages::execute(&mut insn, rs1, rs2, _clk);
+    let alu_result = stages::execute(&mut insn, pc, rs1, rs2, _clk);
 
     if insn.function == Function::Halt {
         write_pipeline.ex_mem.halt_addr = Some(pc as usize);
     }
 
     write_pipeline.ex_mem.pc = pc;
+    write_pipeline.ex_mem.npc = read_pipeline.id_ex.npc;
+    write_pipeline.ex_mem.history = read_pipeline.id_ex.history;
+    write_pipeline.ex_mem.early_npc = read_pipeline.id_ex.early_npc;
     write_pipeline.ex_mem.insn = read_pipeline.id_ex.insn;
     write_pipeline.ex_mem.alu_result = alu_result;
     write_pipeline.ex_mem.rs2 = rs2;
+    write_pipeline.ex_mem.trap = read_pipeline.id_ex.trap;
+    write_pipeline.ex_mem.bubble = read_pipeline.id_ex.bubble;
+    write_pipeline.ex_mem.id = read_pipeline.id_ex.id;
+}
+
+
+/// Records EX operands read stale for lack of forwarding.
+fn check_stale_operands(
+    pl: &Pipeline,
+    hazard_unit: &mut HazardUnit,
+    _clk: u64,
+) {
+    let srcs = [
+        (
+            pl.id_ex.insn.fields.rs1,
+            hazards::ex_hazard_src1(pl),
+            hazards::mem_hazard_src1(pl),
+        ),
+        (
+            pl.id_ex.insn.fields.rs2,
+            hazards::ex_hazard_src2(pl),
+            hazards::mem_hazard_src2(pl),
+        ),
+    ];
+
+    for (i, &(src, ex, mem)) in srcs.iter().enumerate() {
+        let producer_pc = match (ex, mem) {
+            (true, _) => pl.ex_mem.pc,
+            (false, true) => pl.mem_wb.pc,
+            _ => continue,
+        };
+        // Count a register read twice once
+        if i == 1 && src == srcs[0].0 && (srcs[0].1 || srcs[0].2) {
+            continue;
+        }
+        hazard_unit.violation(pl.id_ex.pc, src.unwrap(), producer_pc, _clk);
+    }
+}
+
+
+/// Constructs a forwarding event for the register `src`.
+fn forward(
+    pc: u32,
+    src: Option<u32>,
+    dst: Stage,
+    from_stage: Stage,
+    value: i32,
+) -> Event {
+    Event::Forward {
+        pc,
+        src: src.unwrap_or(0),
+        dst,
+        from_stage,
+        value,
+    }
+}
+
+
+/// Replaces the instruction entering `stage` with a bubble.
+fn flush(
+    write_pipeline: &mut Pipeline,
+    stage: Stage,
+    cause: FlushCause,
+    events: &mut Vec<Event>,
+) {
+    let (pc, bubble) = match stage {
+        Stage::If => {
+            let if_id = &mut write_pipeline.if_id;
+            let flushed = (if_id.pc, if_id.bubble);
+            if_id.raw_insn = consts::NOP;
+            if_id.trap = None;
+            if_id.bubble = true;
+            if_id.id = 0;
+            flushed
+        }
+        Stage::Id => {
+            let id_ex = &mut write_pipeline.id_ex;
+            let flushed = (id_ex.pc, id_ex.bubble);
+            id_ex.insn = Instruction::default(); // NOP
+            id_ex.trap = None;
+            id_ex.bubble = true;
+            id_ex.id = 0;
+            flushed
+        }
+        Stage::Ex => {
+            let ex_mem = &mut write_pipeline.ex_mem;
+            let flushed = (ex_mem.pc, ex_mem.bubble);
+            ex_mem.insn = Instruction::default(); // NOP
+            ex_mem.halt_addr = None;
+            ex_mem.trap = None;
+            ex_mem.bubble = true;
+            ex_mem.id = 0;
+            flushed
+        }
+        _ => unreachable!("Only IF, ID and EX are flushed"),
+    };
+
+    if !bubble {
+        events.push(Event::Flush { pc, stage, cause });
+    }
 }
 
 
-/// MEM: Access memory operand.
+/// Returns the result an instruction in EX/MEM forwards to EX.
+///
+/// Jumps forward their link address rather than the ALU result, which
+/// holds the JALR target.
+fn ex_mem_result(pl: &Pipeline) -> i32 {
+    match pl.ex_mem.insn.opcode {
+        Opcode::Jal | Opcode::Jalr => link_address(pl.ex_mem.pc),
+        _ => pl.ex_mem.alu_result,
+    }
+}
+
+
+/// Returns the address a jump at `pc` writes to its link register.
+fn link_address(pc: u32) -> i32 {
+    (pc + consts::WORD_SIZE as u32) as i32
+}
+
+
+/// MEM: Access memory operand or CSR, and resolve traps and branches.
+///
+/// Branches and jumps train `branch_unit`, and those whose next address
+/// differs from the one fetched flush the pipeline, unless already resolved
+/// in ID.
+///
+/// Returns the number of extra cycles the access holds the MEM stage for.
 pub fn access_memory(
     read_pipeline: &Pipeline,
     write_pipeline: &mut Pipeline,
-    mut mem: &mut DataMemory,
+    mem: &mut DataMemory,
     reg: &mut RegisterFile,
+    branch_unit: &mut BranchUnit,
+    events: &mut Vec<Event>,
     _clk: u64,
-) {
+) -> u32 {
     let pc = read_pipeline.ex_mem.pc;
-    let insn = read_pipeline.ex_mem.insn;
+    let mut insn = read_pipeline.ex_mem.insn;
     let alu_result = read_pipeline.ex_mem.alu_result;
     let rs2 = read_pipeline.ex_mem.rs2;
-    let mem_result =
-        stages::access_memory(&insn, &mut mem, alu_result, rs2, _clk);
+    let result = match read_pipeline.ex_mem.trap {
+        Some(trap) => Err(trap),
+        None if insn.opcode == Opcode::System => {
+            stages::access_csr(&insn, &mut reg.csr, alu_result, _clk)
+                .map(|csr| (csr, 0))
+        }
+        None => {
+            let addr = alu_result as u32;
+            let pmp = &reg.csr.pmp;
+            stages::check_alignment(&insn, mem, addr, _clk)
+                .and_then(|_| stages::check_pmp(&insn, pmp, addr, _clk))
+                .and_then(|_| access_data(&insn, mem, alu_result, rs2))
+        }
+    };
 
-    // Modify program counter for branch or jump
-    if insn.semantics.branch &&
-        !(insn.opcode == Opcode::Branch && alu_result != 0)
-    {
-        let imm = insn.fields.imm.unwrap() as i32;
-        let npc = match insn.opcode {
-            Opcode::Jalr => alu_result & 0xfffe, // LSB -> 0
-            _ => (pc as i32) + imm,
-        } as u32;
+    // Modify program counter for trap, trap return, branch or jump
+    let mut mem_result = 0;
+    let mut stall = 0;
+    let mut bubble = read_pipeline.ex_mem.bubble;
+    let mut id = read_pipeline.ex_mem.id;
+    let mut cause = FlushCause::Mispredict;
+    let npc = match result {
+        Err(trap) => {
+            let npc = reg.csr.trap(pc, trap);
+            debug!(
+                "Trap: {:?} at {:#0x} -> {:#0x} (clock {})",
+                trap,
+                pc,
+                npc,
+                _clk
+            );
+            insn = Instruction::default(); // squash faulting instruction
+            bubble = true;
+            id = 0;
+            cause = FlushCause::Trap;
+            Some(npc)
+        }
+        Ok(_) if insn.function == Function::Mret => {
+            cause = FlushCause::TrapReturn;
+            Some(reg.csr.trap_return())
+        }
+        Ok((result, cycles)) => {
+            mem_result = result;
+            stall = cycles;
+            if insn.semantics.mem_read || insn.semantics.mem_write {
+                events.push(Event::MemAccess {
+                    pc,
+                    addr: alu_result as u32,
+                    size: insn.semantics.mem_size,
+                    write: insn.semantics.mem_write,
+                    value: match insn.semantics.mem_write {
+                        true => rs2 as u32,
+                        false => result,
+                    },
+                });
+            }
+            if insn.semantics.branch {
+                // Branches resolved in ID have already redirected fetch
+                let predicted = read_pipeline.ex_mem.npc;
+                let history = read_pipeline.ex_mem.history;
+                let early_npc = read_pipeline.ex_mem.early_npc;
+                let (npc, penalty) = match early_npc {
+                    Some(npc) => (npc, ID_BRANCH_PENALTY),
+                    None => {
+                        let npc = resolve_branch(&insn, pc, alu_result, _clk);
+                        (npc, MEM_BRANCH_PENALTY)
+                    }
+                };
+                let mispredicted = branch_unit.resolve(
+                    &insn,
+                    pc,
+                    history,
+                    predicted,
+                    npc,
+                    penalty,
+                );
+                match mispredicted && early_npc.is_none() {
+                    true => Some(npc),
+                    false => None,
+                }
+            } else {
+                None
+            }
+        }
+    };
 
+    if let Some(npc) = npc {
         reg.pc.write(npc);
+        branch_unit.flush();
 
-        // Branching - flush
+        // Mispredicted branch, jump or trap - flush
         trace!("Jump: {:#0x} -> {:#0x} (clock {})", pc, npc, _clk);
-        write_pipeline.if_id.raw_insn = consts::NOP;
-        write_pipeline.id_ex.insn = Instruction::default(); // NOP
-        write_pipeline.ex_mem.insn = Instruction::default(); // NOP
-        write_pipeline.ex_mem.halt_addr = None;
+        for &stage in [Stage::Ex, Stage::Id, Stage::If].iter() {
+            flush(write_pipeline, stage, cause, events);
+        }
     }
 
     write_pipeline.mem_wb.pc = pc;
     write_pipeline.mem_wb.insn = insn;
-    write_pipeline.mem_wb.alu_result = alu_result;
+    write_pipeline.mem_wb.alu_result = match insn.opcode {
+        Opcode::Jal | Opcode::Jalr => link_address(pc),
+        _ => alu_result,
+    };
     write_pipeline.mem_wb.mem_result = mem_result;
+    write_pipeline.mem_wb.bubble = bubble;
+    write_pipeline.mem_wb.id = id;
+
+    stall
+}
+
+
+/// Returns the address of the instruction after a branch or jump.
+fn resolve_branch(
+    insn: &Instruction,
+    pc: u32,
+    alu_result: i32,
+    _clk: u64,
+) -> u32 {
+    let taken = !(insn.opcode == Opcode::Branch && alu_result != 0);
+    if !taken {
+        return pc + consts::WORD_SIZE as u32;
+    }
+
+    let imm = insn.fields.imm.unwrap() as i32;
+    match insn.opcode {
+        Opcode::Jalr => (alu_result & !1) as u32, // LSB -> 0
+        _ => ((pc as i32) + imm) as u32,
+    }
+}
+
+
+/// Accesses data memory through the write port and store buffer.
+///
+/// Returns the loaded value and the extra cycles the access takes.
+fn access_data(
+    insn: &Instruction,
+    mem: &mut DataMemory,
+    alu_result: i32,
+    rs2: i32,
+) -> Result<(u32, u32), Trap> {
+    let addr = alu_result as usize;
+    let size = insn.semantics.mem_size;
+    let trap = |cause| Trap::new(cause, alu_result as u32);
+
+    if insn.semantics.mem_read {
+        mem.load(addr, size)
+            .map(|(value, cycles)| (stages::extend_load(insn, value), cycles))
+            .map_err(trap)
+    } else if insn.semantics.mem_write {
+        mem.store(addr, size, rs2 as u32)
+            .map(|cycles| (0, cycles))
+            .map_err(trap)
+    } else if insn.function == Function::Fence {
+        Ok((0, mem.fence()))
+    } else {
+        Ok((0, 0))
+    }
 }
 
 
 /// WB: Write result back to register.
 pub fn reg_writeback(
     read_pipeline: &Pipeline,
-    mut reg: &mut RegisterFile,
+    reg: &mut RegisterFile,
+    events: &mut Vec<Event>,
     _clk: u64,
 ) {
     let pc = read_pipeline.mem_wb.pc;
@@ -203,5 +575,20 @@ pub fn reg_writeback(
     let alu_result = read_pipeline.mem_wb.alu_result;
     let mem_result = read_pipeline.mem_wb.mem_result;
 
-    stages::reg_writeback(pc, &insn, &mut reg, alu_result, mem_result, _clk);
+    stages::reg_writeback(pc, &insn, reg, alu_result, mem_result, _clk);
+
+    if read_pipeline.mem_wb.bubble {
+        return;
+    }
+    match insn.fields.rd {
+        Some(rd) if insn.semantics.reg_write && rd != 0 => {
+            let value = reg.gpr[rd as usize].read();
+            events.push(Event::RegWrite { pc, rd, value });
+        }
+        _ => (),
+    }
+    events.push(Event::Retire {
+        pc,
+        function: insn.function,
+    });
 }
@@ -0,0 +1,527 @@
+//! VCD waveform dumps.
+//!
+//! `VcdWriter` dumps the pipeline registers, the control lines decoded for
+//! the instruction each one holds, the EX forwarding mux selects, the stall
+//! and flush signals and the register file as a Value Change Dump, one time
+//! unit per cycle, for comparison against RTL simulations in a waveform
+//! viewer such as GTKWave. Signals are selected in groups to keep dumps of
+//! long runs small.
+
+
+use alu::{AluOp, AluSrc};
+use instruction::{Instruction, Semantics};
+use memory::data::DataMemory;
+use pipeline::Pipeline;
+use pipeline::events::{Event, Observer, Occupancy, Stage, StallReason};
+use register::RegisterFile;
+
+use std::io::{self, Write};
+
+
+/// A group of signals in the dump.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignalGroup {
+    /// The PC held by each pipeline register
+    Pc,
+    /// The raw instruction held by each pipeline register
+    Insn,
+    /// The control lines decoded for each pipeline register's instruction
+    Control,
+    /// The forwarding mux selects of the ALU operands
+    Forward,
+    /// The stall and flush signals
+    Hazard,
+    /// The program counter and general purpose registers
+    Registers,
+}
+
+
+/// Every signal group, in dump order.
+pub const GROUPS: [SignalGroup; 6] = [
+    SignalGroup::Pc,
+    SignalGroup::Insn,
+    SignalGroup::Control,
+    SignalGroup::Forward,
+    SignalGroup::Hazard,
+    SignalGroup::Registers,
+];
+
+
+impl SignalGroup {
+    /// Returns the group's name.
+    pub fn name(self) -> &'static str {
+        match self {
+            SignalGroup::Pc => "pc",
+            SignalGroup::Insn => "insn",
+            SignalGroup::Control => "control",
+            SignalGroup::Forward => "forward",
+            SignalGroup::Hazard => "hazard",
+            SignalGroup::Registers => "regs",
+        }
+    }
+
+    /// Returns the group called `name`, if any.
+    pub fn by_name(name: &str) -> Option<SignalGroup> {
+        GROUPS.iter().cloned().find(|g| g.name() == name)
+    }
+}
+
+
+/// Names of the pipeline registers, in pipeline order.
+const REGISTERS: [&str; 4] = ["if_id", "id_ex", "ex_mem", "mem_wb"];
+
+/// Control lines and their widths.
+const CONTROL: [(&str, u32); 7] = [
+    ("branch", 1),
+    ("mem_read", 1),
+    ("mem_to_reg", 1),
+    ("alu_op", 5),
+    ("mem_write", 1),
+    ("alu_src", 1),
+    ("reg_write", 1),
+];
+
+/// ForwardA/ForwardB mux selects, as in Patterson & Hennessy.
+const FORWARD_ID_EX: u64 = 0b00;
+const FORWARD_EX_MEM: u64 = 0b10;
+const FORWARD_MEM_WB: u64 = 0b01;
+
+
+/// A dumped signal.
+struct Signal {
+    /// Identifier code in the dump
+    code: String,
+    width: u32,
+    /// Value at the last dumped time
+    value: Option<u64>,
+}
+
+
+/// Writes pipeline signals as a VCD file.
+pub struct VcdWriter<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+    groups: Vec<SignalGroup>,
+    signals: Vec<Signal>,
+    /// Cycle of the last dumped time
+    cycle: Option<u64>,
+    /// Pipeline events and the instruction in EX in the current cycle
+    events: Vec<Event>,
+    ex_insn: Option<u32>,
+}
+
+
+impl<W: Write> VcdWriter<W> {
+    /// Constructs a new `VcdWriter` dumping `groups` to `out`.
+    pub fn new(out: W, groups: &[SignalGroup]) -> VcdWriter<W> {
+        // Dump groups in a fixed order however they were given
+        let groups = GROUPS
+            .iter()
+            .cloned()
+            .filter(|g| groups.contains(g))
+            .collect();
+
+        VcdWriter {
+            out,
+            error: None,
+            groups,
+            signals: Vec::new(),
+            cycle: None,
+            events: Vec::new(),
+            ex_insn: None,
+        }
+    }
+
+    /// Ends the dump, then flushes and returns the writer, or the first
+    /// error writing to it.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if let Some(cycle) = self.cycle {
+            writeln!(self.out, "#{}", cycle + 1)?;
+        }
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    /// Declares a signal in the current scope.
+    fn var(&mut self, name: &str, width: u32) -> io::Result<()> {
+        let code = code(self.signals.len());
+        writeln!(self.out, "$var wire {} {} {} $end", width, code, name)?;
+        self.signals.push(Signal {
+            code,
+            width,
+            value: None,
+        });
+
+        Ok(())
+    }
+
+    /// Writes the header, declaring the signals of the selected groups.
+    fn header(&mut self) -> io::Result<()> {
+        writeln!(self.out, "$version riscv-5stage-simulator $end")?;
+        writeln!(self.out, "$comment one time unit per cycle $end")?;
+        writeln!(self.out, "$timescale 1ns $end")?;
+        writeln!(self.out, "$scope module cpu $end")?;
+
+        let groups = self.groups.clone();
+        let has = |g| groups.contains(&g);
+        let per_register = has(SignalGroup::Pc) || has(SignalGroup::Insn) ||
+            has(SignalGroup::Control);
+        for name in REGISTERS.iter().filter(|_| per_register) {
+            writeln!(self.out, "$scope module {} $end", name)?;
+            if has(SignalGroup::Pc) {
+                self.var("pc", 32)?;
+            }
+            if has(SignalGroup::Insn) {
+                self.var("insn", 32)?;
+            }
+            if has(SignalGroup::Control) {
+                for &(line, width) in CONTROL.iter() {
+                    self.var(line, width)?;
+                }
+            }
+            writeln!(self.out, "$upscope $end")?;
+        }
+        if has(SignalGroup::Forward) {
+            writeln!(self.out, "$scope module forward $end")?;
+            self.var("forward_a", 2)?;
+            self.var("forward_b", 2)?;
+            writeln!(self.out, "$upscope $end")?;
+        }
+        if has(SignalGroup::Hazard) {
+            writeln!(self.out, "$scope module hazard $end")?;
+            self.var("stall", 1)?;
+            self.var("mem_stall", 1)?;
+            self.var("flush", 1)?;
+            writeln!(self.out, "$upscope $end")?;
+        }
+        if has(SignalGroup::Registers) {
+            writeln!(self.out, "$scope module regs $end")?;
+            self.var("pc", 32)?;
+            for i in 0..32 {
+                self.var(&format!("x{}", i), 32)?;
+            }
+            writeln!(self.out, "$upscope $end")?;
+        }
+
+        writeln!(self.out, "$upscope $end")?;
+        writeln!(self.out, "$enddefinitions $end")
+    }
+
+    /// Returns the values of the selected signals, in declaration order.
+    fn values(&self, pipeline: &Pipeline, reg: &RegisterFile) -> Vec<u64> {
+        let mut values = Vec::new();
+        let registers = [
+            (pipeline.if_id.pc, pipeline.if_id.raw_insn),
+            (pipeline.id_ex.pc, pipeline.id_ex.insn.as_u32()),
+            (pipeline.ex_mem.pc, pipeline.ex_mem.insn.as_u32()),
+            (pipeline.mem_wb.pc, pipeline.mem_wb.insn.as_u32()),
+        ];
+        let has = |g| self.groups.contains(&g);
+
+        if has(SignalGroup::Pc) || has(SignalGroup::Insn) ||
+            has(SignalGroup::Control)
+        {
+            for &(pc, raw_insn) in registers.iter() {
+                if has(SignalGroup::Pc) {
+                    values.push(pc as u64);
+                }
+                if has(SignalGroup::Insn) {
+                    values.push(raw_insn as u64);
+                }
+                if has(SignalGroup::Control) {
+                    let semantics = Instruction::new(raw_insn).semantics;
+                    values.extend(control(&semantics).iter());
+                }
+            }
+        }
+        if has(SignalGroup::Forward) {
+            let (a, b) = self.forward_selects();
+            values.push(a);
+            values.push(b);
+        }
+        if has(SignalGroup::Hazard) {
+            let stall = self.events.iter().any(|e| match *e {
+                Event::Stall { stage, .. } => stage == Stage::Id,
+                _ => false,
+            });
+            let mem_stall = self.events.iter().any(|e| match *e {
+                Event::Stall { reason, .. } => reason == StallReason::Memory,
+                _ => false,
+            });
+            let flush = self.events.iter().any(|e| e.name() == "Flush");
+            values.push(stall as u64);
+            values.push(mem_stall as u64);
+            values.push(flush as u64);
+        }
+        if has(SignalGroup::Registers) {
+            values.push(reg.pc.read() as u64);
+            for r in reg.gpr.iter() {
+                values.push(r.read() as u64);
+            }
+        }
+
+        values
+    }
+
+    /// Returns the ForwardA and ForwardB mux selects of the current cycle.
+    fn forward_selects(&self) -> (u64, u64) {
+        let (rs1, rs2) = match self.ex_insn.map(Instruction::new) {
+            Some(insn) => (insn.fields.rs1, insn.fields.rs2),
+            None => (None, None),
+        };
+        let mut a = FORWARD_ID_EX;
+        let mut b = FORWARD_ID_EX;
+
+        // Operand A is forwarded before operand B
+        for event in self.events.iter() {
+            if let Event::Forward {
+                src,
+                dst: Stage::Ex,
+                from_stage,
+                ..
+            } = *event
+            {
+                let select = match from_stage {
+                    Stage::Mem => FORWARD_EX_MEM,
+                    _ => FORWARD_MEM_WB,
+                };
+                if Some(src) == rs1 && a == FORWARD_ID_EX {
+                    a = select;
+                } else if Some(src) == rs2 {
+                    b = select;
+                }
+            }
+        }
+
+        (a, b)
+    }
+
+    /// Dumps the signals that changed in `cycle`.
+    fn dump(
+        &mut self,
+        cycle: u64,
+        pipeline: &Pipeline,
+        reg: &RegisterFile,
+    ) -> io::Result<()> {
+        if self.cycle.is_none() {
+            self.header()?;
+        }
+        if self.cycle == Some(cycle) {
+            return Ok(());
+        }
+        self.cycle = Some(cycle);
+
+        let values = self.values(pipeline, reg);
+        let mut changed = false;
+        for (signal, value) in self.signals.iter_mut().zip(values) {
+            if signal.value == Some(value) {
+                continue;
+            }
+            if !changed {
+                writeln!(self.out, "#{}", cycle)?;
+                changed = true;
+            }
+            signal.value = Some(value);
+            match signal.width {
+                1 => writeln!(self.out, "{}{}", value, signal.code)?,
+                _ => writeln!(self.out, "b{:b} {}", value, signal.code)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl<W: Write> Observer for VcdWriter<W> {
+    fn event(&mut self, _cycle: u64, event: &Event) {
+        self.events.push(*event);
+    }
+
+    fn cycle(&mut self, _cycle: u64, stages: &Occupancy) {
+        self.ex_insn = stages[2].map(|slot| slot.raw_insn);
+    }
+
+    fn state(
+        &mut self,
+        cycle: u64,
+        pipeline: &Pipeline,
+        reg: &RegisterFile,
+        _mem: &DataMemory,
+    ) {
+        if self.error.is_none() {
+            if let Err(e) = self.dump(cycle, pipeline, reg) {
+                self.error = Some(e);
+            }
+        }
+        self.events.clear();
+    }
+}
+
+
+/// Returns the control lines of `semantics`, in `CONTROL` order.
+fn control(semantics: &Semantics) -> [u64; 7] {
+    [
+        semantics.branch as u64,
+        semantics.mem_read as u64,
+        semantics.mem_to_reg as u64,
+        alu_op(semantics.alu_op),
+        semantics.mem_write as u64,
+        (semantics.alu_src == AluSrc::Imm) as u64,
+        semantics.reg_write as u64,
+    ]
+}
+
+
+/// Encodes an ALU operation in declaration order.
+fn alu_op(op: AluOp) -> u64 {
+    match op {
+        AluOp::Add => 0,
+        AluOp::Sub => 1,
+        AluOp::And => 2,
+        AluOp::Or => 3,
+        AluOp::Xor => 4,
+        AluOp::SetOnLessThan => 5,
+        AluOp::SetOnLessThanUnsigned => 6,
+        AluOp::ShiftLeft => 7,
+        AluOp::ShiftRightLogical => 8,
+        AluOp::ShiftRightArithmetic => 9,
+        AluOp::BranchOnEqual => 10,
+        AluOp::BranchOnNotEqual => 11,
+        AluOp::BranchOnLessThan => 12,
+        AluOp::BranchOnLessThanUnsigned => 13,
+        AluOp::BranchOnGreaterOrEqual => 14,
+        AluOp::BranchOnGreaterOrEqualUnsigned => 15,
+    }
+}
+
+
+/// Returns the identifier code of the `n`th signal.
+///
+/// Codes are the shortest strings of printable ASCII characters.
+fn code(mut n: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return code;
+        }
+        n -= 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ca_simulator;
+    use consts;
+    use hazards::{HazardPolicy, HazardUnit};
+    use memory::instruction::TestInstructionMemory;
+    use predictor::BranchUnit;
+    use predictor::direction::NotTaken;
+
+    #[test]
+    fn codes() {
+        assert_eq!(code(0), "!");
+        assert_eq!(code(93), "~");
+        assert_eq!(code(94), "!!");
+        assert_eq!(code(95), "\"!");
+    }
+
+    /// Dumps `groups` for a load-use sequence.
+    fn dump(groups: &[SignalGroup]) -> String {
+        let insns = vec![
+            0x00_01_22_03, // lw x4, 0(x2)
+            0x00_42_07_b3, // add x15, x4, x4
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ];
+
+        let insn_memory = TestInstructionMemory::new(insns);
+        let mut data_memory = DataMemory::new(1024);
+        let mut registers = RegisterFile::new(0x0);
+        let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+        let mut hazard_unit = HazardUnit::new(HazardPolicy::Forwarding);
+        let mut vcd = VcdWriter::new(Vec::new(), groups);
+        data_memory.write(0, consts::WORD_SIZE, 9).unwrap();
+
+        ca_simulator::run_with_observer(
+            &insn_memory,
+            &mut data_memory,
+            &mut registers,
+            &mut branch_unit,
+            &mut hazard_unit,
+            &mut vcd,
+        );
+
+        String::from_utf8(vcd.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn hazards() {
+        let vcd = dump(&[SignalGroup::Hazard, SignalGroup::Forward]);
+        assert_eq!(
+            vcd,
+            "$version riscv-5stage-simulator $end\n\
+             $comment one time unit per cycle $end\n\
+             $timescale 1ns $end\n\
+             $scope module cpu $end\n\
+             $scope module forward $end\n\
+             $var wire 2 ! forward_a $end\n\
+             $var wire 2 \" forward_b $end\n\
+             $upscope $end\n\
+             $scope module hazard $end\n\
+             $var wire 1 # stall $end\n\
+             $var wire 1 $ mem_stall $end\n\
+             $var wire 1 % flush $end\n\
+             $upscope $end\n\
+             $upscope $end\n\
+             $enddefinitions $end\n\
+             #0\n\
+             b0 !\n\
+             b0 \"\n\
+             0#\n\
+             0$\n\
+             0%\n\
+             #2\n\
+             1#\n\
+             #3\n\
+             0#\n\
+             #4\n\
+             b1 !\n\
+             b1 \"\n\
+             #5\n\
+             b0 !\n\
+             b0 \"\n\
+             #9\n"
+        );
+    }
+
+    #[test]
+    fn registers() {
+        let vcd = dump(&[SignalGroup::Control, SignalGroup::Registers]);
+        assert!(vcd.contains("$scope module id_ex $end\n"));
+        assert!(vcd.contains("$var wire 5 + alu_op $end\n"));
+        assert!(vcd.contains("$var wire 32 M x15 $end\n"));
+        assert!(
+            vcd.contains("$scope module if_id $end\n$var wire 1 ! branch")
+        );
+
+        // x4 is loaded, then doubled into x15
+        assert!(vcd.contains("\nb1001 B\n"));
+        assert!(vcd.contains("\nb10010 M\n"));
+    }
+
+}
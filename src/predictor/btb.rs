@@ -0,0 +1,193 @@
+//! Branch target buffer.
+//!
+//! A set-associative cache of branch and jump targets, looked up by the
+//! fetch stage with the address of the instruction being fetched. Tags may
+//! be truncated, in which case different branches can alias and supply each
+//! other's targets.
+
+
+use checkpoint::{Checkpoint, Reader, Writer};
+
+use std::io;
+
+/// A branch target buffer entry.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    tag: u32,
+    /// Full address of the branch, only used to detect aliasing
+    pc: u32,
+    target: u32,
+    last_used: u64,
+}
+
+
+/// A set-associative branch target buffer with LRU replacement.
+#[derive(Clone, Debug)]
+pub struct Btb {
+    sets: usize,
+    ways: usize,
+    tag_mask: u32,
+    entries: Vec<Option<Entry>>,
+    clock: u64,
+}
+
+
+impl Btb {
+    /// Constructs a new `Btb`.
+    ///
+    /// `entries` is split into sets of `ways` entries, and the number of sets
+    /// must be a power of two. Only the low `tag_bits` bits of each tag are
+    /// stored.
+    pub fn new(entries: usize, ways: usize, tag_bits: u32) -> Btb {
+        assert!(
+            ways > 0 && entries.is_multiple_of(ways),
+            "Invalid associativity"
+        );
+        let sets = entries / ways;
+        assert!(sets.is_power_of_two(), "Sets must be a power of two");
+
+        Btb {
+            sets,
+            ways,
+            tag_mask: match tag_bits {
+                0..=31 => (1 << tag_bits) - 1,
+                _ => !0,
+            },
+            entries: vec![None; entries],
+            clock: 0,
+        }
+    }
+
+    /// Returns the total number of entries.
+    pub fn entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the number of entries per set.
+    pub fn ways(&self) -> usize {
+        self.ways
+    }
+
+    /// Looks up the target of the branch at `pc`.
+    ///
+    /// Returns the target, and whether the entry hit belongs to a different
+    /// branch with the same set and tag.
+    pub fn lookup(&mut self, pc: u32) -> Option<(u32, bool)> {
+        self.clock += 1;
+        let clock = self.clock;
+        let tag = self.tag(pc);
+
+        self.set_mut(pc)
+            .iter_mut()
+            .filter_map(|e| e.as_mut())
+            .find(|e| e.tag == tag)
+            .map(|e| {
+                e.last_used = clock;
+                (e.target, e.pc != pc)
+            })
+    }
+
+    /// Records `target` as the destination of the branch at `pc`.
+    pub fn insert(&mut self, pc: u32, target: u32) {
+        self.clock += 1;
+        let entry = Entry {
+            tag: self.tag(pc),
+            pc,
+            target,
+            last_used: self.clock,
+        };
+
+        let set = self.set_mut(pc);
+        let way = set.iter()
+            .position(|e| e.is_some_and(|e| e.tag == entry.tag))
+            .or_else(|| set.iter().position(|e| e.is_none()))
+            .unwrap_or_else(|| {
+                // Evict the least recently used entry
+                (0..set.len())
+                    .min_by_key(|&i| set[i].map_or(0, |e| e.last_used))
+                    .unwrap()
+            });
+        set[way] = Some(entry);
+    }
+
+    fn tag(&self, pc: u32) -> u32 {
+        ((pc >> 2) / self.sets as u32) & self.tag_mask
+    }
+
+    fn set_mut(&mut self, pc: u32) -> &mut [Option<Entry>] {
+        let set = (pc >> 2) as usize & (self.sets - 1);
+        let ways = self.ways;
+        &mut self.entries[set * ways..(set + 1) * ways]
+    }
+}
+
+
+impl Checkpoint for Btb {
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.entries.len());
+        for &entry in self.entries.iter() {
+            w.option(entry, |w, e| {
+                w.u32(e.tag);
+                w.u32(e.pc);
+                w.u32(e.target);
+                w.u64(e.last_used);
+            });
+        }
+        w.u64(self.clock);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_usize("BTB entries", self.entries.len())?;
+        for entry in self.entries.iter_mut() {
+            *entry = r.option(|r| {
+                Ok(Entry {
+                    tag: r.u32()?,
+                    pc: r.u32()?,
+                    target: r.u32()?,
+                    last_used: r.u64()?,
+                })
+            })?;
+        }
+        self.clock = r.u64()?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_after_insert() {
+        let mut btb = Btb::new(16, 2, 32);
+        assert_eq!(btb.lookup(0x40), None);
+        btb.insert(0x40, 0x100);
+        assert_eq!(btb.lookup(0x40), Some((0x100, false)));
+        assert_eq!(btb.lookup(0x44), None);
+    }
+
+    #[test]
+    fn short_tags_alias() {
+        // 4 sets of 1 way: 0x40 and 0x140 share set 0 and a 2-bit tag
+        let mut btb = Btb::new(4, 1, 2);
+        btb.insert(0x40, 0x100);
+        assert_eq!(btb.lookup(0x140), Some((0x100, true)));
+    }
+
+    #[test]
+    fn lru_replacement() {
+        // 1 set of 2 ways
+        let mut btb = Btb::new(2, 2, 32);
+        btb.insert(0x0, 0x100);
+        btb.insert(0x4, 0x200);
+        btb.lookup(0x0);
+        btb.insert(0x8, 0x300);
+
+        assert_eq!(btb.lookup(0x0), Some((0x100, false)));
+        assert_eq!(btb.lookup(0x4), None);
+        assert_eq!(btb.lookup(0x8), Some((0x300, false)));
+    }
+
+}
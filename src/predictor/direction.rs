@@ -0,0 +1,455 @@
+//! Conditional branch direction predictors.
+//!
+//! Table-based predictors index their tables with the word address of the
+//! branch. Global history is updated when a branch resolves rather than
+//! speculatively at fetch, and the history a branch was predicted with is
+//! passed back when it resolves to train the same counter.
+
+
+use checkpoint::{self, Reader, Writer};
+use predictor::BranchPredictor;
+
+use std::io;
+
+
+/// Always predicts not taken (fall through).
+pub struct NotTaken;
+
+
+impl BranchPredictor for NotTaken {
+    fn name(&self) -> &str {
+        "not-taken"
+    }
+
+    fn predict(&self, _pc: u32, _target: u32) -> bool {
+        false
+    }
+
+    fn update(
+        &mut self,
+        _pc: u32,
+        _target: u32,
+        _history: u32,
+        _taken: bool,
+    ) {
+    }
+}
+
+
+/// Always predicts taken.
+pub struct Taken;
+
+
+impl BranchPredictor for Taken {
+    fn name(&self) -> &str {
+        "taken"
+    }
+
+    fn predict(&self, _pc: u32, _target: u32) -> bool {
+        true
+    }
+
+    fn update(
+        &mut self,
+        _pc: u32,
+        _target: u32,
+        _history: u32,
+        _taken: bool,
+    ) {
+    }
+}
+
+
+/// Backward taken, forward not taken.
+///
+/// Backward branches are usually loops, which are usually taken.
+pub struct Btfn;
+
+
+impl BranchPredictor for Btfn {
+    fn name(&self) -> &str {
+        "btfn"
+    }
+
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        target < pc
+    }
+
+    fn update(
+        &mut self,
+        _pc: u32,
+        _target: u32,
+        _history: u32,
+        _taken: bool,
+    ) {
+    }
+}
+
+
+/// Remembers the last outcome of each branch.
+pub struct OneBit {
+    table: Vec<bool>,
+}
+
+
+impl OneBit {
+    /// Constructs a new `OneBit` with `entries` (a power of two) entries.
+    pub fn new(entries: usize) -> OneBit {
+        assert!(entries.is_power_of_two(), "Entries must be a power of two");
+
+        OneBit { table: vec![false; entries] }
+    }
+}
+
+
+impl BranchPredictor for OneBit {
+    fn name(&self) -> &str {
+        "1-bit"
+    }
+
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.table[index(pc, self.table.len())]
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, _history: u32, taken: bool) {
+        let i = index(pc, self.table.len());
+        self.table[i] = taken;
+    }
+
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.table.len());
+        for &taken in self.table.iter() {
+            w.bool(taken);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_usize("predictor entries", self.table.len())?;
+        for taken in self.table.iter_mut() {
+            *taken = r.bool()?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Bimodal predictor with a 2-bit saturating counter per entry.
+pub struct TwoBit {
+    table: Vec<Counter>,
+}
+
+
+impl TwoBit {
+    /// Constructs a new `TwoBit` with `entries` (a power of two) entries.
+    pub fn new(entries: usize) -> TwoBit {
+        assert!(entries.is_power_of_two(), "Entries must be a power of two");
+
+        TwoBit { table: vec![Counter::default(); entries] }
+    }
+}
+
+
+impl BranchPredictor for TwoBit {
+    fn name(&self) -> &str {
+        "2-bit"
+    }
+
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.table[index(pc, self.table.len())].taken()
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, _history: u32, taken: bool) {
+        let i = index(pc, self.table.len());
+        self.table[i].update(taken);
+    }
+
+    fn save(&self, w: &mut Writer) {
+        save_counters(w, &self.table);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        restore_counters(r, &mut self.table)
+    }
+}
+
+
+/// 2-bit counters indexed by the branch address XOR global history.
+pub struct Gshare {
+    table: Vec<Counter>,
+    history: u32,
+    history_mask: u32,
+}
+
+
+impl Gshare {
+    /// Constructs a new `Gshare` with `entries` (a power of two) entries and
+    /// `history_bits` bits of global history.
+    pub fn new(entries: usize, history_bits: u32) -> Gshare {
+        assert!(entries.is_power_of_two(), "Entries must be a power of two");
+        assert!(history_bits < 32, "History must be under 32 bits");
+
+        Gshare {
+            table: vec![Counter::default(); entries],
+            history: 0,
+            history_mask: (1 << history_bits) - 1,
+        }
+    }
+
+    /// Returns the table index for the branch at `pc` with global history
+    /// `history`.
+    fn index(&self, pc: u32, history: u32) -> usize {
+        ((pc >> 2) ^ history) as usize & (self.table.len() - 1)
+    }
+}
+
+
+impl BranchPredictor for Gshare {
+    fn name(&self) -> &str {
+        "gshare"
+    }
+
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.table[self.index(pc, self.history)].taken()
+    }
+
+    fn history(&self) -> u32 {
+        self.history
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, history: u32, taken: bool) {
+        let i = self.index(pc, history);
+        self.table[i].update(taken);
+        self.history = ((self.history << 1) | taken as u32) &
+            self.history_mask;
+    }
+
+    fn save(&self, w: &mut Writer) {
+        save_counters(w, &self.table);
+        w.u32(self.history);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        restore_counters(r, &mut self.table)?;
+        self.history = r.u32()? & self.history_mask;
+
+        Ok(())
+    }
+}
+
+
+/// Chooses per branch between a bimodal and a gshare predictor.
+pub struct Tournament {
+    bimodal: TwoBit,
+    gshare: Gshare,
+    /// Counters that predict "taken" when gshare should be trusted
+    chooser: Vec<Counter>,
+}
+
+
+impl Tournament {
+    /// Constructs a new `Tournament` with `entries` (a power of two) entries
+    /// in each table and `history_bits` bits of global history.
+    pub fn new(entries: usize, history_bits: u32) -> Tournament {
+        Tournament {
+            bimodal: TwoBit::new(entries),
+            gshare: Gshare::new(entries, history_bits),
+            chooser: vec![Counter::default(); entries],
+        }
+    }
+}
+
+
+impl BranchPredictor for Tournament {
+    fn name(&self) -> &str {
+        "tournament"
+    }
+
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        match self.chooser[index(pc, self.chooser.len())].taken() {
+            true => self.gshare.predict(pc, target),
+            false => self.bimodal.predict(pc, target),
+        }
+    }
+
+    fn history(&self) -> u32 {
+        self.gshare.history
+    }
+
+    fn update(&mut self, pc: u32, target: u32, history: u32, taken: bool) {
+        let bimodal = self.bimodal.predict(pc, target) == taken;
+        let i = self.gshare.index(pc, history);
+        let gshare = self.gshare.table[i].taken() == taken;
+
+        // Only train the chooser when exactly one predictor was right
+        if bimodal != gshare {
+            let i = index(pc, self.chooser.len());
+            self.chooser[i].update(gshare);
+        }
+
+        self.bimodal.update(pc, target, history, taken);
+        self.gshare.update(pc, target, history, taken);
+    }
+
+    fn save(&self, w: &mut Writer) {
+        self.bimodal.save(w);
+        self.gshare.save(w);
+        save_counters(w, &self.chooser);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.bimodal.restore(r)?;
+        self.gshare.restore(r)?;
+        restore_counters(r, &mut self.chooser)
+    }
+}
+
+
+/// A 2-bit saturating counter, initially weakly not taken.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Counter(u8);
+
+
+impl Default for Counter {
+    fn default() -> Counter {
+        Counter(1)
+    }
+}
+
+
+impl Counter {
+    fn taken(self) -> bool {
+        self.0 >= 2
+    }
+
+    fn update(&mut self, taken: bool) {
+        self.0 = match taken {
+            true => (self.0 + 1).min(3),
+            false => self.0.saturating_sub(1),
+        };
+    }
+}
+
+
+/// Returns the table index for the branch at `pc`.
+fn index(pc: u32, entries: usize) -> usize {
+    (pc >> 2) as usize & (entries - 1)
+}
+
+
+/// Appends a table of counters to a checkpoint.
+fn save_counters(w: &mut Writer, table: &[Counter]) {
+    w.usize(table.len());
+    for counter in table {
+        w.u8(counter.0);
+    }
+}
+
+
+/// Restores a table of counters saved by `save_counters`.
+fn restore_counters(r: &mut Reader, table: &mut [Counter]) -> io::Result<()> {
+    r.expect_usize("predictor entries", table.len())?;
+    for counter in table.iter_mut() {
+        match r.u8()? {
+            n if n <= 3 => counter.0 = n,
+            n => return Err(checkpoint::invalid(&format!("counter {}", n))),
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trains `predictor` on a repeating pattern at one branch and returns
+    /// the number of mispredictions in the last `pattern.len()` outcomes.
+    fn misses(predictor: &mut dyn BranchPredictor, pattern: &[bool]) -> usize {
+        let (pc, target) = (0x40, 0x20);
+        for _ in 0..20 {
+            for &taken in pattern {
+                let history = predictor.history();
+                predictor.update(pc, target, history, taken);
+            }
+        }
+
+        pattern
+            .iter()
+            .filter(|&&taken| {
+                let miss = predictor.predict(pc, target) != taken;
+                let history = predictor.history();
+                predictor.update(pc, target, history, taken);
+                miss
+            })
+            .count()
+    }
+
+    #[test]
+    fn static_predictors() {
+        assert!(!NotTaken.predict(0x40, 0x20));
+        assert!(Taken.predict(0x40, 0x80));
+        assert!(Btfn.predict(0x40, 0x20));
+        assert!(!Btfn.predict(0x40, 0x80));
+    }
+
+    #[test]
+    fn one_bit_misses_loop_exit_twice() {
+        let mut loop_branch = vec![true; 7];
+        loop_branch.push(false);
+        assert_eq!(misses(&mut OneBit::new(16), &loop_branch), 2);
+    }
+
+    #[test]
+    fn two_bit_misses_loop_exit_once() {
+        let mut loop_branch = vec![true; 7];
+        loop_branch.push(false);
+        assert_eq!(misses(&mut TwoBit::new(16), &loop_branch), 1);
+    }
+
+    #[test]
+    fn two_bit_hysteresis() {
+        let mut p = TwoBit::new(16);
+        p.update(0x40, 0x20, 0, true);
+        p.update(0x40, 0x20, 0, true);
+        p.update(0x40, 0x20, 0, false);
+        assert!(p.predict(0x40, 0x20));
+    }
+
+    #[test]
+    fn gshare_learns_alternating() {
+        let alternating = [true, false];
+        assert_eq!(misses(&mut Gshare::new(64, 4), &alternating), 0);
+        assert_eq!(misses(&mut TwoBit::new(64), &alternating), 2);
+    }
+
+    /// Tests that a branch trains the counter it was predicted with, even
+    /// when an older branch resolves in between.
+    #[test]
+    fn gshare_trains_predicted_counter() {
+        let mut p = Gshare::new(16, 4);
+        let history = p.history();
+
+        p.update(0x84, 0x20, history, true);
+        assert_eq!(p.history(), 1);
+        p.update(0x40, 0x20, history, true);
+
+        assert_eq!(p.table[p.index(0x40, history)], Counter(2));
+        assert_eq!(p.table[p.index(0x40, 1)], Counter(2)); // 0x84 only
+    }
+
+    #[test]
+    fn tournament_picks_better_predictor() {
+        let alternating = [true, false];
+        assert_eq!(misses(&mut Tournament::new(64, 4), &alternating), 0);
+    }
+
+    #[test]
+    fn tables_alias() {
+        let mut p = OneBit::new(4);
+        p.update(0x0, 0x20, 0, true);
+        assert!(p.predict(0x10, 0x20));
+    }
+
+}
@@ -0,0 +1,496 @@
+//! Branch prediction for the fetch stage.
+//!
+//! The IF stage predecodes each fetched instruction, and asks a
+//! `BranchUnit` for the next fetch address. Conditional branch directions
+//! come from a `BranchPredictor`, targets from an optional branch target
+//! buffer, and return addresses from an optional return address stack.
+//! Branches still resolve later in the pipeline, where a wrong prediction
+//! flushes the younger instructions and redirects fetch.
+
+
+pub mod btb;
+pub mod direction;
+pub mod ras;
+
+use checkpoint::{self, Checkpoint, Reader, Writer};
+use consts;
+use instruction::{Instruction, Opcode};
+
+use self::btb::Btb;
+use self::direction::{Btfn, Gshare, OneBit, Taken, NotTaken, Tournament,
+                      TwoBit};
+use self::ras::ReturnAddressStack;
+
+use std::io;
+
+
+/// Predicts the direction of conditional branches.
+pub trait BranchPredictor {
+    /// Returns the predictor's name.
+    fn name(&self) -> &str;
+
+    /// Predicts whether the branch at `pc` to `target` is taken.
+    fn predict(&self, pc: u32, target: u32) -> bool;
+
+    /// Returns the global history `predict` indexes its tables with.
+    ///
+    /// The fetch stage keeps it with the branch and passes it back to
+    /// `update`, so the counter that made a prediction is the one trained,
+    /// even when older branches resolve in between. Predictors without
+    /// global history return 0.
+    fn history(&self) -> u32 {
+        0
+    }
+
+    /// Trains the predictor with a resolved branch, predicted with global
+    /// history `history`.
+    fn update(&mut self, pc: u32, target: u32, history: u32, taken: bool);
+
+    /// Appends the predictor's tables and history to a checkpoint.
+    ///
+    /// Static predictors have nothing to save.
+    fn save(&self, _w: &mut Writer) {}
+
+    /// Restores the state saved by `save`.
+    fn restore(&mut self, _r: &mut Reader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Names accepted by `by_name`.
+pub const NAMES: [&str; 7] = [
+    "not-taken",
+    "taken",
+    "btfn",
+    "1-bit",
+    "2-bit",
+    "gshare",
+    "tournament",
+];
+
+/// Table entries used by predictors constructed with `by_name`.
+pub const DEFAULT_ENTRIES: usize = 1024;
+
+/// Global history bits used by predictors constructed with `by_name`.
+pub const DEFAULT_HISTORY_BITS: u32 = 10;
+
+
+/// Constructs a predictor with default table sizes from its name.
+pub fn by_name(name: &str) -> Option<Box<dyn BranchPredictor>> {
+    let entries = DEFAULT_ENTRIES;
+    let bits = DEFAULT_HISTORY_BITS;
+
+    let predictor: Box<dyn BranchPredictor> = match name {
+        "not-taken" => Box::new(NotTaken),
+        "taken" => Box::new(Taken),
+        "btfn" => Box::new(Btfn),
+        "1-bit" => Box::new(OneBit::new(entries)),
+        "2-bit" => Box::new(TwoBit::new(entries)),
+        "gshare" => Box::new(Gshare::new(entries, bits)),
+        "tournament" => Box::new(Tournament::new(entries, bits)),
+        _ => return None,
+    };
+
+    Some(predictor)
+}
+
+
+/// Branch prediction counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BranchStats {
+    /// Conditional branches resolved
+    pub branches: u64,
+    /// Conditional branches whose next address was mispredicted
+    pub mispredicted: u64,
+    /// Jumps (JAL and JALR) resolved
+    pub jumps: u64,
+    /// Jumps whose target was mispredicted
+    pub jumps_mispredicted: u64,
+    /// Cycles lost to instructions flushed after mispredictions
+    pub penalty_cycles: u64,
+    /// Cycles branches resolved in ID stalled waiting for operands
+    pub stall_cycles: u64,
+    /// Branch target buffer lookups that hit
+    pub btb_hits: u64,
+    /// Branch target buffer lookups that missed
+    pub btb_misses: u64,
+    /// Hits on an entry belonging to a different branch
+    pub btb_aliases: u64,
+    /// Return addresses discarded by calls made with a full stack
+    pub ras_overflows: u64,
+    /// Returns made with an empty return address stack
+    pub ras_underflows: u64,
+}
+
+
+impl BranchStats {
+    /// Returns the fraction of branches predicted correctly.
+    ///
+    /// Returns 1.0 if no branches were resolved.
+    pub fn accuracy(&self) -> f64 {
+        match self.branches {
+            0 => 1.0,
+            n => (n - self.mispredicted) as f64 / n as f64,
+        }
+    }
+}
+
+
+/// Pipeline stage in which conditional branches and JAL resolve.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BranchResolution {
+    /// Resolve in MEM, flushing three instructions on a misprediction
+    #[default]
+    Memory,
+    /// Resolve in ID, flushing one instruction on a misprediction
+    ///
+    /// Branches stall until their operands can be forwarded to ID.
+    Decode,
+}
+
+
+/// Predicts the next fetch address and trains on resolved branches.
+pub struct BranchUnit {
+    predictor: Box<dyn BranchPredictor>,
+    resolution: BranchResolution,
+    btb: Option<Btb>,
+    ras: Option<ReturnAddressStack>,
+    /// Return address stack updated only by resolved jumps
+    committed_ras: Option<ReturnAddressStack>,
+    /// Return address stack before the last fetch pushed or popped it
+    fetch_ras: Option<ReturnAddressStack>,
+    pub stats: BranchStats,
+}
+
+
+impl BranchUnit {
+    /// Constructs a new `BranchUnit` without a BTB or return address stack.
+    ///
+    /// Without a BTB, conditional branch targets are taken from predecode
+    /// and jumps are not predicted.
+    pub fn new(predictor: Box<dyn BranchPredictor>) -> BranchUnit {
+        BranchUnit {
+            predictor,
+            resolution: BranchResolution::default(),
+            btb: None,
+            ras: None,
+            committed_ras: None,
+            fetch_ras: None,
+            stats: BranchStats::default(),
+        }
+    }
+
+    /// Sets the stage in which conditional branches and JAL resolve.
+    ///
+    /// JALR always resolves in MEM.
+    pub fn set_resolution(&mut self, resolution: BranchResolution) {
+        self.resolution = resolution;
+    }
+
+    /// Returns the stage in which conditional branches and JAL resolve.
+    pub fn resolution(&self) -> BranchResolution {
+        self.resolution
+    }
+
+    /// Sets or removes the branch target buffer.
+    pub fn set_btb(&mut self, btb: Option<Btb>) {
+        self.btb = btb;
+    }
+
+    /// Sets or removes the return address stack.
+    ///
+    /// The stack is updated speculatively at fetch, and repaired from a copy
+    /// updated by resolved jumps when the pipeline is flushed.
+    pub fn set_ras(&mut self, ras: Option<ReturnAddressStack>) {
+        self.committed_ras = ras.clone();
+        self.fetch_ras = None;
+        self.ras = ras;
+    }
+
+    /// Returns the direction predictor.
+    pub fn predictor(&self) -> &dyn BranchPredictor {
+        self.predictor.as_ref()
+    }
+
+    /// Predicts the address of the instruction after `raw_insn` at `pc`.
+    ///
+    /// Calls push their return address onto the return address stack, and
+    /// returns pop it, where x1 and x5 are link registers.
+    pub fn predict(&mut self, pc: u32, raw_insn: u32) -> u32 {
+        self.fetch_ras = None;
+        let npc = pc + consts::WORD_SIZE as u32;
+        let opcode = raw_insn & consts::OPCODE_MASK;
+        if opcode != consts::BRANCH_OPCODE && opcode != consts::JAL_OPCODE &&
+            opcode != consts::JALR_OPCODE
+        {
+            return npc;
+        }
+
+        let insn = Instruction::new(raw_insn);
+        let (push, pop) = ras_action(&insn);
+
+        if let Some(ref mut ras) = self.ras {
+            if push || pop {
+                self.fetch_ras = Some(ras.clone());
+            }
+            let ret = match pop {
+                true => ras.pop(),
+                false => None,
+            };
+            if push {
+                ras.push(npc);
+            }
+            if let Some(addr) = ret {
+                return addr;
+            }
+        }
+
+        let target = match self.btb {
+            Some(ref mut btb) => match btb.lookup(pc) {
+                Some((target, aliased)) => {
+                    self.stats.btb_hits += 1;
+                    self.stats.btb_aliases += aliased as u64;
+                    Some(target)
+                }
+                None => {
+                    self.stats.btb_misses += 1;
+                    None
+                }
+            },
+            None if insn.opcode == Opcode::Branch => {
+                Some(pc.wrapping_add(insn.fields.imm.unwrap()))
+            }
+            None => None,
+        };
+
+        match target {
+            Some(target) if insn.opcode != Opcode::Branch => target,
+            Some(target) if self.predictor.predict(pc, target) => target,
+            _ => npc,
+        }
+    }
+
+    /// Trains on a resolved branch or jump.
+    ///
+    /// `predicted` is the address fetched after `insn` at `pc` with global
+    /// history `history`, `npc` the correct one, and `penalty` the cycles
+    /// lost if they differ. Returns true if the branch was mispredicted.
+    pub fn resolve(
+        &mut self,
+        insn: &Instruction,
+        pc: u32,
+        history: u32,
+        predicted: u32,
+        npc: u32,
+        penalty: u64,
+    ) -> bool {
+        let mispredicted = npc != predicted;
+        let mut taken = true;
+
+        if insn.opcode == Opcode::Branch {
+            let target = pc.wrapping_add(insn.fields.imm.unwrap());
+            taken = npc == target;
+            self.predictor.update(pc, target, history, taken);
+            self.stats.branches += 1;
+            self.stats.mispredicted += mispredicted as u64;
+        } else {
+            self.stats.jumps += 1;
+            self.stats.jumps_mispredicted += mispredicted as u64;
+        }
+
+        if mispredicted {
+            self.stats.penalty_cycles += penalty;
+        }
+
+        if let Some(ref mut btb) = self.btb {
+            if taken {
+                btb.insert(pc, npc);
+            }
+        }
+
+        // Overflows and underflows are counted on the committed path only
+        if let Some(ref mut ras) = self.committed_ras {
+            let (push, pop) = ras_action(insn);
+            if pop && ras.pop().is_none() {
+                self.stats.ras_underflows += 1;
+            }
+            if push && ras.push(pc + consts::WORD_SIZE as u32) {
+                self.stats.ras_overflows += 1;
+            }
+        }
+
+        mispredicted
+    }
+
+    /// Discards speculative state after the pipeline is flushed.
+    pub fn flush(&mut self) {
+        if self.ras.is_some() {
+            self.ras = self.committed_ras.clone();
+        }
+    }
+
+    /// Discards the speculative state of the last fetch, after a branch
+    /// resolved in ID flushes the instruction in IF.
+    ///
+    /// Older jumps still in the pipeline keep their return address stack
+    /// updates, which `flush` would discard.
+    pub fn flush_fetch(&mut self) {
+        if let Some(ras) = self.fetch_ras.take() {
+            self.ras = Some(ras);
+        }
+    }
+}
+
+
+impl Checkpoint for BranchUnit {
+    /// Saves the predictor, BTB, both return address stacks and the
+    /// statistics.
+    fn save(&self, w: &mut Writer) {
+        w.str(self.predictor.name());
+        self.predictor.save(w);
+        w.bool(self.btb.is_some());
+        if let Some(ref btb) = self.btb {
+            btb.save(w);
+        }
+        w.bool(self.ras.is_some());
+        if let (Some(ras), Some(committed_ras)) =
+            (self.ras.as_ref(), self.committed_ras.as_ref())
+        {
+            ras.save(w);
+            committed_ras.save(w);
+        }
+
+        let stats = &self.stats;
+        for &counter in [
+            stats.branches,
+            stats.mispredicted,
+            stats.jumps,
+            stats.jumps_mispredicted,
+            stats.penalty_cycles,
+            stats.stall_cycles,
+            stats.btb_hits,
+            stats.btb_misses,
+            stats.btb_aliases,
+            stats.ras_overflows,
+            stats.ras_underflows,
+        ].iter()
+        {
+            w.u64(counter);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_str("predictor", self.predictor.name())?;
+        self.predictor.restore(r)?;
+        match (r.bool()?, self.btb.as_mut()) {
+            (true, Some(btb)) => btb.restore(r)?,
+            (false, None) => {}
+            _ => return Err(checkpoint::invalid("BTB mismatch")),
+        }
+        match (r.bool()?, self.ras.as_mut(), self.committed_ras.as_mut()) {
+            (true, Some(ras), Some(committed_ras)) => {
+                ras.restore(r)?;
+                committed_ras.restore(r)?;
+            }
+            (false, None, None) => {}
+            _ => return Err(checkpoint::invalid("RAS mismatch")),
+        }
+
+        let stats = &mut self.stats;
+        for counter in [
+            &mut stats.branches,
+            &mut stats.mispredicted,
+            &mut stats.jumps,
+            &mut stats.jumps_mispredicted,
+            &mut stats.penalty_cycles,
+            &mut stats.stall_cycles,
+            &mut stats.btb_hits,
+            &mut stats.btb_misses,
+            &mut stats.btb_aliases,
+            &mut stats.ras_overflows,
+            &mut stats.ras_underflows,
+        ].iter_mut()
+        {
+            **counter = r.u64()?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Returns whether a jump pushes and pops the return address stack.
+///
+/// See the link register hints in the RISC-V specification (table 2.1).
+fn ras_action(insn: &Instruction) -> (bool, bool) {
+    let rd = insn.fields.rd.unwrap_or(0);
+    let rs1 = insn.fields.rs1.unwrap_or(0);
+    let (rd_link, rs1_link) = (is_link(rd), is_link(rs1));
+
+    match insn.opcode {
+        Opcode::Jal => (rd_link, false),
+        Opcode::Jalr => (rd_link, rs1_link && (!rd_link || rd != rs1)),
+        _ => (false, false),
+    }
+}
+
+
+/// Indicates `reg` is a link register (x1 or x5).
+fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for name in NAMES.iter() {
+            assert_eq!(by_name(name).unwrap().name(), *name);
+        }
+        assert!(by_name("oracle").is_none());
+    }
+
+    #[test]
+    fn accuracy() {
+        let mut stats = BranchStats::default();
+        assert_eq!(stats.accuracy(), 1.0);
+
+        stats.branches = 8;
+        stats.mispredicted = 2;
+        assert_eq!(stats.accuracy(), 0.75);
+    }
+
+    #[test]
+    fn link_register_hints() {
+        let mut unit = BranchUnit::new(Box::new(NotTaken));
+        unit.set_ras(Some(ReturnAddressStack::new(4)));
+
+        assert_eq!(unit.predict(0x0, 0x100000ef), 0x4); // jal x1: push
+        assert_eq!(unit.predict(0x100, 0x00008067), 0x4); // jalr x0, x1: pop
+
+        assert_eq!(unit.predict(0x8, 0x100002ef), 0xc); // jal x5: push
+        // jalr x1, x5: pop, then push
+        assert_eq!(unit.predict(0x20, 0x000280e7), 0xc);
+        assert_eq!(unit.predict(0x30, 0x00008067), 0x24);
+
+        // jalr x1, x1: push only
+        assert_eq!(unit.predict(0x40, 0x000080e7), 0x44);
+        assert_eq!(unit.predict(0x50, 0x00008067), 0x44);
+    }
+
+    #[test]
+    fn flush_repairs_ras() {
+        let mut unit = BranchUnit::new(Box::new(NotTaken));
+        unit.set_ras(Some(ReturnAddressStack::new(4)));
+
+        // A wrong-path call is forgotten
+        unit.predict(0x0, 0x100000ef);
+        unit.flush();
+        assert_eq!(unit.predict(0x100, 0x00008067), 0x104);
+    }
+
+}
@@ -0,0 +1,95 @@
+//! Return address stack.
+
+
+use checkpoint::{self, Checkpoint, Reader, Writer};
+
+use std::collections::VecDeque;
+use std::io;
+
+
+/// A fixed-depth stack of predicted return addresses.
+///
+/// Pushing onto a full stack discards the oldest entry.
+#[derive(Clone, Debug)]
+pub struct ReturnAddressStack {
+    depth: usize,
+    entries: VecDeque<u32>,
+}
+
+
+impl ReturnAddressStack {
+    /// Constructs a new `ReturnAddressStack` holding up to `depth` addresses.
+    pub fn new(depth: usize) -> ReturnAddressStack {
+        assert!(depth > 0, "Return address stack needs at least one entry");
+
+        ReturnAddressStack {
+            depth,
+            entries: VecDeque::with_capacity(depth),
+        }
+    }
+
+    /// Returns the maximum number of addresses.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Pushes a return address. Returns true if the stack overflowed.
+    pub fn push(&mut self, addr: u32) -> bool {
+        let overflow = self.entries.len() == self.depth;
+        if overflow {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(addr);
+
+        overflow
+    }
+
+    /// Pops the most recent return address, or `None` if empty.
+    pub fn pop(&mut self) -> Option<u32> {
+        self.entries.pop_back()
+    }
+}
+
+
+impl Checkpoint for ReturnAddressStack {
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.depth);
+        w.usize(self.entries.len());
+        for &addr in self.entries.iter() {
+            w.u32(addr);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.expect_usize("RAS depth", self.depth)?;
+        let n = r.usize()?;
+        if n > self.depth {
+            return Err(checkpoint::invalid("RAS overflow"));
+        }
+        self.entries.clear();
+        for _ in 0..n {
+            self.entries.push_back(r.u32()?);
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_discards_oldest() {
+        let mut ras = ReturnAddressStack::new(2);
+        assert!(!ras.push(0x4));
+        assert!(!ras.push(0x8));
+        assert!(ras.push(0xc));
+
+        assert_eq!(ras.pop(), Some(0xc));
+        assert_eq!(ras.pop(), Some(0x8));
+        assert_eq!(ras.pop(), None);
+    }
+
+}
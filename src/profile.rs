@@ -0,0 +1,720 @@
+//! Execution profiles.
+//!
+//! `Profile` observes a run and counts, for every instruction address, how
+//! many times the instruction retired and the cycles it took. An
+//! instruction is charged the cycles since the previous one retired, so a
+//! stalled instruction is charged its stalls, and the first instruction
+//! after a misprediction the cycles of the flushed ones. Stall cycles are
+//! also counted against the instruction held in the stalled stage, and
+//! flushed instructions against the branch, jump or trap that flushed
+//! them, to show where the cycles went.
+//!
+//! The counts are summed by basic block and by symbol. Loops are found
+//! from the backward branches and jumps taken, and the report ends with a
+//! listing of the executed code with the counts in the margin:
+//!
+//! ```text
+//!  retired   cycles       %  stalls  flushes
+//!                                             loop:
+//!        4       13   35.1%       0        0  0x8: lw x6, 0(x0)
+//!        4        8   21.6%       4        0  0xc: add x7, x7, x6
+//!        4        4   10.8%       0        0  0x10: addi x10, x10, 1
+//!        4        4   10.8%       0        9  0x14: blt x10, x11, -12
+//! ```
+//!
+//! Here the add stalled a cycle for the load each iteration, and the loop
+//! branch flushed the three instructions after it each time it was taken,
+//! so the load at its target was charged those cycles as well as its own.
+
+
+use instruction::{Function, Instruction, Opcode};
+use memory::instruction::InstructionMemory;
+use pipeline::events::{Event, Observer, Occupancy, Stage, STAGES};
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+
+/// Rows in each table of the report.
+pub const TOP: usize = 10;
+
+
+/// Counts of a group of instructions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    /// Instructions that retired
+    pub retired: u64,
+    /// Cycles charged to the instructions
+    pub cycles: u64,
+    /// Cycles the instructions stalled in ID or held MEM
+    pub stall_cycles: u64,
+    /// Cycles lost to the instructions they flushed
+    pub flush_cycles: u64,
+}
+
+
+impl Counts {
+    /// Returns the average cycles per retired instruction.
+    ///
+    /// Returns 0.0 if nothing retired.
+    pub fn cpi(&self) -> f64 {
+        match self.retired {
+            0 => 0.0,
+            n => self.cycles as f64 / n as f64,
+        }
+    }
+
+    fn add(&mut self, other: &Counts) {
+        self.retired += other.retired;
+        self.cycles += other.cycles;
+        self.stall_cycles += other.stall_cycles;
+        self.flush_cycles += other.flush_cycles;
+    }
+}
+
+
+/// The counts of one instruction address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PcStats {
+    pub function: Function,
+    pub counts: Counts,
+}
+
+
+/// A sequence of instructions only entered at the first and only left
+/// after the last.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Block {
+    pub start: u32,
+    /// Address of the last instruction
+    pub end: u32,
+    /// Times the first instruction retired
+    pub executions: u64,
+    pub counts: Counts,
+}
+
+
+/// The instructions from the target of a backward branch or jump to the
+/// furthest branch or jump back to it.
+///
+/// The counts include those of inner loops, but not of functions called.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loop {
+    pub head: u32,
+    /// Address of the furthest branch or jump back to the head
+    pub tail: u32,
+    /// Backward branches and jumps taken to the head
+    pub iterations: u64,
+    pub counts: Counts,
+}
+
+
+/// The counts of the instructions from a symbol to the next.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolStats {
+    /// The symbol, or `?` for instructions before the first
+    pub name: String,
+    pub counts: Counts,
+}
+
+
+/// Profiles a run of a simulator executing `insns`.
+pub struct Profile<'a> {
+    insns: &'a dyn InstructionMemory,
+    /// Symbol names by address, the first alphabetically of each
+    symbols: BTreeMap<u32, String>,
+    pcs: BTreeMap<u32, PcStats>,
+    cycles: u64,
+    /// Cycles since an instruction last retired
+    pending: u64,
+    /// Last instruction that retired
+    last: Option<u32>,
+    /// Instruction retiring in the current cycle
+    retiring: Option<u32>,
+    /// Stages that stalled in the current cycle
+    stalls: Vec<Stage>,
+    /// Stages flushed in the current cycle
+    flushes: Vec<Stage>,
+    /// Targets of taken branches and jumps
+    targets: BTreeSet<u32>,
+    /// Furthest backward branch or jump to each loop head, and the times
+    /// those to it were taken
+    back_edges: BTreeMap<u32, (u32, u64)>,
+}
+
+
+impl<'a> Profile<'a> {
+    /// Constructs a new, empty `Profile` of a simulator executing `insns`.
+    pub fn new(insns: &'a dyn InstructionMemory) -> Profile<'a> {
+        Profile {
+            insns,
+            symbols: BTreeMap::new(),
+            pcs: BTreeMap::new(),
+            cycles: 0,
+            pending: 0,
+            last: None,
+            retiring: None,
+            stalls: Vec::new(),
+            flushes: Vec::new(),
+            targets: BTreeSet::new(),
+            back_edges: BTreeMap::new(),
+        }
+    }
+
+    /// Sets the symbols to group instructions by, such as the labels of a
+    /// disassembly or the symbol table of an executable.
+    pub fn set_symbols(&mut self, symbols: &BTreeMap<String, u32>) {
+        self.symbols.clear();
+        for (name, &addr) in symbols.iter() {
+            self.symbols.entry(addr).or_insert_with(|| name.clone());
+        }
+    }
+
+    /// Returns the counts of each instruction that retired by address.
+    pub fn pcs(&self) -> &BTreeMap<u32, PcStats> {
+        &self.pcs
+    }
+
+    /// Returns the number of cycles observed.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Returns the cycles since the last instruction retired, which
+    /// aren't charged to any.
+    pub fn unretired_cycles(&self) -> u64 {
+        self.pending
+    }
+
+    /// Returns the counts of every instruction.
+    pub fn total(&self) -> Counts {
+        let mut total = Counts::default();
+        for stats in self.pcs.values() {
+            total.add(&stats.counts);
+        }
+
+        total
+    }
+
+    /// Returns the basic blocks that were executed, in address order.
+    ///
+    /// A block starts at a symbol, at the target of a taken branch or
+    /// jump, or after an instruction that can change the flow of control.
+    pub fn blocks(&self) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut prev: Option<(u32, Function)> = None;
+        for (&pc, stats) in self.pcs.iter() {
+            let starts = match prev {
+                Some((prev_pc, function)) => {
+                    pc != prev_pc.wrapping_add(4) || ends_block(function) ||
+                        self.targets.contains(&pc) ||
+                        self.symbols.contains_key(&pc)
+                }
+                None => true,
+            };
+            if starts {
+                blocks.push(Block {
+                    start: pc,
+                    end: pc,
+                    executions: stats.counts.retired,
+                    counts: Counts::default(),
+                });
+            }
+
+            let block = blocks.last_mut().unwrap();
+            block.end = pc;
+            block.counts.add(&stats.counts);
+            prev = Some((pc, stats.function));
+        }
+
+        blocks
+    }
+
+    /// Returns the loops that were executed, most cycles first.
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = self.back_edges
+            .iter()
+            .map(|(&head, &(tail, iterations))| Loop {
+                head,
+                tail,
+                iterations,
+                counts: self.sum(head, tail),
+            })
+            .collect();
+        loops.sort_by_key(|l| Reverse(l.counts.cycles));
+
+        loops
+    }
+
+    /// Returns the counts of each symbol with instructions that retired,
+    /// most cycles first.
+    pub fn symbol_stats(&self) -> Vec<SymbolStats> {
+        let mut by_addr: BTreeMap<Option<u32>, Counts> = BTreeMap::new();
+        for (&pc, stats) in self.pcs.iter() {
+            let symbol = self.symbol(pc).map(|(addr, _)| addr);
+            by_addr.entry(symbol).or_default().add(&stats.counts);
+        }
+
+        let mut symbols: Vec<SymbolStats> = by_addr
+            .into_iter()
+            .map(|(addr, counts)| SymbolStats {
+                name: match addr {
+                    Some(addr) => self.symbols[&addr].clone(),
+                    None => String::from("?"),
+                },
+                counts,
+            })
+            .collect();
+        symbols.sort_by_key(|s| Reverse(s.counts.cycles));
+
+        symbols
+    }
+
+    /// Returns `pc` relative to the symbol before it, e.g. `sort+0x8`, or
+    /// the address if there is none.
+    pub fn location(&self, pc: u32) -> String {
+        match self.symbol(pc) {
+            Some((addr, name)) if addr == pc => name.clone(),
+            Some((addr, name)) => format!("{}+{:#0x}", name, pc - addr),
+            None => format!("{:#0x}", pc),
+        }
+    }
+
+    /// Returns the address and name of the last symbol at or before `pc`.
+    fn symbol(&self, pc: u32) -> Option<(u32, &String)> {
+        self.symbols
+            .range(..=pc)
+            .next_back()
+            .map(|(&addr, name)| (addr, name))
+    }
+
+    /// Returns the counts of the instructions from `first` to `last`.
+    fn sum(&self, first: u32, last: u32) -> Counts {
+        let mut counts = Counts::default();
+        for stats in self.pcs.range(first..=last).map(|(_, stats)| stats) {
+            counts.add(&stats.counts);
+        }
+
+        counts
+    }
+
+    /// Returns the counts of the instruction at `pc`, adding it if it's
+    /// new.
+    fn stats(&mut self, pc: u32, function: Function) -> &mut Counts {
+        &mut self.pcs
+            .entry(pc)
+            .or_insert(PcStats {
+                function,
+                counts: Counts::default(),
+            })
+            .counts
+    }
+
+    /// Returns the counts of the instruction in stage `index` of
+    /// `stages`, if it holds one rather than a bubble.
+    fn held(
+        &mut self,
+        stages: &Occupancy,
+        index: usize,
+    ) -> Option<&mut Counts> {
+        match stages.get(index) {
+            Some(&Some(slot)) if !slot.bubble => {
+                let function = Instruction::new(slot.raw_insn).function;
+                Some(self.stats(slot.pc, function))
+            }
+            _ => None,
+        }
+    }
+
+    /// Formats a table row of `counts` preceded by the cycle percentage.
+    fn row(&self, counts: &Counts) -> String {
+        format!(
+            "{:>9}  {:>5.1}%  {:>8}  {:>6.3}  {:>6}  {:>7}",
+            counts.cycles,
+            self.percent(counts.cycles),
+            counts.retired,
+            counts.cpi(),
+            counts.stall_cycles,
+            counts.flush_cycles
+        )
+    }
+
+    /// Returns `cycles` as a percentage of the cycles observed.
+    fn percent(&self, cycles: u64) -> f64 {
+        match self.cycles {
+            0 => 0.0,
+            n => 100.0 * cycles as f64 / n as f64,
+        }
+    }
+}
+
+
+/// Indicates `function` may continue elsewhere than the next instruction.
+fn ends_block(function: Function) -> bool {
+    matches!(
+        function,
+        Function::Jal | Function::Jalr | Function::Beq | Function::Bne |
+        Function::Blt | Function::Bge | Function::Bltu | Function::Bgeu |
+        Function::Ecall | Function::Ebreak | Function::Mret
+    )
+}
+
+
+/// Indicates `raw_insn`, which just retired, closes a loop if it's taken
+/// backward.
+///
+/// Calls, returns and trap returns don't.
+fn closes_loop(raw_insn: u32) -> bool {
+    let insn = Instruction::new(raw_insn);
+    match insn.opcode {
+        Opcode::Branch => true,
+        Opcode::Jal => insn.fields.rd == Some(0),
+        _ => false,
+    }
+}
+
+
+impl<'a> Observer for Profile<'a> {
+    fn event(&mut self, _cycle: u64, event: &Event) {
+        match *event {
+            Event::Retire { pc, function } => {
+                if let Some(prev) = self.last {
+                    if pc != prev.wrapping_add(4) {
+                        self.targets.insert(pc);
+                    }
+                    let raw_insn = self.insns.read(prev as usize);
+                    if pc <= prev && closes_loop(raw_insn) {
+                        let edge =
+                            self.back_edges.entry(pc).or_insert((prev, 0));
+                        edge.0 = edge.0.max(prev);
+                        edge.1 += 1;
+                    }
+                }
+                self.last = Some(pc);
+                self.retiring = Some(pc);
+                self.stats(pc, function).retired += 1;
+            }
+            Event::Stall { stage, .. } => self.stalls.push(stage),
+            Event::Flush { stage, .. } => self.flushes.push(stage),
+            _ => (),
+        }
+    }
+
+    fn cycle(&mut self, _cycle: u64, stages: &Occupancy) {
+        self.cycles += 1;
+        self.pending += 1;
+        if let Some(pc) = self.retiring.take() {
+            let cycles = self.pending;
+            self.pcs.get_mut(&pc).unwrap().counts.cycles += cycles;
+            self.pending = 0;
+        }
+
+        let index = |stage| STAGES.iter().position(|&s| s == stage).unwrap();
+        for stage in self.stalls.split_off(0) {
+            if let Some(counts) = self.held(stages, index(stage)) {
+                counts.stall_cycles += 1;
+            }
+        }
+
+        // The cause is in the stage after the oldest flushed
+        let flushed = self.flushes.split_off(0);
+        if let Some(oldest) = flushed.iter().map(|&s| index(s)).max() {
+            if let Some(counts) = self.held(stages, oldest + 1) {
+                counts.flush_cycles += flushed.len() as u64;
+            }
+        }
+    }
+}
+
+
+impl<'a> fmt::Display for Profile<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        writeln!(
+            f,
+            "Profile: {} cycles, {} retired, CPI {:.4}",
+            self.cycles,
+            total.retired,
+            Counts {
+                cycles: self.cycles,
+                ..total
+            }.cpi()
+        )?;
+        if self.pending > 0 {
+            writeln!(
+                f,
+                "Cycles after the last instruction retired: {}",
+                self.pending
+            )?;
+        }
+        let header = "   cycles       %   retired     CPI  stalls  flushes";
+
+        writeln!(f, "\nSymbols:\n{}  symbol", header)?;
+        for symbol in self.symbol_stats().iter().take(TOP) {
+            writeln!(f, "{}  {}", self.row(&symbol.counts), symbol.name)?;
+        }
+
+        writeln!(f, "\nHot loops:\n{}  iterations  loop", header)?;
+        for l in self.loops().iter().take(TOP) {
+            writeln!(
+                f,
+                "{}  {:>10}  {:#0x}..{:#0x} ({})",
+                self.row(&l.counts),
+                l.iterations,
+                l.head,
+                l.tail,
+                self.location(l.head)
+            )?;
+        }
+
+        let mut blocks = self.blocks();
+        blocks.sort_by_key(|b| Reverse(b.counts.cycles));
+        writeln!(f, "\nHot basic blocks:\n{}  executions  block", header)?;
+        for block in blocks.iter().take(TOP) {
+            writeln!(
+                f,
+                "{}  {:>10}  {:#0x}..{:#0x} ({})",
+                self.row(&block.counts),
+                block.executions,
+                block.start,
+                block.end,
+                self.location(block.start)
+            )?;
+        }
+
+        let mut pcs = self.pcs.keys();
+        let (first, last) = match (pcs.next(), pcs.next_back()) {
+            (Some(&first), Some(&last)) => (first, last),
+            (Some(&first), None) => (first, first),
+            _ => return Ok(()),
+        };
+        write!(f, "\nListing:\n retired   cycles       %  stalls  flushes")?;
+        for pc in (first..=last).step_by(4) {
+            if let Some(name) = self.symbols.get(&pc) {
+                write!(f, "\n{:44}{}:", "", name)?;
+            }
+            // Words that never retired may be data, so aren't decoded
+            let raw_insn = self.insns.read(pc as usize);
+            let (margin, text) = match self.pcs.get(&pc) {
+                Some(stats) => (
+                    format!(
+                        "{:>8} {:>8}  {:>5.1}%  {:>6}  {:>7}",
+                        stats.counts.retired,
+                        stats.counts.cycles,
+                        self.percent(stats.counts.cycles),
+                        stats.counts.stall_cycles,
+                        stats.counts.flush_cycles
+                    ),
+                    Instruction::new(raw_insn).to_string(),
+                ),
+                None => (String::new(), format!(".word {:#010x}", raw_insn)),
+            };
+            write!(f, "\n{:<42}  {:#0x}: {}", margin, pc, text)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ca_simulator::CaSimulator;
+    use consts;
+    use ia_simulator::IaSimulator;
+    use memory::data::DataMemory;
+    use elf::{self, Elf};
+    use memory::instruction::{ElfInstructionMemory, TestInstructionMemory};
+    use register::RegisterFile;
+    use simulator::Simulator;
+
+    /// Adds the word at address 0 to x7 four times, stalling on the load
+    /// each time, then halts.
+    fn words() -> Vec<u32> {
+        let mut insns = vec![
+            0x00_00_05_13, // addi x10, x0, 0
+            0x00_40_05_93, // addi x11, x0, 4
+            0x00_00_23_03, // loop: lw x6, 0(x0)
+            0x00_63_83_b3, // add x7, x7, x6
+            0x00_15_05_13, // addi x10, x10, 1
+            0xfe_b5_4a_e3, // blt x10, x11, loop
+        ];
+        insns.extend_from_slice(&[consts::NOP; 3]);
+        insns.push(consts::HALT);
+        insns.extend_from_slice(&[consts::NOP; 3]);
+
+        insns
+    }
+
+    fn program() -> TestInstructionMemory {
+        TestInstructionMemory::new(words())
+    }
+
+    fn symbols() -> BTreeMap<String, u32> {
+        let mut symbols = BTreeMap::new();
+        symbols.insert(String::from("_start"), 0x0);
+        symbols.insert(String::from("loop"), 0x8);
+
+        symbols
+    }
+
+    fn counts(profile: &Profile, pc: u32) -> Counts {
+        profile.pcs()[&pc].counts
+    }
+
+    #[test]
+    fn ca_attribution() {
+        let insns = program();
+        let mut profile = Profile::new(&insns);
+        let clock = {
+            let mem = DataMemory::new(1024);
+            let reg = RegisterFile::new(0x0);
+            let mut sim = CaSimulator::new(&insns, mem, reg);
+            sim.set_observer(&mut profile);
+            sim.run();
+            sim.clock()
+        };
+        assert_eq!(profile.cycles(), clock);
+
+        // Every cycle but those after the last retirement is charged
+        let total = profile.total();
+        assert_eq!(total.cycles + profile.unretired_cycles(), clock);
+
+        // The add stalls for the load, the first time after the
+        // misprediction flushed what followed the branch
+        assert_eq!(counts(&profile, 0x8).retired, 4);
+        assert_eq!(counts(&profile, 0xc).stall_cycles, 4);
+        assert_eq!(counts(&profile, 0xc).cycles, 8);
+        assert_eq!(counts(&profile, 0x14).flush_cycles, 9);
+        assert_eq!(counts(&profile, 0x8).cycles, 1 + 3 * 4);
+        assert_eq!(total.stall_cycles, 4);
+        assert_eq!(total.flush_cycles, 9);
+    }
+
+    #[test]
+    fn ia_counts() {
+        let insns = program();
+        let mut profile = Profile::new(&insns);
+        {
+            let mem = DataMemory::new(1024);
+            let reg = RegisterFile::new(0x0);
+            let mut sim = IaSimulator::new(&insns, mem, reg);
+            sim.set_observer(&mut profile);
+            assert_eq!(sim.run(), 0x24);
+        }
+
+        // One instruction a cycle, and a cycle for HALT
+        let total = profile.total();
+        assert_eq!(total.retired, 2 + 4 * 4 + 3);
+        assert_eq!(total.cycles, total.retired);
+        assert_eq!(profile.cycles(), total.retired + 1);
+        for (_, stats) in profile.pcs().iter() {
+            assert_eq!(stats.counts.cycles, stats.counts.retired);
+            assert_eq!(stats.counts.stall_cycles, 0);
+        }
+    }
+
+    #[test]
+    fn blocks_loops_and_symbols() {
+        let insns = program();
+        let mut profile = Profile::new(&insns);
+        profile.set_symbols(&symbols());
+        {
+            let mem = DataMemory::new(1024);
+            let reg = RegisterFile::new(0x0);
+            let mut sim = IaSimulator::new(&insns, mem, reg);
+            sim.set_observer(&mut profile);
+            sim.run();
+        }
+
+        let blocks: Vec<(u32, u32, u64)> = profile
+            .blocks()
+            .iter()
+            .map(|b| (b.start, b.end, b.executions))
+            .collect();
+        let expected = vec![(0x0, 0x4, 1), (0x8, 0x14, 4), (0x18, 0x20, 1)];
+        assert_eq!(blocks, expected);
+
+        let loops = profile.loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!((loops[0].head, loops[0].tail), (0x8, 0x14));
+        assert_eq!(loops[0].iterations, 3);
+        assert_eq!(loops[0].counts.retired, 16);
+
+        let symbols = profile.symbol_stats();
+        assert_eq!(symbols[0].name, "loop");
+        assert_eq!(symbols[0].counts.retired, 16 + 3);
+        assert_eq!(symbols[1].name, "_start");
+        assert_eq!(symbols[1].counts.retired, 2);
+
+        assert_eq!(profile.location(0x8), "loop");
+        assert_eq!(profile.location(0x10), "loop+0x8");
+
+        let report = profile.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Profile: 22 cycles, 21 retired, CPI 1.0476");
+        let header = format!("{:44}loop:", "");
+        let position = lines.iter().position(|&line| line == header);
+        assert_eq!(
+            lines[position.unwrap() + 1],
+            "       4        4   18.2%       0        0  0x8: lw x6, 0(x0)"
+        );
+        // The listing ends at the last instruction that retired
+        assert!(lines.last().unwrap().ends_with("0x20: addi x0, x0, 0"));
+    }
+
+    #[test]
+    fn data_in_listing() {
+        let mut words = vec![
+            0x00_c0_00_6f, // jal x0, 12
+            0x00_00_00_00, // .word 0
+            0x00_a0_05_13, // .word 0x00a00513 (addi x10, x0, 10)
+            0x00_10_05_13, // addi x10, x0, 1
+        ];
+        words.extend_from_slice(&[consts::NOP; 3]);
+        words.push(consts::HALT);
+        let insns = TestInstructionMemory::new(words);
+        let mut profile = Profile::new(&insns);
+        {
+            let mem = DataMemory::new(1024);
+            let reg = RegisterFile::new(0x0);
+            let mut sim = IaSimulator::new(&insns, mem, reg);
+            sim.set_observer(&mut profile);
+            assert_eq!(sim.run(), 0x1c);
+        }
+
+        let report = profile.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        let listing = lines.iter().position(|&l| l == "Listing:").unwrap();
+        assert!(lines[listing + 2].ends_with("0x0: jal x0, 12"));
+        let data = |text: &str| format!("{:44}{}", "", text);
+        assert_eq!(lines[listing + 3], data("0x4: .word 0x00000000"));
+        assert_eq!(lines[listing + 4], data("0x8: .word 0x00a00513"));
+        assert!(lines[listing + 5].ends_with("0xc: addi x10, x0, 1"));
+    }
+
+    #[test]
+    fn elf_symbols() {
+        let symbols = [("_start", 0x8000_0000), ("loop", 0x8000_0008)];
+        let base = 0x8000_0000;
+        let bytes = elf::build(base, base, &words(), 0, &symbols);
+        let insns = ElfInstructionMemory::new(&Elf::parse(&bytes).unwrap());
+        let mut profile = Profile::new(&insns);
+        profile.set_symbols(insns.symbols());
+        {
+            let mem = DataMemory::new(1024);
+            let reg = RegisterFile::new(insns.entry());
+            let mut sim = IaSimulator::new(&insns, mem, reg);
+            sim.set_observer(&mut profile);
+            sim.run();
+        }
+
+        let names: Vec<String> =
+            profile.symbol_stats().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["loop", "_start"]);
+        assert_eq!(profile.loops()[0].head, 0x8);
+    }
+}
@@ -1,13 +1,47 @@
 //! 32-bit register and RV32I register file.
 
 
+use checkpoint::{Checkpoint, Reader, Writer};
+use csr::CsrFile;
+
+use std::io;
+
+
+/// ABI names of the general purpose registers.
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1",
+    "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+
+/// Returns the number of the general purpose register `name`.
+///
+/// Accepts ABI names, `fp` and architectural names such as `x10`.
+pub fn gpr_by_name(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(i) = ABI_NAMES.iter().position(|&abi| abi == name) {
+        return Some(i);
+    }
+
+    match name.strip_prefix('x').map(|n| n.parse::<usize>()) {
+        Some(Ok(i)) if i < 32 && format!("x{}", i) == name => Some(i),
+        _ => None,
+    }
+}
+
+
 /// A complete RV32I register file.
 ///
-/// Holds 32 general purpose registers and a program counter register.
+/// Holds 32 general purpose registers, a program counter register and the
+/// machine-mode control and status registers.
 #[derive(Debug)]
 pub struct RegisterFile {
     pub pc: Register,
     pub gpr: [Register; 32],
+    pub csr: CsrFile,
 }
 
 
@@ -17,6 +51,7 @@ impl RegisterFile {
         let mut reg_file = RegisterFile {
             pc: Register::new(pc, true),
             gpr: [Register::new(0, true); 32],
+            csr: CsrFile::new(),
         };
         reg_file.gpr[0] = Register::new(0, false); // reinit x0 as read-only
 
@@ -25,6 +60,26 @@ impl RegisterFile {
 }
 
 
+impl Checkpoint for RegisterFile {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.pc.value);
+        for reg in self.gpr.iter() {
+            w.u32(reg.value);
+        }
+        self.csr.save(w);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.pc.value = r.u32()?;
+        for reg in self.gpr.iter_mut() {
+            reg.value = r.u32()?;
+        }
+
+        self.csr.restore(r)
+    }
+}
+
+
 /// A write-protectable register.
 #[derive(Clone, Copy, Debug)]
 pub struct Register {
@@ -54,3 +109,23 @@ impl Register {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gpr_names() {
+        assert_eq!(gpr_by_name("zero"), Some(0));
+        assert_eq!(gpr_by_name("x0"), Some(0));
+        assert_eq!(gpr_by_name("a0"), Some(10));
+        assert_eq!(gpr_by_name("x10"), Some(10));
+        assert_eq!(gpr_by_name("fp"), Some(8));
+        assert_eq!(gpr_by_name("t6"), Some(31));
+        assert_eq!(gpr_by_name("x32"), None);
+        assert_eq!(gpr_by_name("x01"), None);
+        assert_eq!(gpr_by_name("pc"), None);
+    }
+
+}
@@ -0,0 +1,209 @@
+//! Cycle stepping interface shared by the simulators.
+
+
+use memory::data::DataMemory;
+use pipeline::Pipeline;
+use pipeline::events::Event;
+use register::RegisterFile;
+
+
+/// A RISC-V 32I simulator that owns its state and advances a cycle at a
+/// time.
+///
+/// The instruction accurate model completes one instruction per cycle; the
+/// cycle accurate model advances its pipeline by one stage.
+pub trait Simulator {
+    /// Simulates one clock cycle.
+    ///
+    /// Returns the address of the HALT instruction once it has been
+    /// reached. Stepping a halted simulator has no effect.
+    fn step(&mut self) -> Option<usize>;
+
+    /// Simulates up to `n` clock cycles, stopping early at HALT.
+    ///
+    /// Returns the address of the HALT instruction if it has been reached.
+    fn step_cycles(&mut self, n: u64) -> Option<usize> {
+        for _ in 0..n {
+            if let Some(addr) = self.step() {
+                return Some(addr);
+            }
+        }
+
+        self.halt_addr()
+    }
+
+    /// Simulates clock cycles until `predicate` holds at the end of one.
+    ///
+    /// Returns false if HALT was reached without the predicate holding.
+    fn run_until<P>(&mut self, mut predicate: P) -> bool
+    where
+        Self: Sized,
+        P: FnMut(&Self) -> bool,
+    {
+        loop {
+            let halted = self.step().is_some();
+            if predicate(self) {
+                return true;
+            }
+            if halted {
+                return false;
+            }
+        }
+    }
+
+    /// Simulates clock cycles until HALT, returning its address.
+    fn run(&mut self) -> usize {
+        loop {
+            if let Some(addr) = self.step() {
+                return addr;
+            }
+        }
+    }
+
+    /// Resets the processor.
+    ///
+    /// The registers return to their reset values, the clock restarts from
+    /// zero and any pipeline is emptied. Data memory is kept, as on a
+    /// hardware reset.
+    fn reset(&mut self);
+
+    /// Returns the number of clock cycles simulated.
+    fn clock(&self) -> u64;
+
+    /// Returns the address of the HALT instruction, once it's reached.
+    fn halt_addr(&self) -> Option<usize>;
+
+    fn registers(&self) -> &RegisterFile;
+
+    fn registers_mut(&mut self) -> &mut RegisterFile;
+
+    fn memory(&self) -> &DataMemory;
+
+    fn memory_mut(&mut self) -> &mut DataMemory;
+
+    /// Returns the pipeline registers as they will be read in the next
+    /// cycle, for pipelined models.
+    fn pipeline(&self) -> Option<&Pipeline> {
+        None
+    }
+
+    /// Returns the address of the current instruction, the next one to
+    /// complete.
+    ///
+    /// With a pipeline, that's the oldest instruction in it.
+    fn current_pc(&self) -> u32 {
+        if let Some(pipeline) = self.pipeline() {
+            let oldest = [
+                (pipeline.mem_wb.bubble, pipeline.mem_wb.pc),
+                (pipeline.ex_mem.bubble, pipeline.ex_mem.pc),
+                (pipeline.id_ex.bubble, pipeline.id_ex.pc),
+                (pipeline.if_id.bubble, pipeline.if_id.pc),
+            ].iter()
+                .find(|&&(bubble, _)| !bubble)
+                .map(|&(_, pc)| pc);
+            if let Some(pc) = oldest {
+                return pc;
+            }
+        }
+
+        self.registers().pc.read()
+    }
+
+    /// Continues execution from `pc`.
+    ///
+    /// Any pipeline is emptied, discarding the instructions in it.
+    fn set_pc(&mut self, pc: u32) {
+        self.registers_mut().pc.write(pc);
+    }
+
+    /// Returns the events of the last cycle.
+    ///
+    /// The instruction accurate model only reports memory accesses,
+    /// register writes and retired instructions.
+    fn events(&self) -> &[Event];
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ca_simulator::CaSimulator;
+    use consts;
+    use ia_simulator::IaSimulator;
+    use memory::instruction::TestInstructionMemory;
+
+    /// Increments x10 three times, then halts at 0x18.
+    fn program() -> TestInstructionMemory {
+        let addi = 0x00_15_05_13; // addi x10, x10, 1
+        let mut insns = vec![addi; 3];
+        insns.extend_from_slice(&[consts::NOP; 3]);
+        insns.push(consts::HALT);
+        insns.extend_from_slice(&[consts::NOP; 3]);
+
+        TestInstructionMemory::new(insns)
+    }
+
+    fn x10<S: Simulator>(sim: &S) -> u32 {
+        sim.registers().gpr[10].read()
+    }
+
+    #[test]
+    fn ia_stepping() {
+        let insns = program();
+        let mem = DataMemory::new(1024);
+        let mut sim = IaSimulator::new(&insns, mem, RegisterFile::new(0x0));
+
+        assert_eq!(sim.step(), None);
+        assert_eq!(sim.clock(), 1);
+        assert_eq!(x10(&sim), 1);
+        assert!(sim.pipeline().is_none());
+
+        assert!(sim.run_until(|sim| x10(sim) == 3));
+        assert_eq!(sim.clock(), 3);
+
+        assert_eq!(sim.step_cycles(100), Some(0x18));
+        assert_eq!(sim.clock(), 7);
+        assert_eq!(sim.step(), Some(0x18));
+        assert_eq!(sim.clock(), 7);
+        assert!(!sim.run_until(|sim| x10(sim) == 4));
+
+        sim.reset();
+        assert_eq!(sim.clock(), 0);
+        assert_eq!(sim.halt_addr(), None);
+        assert_eq!(x10(&sim), 0);
+        assert_eq!(sim.run(), 0x18);
+        assert_eq!(x10(&sim), 3);
+    }
+
+    #[test]
+    fn ca_stepping() {
+        let insns = program();
+        let mem = DataMemory::new(1024);
+        let mut sim = CaSimulator::new(&insns, mem, RegisterFile::new(0x0));
+
+        assert_eq!(sim.step_cycles(2), None);
+        assert_eq!(sim.clock(), 2);
+        {
+            let pipeline = sim.pipeline().unwrap();
+            assert_eq!(pipeline.if_id.pc, 0x4);
+            assert_eq!(pipeline.id_ex.pc, 0x0);
+        }
+
+        // The first addi writes back in cycle 4
+        assert!(sim.run_until(|sim| x10(sim) == 1));
+        assert_eq!(sim.clock(), 5);
+
+        assert_eq!(sim.run(), 0x18);
+        assert_eq!(sim.clock(), sim.stats().cycles);
+        assert_eq!(x10(&sim), 3);
+
+        sim.reset();
+        assert_eq!(sim.clock(), 0);
+        assert_eq!(x10(&sim), 0);
+        assert_eq!(sim.pipeline().unwrap().if_id.pc, 0x0);
+        assert_eq!(sim.run(), 0x18);
+        assert_eq!(x10(&sim), 3);
+    }
+
+}
@@ -3,21 +3,58 @@
 
 use alu::{alu, AluSrc};
 use consts;
-use instruction::{Instruction, Opcode};
+use csr::CsrFile;
+use instruction::{Function, Instruction, Opcode};
 use memory::data::DataMemory;
 use memory::instruction::InstructionMemory;
+use memory::pmp::{Access, Pmp};
 use register::RegisterFile;
+use trap::{Exception, Trap};
 
 
 /// IF: Instruction fetch from memory.
-pub fn insn_fetch(mem: &InstructionMemory, pc: u32, _clk: u64) -> u32 {
-    mem.read(pc as usize)
+///
+/// Raises an instruction address misaligned exception if `pc` isn't word
+/// aligned, and an instruction access fault if it's past the end of `mem`
+/// or not executable. The CA model may fetch such an address on a wrong
+/// path, e.g., a predicted target, so the trap is only taken if the
+/// instruction commits.
+pub fn insn_fetch(
+    mem: &dyn InstructionMemory,
+    pmp: &Pmp,
+    pc: u32,
+    _clk: u64,
+) -> Result<u32, Trap> {
+    if pc & 0x3 != 0 {
+        debug!("Fetch from misaligned {:#0x} (clock {})", pc, _clk);
+        return Err(Trap::new(Exception::InstructionAddressMisaligned, pc));
+    }
+    if pc as usize >= mem.size() {
+        debug!("Fetch from {:#0x} out of range (clock {})", pc, _clk);
+        return Err(Trap::new(Exception::InstructionAccessFault, pc));
+    }
+    if !pmp.check(pc, consts::WORD_SIZE, Access::Execute) {
+        debug!("PMP: fetch from {:#0x} denied (clock {})", pc, _clk);
+        return Err(Trap::new(Exception::InstructionAccessFault, pc));
+    }
+
+    Ok(mem.read(pc as usize))
 }
 
 
 /// ID 1: Instruction decode
-pub fn insn_decode(raw_insn: u32, _clk: u64) -> Instruction {
-    Instruction::new(raw_insn)
+///
+/// Raises an illegal instruction exception if `raw_insn` isn't an
+/// instruction the simulator implements, e.g., data or an unsupported
+/// extension.
+pub fn insn_decode(raw_insn: u32, _clk: u64) -> Result<Instruction, Trap> {
+    let insn = Instruction::new(raw_insn);
+    if insn.function == Function::Illegal {
+        debug!("Illegal instruction {:#0x} (clock {})", raw_insn, _clk);
+        return Err(Trap::new(Exception::IllegalInstruction, raw_insn));
+    }
+
+    Ok(insn)
 }
 
 
@@ -34,34 +71,163 @@ pub fn reg_read(
 }
 
 /// EX: Execute operation or calculate address.
-pub fn execute(insn: &mut Instruction, rs1: i32, rs2: i32, _clk: u64) -> i32 {
-    let src1 = rs1;
+///
+/// AUIPC adds its immediate to `pc`, the instruction's address, in place of
+/// rs1.
+pub fn execute(
+    insn: &mut Instruction,
+    pc: u32,
+    rs1: i32,
+    rs2: i32,
+    _clk: u64,
+) -> i32 {
+    let src1 = match insn.opcode {
+        Opcode::AuiPc => pc as i32,
+        _ => rs1,
+    };
     let src2 = match insn.semantics.alu_src {
         AluSrc::Reg => rs2,
         AluSrc::Imm => insn.fields.imm.unwrap() as i32,
     };
 
-    alu(&insn, src1, src2, _clk)
+    alu(insn, src1, src2, _clk)
 }
 
 
 /// MEM: Access memory operand.
+///
+/// Raises an address misaligned exception if data memory is set to trap
+/// misaligned accesses, or else an access fault if the PMP unit denies the
+/// access.
 pub fn access_memory(
     insn: &Instruction,
     mem: &mut DataMemory,
+    pmp: &Pmp,
     alu_result: i32,
     rs2: i32,
     _clk: u64,
-) -> u32 {
+) -> Result<u32, Trap> {
     let mut mem_result: u32 = 0;
+    let addr = alu_result as u32;
+    let size = insn.semantics.mem_size;
+
+    check_alignment(insn, mem, addr, _clk)?;
+    check_pmp(insn, pmp, addr, _clk)?;
 
     if insn.semantics.mem_read {
-        mem_result = mem.read(alu_result as usize, insn.semantics.mem_size);
+        let value = mem.read(addr as usize, size)
+            .map_err(|cause| Trap::new(cause, addr))?;
+        mem_result = extend_load(insn, value);
+    } else if insn.semantics.mem_write {
+        mem.write(addr as usize, size, rs2 as u32)
+            .map_err(|cause| Trap::new(cause, addr))?;
+    }
+
+    Ok(mem_result)
+}
+
+
+/// Sign-extends a byte or halfword loaded by LB or LH.
+pub fn extend_load(insn: &Instruction, value: u32) -> u32 {
+    match insn.function {
+        Function::Lb => value as u8 as i8 as u32,
+        Function::Lh => value as u16 as i16 as u32,
+        _ => value,
+    }
+}
+
+
+/// Checks a load or store's alignment against the misaligned policy.
+///
+/// Raises a load or store address misaligned exception, which takes
+/// priority over an access fault.
+pub fn check_alignment(
+    insn: &Instruction,
+    mem: &DataMemory,
+    addr: u32,
+    _clk: u64,
+) -> Result<(), Trap> {
+    let size = insn.semantics.mem_size;
+    let misaligned = if insn.semantics.mem_read {
+        Exception::LoadAddressMisaligned
     } else if insn.semantics.mem_write {
-        mem.write(alu_result as usize, insn.semantics.mem_size, rs2 as u32);
+        Exception::StoreAddressMisaligned
+    } else {
+        return Ok(());
+    };
+
+    mem.check_alignment(addr as usize, size, misaligned)
+        .map_err(|cause| Trap::new(cause, addr))
+}
+
+
+/// Checks a load or store against the PMP unit.
+///
+/// Raises a load or store access fault if the access is denied.
+pub fn check_pmp(
+    insn: &Instruction,
+    pmp: &Pmp,
+    addr: u32,
+    _clk: u64,
+) -> Result<(), Trap> {
+    let size = insn.semantics.mem_size;
+
+    if insn.semantics.mem_read && !pmp.check(addr, size, Access::Read) {
+        debug!("PMP: load from {:#0x} denied (clock {})", addr, _clk);
+        return Err(Trap::new(Exception::LoadAccessFault, addr));
+    }
+
+    if insn.semantics.mem_write && !pmp.check(addr, size, Access::Write) {
+        debug!("PMP: store to {:#0x} denied (clock {})", addr, _clk);
+        return Err(Trap::new(Exception::StoreAccessFault, addr));
+    }
+
+    Ok(())
+}
+
+
+/// MEM: Access control and status register, or raise an environment trap.
+///
+/// `alu_result` carries the value of rs1 for register-sourced CSR
+/// instructions. Returns the old value of the CSR.
+pub fn access_csr(
+    insn: &Instruction,
+    csr: &mut CsrFile,
+    alu_result: i32,
+    _clk: u64,
+) -> Result<u32, Trap> {
+    let illegal = Trap::new(Exception::IllegalInstruction, insn.as_u32());
+    let addr = insn.fields.imm.unwrap();
+    let zimm = (insn.as_u32() & consts::RS1_MASK) >> consts::RS1_SHIFT;
+
+    let (src, writes) = match insn.function {
+        Function::Ecall => {
+            return Err(Trap::new(Exception::EnvironmentCallFromMMode, 0));
+        }
+        Function::Ebreak => return Err(Trap::new(Exception::Breakpoint, 0)),
+        Function::Mret => return Ok(0),
+        Function::Csrrw => (alu_result as u32, true),
+        Function::Csrrwi => (zimm, true),
+        // Set and clear variants don't write when rs1 is x0 or zimm is 0
+        Function::Csrrs | Function::Csrrc => {
+            (alu_result as u32, insn.fields.rs1 != Some(0))
+        }
+        _ => (zimm, zimm != 0),
+    };
+
+    let old = csr.read(addr).map_err(|_| illegal)?;
+
+    if writes {
+        let new = match insn.function {
+            Function::Csrrs | Function::Csrrsi => old | src,
+            Function::Csrrc | Function::Csrrci => old & !src,
+            _ => src,
+        };
+        csr.write(addr, new).map_err(|_| illegal)?;
+        trace!("CSR: [{:#0x}] = {:#0x} (clock {})", addr, new, _clk);
     }
 
-    mem_result
+    Ok(old)
 }
 
 
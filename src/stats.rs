@@ -0,0 +1,376 @@
+//! Cycle accurate simulator run statistics.
+
+
+use checkpoint::{self, Checkpoint, Reader, Writer};
+use instruction::{Function, FUNCTIONS};
+use pipeline::events::{Event, Observer, Stage, StallReason};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+
+/// Results forwarded along each bypass path.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ForwardStats {
+    /// From the EX/MEM pipeline register, to EX or to ID
+    pub ex_mem: u64,
+    /// From the MEM/WB pipeline register to EX
+    pub mem_wb: u64,
+    /// From writeback to the register read in ID
+    pub register_file: u64,
+}
+
+
+/// Statistics for a cycle accurate simulator run.
+///
+/// Collected from the pipeline events of the run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimStats {
+    /// Address of the HALT instruction
+    pub halt_addr: usize,
+    /// Cycles up to and including the one HALT was detected in
+    pub cycles: u64,
+    /// Instructions that completed writeback, not counting bubbles
+    pub retired: u64,
+    /// Cycles ID stalled for a load or CSR read result
+    pub load_use_stall_cycles: u64,
+    /// Cycles ID stalled for a register without forwarding
+    pub data_hazard_stall_cycles: u64,
+    /// Cycles ID stalled for the operands of a branch resolved in ID
+    pub branch_stall_cycles: u64,
+    /// Cycles the pipeline froze for multi-cycle memory accesses
+    pub memory_stall_cycles: u64,
+    /// Cycles lost to instructions flushed after mispredictions
+    pub branch_flush_cycles: u64,
+    pub forwards: ForwardStats,
+    /// Retired instructions by function
+    pub mix: HashMap<Function, u64>,
+}
+
+
+impl SimStats {
+    /// Returns the average cycles per retired instruction.
+    ///
+    /// Returns 0.0 if nothing retired.
+    pub fn cpi(&self) -> f64 {
+        match self.retired {
+            0 => 0.0,
+            n => self.cycles as f64 / n as f64,
+        }
+    }
+
+    /// Returns the instruction mix, most frequent first.
+    ///
+    /// Functions with the same count are sorted by name.
+    pub fn instruction_mix(&self) -> Vec<(Function, u64)> {
+        let mut mix: Vec<(Function, u64)> =
+            self.mix.iter().map(|(&f, &n)| (f, n)).collect();
+        mix.sort_by(|a, b| {
+            b.1.cmp(&a.1).then_with(|| {
+                format!("{:?}", a.0).cmp(&format!("{:?}", b.0))
+            })
+        });
+
+        mix
+    }
+
+    /// Returns the statistics as named counters, in report order.
+    ///
+    /// The instruction mix follows as `mix.<Function>` counters.
+    fn counters(&self) -> Vec<(String, String)> {
+        let mut counters = Vec::new();
+        {
+            let mut push = |name: &str, value: String| {
+                counters.push((name.to_string(), value));
+            };
+            push("halt_addr", self.halt_addr.to_string());
+            push("cycles", self.cycles.to_string());
+            push("retired", self.retired.to_string());
+            push("cpi", format!("{:.4}", self.cpi()));
+            push(
+                "load_use_stall_cycles",
+                self.load_use_stall_cycles.to_string(),
+            );
+            push(
+                "data_hazard_stall_cycles",
+                self.data_hazard_stall_cycles.to_string(),
+            );
+            push("branch_stall_cycles", self.branch_stall_cycles.to_string());
+            push("memory_stall_cycles", self.memory_stall_cycles.to_string());
+            push("branch_flush_cycles", self.branch_flush_cycles.to_string());
+            push("forwards.ex_mem", self.forwards.ex_mem.to_string());
+            push("forwards.mem_wb", self.forwards.mem_wb.to_string());
+            push(
+                "forwards.register_file",
+                self.forwards.register_file.to_string(),
+            );
+        }
+
+        for (function, n) in self.instruction_mix() {
+            counters.push((format!("mix.{:?}", function), n.to_string()));
+        }
+
+        counters
+    }
+
+    /// Formats the statistics as CSV with a `metric,value` header.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("metric,value\n");
+        for (name, value) in self.counters() {
+            csv.push_str(&format!("{},{}\n", name, value));
+        }
+
+        csv
+    }
+
+    /// Formats the statistics as a JSON object.
+    ///
+    /// Forwarding counts and the instruction mix are nested objects.
+    pub fn to_json(&self) -> String {
+        let mix: Vec<String> = self.instruction_mix()
+            .iter()
+            .map(|&(function, n)| format!("\"{:?}\":{}", function, n))
+            .collect();
+
+        format!(
+            "{{\"halt_addr\":{},\"cycles\":{},\"retired\":{},\"cpi\":{:.4},\
+             \"load_use_stall_cycles\":{},\"data_hazard_stall_cycles\":{},\
+             \"branch_stall_cycles\":{},\"memory_stall_cycles\":{},\
+             \"branch_flush_cycles\":{},\"forwards\":{{\"ex_mem\":{},\
+             \"mem_wb\":{},\"register_file\":{}}},\
+             \"instruction_mix\":{{{}}}}}",
+            self.halt_addr,
+            self.cycles,
+            self.retired,
+            self.cpi(),
+            self.load_use_stall_cycles,
+            self.data_hazard_stall_cycles,
+            self.branch_stall_cycles,
+            self.memory_stall_cycles,
+            self.branch_flush_cycles,
+            self.forwards.ex_mem,
+            self.forwards.mem_wb,
+            self.forwards.register_file,
+            mix.join(",")
+        )
+    }
+}
+
+
+impl Observer for SimStats {
+    fn event(&mut self, _cycle: u64, event: &Event) {
+        match *event {
+            Event::Retire { function, .. } => {
+                self.retired += 1;
+                *self.mix.entry(function).or_insert(0) += 1;
+            }
+            Event::Stall { reason, .. } => {
+                let cycles = match reason {
+                    StallReason::LoadUse => &mut self.load_use_stall_cycles,
+                    StallReason::DataHazard => {
+                        &mut self.data_hazard_stall_cycles
+                    }
+                    StallReason::BranchOperands => {
+                        &mut self.branch_stall_cycles
+                    }
+                    StallReason::Memory => &mut self.memory_stall_cycles,
+                };
+                *cycles += 1;
+            }
+            Event::Forward { dst, from_stage, .. } => {
+                match (from_stage, dst) {
+                    (Stage::Mem, _) => self.forwards.ex_mem += 1,
+                    (Stage::Wb, Stage::Ex) => self.forwards.mem_wb += 1,
+                    _ => self.forwards.register_file += 1,
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+
+impl Checkpoint for SimStats {
+    fn save(&self, w: &mut Writer) {
+        w.usize(self.halt_addr);
+        w.u64(self.cycles);
+        w.u64(self.retired);
+        w.u64(self.load_use_stall_cycles);
+        w.u64(self.data_hazard_stall_cycles);
+        w.u64(self.branch_stall_cycles);
+        w.u64(self.memory_stall_cycles);
+        w.u64(self.branch_flush_cycles);
+        w.u64(self.forwards.ex_mem);
+        w.u64(self.forwards.mem_wb);
+        w.u64(self.forwards.register_file);
+
+        let mut mix: Vec<(u32, u64)> =
+            self.mix.iter().map(|(&f, &n)| (f as u32, n)).collect();
+        mix.sort();
+        w.usize(mix.len());
+        for (function, n) in mix {
+            w.u32(function);
+            w.u64(n);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.halt_addr = r.usize()?;
+        self.cycles = r.u64()?;
+        self.retired = r.u64()?;
+        self.load_use_stall_cycles = r.u64()?;
+        self.data_hazard_stall_cycles = r.u64()?;
+        self.branch_stall_cycles = r.u64()?;
+        self.memory_stall_cycles = r.u64()?;
+        self.branch_flush_cycles = r.u64()?;
+        self.forwards.ex_mem = r.u64()?;
+        self.forwards.mem_wb = r.u64()?;
+        self.forwards.register_file = r.u64()?;
+
+        self.mix.clear();
+        for _ in 0..r.usize()? {
+            let code = r.u32()?;
+            let function = match FUNCTIONS.get(code as usize) {
+                Some(&function) => function,
+                None => {
+                    let msg = format!("invalid function {}", code);
+                    return Err(checkpoint::invalid(&msg));
+                }
+            };
+            self.mix.insert(function, r.u64()?);
+        }
+
+        Ok(())
+    }
+}
+
+
+impl fmt::Display for SimStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Run statistics:")?;
+        writeln!(f, "    cycles:                {}", self.cycles)?;
+        writeln!(f, "    retired:               {}", self.retired)?;
+        writeln!(f, "    CPI:                   {:.4}", self.cpi())?;
+        writeln!(f, "Stall cycles:")?;
+        writeln!(
+            f,
+            "    load-use:              {}",
+            self.load_use_stall_cycles
+        )?;
+        writeln!(
+            f,
+            "    data hazard:           {}",
+            self.data_hazard_stall_cycles
+        )?;
+        writeln!(
+            f,
+            "    branch operands:       {}",
+            self.branch_stall_cycles
+        )?;
+        writeln!(
+            f,
+            "    memory:                {}",
+            self.memory_stall_cycles
+        )?;
+        writeln!(
+            f,
+            "    branch flush:          {}",
+            self.branch_flush_cycles
+        )?;
+        writeln!(f, "Forwarding:")?;
+        writeln!(f, "    EX/MEM:                {}", self.forwards.ex_mem)?;
+        writeln!(f, "    MEM/WB:                {}", self.forwards.mem_wb)?;
+        writeln!(
+            f,
+            "    register file bypass:  {}",
+            self.forwards.register_file
+        )?;
+        write!(f, "Instruction mix:")?;
+        for (function, n) in self.instruction_mix() {
+            let percent = 100.0 * n as f64 / self.retired as f64;
+            write!(
+                f,
+                "\n    {:<22} {:>8}  {:>6.2}%",
+                format!("{:?}:", function),
+                n,
+                percent
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> SimStats {
+        let mut stats = SimStats::default();
+        let events = [
+            Event::Retire {
+                pc: 0x0,
+                function: Function::Addi,
+            },
+            Event::Retire {
+                pc: 0x4,
+                function: Function::Lw,
+            },
+            Event::Retire {
+                pc: 0x8,
+                function: Function::Addi,
+            },
+            Event::Stall {
+                stage: Stage::Id,
+                reason: StallReason::LoadUse,
+            },
+            Event::Forward {
+                pc: 0x8,
+                src: 1,
+                dst: Stage::Id,
+                from_stage: Stage::Wb,
+                value: 0,
+            },
+        ];
+        for event in events.iter() {
+            stats.event(0, event);
+        }
+        stats.cycles = 6;
+
+        stats
+    }
+
+    #[test]
+    fn counters() {
+        let stats = stats();
+        assert_eq!(stats.retired, 3);
+        assert_eq!(stats.cpi(), 2.0);
+        assert_eq!(stats.load_use_stall_cycles, 1);
+        assert_eq!(stats.forwards.register_file, 1);
+        assert_eq!(
+            stats.instruction_mix(),
+            vec![(Function::Addi, 2), (Function::Lw, 1)]
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let csv = stats().to_csv();
+        assert!(csv.starts_with("metric,value\nhalt_addr,0\ncycles,6\n"));
+        assert!(csv.contains("\ncpi,2.0000\n"));
+        assert!(csv.ends_with("\nmix.Addi,2\nmix.Lw,1\n"));
+    }
+
+    #[test]
+    fn json() {
+        let json = stats().to_json();
+        assert!(json.starts_with("{\"halt_addr\":0,\"cycles\":6,"));
+        assert!(json.contains(
+            "\"forwards\":{\"ex_mem\":0,\"mem_wb\":0,\"register_file\":1}"
+        ));
+        assert!(json.ends_with("\"instruction_mix\":{\"Addi\":2,\"Lw\":1}}"));
+    }
+
+}
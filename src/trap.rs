@@ -0,0 +1,63 @@
+//! Synchronous exceptions and machine-mode traps.
+
+
+/// Synchronous exception causes, encoded as their `mcause` value.
+///
+/// See RISC-V Privileged Architecture V1.10, table 3.6.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EnvironmentCallFromMMode = 11,
+}
+
+
+impl Exception {
+    /// Returns the exception code written to `mcause`.
+    pub fn code(&self) -> u32 {
+        *self as u32
+    }
+
+    /// Returns the exception with `mcause` value `code`, if any.
+    pub fn from_code(code: u32) -> Option<Exception> {
+        let exception = match code {
+            0 => Exception::InstructionAddressMisaligned,
+            1 => Exception::InstructionAccessFault,
+            2 => Exception::IllegalInstruction,
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadAddressMisaligned,
+            5 => Exception::LoadAccessFault,
+            6 => Exception::StoreAddressMisaligned,
+            7 => Exception::StoreAccessFault,
+            11 => Exception::EnvironmentCallFromMMode,
+            _ => return None,
+        };
+
+        Some(exception)
+    }
+}
+
+
+/// An exception raised by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trap {
+    /// Reason for the trap
+    pub cause: Exception,
+
+    /// Exception-specific value written to `mtval`, e.g., the faulting address
+    pub tval: u32,
+}
+
+
+impl Trap {
+    /// Constructs a new `Trap`.
+    pub fn new(cause: Exception, tval: u32) -> Trap {
+        Trap { cause, tval }
+    }
+}
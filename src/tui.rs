@@ -0,0 +1,566 @@
+//! Interactive terminal UI for the cycle accurate simulator.
+//!
+//! `Tui` is an `Observer` that redraws the terminal at the end of each
+//! cycle it is stepping through: the instruction in each of the five
+//! stages, the pipeline registers, the register file and a window of data
+//! memory. Forwarded operands, stalls and flushes are highlighted in the
+//! stage they affected, and registers written in the cycle are highlighted
+//! in the register file.
+//!
+//! When stopped, it reads one command per line:
+//!
+//! ```text
+//! s [N]       step N cycles (default 1)
+//! r [N]       go back N cycles in the recorded history (default 1)
+//! c           run until an instruction at a breakpoint is fetched
+//! b [ADDR]    toggle a breakpoint at ADDR, or list breakpoints
+//! m ADDR      show data memory from ADDR
+//! q           run to HALT without stopping
+//! ```
+//!
+//! An empty line repeats a step. Going back only changes what is shown;
+//! stepping from a past cycle moves forward through the history first.
+
+
+use memory::data::DataMemory;
+use pipeline::Pipeline;
+use pipeline::events::{Event, Observer, Occupancy, Stage, STAGES};
+use register::RegisterFile;
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+
+
+/// Cycles of history kept for going back.
+pub const HISTORY_CYCLES: usize = 1024;
+
+/// Words of data memory shown, four per row.
+const MEMORY_WORDS: usize = 32;
+
+const CLEAR: &str = "\x1b[2J\x1b[H";
+const RESET: &str = "\x1b[0m";
+const STALL_COLOR: &str = "\x1b[1;31m";
+const FORWARD_COLOR: &str = "\x1b[1;33m";
+const WRITE_COLOR: &str = "\x1b[1;32m";
+
+const HELP: &str = "s [N] step, r [N] back, c continue, b [ADDR] \
+                    breakpoint, m ADDR memory, q quit";
+
+
+/// What the pipeline held at the end of a cycle.
+struct Snapshot {
+    cycle: u64,
+    stages: Occupancy,
+    events: Vec<Event>,
+    pipeline: Pipeline,
+    pc: u32,
+    gpr: [u32; 32],
+    mem_addr: usize,
+    /// Words from `mem_addr`, or `None` where unreadable
+    mem: Vec<Option<u32>>,
+}
+
+
+/// When to stop next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Run {
+    /// After this many more cycles
+    Cycles(u64),
+    /// When an instruction at a breakpoint is fetched
+    Breakpoint,
+    /// Not before HALT
+    Finish,
+}
+
+
+/// Steps through a run in the terminal.
+pub struct Tui<R: BufRead, W: Write> {
+    input: R,
+    out: W,
+    error: Option<io::Error>,
+    color: bool,
+    run: Run,
+    breakpoints: Vec<u32>,
+    mem_addr: usize,
+    history: VecDeque<Snapshot>,
+    /// Cycles back from the latest snapshot that are shown
+    back: usize,
+    /// Events and stage occupancy of the current cycle
+    events: Vec<Event>,
+    stages: Occupancy,
+    /// Feedback to the last command
+    message: String,
+}
+
+
+impl<R: BufRead, W: Write> Tui<R, W> {
+    /// Constructs a new `Tui` reading commands from `input` and drawing to
+    /// `out`.
+    ///
+    /// Stops at the end of the first cycle.
+    pub fn new(input: R, out: W) -> Tui<R, W> {
+        Tui {
+            input,
+            out,
+            error: None,
+            color: true,
+            run: Run::Cycles(1),
+            breakpoints: Vec::new(),
+            mem_addr: 0,
+            history: VecDeque::new(),
+            back: 0,
+            events: Vec::new(),
+            stages: [None; 5],
+            message: String::from(HELP),
+        }
+    }
+
+    /// Enables or disables highlighting with ANSI colors.
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    /// Toggles a breakpoint on the instruction at `addr`.
+    pub fn toggle_breakpoint(&mut self, addr: u32) {
+        match self.breakpoints.iter().position(|&b| b == addr) {
+            Some(i) => {
+                self.breakpoints.remove(i);
+            }
+            None => self.breakpoints.push(addr),
+        }
+    }
+
+    /// Flushes and returns the output, or the first error reading input or
+    /// drawing.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    /// Reads the memory window from `mem`, past its end as `None`.
+    fn read_memory(&self, mem: &DataMemory) -> Vec<Option<u32>> {
+        (0..MEMORY_WORDS)
+            .map(|i| self.mem_addr + 4 * i)
+            .map(|addr| match mem.contains(addr, 4) {
+                true => mem.read(addr, 4).ok(),
+                false => None,
+            })
+            .collect()
+    }
+
+    /// Returns whether to stop at the end of the latest cycle.
+    fn stop(&mut self) -> bool {
+        match self.run {
+            Run::Cycles(n) => {
+                self.run = Run::Cycles(n - 1);
+                n == 1
+            }
+            Run::Breakpoint => match self.stages[0] {
+                Some(slot) => self.breakpoints.contains(&slot.pc),
+                None => false,
+            },
+            Run::Finish => false,
+        }
+    }
+
+    /// Reads and carries out commands until one resumes the run.
+    fn prompt(&mut self, mem: &DataMemory) -> io::Result<()> {
+        loop {
+            self.draw()?;
+            write!(self.out, "> ")?;
+            self.out.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                self.run = Run::Finish;
+                return Ok(());
+            }
+            if self.command(line.trim(), mem) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Carries out a command, returning whether it resumes the run.
+    fn command(&mut self, line: &str, mem: &DataMemory) -> bool {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("s");
+        let arg = words.next();
+        let count = match arg.map(parse_number) {
+            None => Some(1),
+            Some(Some(n)) if n > 0 => Some(n),
+            Some(_) => None,
+        };
+        self.message.clear();
+
+        match (command, count) {
+            ("s", Some(n)) | ("step", Some(n)) => {
+                let back = self.back as u64;
+                if n <= back {
+                    self.back -= n as usize;
+                    return false;
+                }
+                self.back = 0;
+                self.run = Run::Cycles(n - back);
+                return true;
+            }
+            ("r", Some(n)) | ("reverse", Some(n)) => {
+                let oldest = self.history.len() - 1;
+                self.back = oldest.min(self.back + n as usize);
+                if self.back == oldest {
+                    self.message = String::from("oldest recorded cycle");
+                }
+            }
+            ("c", _) | ("continue", _) => {
+                self.back = 0;
+                self.run = Run::Breakpoint;
+                return true;
+            }
+            ("b", _) | ("break", _) => match arg.map(parse_number) {
+                None => {
+                    let addrs: Vec<String> = self.breakpoints
+                        .iter()
+                        .map(|addr| format!("{:#0x}", addr))
+                        .collect();
+                    self.message = format!("breakpoints: {}", addrs.join(" "));
+                }
+                Some(Some(addr)) => self.toggle_breakpoint(addr as u32),
+                Some(None) => {
+                    self.message = String::from("invalid address")
+                }
+            },
+            ("m", _) | ("memory", _) => match arg.and_then(parse_number) {
+                Some(addr) => {
+                    self.mem_addr = addr as usize & !0x3;
+                    if self.back == 0 {
+                        let words = self.read_memory(mem);
+                        let latest = self.history.back_mut().unwrap();
+                        latest.mem_addr = self.mem_addr;
+                        latest.mem = words;
+                    }
+                }
+                None => self.message = String::from("invalid address"),
+            },
+            ("q", _) | ("quit", _) => {
+                self.run = Run::Finish;
+                return true;
+            }
+            _ => self.message = format!("unknown command: {}\n{}", line, HELP),
+        }
+
+        false
+    }
+
+    /// Wraps `text` in `color` if colors are enabled.
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Draws the snapshot being shown.
+    fn draw(&mut self) -> io::Result<()> {
+        let snapshot = &self.history[self.history.len() - 1 - self.back];
+        let mut screen = String::new();
+        if self.color {
+            screen.push_str(CLEAR);
+        }
+
+        screen.push_str(&format!("Cycle {}", snapshot.cycle));
+        if self.back > 0 {
+            screen.push_str(&format!(" ({} back)", self.back));
+        }
+        screen.push_str("\n\n");
+
+        // Stages, with what happened to them in the cycle
+        for (slot, &stage) in snapshot.stages.iter().zip(STAGES.iter()) {
+            let label = match *slot {
+                Some(slot) => slot.label(),
+                None => String::from("-"),
+            };
+            let notes: Vec<String> = snapshot
+                .events
+                .iter()
+                .filter_map(|event| self.note(stage, event))
+                .collect();
+            let line =
+                format!("{:<4}{:<32}{}", stage.name(), label, notes.join(" "));
+            screen.push_str(line.trim_end());
+            screen.push('\n');
+        }
+
+        // Pipeline registers
+        let pl = &snapshot.pipeline;
+        screen.push_str(&format!(
+            "\nIF/ID   pc {:#010x}  npc {:#010x}  insn {:#010x}{}\n",
+            pl.if_id.pc,
+            pl.if_id.npc,
+            pl.if_id.raw_insn,
+            if pl.if_id.bubble { "  bubble" } else { "" }
+        ));
+        screen.push_str(&format!(
+            "ID/EX   pc {:#010x}  rs1 {:#010x}  rs2 {:#010x}  {}\n",
+            pl.id_ex.pc,
+            pl.id_ex.rs1,
+            pl.id_ex.rs2,
+            pl.id_ex.insn
+        ));
+        screen.push_str(&format!(
+            "EX/MEM  pc {:#010x}  alu {:#010x}  rs2 {:#010x}  {}\n",
+            pl.ex_mem.pc,
+            pl.ex_mem.alu_result,
+            pl.ex_mem.rs2,
+            pl.ex_mem.insn
+        ));
+        screen.push_str(&format!(
+            "MEM/WB  pc {:#010x}  alu {:#010x}  mem {:#010x}  {}\n",
+            pl.mem_wb.pc,
+            pl.mem_wb.alu_result,
+            pl.mem_wb.mem_result,
+            pl.mem_wb.insn
+        ));
+
+        // Register file
+        screen.push_str(&format!("\npc  {:08x}", snapshot.pc));
+        for (i, value) in snapshot.gpr.iter().enumerate() {
+            let written = snapshot.events.iter().any(|event| match *event {
+                Event::RegWrite { rd, .. } => rd as usize == i,
+                _ => false,
+            });
+            let text = format!("{:08x}", value);
+            let text = if written {
+                self.paint(WRITE_COLOR, &text)
+            } else {
+                text
+            };
+            let sep = if i % 4 == 0 { "\n" } else { "    " };
+            screen.push_str(&format!("{}x{:<2} {}", sep, i, text));
+        }
+        screen.push('\n');
+
+        // Data memory
+        for (i, words) in snapshot.mem.chunks(4).enumerate() {
+            screen.push_str(&format!(
+                "\n{:#010x} ",
+                snapshot.mem_addr + 16 * i
+            ));
+            for word in words {
+                match *word {
+                    Some(word) => screen.push_str(&format!(" {:08x}", word)),
+                    None => screen.push_str(" --------"),
+                }
+            }
+        }
+        screen.push('\n');
+
+        if !self.message.is_empty() {
+            screen.push_str(&format!("\n{}\n", self.message));
+        }
+
+        write!(self.out, "{}", screen)
+    }
+
+    /// Returns a note on what `event` did to `stage`, if anything.
+    fn note(&self, stage: Stage, event: &Event) -> Option<String> {
+        match *event {
+            Event::Forward {
+                src,
+                dst,
+                from_stage,
+                value,
+                ..
+            } if dst == stage => {
+                let text = format!(
+                    "x{} <- {} {:#0x}",
+                    src,
+                    from_stage.name(),
+                    value
+                );
+                Some(self.paint(FORWARD_COLOR, &text))
+            }
+            Event::Stall { stage: stalled, reason }
+                if stalled == stage ||
+                    (stalled == Stage::Id && stage == Stage::If) =>
+            {
+                let text = format!("stall: {}", reason.name());
+                Some(self.paint(STALL_COLOR, &text))
+            }
+            Event::Flush {
+                stage: flushed,
+                cause,
+                ..
+            } if flushed == stage =>
+            {
+                let text = format!("flush: {}", cause.name());
+                Some(self.paint(STALL_COLOR, &text))
+            }
+            _ => None,
+        }
+    }
+}
+
+
+impl<R: BufRead, W: Write> Observer for Tui<R, W> {
+    fn event(&mut self, _cycle: u64, event: &Event) {
+        self.events.push(*event);
+    }
+
+    fn cycle(&mut self, _cycle: u64, stages: &Occupancy) {
+        self.stages = *stages;
+    }
+
+    fn state(
+        &mut self,
+        cycle: u64,
+        pipeline: &Pipeline,
+        reg: &RegisterFile,
+        mem: &DataMemory,
+    ) {
+        let mut gpr = [0; 32];
+        for (value, register) in gpr.iter_mut().zip(reg.gpr.iter()) {
+            *value = register.read();
+        }
+        let snapshot = Snapshot {
+            cycle,
+            stages: self.stages,
+            events: self.events.drain(..).collect(),
+            pipeline: *pipeline,
+            pc: reg.pc.read(),
+            gpr,
+            mem_addr: self.mem_addr,
+            mem: self.read_memory(mem),
+        };
+        if self.history.len() == HISTORY_CYCLES {
+            self.history.pop_front();
+        }
+        self.history.push_back(snapshot);
+
+        if self.error.is_some() || self.run == Run::Finish {
+            return;
+        }
+        let result = if self.stop() {
+            self.prompt(mem)
+        } else if let Run::Cycles(_) = self.run {
+            self.draw()
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+            self.run = Run::Finish;
+        }
+    }
+}
+
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+pub fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ca_simulator;
+    use consts;
+    use hazards::{HazardPolicy, HazardUnit};
+    use memory::instruction::TestInstructionMemory;
+    use predictor::BranchUnit;
+    use predictor::direction::NotTaken;
+
+    /// Runs a load-use sequence, commanded by `input`.
+    ///
+    /// Returns the screens drawn.
+    fn run(input: &str) -> Vec<String> {
+        let insns = vec![
+            0x00_01_22_03, // lw x4, 0(x2)
+            0x00_42_07_b3, // add x15, x4, x4
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+            consts::HALT,
+            consts::NOP,
+            consts::NOP,
+            consts::NOP,
+        ];
+
+        let insn_memory = TestInstructionMemory::new(insns);
+        let mut data_memory = DataMemory::new(1024);
+        let mut registers = RegisterFile::new(0x0);
+        let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+        let mut hazard_unit = HazardUnit::new(HazardPolicy::Forwarding);
+        let mut tui = Tui::new(input.as_bytes(), Vec::new());
+        tui.set_color(false);
+        data_memory.write(0, consts::WORD_SIZE, 9).unwrap();
+
+        ca_simulator::run_with_observer(
+            &insn_memory,
+            &mut data_memory,
+            &mut registers,
+            &mut branch_unit,
+            &mut hazard_unit,
+            &mut tui,
+        );
+
+        let out = String::from_utf8(tui.into_inner().unwrap()).unwrap();
+        out.split("> ").map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn step() {
+        let screens = run("s\ns 4\nq\n");
+        assert!(screens[0].starts_with("Cycle 0\n\nIF  0x0: lw x4, 0(x2)"));
+        assert!(screens[1].starts_with("Cycle 1\n"));
+
+        // Every cycle is drawn while stepping
+        assert!(screens[2].contains("ID  0x4: add x15, x4, x4"));
+        assert!(screens[2].contains("stall: load-use"));
+        assert!(screens[2].contains("x4 <- WB 0x9"));
+        assert!(screens[2].starts_with("Cycle 2\n"));
+        assert!(screens[2].contains("\nCycle 5\n"));
+        assert!(screens[2].contains("\n0x00000000  00000009 00000000"));
+        assert_eq!(screens[3], "");
+    }
+
+    #[test]
+    fn reverse() {
+        let screens = run("s 3\nr 2\nr 5\n\ns 3\nq\n");
+        assert!(screens[1].contains("\nCycle 3\n"));
+        assert!(screens[2].starts_with("Cycle 1 (2 back)\n"));
+        assert!(screens[3].starts_with("Cycle 0 (3 back)\n"));
+        assert!(screens[3].contains("oldest recorded cycle"));
+        assert!(screens[4].starts_with("Cycle 1 (2 back)\n"));
+        assert!(screens[5].starts_with("Cycle 4\n"));
+    }
+
+    #[test]
+    fn breakpoint() {
+        let screens = run("b 0x14\nb\nc\nm 0x10\nq\n");
+        assert!(screens[2].contains("breakpoints: 0x14"));
+        assert!(screens[3].starts_with("Cycle 6\n\nIF  0x14: halt"));
+        assert!(screens[4].contains("\n0x00000010  00000000"));
+    }
+
+    #[test]
+    fn memory_past_end() {
+        let screens = run("m 0xff8\nm 0xfffffff0\nq\n");
+        assert!(screens[1].contains(
+            "\n0x00000ff8  00000000 00000000 -------- --------\n"
+        ));
+        assert!(screens[2].contains(
+            "\n0xfffffff0  -------- -------- -------- --------\n"
+        ));
+    }
+
+}
@@ -0,0 +1,164 @@
+//! Bootloader-style sector reads and writes through the block device.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::{ca_simulator, ia_simulator};
+use riscv_5stage_simulator::devices::block::{self, BlockDevice, WriteMode};
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAM: &str = "tests/block_device_disassembly.txt";
+const IMAGE: &str = "tests/block_device_image.bin";
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Reads a whole file.
+fn read_file(path: &Path) -> Vec<u8> {
+    let mut contents = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+    contents
+}
+
+
+/// Runs the bootloader program with a block device attached.
+///
+/// Returns the registers and data memory after HALT.
+fn run(
+    ca: bool,
+    image: &Path,
+    mode: WriteMode,
+    latency: u32,
+) -> (RegisterFile, DataMemory) {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(1024);
+    let mut reg = RegisterFile::new(0x0);
+
+    let mut dev = BlockDevice::open(image, mode).unwrap();
+    dev.set_latency(latency, 2 * latency);
+    mem.attach(block::BASE, Box::new(dev));
+
+    let halt_addr = match ca {
+        true => ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr,
+        false => ia_simulator::run(&insns, &mut mem, &mut reg),
+    };
+    assert_eq!(halt_addr, 0xa8);
+
+    (reg, mem)
+}
+
+
+/// The program DMAs sector 1 into RAM, writes a modified copy of its first
+/// word to sector 2, reads it back, and then reads past the last sector.
+fn check_bootloader(reg: &RegisterFile, mem: &DataMemory) {
+    assert_eq!(reg.gpr[11].read(), 4); // sectors in image
+    assert_eq!(reg.gpr[12].read(), 0x43424140); // DMA'd sector 1
+    assert_eq!(reg.gpr[13].read(), 0x3f3e3d3c);
+    assert_eq!(mem.read(0x200, 4), Ok(0x43424140));
+    assert_eq!(mem.read(0x3fc, 4), Ok(0x3f3e3d3c));
+    assert_eq!(reg.gpr[15].read(), 0x43424141); // sector 2 read back
+    assert_eq!(reg.gpr[16].read(), 0); // status idle
+    assert_eq!(reg.gpr[17].read(), block::ERROR); // out of range
+}
+
+
+/// Copy-on-write runs never modify the golden image.
+fn check_copy_on_write(ca: bool) {
+    let golden = read_file(Path::new(IMAGE));
+    let (reg, mem) = run(ca, Path::new(IMAGE), WriteMode::CopyOnWrite, 0);
+
+    check_bootloader(&reg, &mem);
+    assert!(read_file(Path::new(IMAGE)) == golden);
+}
+
+
+/// Write-through runs update the image file.
+fn check_write_through(ca: bool, name: &str) {
+    let image = env::temp_dir()
+        .join(format!("riscv-block-{}-{}.bin", name, process::id()));
+    fs::copy(IMAGE, &image).unwrap();
+
+    let (reg, mem) = run(ca, &image, WriteMode::WriteThrough, 0);
+
+    check_bootloader(&reg, &mem);
+    let golden = read_file(Path::new(IMAGE));
+    let contents = read_file(&image);
+    assert_eq!(&contents[1024..1028], &[0x41, 0x41, 0x42, 0x43]);
+    assert!(contents[1028..1536].iter().all(|&b| b == 0)); // buffer
+    assert!(contents[..1024] == golden[..1024]);
+    assert!(contents[1536..] == golden[1536..]);
+
+    fs::remove_file(&image).unwrap();
+}
+
+
+/// Device latency shows up as extra polling of the status register.
+///
+/// Returns the number of polls without latency and with a latency of 40.
+fn check_latency(ca: bool) -> (u32, u32) {
+    let image = PathBuf::from(IMAGE);
+    let (fast, _) = run(ca, &image, WriteMode::CopyOnWrite, 0);
+    let (slow, mem) = run(ca, &image, WriteMode::CopyOnWrite, 40);
+
+    check_bootloader(&slow, &mem);
+    assert!(slow.gpr[20].read() > fast.gpr[20].read() + 10);
+
+    (fast.gpr[20].read(), slow.gpr[20].read())
+}
+
+
+#[test]
+fn test_ia_simulator_block_device_copy_on_write() {
+    check_copy_on_write(false);
+}
+
+
+#[test]
+fn test_ca_simulator_block_device_copy_on_write() {
+    check_copy_on_write(true);
+}
+
+
+#[test]
+fn test_ia_simulator_block_device_write_through() {
+    check_write_through(false, "ia");
+}
+
+
+#[test]
+fn test_ca_simulator_block_device_write_through() {
+    check_write_through(true, "ca");
+}
+
+
+#[test]
+fn test_ia_simulator_block_device_latency() {
+    // Polls read STATUS 2 cycles after the command and every 4 cycles after
+    // that, so latencies of 40, 80 and 40 cycles take 11, 21 and 11 polls
+    assert_eq!(check_latency(false), (3, 43));
+}
+
+
+#[test]
+fn test_ca_simulator_block_device_latency() {
+    check_latency(true);
+}
@@ -0,0 +1,159 @@
+//! Branch predictors compared on the disassembly test programs.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::pipeline::stages::{ID_BRANCH_PENALTY,
+                                               MEM_BRANCH_PENALTY};
+use riscv_5stage_simulator::predictor::{self, BranchResolution, BranchStats,
+                                        BranchUnit};
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAMS: [(&str, usize, usize); 3] = [
+    ("tests/riscv_32i_disassembly_1.txt", 1024, 0x4c0),
+    ("tests/riscv_32i_disassembly_2.txt", 1024, 0x56c),
+    ("tests/riscv_32i_sorting_disassembly.txt", 8192, 0xd8),
+];
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs a program with the named predictor, resolving branches in MEM.
+///
+/// Returns the registers, data memory and branch statistics after HALT.
+fn run(
+    program: (&str, usize, usize),
+    name: &str,
+) -> (RegisterFile, DataMemory, BranchStats) {
+    run_resolved_in(program, name, BranchResolution::Memory)
+}
+
+
+/// Runs a program with the named predictor, resolving branches in `stage`.
+fn run_resolved_in(
+    program: (&str, usize, usize),
+    name: &str,
+    stage: BranchResolution,
+) -> (RegisterFile, DataMemory, BranchStats) {
+    setup_logger();
+    let (filename, nwords, expected_halt_addr) = program;
+    let f = File::open(filename).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(nwords);
+    let mut reg = RegisterFile::new(0x0);
+    let mut branch_unit = BranchUnit::new(predictor::by_name(name).unwrap());
+    branch_unit.set_resolution(stage);
+
+    let halt_addr = ca_simulator::run_with_branch_unit(
+        &insns,
+        &mut mem,
+        &mut reg,
+        &mut branch_unit,
+    ).halt_addr;
+    assert_eq!(halt_addr, expected_halt_addr);
+
+    (reg, mem, branch_unit.stats)
+}
+
+
+/// Every predictor leaves the same architectural state as not-taken.
+#[test]
+fn test_ca_simulator_predictors_preserve_results() {
+    for program in PROGRAMS.iter() {
+        let (expected_reg, expected_mem, expected) =
+            run(*program, "not-taken");
+
+        for name in predictor::NAMES.iter() {
+            let (reg, mem, stats) = run(*program, name);
+
+            for i in 0..32 {
+                assert_eq!(reg.gpr[i].read(), expected_reg.gpr[i].read());
+            }
+            for addr in (0..program.1 * 4).step_by(4) {
+                assert_eq!(mem.read(addr, 4), expected_mem.read(addr, 4));
+            }
+            assert_eq!(stats.branches, expected.branches);
+            assert_eq!(
+                stats.penalty_cycles,
+                (stats.mispredicted + stats.jumps_mispredicted) *
+                    MEM_BRANCH_PENALTY
+            );
+        }
+    }
+}
+
+
+/// Dynamic predictors beat static ones on the sorting loops.
+#[test]
+fn test_ca_simulator_predictor_accuracy() {
+    let sorting = PROGRAMS[2];
+    let accuracy = |name| run(sorting, name).2.accuracy();
+
+    assert!(accuracy("taken") > accuracy("not-taken"));
+    assert!(accuracy("btfn") > accuracy("taken"));
+    assert!(accuracy("2-bit") > accuracy("1-bit"));
+    assert!(accuracy("tournament") > accuracy("2-bit"));
+    assert!(accuracy("tournament") > accuracy("gshare"));
+}
+
+
+/// Resolving branches in ID leaves the same architectural state as MEM.
+#[test]
+fn test_ca_simulator_id_resolution_preserves_results() {
+    for program in PROGRAMS.iter() {
+        let (expected_reg, expected_mem, expected) =
+            run(*program, "not-taken");
+
+        for name in ["not-taken", "2-bit"].iter() {
+            let (reg, mem, stats) =
+                run_resolved_in(*program, name, BranchResolution::Decode);
+
+            for i in 0..32 {
+                assert_eq!(reg.gpr[i].read(), expected_reg.gpr[i].read());
+            }
+            for addr in (0..program.1 * 4).step_by(4) {
+                assert_eq!(mem.read(addr, 4), expected_mem.read(addr, 4));
+            }
+            assert_eq!(stats.branches, expected.branches);
+            assert_eq!(stats.jumps, expected.jumps);
+        }
+    }
+}
+
+
+/// Resolving branches in ID loses fewer cycles, despite its extra stalls.
+#[test]
+fn test_ca_simulator_id_resolution_penalty() {
+    let sorting = PROGRAMS[2];
+    let (_, _, mem) = run(sorting, "not-taken");
+    let (_, _, id) =
+        run_resolved_in(sorting, "not-taken", BranchResolution::Decode);
+
+    assert_eq!(mem.stall_cycles, 0);
+    assert!(id.stall_cycles > 0);
+    assert_eq!(id.mispredicted, mem.mispredicted);
+    // JALR still resolves in MEM
+    assert!(
+        id.penalty_cycles >=
+            (id.mispredicted + id.jumps_mispredicted) * ID_BRANCH_PENALTY
+    );
+    assert!(id.penalty_cycles < mem.penalty_cycles);
+    assert!(
+        id.penalty_cycles + id.stall_cycles <
+            mem.penalty_cycles + mem.stall_cycles
+    );
+}
@@ -0,0 +1,119 @@
+//! Jump target prediction with the BTB and return address stack.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::predictor::{BranchStats, BranchUnit};
+use riscv_5stage_simulator::predictor::btb::Btb;
+use riscv_5stage_simulator::predictor::direction::TwoBit;
+use riscv_5stage_simulator::predictor::ras::ReturnAddressStack;
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAM: &str = "tests/branch_targets_disassembly.txt";
+
+/// Calls and returns made by the program.
+const JUMPS: u64 = 8 * 4 + 1;
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs the program with an optional BTB and return address stack.
+///
+/// The program calls `outer` (linking x1) eight times, which calls `inner`
+/// (linking x5), and finally returns through x5 without a call.
+fn run(btb: Option<Btb>, ras: Option<ReturnAddressStack>) -> BranchStats {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(1024);
+    let mut reg = RegisterFile::new(0x0);
+    let mut branch_unit = BranchUnit::new(Box::new(TwoBit::new(64)));
+    branch_unit.set_btb(btb);
+    branch_unit.set_ras(ras);
+
+    let halt_addr = ca_simulator::run_with_branch_unit(
+        &insns,
+        &mut mem,
+        &mut reg,
+        &mut branch_unit,
+    ).halt_addr;
+
+    assert_eq!(halt_addr, 0x2c);
+    assert_eq!(reg.gpr[10].read(), 8 * 3);
+    assert_eq!(reg.gpr[11].read(), 8);
+
+    let stats = branch_unit.stats;
+    assert_eq!(stats.jumps, JUMPS);
+    assert_eq!(stats.branches, 8);
+    stats
+}
+
+
+/// Without a BTB, every jump flushes the pipeline.
+#[test]
+fn test_ca_simulator_jumps_unpredicted() {
+    let stats = run(None, None);
+    assert_eq!(stats.jumps_mispredicted, JUMPS);
+}
+
+
+/// The BTB learns the calls, but returns to varying sites still miss.
+#[test]
+fn test_ca_simulator_btb() {
+    let stats = run(Some(Btb::new(16, 2, 32)), None);
+
+    assert!(stats.btb_hits > 0);
+    assert_eq!(stats.btb_aliases, 0);
+    assert!(stats.jumps_mispredicted < JUMPS);
+}
+
+
+/// With the RAS, only the first calls and the unmatched return miss.
+#[test]
+fn test_ca_simulator_ras() {
+    let btb = run(Some(Btb::new(16, 2, 32)), None);
+    let stats = run(
+        Some(Btb::new(16, 2, 32)),
+        Some(ReturnAddressStack::new(4)),
+    );
+
+    assert_eq!(stats.ras_overflows, 0);
+    assert_eq!(stats.ras_underflows, 1);
+    assert_eq!(stats.jumps_mispredicted, 3);
+    assert!(stats.penalty_cycles < btb.penalty_cycles);
+}
+
+
+/// A single-entry RAS loses the outer return address to the inner call.
+#[test]
+fn test_ca_simulator_ras_overflow() {
+    let stats = run(
+        Some(Btb::new(16, 2, 32)),
+        Some(ReturnAddressStack::new(1)),
+    );
+
+    assert_eq!(stats.ras_overflows, 8);
+    assert_eq!(stats.ras_underflows, 9);
+}
+
+
+/// A BTB without tags hands branches each other's targets.
+#[test]
+fn test_ca_simulator_btb_aliasing() {
+    let stats = run(Some(Btb::new(2, 1, 0)), None);
+    assert!(stats.btb_aliases > 0);
+}
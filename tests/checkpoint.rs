@@ -0,0 +1,188 @@
+//! Checkpoints restored mid-run must continue cycle for cycle like an
+//! uninterrupted run.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator::CaSimulator;
+use riscv_5stage_simulator::checkpoint::{self, Checkpoint};
+use riscv_5stage_simulator::ia_simulator::IaSimulator;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::memory::store_buffer::{DrainPolicy, StoreBuffer};
+use riscv_5stage_simulator::pipeline::events::Event;
+use riscv_5stage_simulator::predictor::BranchUnit;
+use riscv_5stage_simulator::predictor::btb::Btb;
+use riscv_5stage_simulator::predictor::direction::Tournament;
+use riscv_5stage_simulator::predictor::ras::ReturnAddressStack;
+use riscv_5stage_simulator::register::RegisterFile;
+use riscv_5stage_simulator::simulator::Simulator;
+
+use std::env;
+use std::fs::{self, File};
+use std::process;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAMS: [&str; 3] = [
+    "tests/riscv_32i_disassembly_1.txt",
+    "tests/riscv_32i_disassembly_2.txt",
+    "tests/riscv_32i_sorting_disassembly.txt",
+];
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Returns data memory with a store buffer and write latency, so that
+/// stores are in flight when checkpoints are taken.
+fn data_memory() -> DataMemory {
+    let mut mem = DataMemory::new(8192);
+    mem.set_write_latency(2);
+    let mut store_buffer = StoreBuffer::new(4, DrainPolicy::Watermark(2));
+    store_buffer.set_combining(true);
+    mem.set_store_buffer(Some(store_buffer));
+
+    mem
+}
+
+
+/// Constructs a CA simulator with a trained predictor, BTB and return
+/// address stack.
+fn ca_simulator<'a>(insns: &'a DisassemblyInstructionMemory)
+    -> CaSimulator<'a>
+{
+    let mut sim = CaSimulator::new(insns, data_memory(), RegisterFile::new(0));
+    let mut branch_unit = BranchUnit::new(Box::new(Tournament::new(64, 6)));
+    branch_unit.set_btb(Some(Btb::new(16, 2, 8)));
+    branch_unit.set_ras(Some(ReturnAddressStack::new(4)));
+    sim.set_branch_unit(branch_unit);
+
+    sim
+}
+
+
+fn ia_simulator<'a>(insns: &'a DisassemblyInstructionMemory)
+    -> IaSimulator<'a>
+{
+    IaSimulator::new(insns, data_memory(), RegisterFile::new(0))
+}
+
+
+/// Runs `sim` to HALT, returning the events of each remaining cycle.
+fn finish<S: Simulator>(sim: &mut S) -> Vec<Vec<Event>> {
+    let mut cycles = Vec::new();
+    while sim.halt_addr().is_none() {
+        sim.step();
+        cycles.push(sim.events().to_vec());
+    }
+
+    cycles
+}
+
+
+/// Checkpoints a run at several cycles, restores each checkpoint into a
+/// fresh simulator from `new`, and checks that both finish identically.
+fn check<'a, S, F>(insns: &'a DisassemblyInstructionMemory, new: F)
+where
+    S: Simulator + Checkpoint,
+    F: Fn(&'a DisassemblyInstructionMemory) -> S,
+{
+    let cycles = {
+        let mut sim = new(insns);
+        sim.run();
+        sim.clock()
+    };
+    assert!(cycles > 10);
+
+    for &at in [1, cycles / 3, 2 * cycles / 3].iter() {
+        let mut original = new(insns);
+        original.step_cycles(at);
+        let saved = checkpoint::save(&original);
+
+        let mut restored = new(insns);
+        checkpoint::restore(&mut restored, &saved).unwrap();
+        assert_eq!(restored.clock(), at);
+        assert_eq!(checkpoint::save(&restored), saved);
+
+        assert_eq!(finish(&mut restored), finish(&mut original));
+        assert_eq!(restored.clock(), cycles);
+        assert_eq!(restored.halt_addr(), original.halt_addr());
+        assert_eq!(checkpoint::save(&restored), checkpoint::save(&original));
+    }
+}
+
+
+#[test]
+fn ia_restore_is_cycle_identical() {
+    setup_logger();
+    for program in PROGRAMS.iter() {
+        let f = File::open(program).unwrap();
+        let insns = DisassemblyInstructionMemory::new(&f);
+        check(&insns, ia_simulator);
+    }
+}
+
+
+#[test]
+fn ca_restore_is_cycle_identical() {
+    setup_logger();
+    for program in PROGRAMS.iter() {
+        let f = File::open(program).unwrap();
+        let insns = DisassemblyInstructionMemory::new(&f);
+        check(&insns, ca_simulator);
+
+        let stats = {
+            let mut sim = ca_simulator(&insns);
+            sim.run();
+            sim.stats().clone()
+        };
+        let mut original = ca_simulator(&insns);
+        original.step_cycles(stats.cycles / 2);
+        let mut restored = ca_simulator(&insns);
+        checkpoint::restore(&mut restored, &checkpoint::save(&original))
+            .unwrap();
+        restored.run();
+        assert_eq!(*restored.stats(), stats);
+    }
+}
+
+
+#[test]
+fn files_and_mismatches() {
+    setup_logger();
+    let f = File::open(PROGRAMS[0]).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let path = env::temp_dir()
+        .join(format!("casim-checkpoint-files-{}.ckpt", process::id()));
+
+    let mut sim = ca_simulator(&insns);
+    sim.step_cycles(100);
+    checkpoint::save_file(&sim, &path).unwrap();
+    let mut restored = ca_simulator(&insns);
+    checkpoint::restore_file(&mut restored, &path).unwrap();
+    assert_eq!(restored.clock(), 100);
+
+    // A different model, predictor or memory is rejected
+    assert!(checkpoint::restore_file(&mut ia_simulator(&insns), &path)
+        .is_err());
+    let mut plain =
+        CaSimulator::new(&insns, data_memory(), RegisterFile::new(0));
+    assert!(checkpoint::restore_file(&mut plain, &path).is_err());
+    let mut small =
+        CaSimulator::new(&insns, DataMemory::new(1024), RegisterFile::new(0));
+    assert!(checkpoint::restore_file(&mut small, &path).is_err());
+
+    // So is a truncated checkpoint
+    let saved = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let mut restored = ca_simulator(&insns);
+    let truncated = &saved[..saved.len() - 1];
+    assert!(checkpoint::restore(&mut restored, truncated).is_err());
+}
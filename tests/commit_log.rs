@@ -0,0 +1,136 @@
+//! Commit logs of the disassembly test programs, compared against snapshot
+//! logs.
+//!
+//! The snapshots were recorded from the IA model with
+//! `--model ia --commit-log`, in the format of Spike's `--log-commits`, not
+//! by Spike itself. They catch unintended changes to either model's results
+//! rather than check them against an independent reference, and are
+//! recorded again when a fix changes what a program computes. The LB and LH
+//! sign extension fix did so for three loads: x8 at 0x3c4 in the first
+//! program, and x15 at 0x230 and 0x24c in the second.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator::{self, CaSimulator};
+use riscv_5stage_simulator::commit_log::{self, Commit, CommitLog};
+use riscv_5stage_simulator::hazards::{HazardPolicy, HazardUnit};
+use riscv_5stage_simulator::ia_simulator::IaSimulator;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::predictor::BranchUnit;
+use riscv_5stage_simulator::predictor::direction::{NotTaken, TwoBit};
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::{self, File};
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAMS: [(&str, &str); 2] = [
+    (
+        "tests/riscv_32i_disassembly_1.txt",
+        "tests/riscv_32i_disassembly_1_commits.log",
+    ),
+    (
+        "tests/riscv_32i_disassembly_2.txt",
+        "tests/riscv_32i_disassembly_2_commits.log",
+    ),
+];
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Reads the snapshot log at `path`.
+fn reference(path: &str) -> Vec<(usize, Commit)> {
+    let log = fs::read_to_string(path).unwrap();
+    commit_log::read_log(log.as_bytes()).unwrap()
+}
+
+
+#[test]
+fn ca_log_matches_reference() {
+    setup_logger();
+    for &(program, log) in PROGRAMS.iter() {
+        let f = File::open(program).unwrap();
+        let insns = DisassemblyInstructionMemory::new(&f);
+        let mut commit_log = CommitLog::new(&insns, Vec::new());
+        ca_simulator::run_with_observer(
+            &insns,
+            &mut DataMemory::new(1024),
+            &mut RegisterFile::new(0x0),
+            &mut BranchUnit::new(Box::new(NotTaken)),
+            &mut HazardUnit::new(HazardPolicy::Forwarding),
+            &mut commit_log,
+        );
+        let written = commit_log.into_inner().unwrap();
+
+        // The instructions ahead of HALT are still in the pipeline
+        let expected = fs::read_to_string(log).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.len() > 1000);
+        assert!(expected.starts_with(&written));
+        assert!(expected[written.len()..].lines().count() <= 2);
+    }
+}
+
+
+#[test]
+fn both_models_diff_clean() {
+    setup_logger();
+    for &(program, log) in PROGRAMS.iter() {
+        let f = File::open(program).unwrap();
+        let insns = DisassemblyInstructionMemory::new(&f);
+        let reference = reference(log);
+
+        let mem = DataMemory::new(1024);
+        let mut ia = IaSimulator::new(&insns, mem, RegisterFile::new(0));
+        let matched = commit_log::diff(&mut ia, &insns, &reference, 5);
+        assert_eq!(matched, Ok(reference.len()));
+
+        let mem = DataMemory::new(1024);
+        let mut ca = CaSimulator::new(&insns, mem, RegisterFile::new(0));
+        ca.set_branch_unit(BranchUnit::new(Box::new(TwoBit::new(16))));
+        let matched = commit_log::diff(&mut ca, &insns, &reference, 5);
+        assert!(matched.unwrap() + 2 >= reference.len());
+    }
+}
+
+
+#[test]
+fn first_mismatch_is_reported() {
+    setup_logger();
+    let (program, log) = PROGRAMS[0];
+    let f = File::open(program).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+
+    // Corrupt a register write after the first few instructions
+    let mut lines: Vec<String> = fs::read_to_string(log)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    let line = (10..lines.len())
+        .find(|&i| lines[i].contains(" x") && !lines[i].contains(" mem "))
+        .unwrap();
+    let value = lines[line].len() - "0x00000000".len();
+    lines[line].replace_range(value.., "0xdeadbeef");
+    let corrupted = lines.join("\n");
+    let reference = commit_log::read_log(corrupted.as_bytes()).unwrap();
+
+    let mem = DataMemory::new(1024);
+    let mut ca = CaSimulator::new(&insns, mem, RegisterFile::new(0));
+    let mismatch = commit_log::diff(&mut ca, &insns, &reference, 3)
+        .unwrap_err();
+    assert_eq!(mismatch.line, line + 1);
+    assert_eq!(mismatch.matched, line);
+    assert_eq!(mismatch.context.len(), 3);
+    assert_eq!(mismatch.actual.unwrap().pc, mismatch.expected.pc);
+    assert!(mismatch.to_string().contains(&lines[line - 1]));
+}
@@ -0,0 +1,122 @@
+//! The cycle accurate model must retire the same instructions with the same
+//! results as the instruction accurate one, whatever its configuration.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator::CaSimulator;
+use riscv_5stage_simulator::cosim::Lockstep;
+use riscv_5stage_simulator::hazards::{HazardPolicy, HazardUnit};
+use riscv_5stage_simulator::ia_simulator::IaSimulator;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::memory::store_buffer::{DrainPolicy, StoreBuffer};
+use riscv_5stage_simulator::predictor::{BranchResolution, BranchUnit};
+use riscv_5stage_simulator::predictor::btb::Btb;
+use riscv_5stage_simulator::predictor::direction::{Gshare, NotTaken};
+use riscv_5stage_simulator::predictor::ras::ReturnAddressStack;
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAMS: [(&str, usize); 3] = [
+    ("tests/riscv_32i_disassembly_1.txt", 0x4c0),
+    ("tests/riscv_32i_disassembly_2.txt", 0x56c),
+    ("tests/riscv_32i_sorting_disassembly.txt", 0xd8),
+];
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs `ca` in lockstep with a fresh IA model of `insns`, returning the
+/// HALT address or the report of the first divergence.
+fn lockstep<'a>(
+    insns: &'a DisassemblyInstructionMemory,
+    ca: CaSimulator<'a>,
+) -> Result<usize, String>
+{
+    let ia = IaSimulator::new(insns, DataMemory::new(8192),
+                              RegisterFile::new(0));
+
+    Lockstep::new(insns, ca, ia).run().map_err(|d| d.to_string())
+}
+
+
+#[test]
+fn default_pipeline() {
+    setup_logger();
+    for &(program, halt_addr) in PROGRAMS.iter() {
+        let f = File::open(program).unwrap();
+        let insns = DisassemblyInstructionMemory::new(&f);
+        let mem = DataMemory::new(8192);
+        let ca = CaSimulator::new(&insns, mem, RegisterFile::new(0));
+        assert_eq!(lockstep(&insns, ca), Ok(halt_addr));
+    }
+}
+
+
+#[test]
+fn configured_pipeline() {
+    setup_logger();
+    for &(program, halt_addr) in PROGRAMS.iter() {
+        let f = File::open(program).unwrap();
+        let insns = DisassemblyInstructionMemory::new(&f);
+
+        let mut mem = DataMemory::new(8192);
+        mem.set_write_latency(2);
+        let mut store_buffer = StoreBuffer::new(4, DrainPolicy::Lazy);
+        store_buffer.set_combining(true);
+        mem.set_store_buffer(Some(store_buffer));
+        let mut ca = CaSimulator::new(&insns, mem, RegisterFile::new(0));
+
+        let mut branch_unit = BranchUnit::new(Box::new(Gshare::new(64, 6)));
+        branch_unit.set_btb(Some(Btb::new(16, 2, 8)));
+        branch_unit.set_ras(Some(ReturnAddressStack::new(4)));
+        branch_unit.set_resolution(BranchResolution::Decode);
+        ca.set_branch_unit(branch_unit);
+        assert_eq!(lockstep(&insns, ca), Ok(halt_addr));
+    }
+}
+
+
+#[test]
+fn stall_only_pipeline() {
+    setup_logger();
+    let f = File::open(PROGRAMS[0].0).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut ca =
+        CaSimulator::new(&insns, DataMemory::new(8192), RegisterFile::new(0));
+    ca.set_branch_unit(BranchUnit::new(Box::new(NotTaken)));
+    ca.set_hazard_unit(HazardUnit::new(HazardPolicy::StallOnly));
+    assert_eq!(lockstep(&insns, ca), Ok(PROGRAMS[0].1));
+}
+
+
+/// Without interlocks the first dependent instruction reads a stale
+/// register, which the lockstep check catches at once.
+#[test]
+fn missing_interlock_diverges() {
+    setup_logger();
+    let f = File::open(PROGRAMS[0].0).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut ca =
+        CaSimulator::new(&insns, DataMemory::new(8192), RegisterFile::new(0));
+    ca.set_hazard_unit(HazardUnit::new(HazardPolicy::NoInterlock));
+
+    let report = lockstep(&insns, ca).unwrap_err();
+    assert!(report.contains("after 1 matching instructions:\n\
+                             \x20   CA  0x4: sw x5, 1500(x0)  \
+                             mem[0x5dc; 4] <- 0x0\n\
+                             \x20   IA  0x4: sw x5, 1500(x0)  \
+                             mem[0x5dc; 4] <- 0x5a5\n"));
+    assert!(report.contains("MEM/WB 0x8: addi x0, x0, 0\n"));
+}
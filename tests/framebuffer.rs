@@ -0,0 +1,128 @@
+//! Framebuffer frame dumps compared against golden images.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::{ca_simulator, ia_simulator};
+use riscv_5stage_simulator::devices::framebuffer::{self, Framebuffer,
+                                                   ImageFormat, PixelFormat};
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAM: &str = "tests/framebuffer_disassembly.txt";
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Returns an empty scratch directory for frame dumps.
+fn output_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir()
+        .join(format!("riscv-framebuffer-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+
+/// Reads a whole file.
+fn read_file(path: PathBuf) -> Vec<u8> {
+    let mut contents = Vec::new();
+    File::open(&path)
+        .unwrap_or_else(|_| panic!("missing {}", path.display()))
+        .read_to_end(&mut contents)
+        .unwrap();
+    contents
+}
+
+
+/// Runs the drawing program with an 8x4 RGB565 framebuffer attached.
+///
+/// Returns the register file after HALT.
+fn run(ca: bool, dir: &Path, format: ImageFormat) -> RegisterFile {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(1024);
+    let mut reg = RegisterFile::new(0x0);
+
+    let mut fb = Framebuffer::new(8, 4, PixelFormat::Rgb565);
+    fb.set_output(dir.to_path_buf(), format);
+    mem.attach(framebuffer::BASE, Box::new(fb));
+
+    let halt_addr = match ca {
+        true => ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr,
+        false => ia_simulator::run(&insns, &mut mem, &mut reg),
+    };
+    assert_eq!(halt_addr, 0x84);
+
+    reg
+}
+
+
+/// Both committed frames match their golden images.
+fn check_frames(ca: bool, format: ImageFormat, name: &str) {
+    let dir = output_dir(name);
+    let reg = run(ca, &dir, format);
+
+    assert_eq!(reg.gpr[11].read(), 8); // WIDTH
+    assert_eq!(reg.gpr[12].read(), 4); // HEIGHT
+    assert_eq!(reg.gpr[18].read(), 2); // frames committed
+
+    let extension = match format {
+        ImageFormat::Ppm => "ppm",
+        ImageFormat::Png => "png",
+    };
+    for frame in 0..2 {
+        let filename = format!("frame_{:04}.{}", frame, extension);
+        let golden =
+            format!("tests/framebuffer_golden_{}.{}", frame, extension);
+        let rendered = read_file(dir.join(&filename));
+        assert!(
+            rendered == read_file(PathBuf::from(&golden)),
+            "{} differs from {}",
+            filename,
+            golden
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn test_ia_simulator_framebuffer_ppm() {
+    check_frames(false, ImageFormat::Ppm, "ia-ppm");
+}
+
+
+#[test]
+fn test_ca_simulator_framebuffer_ppm() {
+    check_frames(true, ImageFormat::Ppm, "ca-ppm");
+}
+
+
+#[test]
+fn test_ia_simulator_framebuffer_png() {
+    check_frames(false, ImageFormat::Png, "ia-png");
+}
+
+
+#[test]
+fn test_ca_simulator_framebuffer_png() {
+    check_frames(true, ImageFormat::Png, "ca-png");
+}
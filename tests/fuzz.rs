@@ -0,0 +1,122 @@
+//! Random programs must leave both models in the same final state, whatever
+//! the configuration of the cycle accurate one.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator::CaSimulator;
+use riscv_5stage_simulator::expect::{Case, Expectations, Model};
+use riscv_5stage_simulator::fuzz::{self, Generator};
+use riscv_5stage_simulator::hazards::{HazardPolicy, HazardUnit};
+use riscv_5stage_simulator::memory::store_buffer::{DrainPolicy, StoreBuffer};
+use riscv_5stage_simulator::predictor::{BranchResolution, BranchUnit};
+use riscv_5stage_simulator::predictor::btb::Btb;
+use riscv_5stage_simulator::predictor::direction::Gshare;
+use riscv_5stage_simulator::predictor::ras::ReturnAddressStack;
+use riscv_5stage_simulator::simulator::Simulator;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAMS: u64 = 300;
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Fuzzes the CA model set up by `configure`, returning the differences
+/// of the first failing program.
+fn differences<F>(configure: F) -> Option<Vec<String>>
+where
+    F: Fn(&mut CaSimulator),
+{
+    let mut generator = Generator::new();
+    generator.set_length(100);
+
+    fuzz::fuzz(&generator, 0, PROGRAMS, configure).map(|f| f.differences)
+}
+
+
+#[test]
+fn default_pipeline() {
+    setup_logger();
+    assert_eq!(differences(|_| ()), None);
+}
+
+
+#[test]
+fn stall_only() {
+    setup_logger();
+    let configure = |ca: &mut CaSimulator| {
+        ca.set_hazard_unit(HazardUnit::new(HazardPolicy::StallOnly));
+    };
+
+    assert_eq!(differences(configure), None);
+}
+
+
+#[test]
+fn predicted_and_resolved_in_decode() {
+    setup_logger();
+    let configure = |ca: &mut CaSimulator| {
+        let mut branch_unit = BranchUnit::new(Box::new(Gshare::new(64, 6)));
+        branch_unit.set_btb(Some(Btb::new(16, 2, 8)));
+        branch_unit.set_ras(Some(ReturnAddressStack::new(4)));
+        branch_unit.set_resolution(BranchResolution::Decode);
+        ca.set_branch_unit(branch_unit);
+    };
+
+    assert_eq!(differences(configure), None);
+}
+
+
+#[test]
+fn store_buffer() {
+    setup_logger();
+    let configure = |ca: &mut CaSimulator| {
+        let mut store_buffer = StoreBuffer::new(2, DrainPolicy::Lazy);
+        store_buffer.set_combining(true);
+        ca.memory_mut().set_store_buffer(Some(store_buffer));
+        ca.memory_mut().set_write_latency(3);
+    };
+
+    assert_eq!(differences(configure), None);
+}
+
+
+/// A minimized failure is a test program expecting the IA model's state.
+#[test]
+fn missing_interlock_regression_test() {
+    setup_logger();
+    let failure = fuzz::fuzz(&Generator::new(), 0, PROGRAMS, |ca| {
+        ca.set_hazard_unit(HazardUnit::new(HazardPolicy::NoInterlock));
+    }).expect("no difference found");
+    assert!(failure.minimized.ops().len() <= 4);
+
+    let mut text = String::new();
+    for directive in failure.minimized.run_ia().expectations() {
+        text += &format!("# expect: {}\n", directive);
+    }
+    let mut program = text.into_bytes();
+    failure.minimized.write_disassembly(&mut program).unwrap();
+    let path = env::temp_dir()
+        .join(format!("casim-fuzz-minimized-{}.txt", process::id()));
+    fs::write(&path, program).unwrap();
+
+    let case = Case {
+        name: String::from("fuzz"),
+        expectations: Expectations::for_program(&path).unwrap().unwrap(),
+        path,
+    };
+    assert_eq!(case.run(Model::Ia), Ok(()));
+    assert!(case.run(Model::Ca).is_ok());
+}
@@ -0,0 +1,244 @@
+//! GDB remote serial protocol sessions driven by a scripted client.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator::CaSimulator;
+use riscv_5stage_simulator::gdb::GdbStub;
+use riscv_5stage_simulator::ia_simulator::IaSimulator;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::{
+    DisassemblyInstructionMemory,
+    InstructionMemory,
+    TestInstructionMemory,
+};
+use riscv_5stage_simulator::register::RegisterFile;
+use riscv_5stage_simulator::simulator::Simulator;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Once;
+use std::thread::{self, JoinHandle};
+
+
+static INIT: Once = Once::new();
+
+/// Fills 16 words from 0x100, then sums them from the `sum` loop at 0x64.
+const PROGRAM: &str = "tests/store_buffer_disassembly.txt";
+
+/// Loops at 0x0 forever.
+const SPIN: [u32; 4] = [
+    0x00_00_00_6f, // loop: jal x0, loop
+    0x00_00_00_13, // addi x0, x0, 0
+    0x00_00_00_13, // addi x0, x0, 0
+    0x00_00_00_13, // addi x0, x0, 0
+];
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// A scripted GDB client.
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+
+impl Client {
+    /// Sends raw bytes and returns the acknowledgement.
+    fn send_raw(&mut self, data: &str) -> u8 {
+        self.stream.write_all(data.as_bytes()).unwrap();
+        let mut ack = [0];
+        self.reader.read_exact(&mut ack).unwrap();
+
+        ack[0]
+    }
+
+    /// Sends a packet and returns the reply.
+    fn request(&mut self, packet: &str) -> String {
+        let sum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let ack = self.send_raw(&format!("${}#{:02x}", packet, sum));
+        assert_eq!(ack, b'+');
+
+        self.reply()
+    }
+
+    /// Waits for a reply packet and acknowledges it.
+    fn reply(&mut self) -> String {
+        let mut reply = Vec::new();
+        self.reader.read_until(b'$', &mut reply).unwrap();
+        reply.clear();
+        self.reader.read_until(b'#', &mut reply).unwrap();
+        reply.pop();
+        let mut sum = [0; 2];
+        self.reader.read_exact(&mut sum).unwrap();
+        self.stream.write_all(b"+").unwrap();
+
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+
+/// Serves one GDB connection for the program, or `words` if given, on the
+/// IA or CA simulator.
+///
+/// Returns a connected client and the server thread, which returns x13 at
+/// the end of the session.
+fn connect(ia: bool, words: Option<&[u32]>) -> (Client, JoinHandle<u32>) {
+    setup_logger();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let words = words.map(|words| words.to_vec());
+
+    let server = thread::spawn(move || {
+        let insns: Box<dyn InstructionMemory> = match words {
+            Some(words) => Box::new(TestInstructionMemory::new(words)),
+            None => {
+                let f = File::open(PROGRAM).unwrap();
+                Box::new(DisassemblyInstructionMemory::new(&f))
+            }
+        };
+        let insns = &*insns;
+        let mem = DataMemory::new(2048);
+        let reg = RegisterFile::new(0x0);
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let input = BufReader::new(stream.try_clone().unwrap());
+        let interrupt = Box::new(stream.try_clone().unwrap());
+
+        if ia {
+            let sim = IaSimulator::new(insns, mem, reg);
+            let mut stub = GdbStub::new(sim, insns, input, stream);
+            stub.set_interrupt(Some(interrupt));
+            stub.run().unwrap();
+            stub.simulator().registers().gpr[13].read()
+        } else {
+            let sim = CaSimulator::new(insns, mem, reg);
+            let mut stub = GdbStub::new(sim, insns, input, stream);
+            stub.set_interrupt(Some(interrupt));
+            stub.run().unwrap();
+            stub.simulator().registers().gpr[13].read()
+        }
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+
+    (Client { stream, reader }, server)
+}
+
+
+/// Runs a debugging session through breakpoints and watchpoints.
+fn session(ia: bool) {
+    let (mut gdb, server) = connect(ia, None);
+
+    assert!(gdb.request("qSupported:swbreak+;hwbreak+")
+        .contains("qXfer:features:read+"));
+    let xml = gdb.request("qXfer:features:read:target.xml:0,1000");
+    assert!(xml.starts_with("l<?xml"));
+    assert!(xml.contains("<architecture>riscv:rv32</architecture>"));
+    assert!(xml.contains("<reg name=\"pc\" bitsize=\"32\""));
+    assert_eq!(gdb.request("?"), "S05");
+
+    // Registers are little endian, x0 to x31 then the PC
+    let regs = gdb.request("g");
+    assert_eq!(regs.len(), 33 * 8);
+    assert_eq!(&regs[32 * 8..], "00000000");
+    assert_eq!(gdb.request("P5=78563412"), "OK");
+    assert_eq!(gdb.request("p5"), "78563412");
+
+    // Software breakpoint on fill, then a single step
+    assert_eq!(gdb.request("Z0,10,4"), "OK");
+    assert_eq!(gdb.request("c"), "T05swbreak:;");
+    assert_eq!(gdb.request("p20"), "10000000");
+    assert_eq!(gdb.request("pa"), "00010000");
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.request("p20"), "14000000");
+    assert_eq!(gdb.request("z0,10,4"), "OK");
+
+    // The first store writes 0 to 0x100, the first load reads 0x104
+    assert_eq!(gdb.request("Z2,100,4"), "OK");
+    assert_eq!(gdb.request("c"), "T05watch:100;");
+    assert_eq!(gdb.request("m100,4"), "00000000");
+    assert_eq!(gdb.request("z2,100,4"), "OK");
+    assert_eq!(gdb.request("Z3,104,4"), "OK");
+    assert_eq!(gdb.request("c"), "T05rwatch:104;");
+    assert_eq!(gdb.request("z3,104,4"), "OK");
+
+    // Hardware breakpoint on sum, after the fill loop
+    assert_eq!(gdb.request("Z1,64,4"), "OK");
+    assert_eq!(gdb.request("c"), "T05hwbreak:;");
+    assert_eq!(gdb.request("p20"), "64000000");
+    assert_eq!(gdb.request("z1,64,4"), "OK");
+
+    // Data memory is writable, instruction memory is not
+    assert_eq!(gdb.request("M100,4:efbeadde"), "OK");
+    assert_eq!(gdb.request("m100,4"), "efbeadde");
+    assert_eq!(gdb.request("m0,4"), "13050010"); // addi x10, x0, 256
+    assert_eq!(gdb.request("M0,4:00000000"), "E01");
+
+    // Data memory ends at 0x2000
+    assert_eq!(gdb.request("m10000,4"), "E01");
+    assert_eq!(gdb.request("m1ffe,4"), "0000");
+    assert_eq!(gdb.request("M10000,4:00000000"), "E01");
+    assert_eq!(gdb.request("M1ffe,4:00000000"), "E01");
+
+    // Corrupt packets are rejected
+    assert_eq!(gdb.send_raw("$g#00"), b'-');
+
+    assert_eq!(gdb.request("c"), "W00");
+    gdb.request("D");
+
+    // The fill loop sums 0 to 15
+    assert_eq!(server.join().unwrap(), 120);
+}
+
+
+#[test]
+fn ia_session() {
+    session(true);
+}
+
+
+#[test]
+fn ca_session() {
+    session(false);
+}
+
+
+/// Interrupts a program that never stops by itself.
+fn interrupt(ia: bool) {
+    let (mut gdb, server) = connect(ia, Some(&SPIN));
+
+    assert_eq!(gdb.send_raw("$c#63"), b'+');
+    gdb.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb.reply(), "S02");
+    assert_eq!(gdb.request("p20"), "00000000");
+
+    // The program can be continued again
+    assert_eq!(gdb.send_raw("$c#63"), b'+');
+    gdb.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb.reply(), "S02");
+    gdb.request("D");
+
+    server.join().unwrap();
+}
+
+
+#[test]
+fn ia_interrupt() {
+    interrupt(true);
+}
+
+
+#[test]
+fn ca_interrupt() {
+    interrupt(false);
+}
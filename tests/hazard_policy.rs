@@ -0,0 +1,103 @@
+//! Hazard policies compared on the disassembly test programs.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator;
+use riscv_5stage_simulator::hazards::{HazardPolicy, HazardStats, HazardUnit};
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::predictor::{BranchResolution, BranchUnit};
+use riscv_5stage_simulator::predictor::direction::NotTaken;
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAMS: [(&str, usize, usize); 3] = [
+    ("tests/riscv_32i_disassembly_1.txt", 1024, 0x4c0),
+    ("tests/riscv_32i_disassembly_2.txt", 1024, 0x56c),
+    ("tests/riscv_32i_sorting_disassembly.txt", 8192, 0xd8),
+];
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs a program with `policy`, resolving branches in `stage`.
+///
+/// Returns the HALT address, and the registers, data memory and hazard
+/// statistics after HALT.
+fn run(
+    program: (&str, usize, usize),
+    policy: HazardPolicy,
+    stage: BranchResolution,
+) -> (usize, RegisterFile, DataMemory, HazardStats) {
+    setup_logger();
+    let (filename, nwords, _) = program;
+    let f = File::open(filename).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(nwords);
+    let mut reg = RegisterFile::new(0x0);
+    let mut branch_unit = BranchUnit::new(Box::new(NotTaken));
+    let mut hazard_unit = HazardUnit::new(policy);
+    branch_unit.set_resolution(stage);
+
+    let halt_addr = ca_simulator::run_with_units(
+        &insns,
+        &mut mem,
+        &mut reg,
+        &mut branch_unit,
+        &mut hazard_unit,
+    ).halt_addr;
+
+    (halt_addr, reg, mem, hazard_unit.stats)
+}
+
+
+/// Stalling instead of forwarding leaves the same architectural state.
+#[test]
+fn test_ca_simulator_stall_only_preserves_results() {
+    let stages = [BranchResolution::Memory, BranchResolution::Decode];
+
+    for program in PROGRAMS.iter() {
+        for &stage in stages.iter() {
+            let (_, expected_reg, expected_mem, forwarding) =
+                run(*program, HazardPolicy::Forwarding, stage);
+            let (halt_addr, reg, mem, stall_only) =
+                run(*program, HazardPolicy::StallOnly, stage);
+
+            assert_eq!(halt_addr, program.2);
+            for i in 0..32 {
+                assert_eq!(reg.gpr[i].read(), expected_reg.gpr[i].read());
+            }
+            for addr in (0..program.1 * 4).step_by(4) {
+                assert_eq!(mem.read(addr, 4), expected_mem.read(addr, 4));
+            }
+            assert!(stall_only.stall_cycles > forwarding.stall_cycles);
+            assert_eq!(stall_only.violations, 0);
+        }
+    }
+}
+
+
+/// Programs scheduled for forwarding read stale registers without
+/// interlocks.
+#[test]
+fn test_ca_simulator_no_interlock_reports_violations() {
+    let program = PROGRAMS[0];
+    let (halt_addr, _, _, stats) =
+        run(program, HazardPolicy::NoInterlock, BranchResolution::Memory);
+
+    assert!(halt_addr != program.2);
+
+    assert_eq!(stats.stall_cycles, 0);
+    assert!(stats.violations > 0);
+}
@@ -0,0 +1,124 @@
+//! Misaligned loads and stores under each misaligned access policy.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::{ca_simulator, ia_simulator};
+use riscv_5stage_simulator::consts;
+use riscv_5stage_simulator::memory::data::{DataMemory, MisalignedPolicy};
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAM: &str = "tests/misaligned_disassembly.txt";
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs the misaligned access program on the IA simulator.
+fn run_ia(policy: MisalignedPolicy) -> (usize, DataMemory, RegisterFile) {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(2048);
+    mem.set_misaligned_policy(policy);
+    let mut reg = RegisterFile::new(0x0);
+    let halt_addr = ia_simulator::run(&insns, &mut mem, &mut reg);
+
+    (halt_addr, mem, reg)
+}
+
+
+/// Runs the misaligned access program on the CA simulator.
+fn run_ca(policy: MisalignedPolicy) -> (usize, DataMemory, RegisterFile) {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(2048);
+    mem.set_misaligned_policy(policy);
+    let mut reg = RegisterFile::new(0x0);
+    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr;
+
+    (halt_addr, mem, reg)
+}
+
+
+/// Every misaligned access traps, and the handler skips it.
+fn check_trap(result: (usize, DataMemory, RegisterFile)) {
+    let (halt_addr, mem, reg) = result;
+
+    assert_eq!(halt_addr, 0x44);
+    assert_eq!(reg.gpr[20].read(), 6); // six misaligned accesses
+    assert_eq!(reg.gpr[21].read(), 4); // last was a load
+    assert_eq!(reg.gpr[6].read(), 0);
+    assert_eq!(reg.gpr[7].read(), 0);
+    assert_eq!(reg.gpr[9].read(), 0);
+    assert_eq!(reg.gpr[10].read(), 0);
+    assert_eq!(reg.gpr[11].read(), 0);
+    assert_eq!(mem.read(0x100, consts::WORD_SIZE), Ok(0));
+    assert_eq!(mem.read(0xffc, consts::WORD_SIZE), Ok(0));
+}
+
+
+/// Misaligned accesses complete, including across word and page boundaries.
+fn check_complete(result: (usize, DataMemory, RegisterFile)) {
+    let (halt_addr, mem, reg) = result;
+
+    assert_eq!(halt_addr, 0x44);
+    assert_eq!(reg.gpr[20].read(), 0); // no traps
+    assert_eq!(reg.gpr[6].read(), 0x11223344); // across a word
+    assert_eq!(reg.gpr[7].read(), 0x2233);
+    assert_eq!(reg.gpr[12].read(), 0x33440000);
+    assert_eq!(reg.gpr[9].read(), 0x11223344); // across a page
+    assert_eq!(reg.gpr[10].read(), 0x2233);
+    assert_eq!(reg.gpr[11].read(), 0x1122);
+    assert_eq!(mem.read(0x104, consts::WORD_SIZE), Ok(0x1122));
+    assert_eq!(mem.read(0xffc, consts::WORD_SIZE), Ok(0x33440000));
+    assert_eq!(mem.read(0x1000, consts::WORD_SIZE), Ok(0x1122));
+}
+
+
+#[test]
+fn test_ia_simulator_misaligned_trap() {
+    check_trap(run_ia(MisalignedPolicy::Trap));
+}
+
+
+#[test]
+fn test_ca_simulator_misaligned_trap() {
+    check_trap(run_ca(MisalignedPolicy::Trap));
+}
+
+
+#[test]
+fn test_ia_simulator_misaligned_split() {
+    check_complete(run_ia(MisalignedPolicy::Split));
+}
+
+
+#[test]
+fn test_ca_simulator_misaligned_split() {
+    check_complete(run_ca(MisalignedPolicy::Split));
+}
+
+
+#[test]
+fn test_ia_simulator_misaligned_emulate() {
+    check_complete(run_ia(MisalignedPolicy::Emulate(10)));
+}
+
+
+#[test]
+fn test_ca_simulator_misaligned_emulate() {
+    check_complete(run_ca(MisalignedPolicy::Emulate(10)));
+}
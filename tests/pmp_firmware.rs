@@ -0,0 +1,160 @@
+//! Firmware scenarios exercising locked physical memory protection regions.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::{ca_simulator, ia_simulator};
+use riscv_5stage_simulator::consts;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs a firmware image on the IA simulator.
+fn run_ia(filename: &str) -> (usize, DataMemory, RegisterFile) {
+    setup_logger();
+    let f = File::open(filename).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(1024);
+    let mut reg = RegisterFile::new(0x0);
+    let halt_addr = ia_simulator::run(&insns, &mut mem, &mut reg);
+
+    (halt_addr, mem, reg)
+}
+
+
+/// Runs a firmware image on the CA simulator.
+fn run_ca(filename: &str) -> (usize, DataMemory, RegisterFile) {
+    setup_logger();
+    let f = File::open(filename).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(1024);
+    let mut reg = RegisterFile::new(0x0);
+    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr;
+
+    (halt_addr, mem, reg)
+}
+
+
+/// A store into a locked read-only NAPOT region traps to the handler.
+fn check_locked_store(result: (usize, DataMemory, RegisterFile)) {
+    let (halt_addr, mem, reg) = result;
+
+    assert_eq!(halt_addr, 0x98); // handler's HALT
+    assert_eq!(reg.gpr[10].read(), 7); // store access fault
+    assert_eq!(reg.gpr[11].read(), 0x404); // faulting address
+    assert_eq!(reg.gpr[12].read(), 0x24); // faulting store
+    assert_eq!(reg.gpr[8].read(), 0); // instruction after store squashed
+    assert_eq!(mem.read(0x300, consts::WORD_SIZE), Ok(0x123));
+    assert_eq!(mem.read(0x404, consts::WORD_SIZE), Ok(0));
+}
+
+
+#[test]
+fn test_ia_simulator_pmp_locked_store() {
+    check_locked_store(run_ia("tests/pmp_locked_store_disassembly.txt"));
+}
+
+
+#[test]
+fn test_ca_simulator_pmp_locked_store() {
+    check_locked_store(run_ca("tests/pmp_locked_store_disassembly.txt"));
+}
+
+
+/// A load from a locked write-only NA4 word takes priority over the
+/// enclosing read-write NAPOT region.
+fn check_locked_load(result: (usize, DataMemory, RegisterFile)) {
+    let (halt_addr, mem, reg) = result;
+
+    assert_eq!(halt_addr, 0x98);
+    assert_eq!(reg.gpr[10].read(), 5); // load access fault
+    assert_eq!(reg.gpr[11].read(), 0x500);
+    assert_eq!(reg.gpr[12].read(), 0x34);
+    assert_eq!(reg.gpr[7].read(), 0x66);
+    assert_eq!(reg.gpr[8].read(), 0);
+    assert_eq!(reg.gpr[9].read(), 0);
+    assert_eq!(mem.read(0x500, consts::WORD_SIZE), Ok(0x66));
+}
+
+
+#[test]
+fn test_ia_simulator_pmp_locked_load() {
+    check_locked_load(run_ia("tests/pmp_locked_load_disassembly.txt"));
+}
+
+
+#[test]
+fn test_ca_simulator_pmp_locked_load() {
+    check_locked_load(run_ca("tests/pmp_locked_load_disassembly.txt"));
+}
+
+
+/// Jumping into a locked region without execute permission faults on fetch,
+/// while an unlocked region with no permissions doesn't restrict M-mode.
+fn check_locked_execute(result: (usize, DataMemory, RegisterFile)) {
+    let (halt_addr, mem, reg) = result;
+
+    assert_eq!(halt_addr, 0x98);
+    assert_eq!(reg.gpr[10].read(), 1); // instruction access fault
+    assert_eq!(reg.gpr[11].read(), 0x100);
+    assert_eq!(reg.gpr[12].read(), 0x100);
+    assert_eq!(reg.gpr[9].read(), 0); // protected code never ran
+    assert_eq!(reg.gpr[7].read(), 7);
+    assert_eq!(mem.read(0x200, consts::WORD_SIZE), Ok(7));
+}
+
+
+#[test]
+fn test_ia_simulator_pmp_locked_execute() {
+    check_locked_execute(run_ia("tests/pmp_locked_execute_disassembly.txt"));
+}
+
+
+#[test]
+fn test_ca_simulator_pmp_locked_execute() {
+    check_locked_execute(run_ca("tests/pmp_locked_execute_disassembly.txt"));
+}
+
+
+/// Firmware can't loosen a locked entry, and a handler can skip faulting
+/// stores and resume with MRET.
+fn check_lock_reconfigure(result: (usize, DataMemory, RegisterFile)) {
+    let (halt_addr, mem, reg) = result;
+
+    assert_eq!(halt_addr, 0x4c);
+    assert_eq!(reg.gpr[13].read(), 0x99); // pmpcfg0 unchanged
+    assert_eq!(reg.gpr[14].read(), 0x11f); // pmpaddr0 unchanged
+    assert_eq!(reg.gpr[15].read(), 42); // resumed after first fault
+    assert_eq!(reg.gpr[20].read(), 2); // two faulting stores
+    assert_eq!(mem.read(0x400, consts::WORD_SIZE), Ok(0));
+    assert_eq!(mem.read(0x4fc, consts::WORD_SIZE), Ok(0));
+    assert_eq!(mem.read(0x500, consts::WORD_SIZE), Ok(0x55));
+}
+
+
+#[test]
+fn test_ia_simulator_pmp_lock_reconfigure() {
+    let filename = "tests/pmp_lock_reconfigure_disassembly.txt";
+    check_lock_reconfigure(run_ia(filename));
+}
+
+
+#[test]
+fn test_ca_simulator_pmp_lock_reconfigure() {
+    let filename = "tests/pmp_lock_reconfigure_disassembly.txt";
+    check_lock_reconfigure(run_ca(filename));
+}
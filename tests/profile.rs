@@ -0,0 +1,126 @@
+//! Profiles of the sorting test program on both models.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator;
+use riscv_5stage_simulator::hazards::{HazardPolicy, HazardUnit};
+use riscv_5stage_simulator::ia_simulator::IaSimulator;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::predictor::BranchUnit;
+use riscv_5stage_simulator::predictor::direction::NotTaken;
+use riscv_5stage_simulator::profile::Profile;
+use riscv_5stage_simulator::register::RegisterFile;
+use riscv_5stage_simulator::simulator::Simulator;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAM: &str = "tests/riscv_32i_sorting_disassembly.txt";
+const MEMORY_WORDS: usize = 8192;
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// The profile accounts for the cycles, stalls and retirements in the run
+/// statistics, and finds the sorting loops.
+#[test]
+fn ca_profile_matches_stats() {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut profile = Profile::new(&insns);
+    profile.set_symbols(insns.symbols());
+
+    let stats = ca_simulator::run_with_observer(
+        &insns,
+        &mut DataMemory::new(MEMORY_WORDS),
+        &mut RegisterFile::new(0x0),
+        &mut BranchUnit::new(Box::new(NotTaken)),
+        &mut HazardUnit::new(HazardPolicy::StallOnly),
+        &mut profile,
+    );
+
+    let total = profile.total();
+    assert_eq!(profile.cycles(), stats.cycles);
+    assert_eq!(total.cycles + profile.unretired_cycles(), stats.cycles);
+    assert_eq!(total.retired, stats.retired);
+    assert_eq!(
+        total.stall_cycles,
+        stats.data_hazard_stall_cycles + stats.memory_stall_cycles
+    );
+    // Stall bubbles flushed don't count
+    assert!(total.flush_cycles <= stats.branch_flush_cycles);
+    assert!(total.flush_cycles > 0);
+
+    let symbols = profile.symbol_stats();
+    let cycles: u64 = symbols.iter().map(|s| s.counts.cycles).sum();
+    assert_eq!(cycles, total.cycles);
+
+    // The bubble sort's loops are hottest, the inner one nested in the
+    // outer
+    let loops = profile.loops();
+    let (outer, inner) = (loops[0], loops[1]);
+    assert!(outer.counts.cycles * 2 > stats.cycles);
+    assert!(outer.head < inner.head && inner.tail <= outer.tail);
+    assert!(inner.iterations > 100 * outer.iterations);
+    assert!(outer.counts.stall_cycles >= inner.counts.stall_cycles);
+
+    let blocks = profile.blocks();
+    let retired: u64 = blocks.iter().map(|b| b.counts.retired).sum();
+    assert_eq!(retired, total.retired);
+    for block in blocks.iter() {
+        for (_, stats) in profile.pcs().range(block.start..=block.end) {
+            assert_eq!(stats.counts.retired, block.executions);
+        }
+    }
+
+    let report = profile.to_string();
+    assert!(report.contains("bubble_sort:"));
+    assert!(report.contains("Hot loops:"));
+}
+
+
+/// Instructions retire the same number of times on both models, except
+/// those the CA model leaves unfinished at HALT.
+#[test]
+fn models_agree_on_counts() {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+
+    let mut ia_profile = Profile::new(&insns);
+    {
+        let mem = DataMemory::new(MEMORY_WORDS);
+        let mut ia = IaSimulator::new(&insns, mem, RegisterFile::new(0x0));
+        ia.set_observer(&mut ia_profile);
+        ia.run();
+    }
+    let mut ca_profile = Profile::new(&insns);
+    ca_simulator::run_with_observer(
+        &insns,
+        &mut DataMemory::new(MEMORY_WORDS),
+        &mut RegisterFile::new(0x0),
+        &mut BranchUnit::new(Box::new(NotTaken)),
+        &mut HazardUnit::new(HazardPolicy::Forwarding),
+        &mut ca_profile,
+    );
+
+    let mut unfinished = 0;
+    for (pc, ia) in ia_profile.pcs().iter() {
+        assert_eq!(ia.counts.cycles, ia.counts.retired);
+        let ca = ca_profile.pcs().get(pc).map_or(0, |s| s.counts.retired);
+        unfinished += ia.counts.retired - ca;
+    }
+    assert!(unfinished <= 2);
+    assert!(ca_profile.total().cpi() > ia_profile.total().cpi());
+}
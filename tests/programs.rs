@@ -0,0 +1,92 @@
+//! Runs every program under `tests` that has expectations, on each model.
+//!
+//! A program gets expectations from a sidecar `.expect` file or from
+//! `# expect:` lines (see `expect`), so adding a regression test needs no
+//! Rust code. Each program and model is a test named `PROGRAM::MODEL`.
+//! Like the built-in harness, arguments filter tests by name and `--list`
+//! lists them.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::expect::{self, Case, Model};
+
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+
+
+const DIR: &str = "tests";
+
+
+/// Runs `case` on `model`, turning a panic into a failure.
+fn run(case: &Case, model: Model) -> Result<(), Vec<String>> {
+    match panic::catch_unwind(AssertUnwindSafe(|| case.run(model))) {
+        Ok(result) => result,
+        Err(_) => Err(vec![String::from("panicked")]),
+    }
+}
+
+
+fn main() {
+    env_logger::init().unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let list = args.iter().any(|arg| arg == "--list");
+    let filters: Vec<&String> =
+        args.iter().filter(|arg| !arg.starts_with('-')).collect();
+
+    let cases = expect::discover(Path::new(DIR))
+        .unwrap_or_else(|e| panic!("error finding programs: {}", e));
+    let tests: Vec<(String, &Case, Model)> = cases
+        .iter()
+        .flat_map(|case| {
+            expect::MODELS.iter().map(move |&model| {
+                (format!("{}::{}", case.name, model), case, model)
+            })
+        })
+        .filter(|(name, _, _)| {
+            filters.is_empty() || filters.iter().any(|f| name.contains(*f))
+        })
+        .collect();
+
+    if list {
+        for (name, _, _) in &tests {
+            println!("{}: test", name);
+        }
+        return;
+    }
+
+    println!("\nrunning {} tests", tests.len());
+    let mut failures = Vec::new();
+    for &(ref name, case, model) in &tests {
+        match run(case, model) {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(errors) => {
+                println!("test {} ... FAILED", name);
+                failures.push((name, errors));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for &(name, ref errors) in &failures {
+            println!("    {}:", name);
+            for error in errors {
+                println!("        {}", error);
+            }
+        }
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed\n",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        tests.len() - failures.len(),
+        failures.len()
+    );
+
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+}
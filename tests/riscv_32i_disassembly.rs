@@ -10,10 +10,10 @@ use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
 use riscv_5stage_simulator::register::RegisterFile;
 
 use std::fs::File;
-use std::sync::{Once, ONCE_INIT};
+use std::sync::Once;
 
 
-static INIT: Once = ONCE_INIT;
+static INIT: Once = Once::new();
 
 
 /// Sets up logging subsystem once even if called multiple times
@@ -79,7 +79,7 @@ fn test_ca_simulator_riscv_32i_disassembly_1() {
     let insns = DisassemblyInstructionMemory::new(&f);
     let mut mem = DataMemory::new(1024);
     let mut reg = RegisterFile::new(0x0);
-    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg);
+    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr;
     let expected_halt_addr = 0x4c0;
 
     assert_eq!(halt_addr, expected_halt_addr);
@@ -95,7 +95,7 @@ fn test_ca_simulator_riscv_32i_disassembly_2() {
     let insns = DisassemblyInstructionMemory::new(&f);
     let mut mem = DataMemory::new(1024);
     let mut reg = RegisterFile::new(0x0);
-    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg);
+    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr;
     let expected_halt_addr = 0x56c;
 
     assert_eq!(halt_addr, expected_halt_addr);
@@ -111,7 +111,7 @@ fn test_ca_simulator_riscv_32i_sorting_disassembly() {
     let insns = DisassemblyInstructionMemory::new(&f);
     let mut mem = DataMemory::new(8192);
     let mut reg = RegisterFile::new(0x0);
-    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg);
+    let halt_addr = ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr;
     let expected_halt_addr = 0xd8;
 
     assert_eq!(halt_addr, expected_halt_addr);
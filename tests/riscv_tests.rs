@@ -0,0 +1,123 @@
+//! Programs following the riscv-tests and riscv-arch-test conventions.
+//!
+//! The programs in `tests/riscv_tests` are built from the sources alongside
+//! with `make`. They report through `tohost` like the official suites, but
+//! are hand-written stand-ins for a few of their tests, not the upstream
+//! programs.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator::CaSimulator;
+use riscv_5stage_simulator::compliance::{self, Outcome, TestProgram};
+use riscv_5stage_simulator::hazards::{HazardPolicy, HazardUnit};
+use riscv_5stage_simulator::ia_simulator::IaSimulator;
+use riscv_5stage_simulator::memory::store_buffer::{DrainPolicy, StoreBuffer};
+use riscv_5stage_simulator::predictor::{BranchResolution, BranchUnit};
+use riscv_5stage_simulator::predictor::direction::TwoBit;
+use riscv_5stage_simulator::simulator::Simulator;
+
+use std::fs;
+use std::path::Path;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const DIR: &str = "tests/riscv_tests";
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+#[test]
+fn suite() {
+    setup_logger();
+    let matrix =
+        compliance::run_suite(Path::new(DIR), 10_000, |_| ()).unwrap();
+    let names: Vec<&str> =
+        matrix.rows.iter().map(|row| row.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "local-rv32ui-add",
+            "local-rv32ui-beq",
+            "local-rv32ui-fence_i",
+            "local-rv32ui-jal",
+            "local-rv32ui-lw",
+            "local-rv32ui-simple",
+            "local-rv32ui-sw",
+        ]
+    );
+
+    for row in &matrix.rows {
+        let expected = match compliance::expected_failure(&row.name) {
+            Some(_) => Outcome::Fail(2),
+            None => Outcome::Pass,
+        };
+        assert_eq!((row.ia, row.ca), (expected, expected), "{}", row.name);
+    }
+    assert_eq!(matrix.passed(), (6, 6));
+    assert!(!matrix.all_passed());
+}
+
+
+#[test]
+fn configured_pipeline() {
+    setup_logger();
+    let matrix = compliance::run_suite(Path::new(DIR), 10_000, |ca| {
+        let mut branch_unit = BranchUnit::new(Box::new(TwoBit::new(16)));
+        branch_unit.set_resolution(BranchResolution::Decode);
+        ca.set_branch_unit(branch_unit);
+        ca.set_hazard_unit(HazardUnit::new(HazardPolicy::StallOnly));
+        let store_buffer = StoreBuffer::new(4, DrainPolicy::Lazy);
+        ca.memory_mut().set_store_buffer(Some(store_buffer));
+    }).unwrap();
+
+    assert_eq!(matrix.passed(), (6, 6));
+}
+
+
+#[test]
+fn missing_interlock_fails() {
+    setup_logger();
+    let matrix = compliance::run_suite(Path::new(DIR), 10_000, |ca| {
+        ca.set_hazard_unit(HazardUnit::new(HazardPolicy::NoInterlock));
+    }).unwrap();
+
+    let add = &matrix.rows[0];
+    assert_eq!(add.ia, Outcome::Pass);
+    assert!(add.ca != Outcome::Pass);
+}
+
+
+#[test]
+fn signature() {
+    setup_logger();
+    let program = TestProgram::open(&Path::new(DIR).join("add-01.elf"))
+        .unwrap();
+    let reference =
+        fs::read_to_string(Path::new(DIR).join("add-01.reference_output"))
+            .unwrap();
+    let insns = program.instructions();
+
+    let mut ia =
+        IaSimulator::new(insns, program.data_memory(), program.registers());
+    assert_eq!(program.run(&mut ia, 1000), Outcome::Pass);
+    let mut ca =
+        CaSimulator::new(insns, program.data_memory(), program.registers());
+    let store_buffer = StoreBuffer::new(8, DrainPolicy::Lazy);
+    ca.memory_mut().set_store_buffer(Some(store_buffer));
+    assert_eq!(program.run(&mut ca, 1000), Outcome::Pass);
+
+    for memory in [ia.memory_mut(), ca.memory_mut()].iter_mut() {
+        let words = program.signature(memory).unwrap();
+        let mut text = Vec::new();
+        compliance::write_signature(&mut text, &words).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(), reference);
+    }
+}
@@ -0,0 +1,116 @@
+//! Run statistics of the disassembly test programs.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::ca_simulator;
+use riscv_5stage_simulator::instruction::Function;
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::register::RegisterFile;
+use riscv_5stage_simulator::stats::SimStats;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAMS: [(&str, usize, usize); 3] = [
+    ("tests/riscv_32i_disassembly_1.txt", 1024, 0x4c0),
+    ("tests/riscv_32i_disassembly_2.txt", 1024, 0x56c),
+    ("tests/riscv_32i_sorting_disassembly.txt", 8192, 0xd8),
+];
+
+/// Cycles before the first instruction retires, plus the cycle HALT is
+/// detected in while the NOPs ahead of it are still in MEM and WB.
+const FILL_CYCLES: u64 = 5;
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs a program and returns its statistics.
+fn run(program: (&str, usize, usize)) -> SimStats {
+    setup_logger();
+    let (filename, nwords, expected_halt_addr) = program;
+    let f = File::open(filename).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(nwords);
+    let mut reg = RegisterFile::new(0x0);
+
+    let stats = ca_simulator::run(&insns, &mut mem, &mut reg);
+    assert_eq!(stats.halt_addr, expected_halt_addr);
+
+    stats
+}
+
+
+/// Every cycle either retires an instruction or is accounted for.
+///
+/// A stall bubble flushed by a mispredicted branch counts as both a stall
+/// and a flush cycle.
+#[test]
+fn test_ca_simulator_cycles_accounted_for() {
+    for program in PROGRAMS.iter() {
+        let stats = run(*program);
+        let accounted = stats.retired + stats.load_use_stall_cycles +
+            stats.branch_flush_cycles + FILL_CYCLES;
+
+        assert!(stats.cycles <= accounted);
+        assert!(stats.cycles + stats.load_use_stall_cycles >= accounted);
+        assert_eq!(stats.mix.values().sum::<u64>(), stats.retired);
+        assert!(stats.cpi() > 1.0);
+    }
+}
+
+
+/// The exact counts of both short programs.
+///
+/// One of the first program's load-use bubbles is flushed by a mispredicted
+/// branch, so it is counted as a stall and a flush cycle.
+#[test]
+fn test_ca_simulator_exact_stats() {
+    let stats = run(PROGRAMS[0]);
+    assert_eq!(stats.cycles, 296);
+    assert_eq!(stats.retired, 185);
+    assert_eq!(stats.load_use_stall_cycles, 2);
+    assert_eq!(stats.data_hazard_stall_cycles, 0);
+    assert_eq!(stats.memory_stall_cycles, 0);
+    assert_eq!(stats.branch_flush_cycles, 105);
+    assert_eq!(stats.cycles + 1, stats.retired + 2 + 105 + FILL_CYCLES);
+
+    let stats = run(PROGRAMS[1]);
+    assert_eq!(stats.cycles, 284);
+    assert_eq!(stats.retired, 200);
+    assert_eq!(stats.load_use_stall_cycles, 4);
+    assert_eq!(stats.data_hazard_stall_cycles, 0);
+    assert_eq!(stats.memory_stall_cycles, 0);
+    assert_eq!(stats.branch_flush_cycles, 75);
+    assert_eq!(stats.cycles, stats.retired + 4 + 75 + FILL_CYCLES);
+}
+
+
+/// The sorting program's instruction mix and forwarding.
+#[test]
+fn test_ca_simulator_sorting_stats() {
+    let stats = run(PROGRAMS[2]);
+
+    let mix = stats.instruction_mix();
+    assert_eq!(mix[0].0, Function::Addi);
+    assert_eq!(stats.mix[&Function::Jal], 94);
+    assert_eq!(stats.mix[&Function::Jalr], 90);
+
+    assert_eq!(stats.cycles, 672115);
+    assert_eq!(stats.retired, 475669);
+    assert_eq!(stats.load_use_stall_cycles, 1);
+    assert_eq!(stats.branch_flush_cycles, 196440);
+
+    assert_eq!(stats.forwards.ex_mem, 54082);
+    assert_eq!(stats.forwards.mem_wb, 51819);
+    assert_eq!(stats.forwards.register_file, 54415);
+}
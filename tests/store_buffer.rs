@@ -0,0 +1,138 @@
+//! Store buffer forwarding, combining and draining in the MEM stage.
+
+
+extern crate env_logger;
+extern crate riscv_5stage_simulator;
+
+use riscv_5stage_simulator::{ca_simulator, ia_simulator};
+use riscv_5stage_simulator::memory::data::DataMemory;
+use riscv_5stage_simulator::memory::instruction::DisassemblyInstructionMemory;
+use riscv_5stage_simulator::memory::store_buffer::{DrainPolicy, StoreBuffer,
+                                                    StoreBufferStats};
+use riscv_5stage_simulator::register::RegisterFile;
+
+use std::fs::File;
+use std::sync::Once;
+
+
+static INIT: Once = Once::new();
+
+const PROGRAM: &str = "tests/store_buffer_disassembly.txt";
+
+
+/// Sets up logging subsystem once even if called multiple times
+fn setup_logger() {
+    INIT.call_once(|| { env_logger::init().unwrap(); });
+}
+
+
+/// Runs the program, optionally through a store buffer.
+///
+/// Returns the registers and data memory after HALT.
+fn run(
+    ca: bool,
+    write_latency: u32,
+    store_buffer: Option<StoreBuffer>,
+) -> (RegisterFile, DataMemory) {
+    setup_logger();
+    let f = File::open(PROGRAM).unwrap();
+    let insns = DisassemblyInstructionMemory::new(&f);
+    let mut mem = DataMemory::new(1024);
+    let mut reg = RegisterFile::new(0x0);
+    mem.set_write_latency(write_latency);
+    mem.set_store_buffer(store_buffer);
+
+    let halt_addr = match ca {
+        true => ca_simulator::run(&insns, &mut mem, &mut reg).halt_addr,
+        false => ia_simulator::run(&insns, &mut mem, &mut reg),
+    };
+    assert_eq!(halt_addr, 0x88);
+
+    (reg, mem)
+}
+
+
+/// Runs the program on the pipelined model and returns the buffer's stats.
+fn run_buffered(
+    write_latency: u32,
+    depth: usize,
+    policy: DrainPolicy,
+    combining: bool,
+) -> StoreBufferStats {
+    let mut store_buffer = StoreBuffer::new(depth, policy);
+    store_buffer.set_combining(combining);
+    let (reg, mem) = run(true, write_latency, Some(store_buffer));
+
+    check_program(&reg, &mem);
+    mem.store_buffer().unwrap().stats
+}
+
+
+/// The program stores and immediately reloads an array, assembles a word
+/// from byte stores, fences, and sums the array back.
+fn check_program(reg: &RegisterFile, mem: &DataMemory) {
+    for i in 0..16 {
+        assert_eq!(mem.read(0x100 + 4 * i, 4), Ok(i as u32));
+    }
+    assert_eq!(reg.gpr[13].read(), 120); // sum of reloaded stores
+    assert_eq!(reg.gpr[14].read(), 0x44332211); // bytes assembled
+    assert_eq!(mem.read(0x180, 4), Ok(0x44332211));
+    assert_eq!(reg.gpr[15].read(), 0x2211);
+    assert_eq!(mem.read(0x184, 4), Ok(0x2211));
+    assert_eq!(reg.gpr[16].read(), 120); // sum after the fence
+}
+
+
+#[test]
+fn test_ia_simulator_store_buffer_program() {
+    let (reg, mem) = run(false, 0, None);
+    check_program(&reg, &mem);
+}
+
+
+#[test]
+fn test_ca_simulator_blocking_stores() {
+    let (reg, mem) = run(true, 4, None);
+    check_program(&reg, &mem);
+    assert!(mem.store_buffer().is_none());
+}
+
+
+/// An eager buffer deep enough for the store rate never stalls.
+#[test]
+fn test_ca_simulator_store_buffer_eager() {
+    let stats = run_buffered(4, 8, DrainPolicy::Eager, false);
+
+    assert_eq!(stats.stores, 21);
+    assert_eq!(stats.forwarded_loads, 18);
+    assert_eq!(stats.blocking_stall_cycles, 21 * 4);
+    assert!(stats.stalls_saved() > 0);
+}
+
+
+/// A lazy buffer combines the byte stores and drains at the FENCE.
+#[test]
+fn test_ca_simulator_store_buffer_lazy_combining() {
+    let stats = run_buffered(2, 32, DrainPolicy::Lazy, true);
+
+    assert_eq!(stats.combined, 3);
+    assert_eq!(stats.full_stall_cycles, 0);
+    assert_eq!(stats.drain_stall_cycles, 17 * 3); // fence
+}
+
+
+#[test]
+fn test_ca_simulator_store_buffer_watermark() {
+    let stats = run_buffered(4, 4, DrainPolicy::Watermark(2), false);
+    assert!(stats.stalls_saved() > 0);
+}
+
+
+/// A single-entry buffer fills up and stalls the MEM stage.
+#[test]
+fn test_ca_simulator_store_buffer_full() {
+    let stats = run_buffered(4, 1, DrainPolicy::Lazy, false);
+
+    assert!(stats.full_stall_cycles > 0);
+    assert!(stats.stalls_saved() < 0);
+}